@@ -1,6 +1,9 @@
 use std;
+use std::cell::RefCell;
 use std::io::Read;
+use std::os::unix::process::CommandExt;
 use std::process;
+use std::rc::Rc;
 use std::sync::atomic::{compiler_fence, Ordering};
 
 use filter::*;
@@ -11,6 +14,7 @@ use FlagFilter;
 use rust_htslib::bam;
 use rust_htslib::bam::Read as BamRead;
 
+use nix::sys::signal;
 use nix::sys::stat;
 use nix::unistd;
 use tempdir::TempDir;
@@ -35,14 +39,24 @@ pub trait NamedBamReader {
 
     // Number of reads that were detected
     fn num_detected_primary_alignments(&self) -> u64;
+
+    // Restrict subsequent reads to the records overlapping a region of a
+    // reference sequence. Returns false if the reader cannot fetch regions,
+    // in which case all records continue to be read.
+    fn fetch(&mut self, _tid: u32, _start: u64, _end: u64) -> bool {
+        false
+    }
 }
 
 pub trait NamedBamReaderGenerator<T> {
     // For readers that map, start the process of mapping
     fn start(self) -> T;
+
+    // Name of the stoit, known before any mapping is started
+    fn stoit_name(&self) -> &str;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum MappingProgram {
     BWA_MEM,
@@ -53,11 +67,32 @@ pub enum MappingProgram {
     MINIMAP2_HIFI,
     MINIMAP2_NO_PRESET,
     STROBEALIGN,
+    BOWTIE2,
+}
+
+/// Parse the name of a mapping program as given to --mapper.
+pub fn parse_mapping_program_name(name: &str) -> Option<MappingProgram> {
+    match name {
+        "bwa-mem" => Some(MappingProgram::BWA_MEM),
+        "bwa-mem2" => Some(MappingProgram::BWA_MEM2),
+        "minimap2-sr" => Some(MappingProgram::MINIMAP2_SR),
+        "minimap2-ont" => Some(MappingProgram::MINIMAP2_ONT),
+        "minimap2-pb" => Some(MappingProgram::MINIMAP2_PB),
+        "minimap2-hifi" => Some(MappingProgram::MINIMAP2_HIFI),
+        "minimap2-no-preset" => Some(MappingProgram::MINIMAP2_NO_PRESET),
+        "strobealign" => Some(MappingProgram::STROBEALIGN),
+        "bowtie2" => Some(MappingProgram::BOWTIE2),
+        _ => None,
+    }
 }
 
 pub struct BamFileNamedReader {
     stoit_name: String,
     bam_reader: bam::Reader,
+    bam_path: String,
+    // Reader used once regions are fetched using the BAM index
+    indexed_reader: Option<bam::IndexedReader>,
+    num_threads: usize,
     num_detected_primary_alignments: u64,
 }
 
@@ -66,11 +101,17 @@ impl NamedBamReader for BamFileNamedReader {
         &(self.stoit_name)
     }
     fn read(&mut self, record: &mut bam::record::Record) -> Option<HtslibResult<()>> {
-        let res = self.bam_reader.read(record);
-        if res == Some(Ok(())) && !record.is_secondary() && !record.is_supplementary() {
-            self.num_detected_primary_alignments += 1;
+        match self.indexed_reader {
+            // Primary alignments were counted when the index was opened
+            Some(ref mut reader) => reader.read(record),
+            None => {
+                let res = self.bam_reader.read(record);
+                if res == Some(Ok(())) && !record.is_secondary() && !record.is_supplementary() {
+                    self.num_detected_primary_alignments += 1;
+                }
+                res
+            }
         }
-        res
     }
     fn header(&self) -> &bam::HeaderView {
         self.bam_reader.header()
@@ -78,6 +119,7 @@ impl NamedBamReader for BamFileNamedReader {
     fn finish(self) {}
 
     fn set_threads(&mut self, n_threads: usize) {
+        self.num_threads = n_threads;
         if n_threads > 1 {
             self.bam_reader.set_threads(n_threads - 1).unwrap();
         }
@@ -86,14 +128,65 @@ impl NamedBamReader for BamFileNamedReader {
     fn num_detected_primary_alignments(&self) -> u64 {
         self.num_detected_primary_alignments
     }
+
+    fn fetch(&mut self, tid: u32, start: u64, end: u64) -> bool {
+        if self.indexed_reader.is_none() {
+            let mut reader = match bam::IndexedReader::from_path(&self.bam_path) {
+                Ok(reader) => reader,
+                Err(_) => {
+                    debug!("No index found for BAM file {}", self.bam_path);
+                    return false;
+                }
+            };
+            if self.num_threads > 1 {
+                reader.set_threads(self.num_threads - 1).unwrap();
+            }
+            // Only reads overlapping the fetched regions are read, so count
+            // the primary alignments in the rest of the file as they would
+            // be counted were the whole file read.
+            let mut record = bam::Record::new();
+            while let Some(result) = self.bam_reader.read(&mut record) {
+                match result {
+                    Ok(()) => {
+                        if !record.is_secondary() && !record.is_supplementary() {
+                            self.num_detected_primary_alignments += 1;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error reading BAM file {}: {}", self.bam_path, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            self.indexed_reader = Some(reader);
+        }
+        match self
+            .indexed_reader
+            .as_mut()
+            .unwrap()
+            .fetch((tid as i32, start as i64, end as i64))
+        {
+            Ok(()) => true,
+            Err(e) => {
+                error!(
+                    "Failed to fetch region {}:{}-{} from {}: {}",
+                    tid, start, end, self.bam_path, e
+                );
+                process::exit(1);
+            }
+        }
+    }
 }
 
 impl NamedBamReaderGenerator<BamFileNamedReader> for BamFileNamedReader {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> BamFileNamedReader {
         BamFileNamedReader {
-            stoit_name: self.stoit_name,
-            bam_reader: self.bam_reader,
             num_detected_primary_alignments: 0,
+            ..self
         }
     }
 }
@@ -114,7 +207,7 @@ pub struct StreamingNamedBamReaderGenerator {
     stoit_name: String,
     tempdir: TempDir,
     fifo_path: std::path::PathBuf,
-    pre_processes: Vec<std::process::Command>,
+    pre_processes: MappingProcesses,
     command_strings: Vec<String>,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
@@ -122,13 +215,12 @@ pub struct StreamingNamedBamReaderGenerator {
 }
 
 impl NamedBamReaderGenerator<StreamingNamedBamReader> for StreamingNamedBamReaderGenerator {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> StreamingNamedBamReader {
-        debug!("Starting mapping processes");
-        let mut processes = vec![];
-        for (i, mut preprocess) in self.pre_processes.into_iter().enumerate() {
-            debug!("Running mapping command: {}", self.command_strings[i]);
-            processes.push(preprocess.spawn().expect("Unable to execute bash"));
-        }
+        let processes = self.pre_processes.spawn();
         let bam_reader = match bam::Reader::from_path(&self.fifo_path) {
             Ok(reader) => reader,
             Err(upstream_error) => {
@@ -216,6 +308,153 @@ pub fn complete_processes(
     debug!("After fence, for tempdir {:?}", tempdir);
 }
 
+/// The mapping processes of one sample, which are spawned when its generator
+/// is started, or earlier when scheduled by a MappingScheduler.
+pub struct MappingProcesses {
+    state: MappingProcessesState,
+}
+
+enum MappingProcessesState {
+    Unscheduled(Vec<std::process::Command>),
+    Scheduled(ScheduledMapping),
+}
+
+impl MappingProcesses {
+    fn new(commands: Vec<std::process::Command>) -> MappingProcesses {
+        MappingProcesses {
+            state: MappingProcessesState::Unscheduled(commands),
+        }
+    }
+
+    fn spawn(self) -> Vec<std::process::Child> {
+        match self.state {
+            MappingProcessesState::Unscheduled(commands) => spawn_mapping_commands(commands),
+            MappingProcessesState::Scheduled(scheduled) => scheduled.take_processes(),
+        }
+    }
+}
+
+fn spawn_mapping_commands(commands: Vec<std::process::Command>) -> Vec<std::process::Child> {
+    debug!("Starting mapping processes");
+    commands
+        .into_iter()
+        .map(|mut command| {
+            debug!("Running mapping command: {:?}", command);
+            command.spawn().expect("Unable to execute bash")
+        })
+        .collect()
+}
+
+/// Starts the mapping of several samples concurrently, so that later samples
+/// are already being mapped while the coverage of earlier samples is
+/// calculated. When the generator of a sample is started, the mapping of that
+/// sample and of the samples following it is started, such that at most
+/// concurrent_mappings samples are mapped at once.
+pub struct MappingScheduler {
+    pending: Vec<Option<Vec<std::process::Command>>>,
+    started: Vec<Option<Vec<std::process::Child>>>,
+    concurrent_mappings: usize,
+}
+
+impl MappingScheduler {
+    /// Schedule the mapping of each generator, which must be started in
+    /// order.
+    pub fn schedule<G: ScheduledMappingGenerator>(
+        generators: &mut [G],
+        concurrent_mappings: usize,
+    ) {
+        if concurrent_mappings <= 1 {
+            return;
+        }
+        let scheduler = Rc::new(RefCell::new(MappingScheduler {
+            pending: vec![],
+            started: vec![],
+            concurrent_mappings,
+        }));
+        for (index, generator) in generators.iter_mut().enumerate() {
+            let processes = std::mem::replace(
+                &mut generator.mapping_processes().state,
+                MappingProcessesState::Scheduled(ScheduledMapping {
+                    scheduler: scheduler.clone(),
+                    index,
+                    taken: false,
+                }),
+            );
+            let mut scheduler = scheduler.borrow_mut();
+            match processes {
+                MappingProcessesState::Unscheduled(commands) => {
+                    scheduler.pending.push(Some(commands))
+                }
+                MappingProcessesState::Scheduled(_) => panic!("Mapping was scheduled twice"),
+            }
+            scheduler.started.push(None);
+        }
+    }
+
+    fn take_processes(&mut self, index: usize) -> Vec<std::process::Child> {
+        let end = std::cmp::min(index + self.concurrent_mappings, self.pending.len());
+        for i in index..end {
+            if let Some(commands) = self.pending[i].take() {
+                self.started[i] = Some(spawn_mapping_commands(commands));
+            }
+        }
+        self.started[index]
+            .take()
+            .expect("Mapping processes were taken twice")
+    }
+
+    fn cancel(&mut self, index: usize) {
+        self.pending[index] = None;
+        if let Some(processes) = self.started[index].take() {
+            debug!("Killing mapping processes of skipped sample");
+            for mut process in processes {
+                signal::killpg(unistd::Pid::from_raw(process.id() as i32), signal::SIGKILL).ok();
+                process.wait().ok();
+            }
+        }
+    }
+}
+
+/// The position of a sample's mapping in a MappingScheduler.
+struct ScheduledMapping {
+    scheduler: Rc<RefCell<MappingScheduler>>,
+    index: usize,
+    taken: bool,
+}
+
+impl ScheduledMapping {
+    fn take_processes(mut self) -> Vec<std::process::Child> {
+        self.taken = true;
+        self.scheduler.borrow_mut().take_processes(self.index)
+    }
+}
+
+impl Drop for ScheduledMapping {
+    fn drop(&mut self) {
+        // The generator was dropped without being started
+        if !self.taken {
+            self.scheduler.borrow_mut().cancel(self.index)
+        }
+    }
+}
+
+/// Generators whose mapping can be scheduled by a MappingScheduler.
+pub trait ScheduledMappingGenerator {
+    fn mapping_processes(&mut self) -> &mut MappingProcesses;
+}
+
+impl ScheduledMappingGenerator for StreamingNamedBamReaderGenerator {
+    fn mapping_processes(&mut self) -> &mut MappingProcesses {
+        &mut self.pre_processes
+    }
+}
+
+impl ScheduledMappingGenerator for StreamingFilteredNamedBamReaderGenerator {
+    fn mapping_processes(&mut self) -> &mut MappingProcesses {
+        &mut self.pre_processes
+    }
+}
+
 impl NamedBamReader for StreamingNamedBamReader {
     fn name(&self) -> &str {
         &(self.stoit_name)
@@ -284,6 +523,9 @@ pub fn generate_named_bam_readers_from_bam_files(bam_paths: Vec<&str>) -> Vec<Ba
                 .to_string(),
             bam_reader: bam::Reader::from_path(path)
                 .unwrap_or_else(|_| panic!("Unable to find BAM file {}", path)),
+            bam_path: path.to_string(),
+            indexed_reader: None,
+            num_threads: 1,
             num_detected_primary_alignments: 0,
         })
         .collect()
@@ -301,6 +543,7 @@ pub fn generate_named_bam_readers_from_reads(
     discard_unmapped: bool,
     mapping_options: Option<&str>,
     include_reference_in_stoit_name: bool,
+    sample_name: Option<&str>,
 ) -> StreamingNamedBamReaderGenerator {
     let tmp_dir = TempDir::new("coverm_fifo").expect("Unable to create temporary directory");
     let fifo_path = tmp_dir.path().join("foo.pipe");
@@ -361,11 +604,16 @@ pub fn generate_named_bam_readers_from_reads(
         .prefix("coverm-make-samtools-sort")
         .tempfile_in(tmp_dir.path())
         .expect("Failed to create tempfile as samtools sort prefix");
+    // Holding the FIFO open for reading and writing means that writing to it
+    // does not block until CoverM reads it, so mapping can be started before
+    // the coverage of previous samples has been calculated.
     let cmd_string = format!(
         "set -e -o pipefail; \
+         exec 3<>{:?}; \
          {} 2>{} \
          | samtools sort -T '{}' -l0 -@ {} 2>{} \
          {}",
+        fifo_path,
         // Mapping program
         mapping_command,
         mapping_log
@@ -390,10 +638,16 @@ pub fn generate_named_bam_readers_from_reads(
     cmd.arg("-c")
         .arg(&cmd_string)
         .stderr(std::process::Stdio::piped());
+    // Run in its own process group, so that the whole pipeline can be killed
+    // if its sample is skipped after mapping has been started.
+    cmd.process_group(0);
 
     // Required because of https://github.com/wwood/CoverM/issues/58
     let minimap2_log_file_index = match mapping_program {
-        MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 | MappingProgram::STROBEALIGN => None,
+        MappingProgram::BWA_MEM
+        | MappingProgram::BWA_MEM2
+        | MappingProgram::STROBEALIGN
+        | MappingProgram::BOWTIE2 => None,
         // Required because of https://github.com/lh3/minimap2/issues/527
         MappingProgram::MINIMAP2_SR
         | MappingProgram::MINIMAP2_ONT
@@ -423,17 +677,20 @@ pub fn generate_named_bam_readers_from_reads(
                 + "/"
         }
         false => "".to_string(),
-    } + std::path::Path::new(read1_path)
-        .file_name()
-        .expect("Unable to convert read1 name to file name")
-        .to_str()
-        .expect("Unable to covert file name into str");
+    } + match sample_name {
+        Some(name) => name,
+        None => std::path::Path::new(read1_path)
+            .file_name()
+            .expect("Unable to convert read1 name to file name")
+            .to_str()
+            .expect("Unable to covert file name into str"),
+    };
 
     StreamingNamedBamReaderGenerator {
         stoit_name,
         tempdir: tmp_dir,
         fifo_path,
-        pre_processes: vec![cmd],
+        pre_processes: MappingProcesses::new(vec![cmd]),
         command_strings: vec![format!("bash -c \"{}\"", cmd_string)],
         log_file_descriptions: log_descriptions,
         log_files,
@@ -471,6 +728,10 @@ impl NamedBamReader for FilteredBamReader {
 }
 
 impl NamedBamReaderGenerator<FilteredBamReader> for FilteredBamReader {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> FilteredBamReader {
         FilteredBamReader {
             stoit_name: self.stoit_name,
@@ -486,9 +747,16 @@ pub fn generate_filtered_bam_readers_from_bam_files(
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
+    pair_constraints: PairConstraints,
 ) -> Vec<FilteredBamReader> {
     let mut generators: Vec<FilteredBamReader> = vec![];
 
@@ -511,9 +779,16 @@ pub fn generate_filtered_bam_readers_from_bam_files(
                 min_aligned_length_single,
                 min_percent_identity_single,
                 min_aligned_percent_single,
+                min_mapq_single,
+                min_as_xs_difference_single,
+                max_clipped_fraction_single,
                 min_aligned_length_pair,
                 min_percent_identity_pair,
                 min_aligned_percent_pair,
+                min_mapq_pair,
+                min_as_xs_difference_pair,
+                max_clipped_fraction_pair,
+                pair_constraints,
                 true,
             ),
         };
@@ -538,15 +813,22 @@ pub struct StreamingFilteredNamedBamReaderGenerator {
     stoit_name: String,
     tempdir: TempDir,
     fifo_path: std::path::PathBuf,
-    pre_processes: Vec<std::process::Command>,
+    pre_processes: MappingProcesses,
     command_strings: Vec<String>,
     flag_filters: FlagFilter,
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
+    pair_constraints: PairConstraints,
     log_file_descriptions: Vec<String>,
     log_files: Vec<tempfile::NamedTempFile>,
 }
@@ -554,12 +836,12 @@ pub struct StreamingFilteredNamedBamReaderGenerator {
 impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader>
     for StreamingFilteredNamedBamReaderGenerator
 {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> StreamingFilteredNamedBamReader {
-        debug!("Starting mapping processes");
-        let mut processes = vec![];
-        for mut preprocess in self.pre_processes {
-            processes.push(preprocess.spawn().expect("Unable to execute bash"));
-        }
+        let processes = self.pre_processes.spawn();
         let bam_reader = match bam::Reader::from_path(&self.fifo_path) {
             Ok(reader) => reader,
             Err(upstream_error) => {
@@ -584,9 +866,16 @@ impl NamedBamReaderGenerator<StreamingFilteredNamedBamReader>
             self.min_aligned_length_single,
             self.min_percent_identity_single,
             self.min_aligned_percent_single,
+            self.min_mapq_single,
+            self.min_as_xs_difference_single,
+            self.max_clipped_fraction_single,
             self.min_aligned_length_pair,
             self.min_percent_identity_pair,
             self.min_aligned_percent_pair,
+            self.min_mapq_pair,
+            self.min_as_xs_difference_pair,
+            self.max_clipped_fraction_pair,
+            self.pair_constraints,
             true,
         );
         StreamingFilteredNamedBamReader {
@@ -651,12 +940,20 @@ pub fn generate_filtered_named_bam_readers_from_reads(
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
+    pair_constraints: PairConstraints,
     bwa_options: Option<&str>,
     discard_unmapped: bool,
     include_reference_in_stoit_name: bool,
+    sample_name: Option<&str>,
 ) -> StreamingFilteredNamedBamReaderGenerator {
     let streaming = generate_named_bam_readers_from_reads(
         mapping_program,
@@ -669,6 +966,7 @@ pub fn generate_filtered_named_bam_readers_from_reads(
         discard_unmapped,
         bwa_options,
         include_reference_in_stoit_name,
+        sample_name,
     );
     StreamingFilteredNamedBamReaderGenerator {
         stoit_name: streaming.stoit_name,
@@ -682,9 +980,16 @@ pub fn generate_filtered_named_bam_readers_from_reads(
         min_aligned_length_single,
         min_percent_identity_single,
         min_aligned_percent_single,
+        min_mapq_single,
+        min_as_xs_difference_single,
+        max_clipped_fraction_single,
         min_aligned_length_pair,
         min_percent_identity_pair,
         min_aligned_percent_pair,
+        min_mapq_pair,
+        min_as_xs_difference_pair,
+        max_clipped_fraction_pair,
+        pair_constraints,
     }
 }
 
@@ -817,6 +1122,10 @@ pub fn generate_bam_maker_generator_from_reads(
 }
 
 impl NamedBamReaderGenerator<NamedBamMaker> for NamedBamMakerGenerator {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> NamedBamMaker {
         debug!("Starting mapping processes");
         let mut processes = vec![];
@@ -873,14 +1182,35 @@ pub fn build_mapping_command(
             ReadFormat::Interleaved => "--interleaved",
             ReadFormat::Coupled | ReadFormat::Single => "",
         },
+        MappingProgram::BOWTIE2 => match read_format {
+            ReadFormat::Interleaved => "--interleaved",
+            ReadFormat::Coupled => "-1",
+            ReadFormat::Single => "-U",
+        },
     };
 
     let read_params2 = match read_format {
         ReadFormat::Interleaved => format!("'{}'", read1_path),
-        ReadFormat::Coupled => format!("'{}' '{}'", read1_path, read2_path.unwrap()),
+        ReadFormat::Coupled => match mapping_program {
+            MappingProgram::BOWTIE2 => format!("'{}' -2 '{}'", read1_path, read2_path.unwrap()),
+            _ => format!("'{}' '{}'", read1_path, read2_path.unwrap()),
+        },
         ReadFormat::Single => format!("'{}'", read1_path),
     };
 
+    // bowtie2 differs from the other mappers in how threads and the index
+    // are specified.
+    if let MappingProgram::BOWTIE2 = mapping_program {
+        return format!(
+            "bowtie2 {} -p {} -x '{}' {} {}",
+            mapping_options.unwrap_or(""),
+            threads,
+            reference.index_path(),
+            read_params1,
+            read_params2
+        );
+    }
+
     format!(
         "{} {} -t {} {} {} '{}' {}",
         match mapping_program {
@@ -906,7 +1236,8 @@ pub fn build_mapping_command(
                     match mapping_program {
                         MappingProgram::BWA_MEM
                         | MappingProgram::BWA_MEM2
-                        | MappingProgram::STROBEALIGN => unreachable!(),
+                        | MappingProgram::STROBEALIGN
+                        | MappingProgram::BOWTIE2 => unreachable!(),
                         MappingProgram::MINIMAP2_SR => "-x sr",
                         MappingProgram::MINIMAP2_ONT => "-x map-ont",
                         MappingProgram::MINIMAP2_HIFI => "-x map-hifi",
@@ -1,16 +1,24 @@
 extern crate coverm;
 use coverm::bam_generator::*;
+use coverm::checkpoint::*;
 use coverm::cli::*;
+use coverm::compression_extension;
 use coverm::coverage_printer::*;
 use coverm::coverage_takers::*;
 use coverm::external_command_checker;
 use coverm::filter;
+use coverm::filter::{PairConstraints, PairOrientation};
 use coverm::genome_exclusion::*;
 use coverm::genomes_and_contigs::GenomesAndContigs;
 use coverm::mapping_index_maintenance::check_reference_existence;
 use coverm::mapping_parameters::*;
+use coverm::merge::*;
+use coverm::microdiversity::MicrodiversityStatistic;
 use coverm::mosdepth_genome_coverage_estimators::*;
+use coverm::provenance::write_provenance;
+use coverm::sample_sheet::{SampleReadFilters, SampleSheet};
 use coverm::shard_bam_reader::*;
+use coverm::strandedness::Strandedness;
 use coverm::FlagFilter;
 use coverm::OutputWriter;
 use coverm::CONCATENATED_FASTA_FILE_SEPARATOR;
@@ -24,6 +32,7 @@ use rust_htslib::bam::Read;
 
 use std::collections::HashSet;
 use std::env;
+use std::io::Write;
 use std::process;
 use std::str;
 
@@ -56,7 +65,7 @@ fn main() {
             let m = matches.subcommand_matches("genome").unwrap();
             bird_tool_utils::clap_utils::print_full_help_if_needed(m, genome_full_help());
             set_log_level(m, true);
-            print_stream = OutputWriter::generate(m.get_one::<String>("output-file").map(|x| &**x));
+            print_stream = coverage_output_writer(m);
 
             let genome_names_content: Vec<u8>;
 
@@ -157,7 +166,17 @@ fn main() {
                 // Associate genomes and contig names, if required
                 let genomes_and_contigs_option = parse_all_genome_definitions(m);
 
-                if filter_params.doing_filtering() {
+                if m.get_one::<String>("multimapper-resolution").unwrap() == "em" {
+                    run_genome_em(
+                        bam_files,
+                        m,
+                        &mut estimators_and_taker,
+                        separator,
+                        &genomes_and_contigs_option,
+                        &filter_params,
+                        &mut print_stream,
+                    );
+                } else if filter_params.doing_filtering() {
                     run_genome(
                         coverm::bam_generator::generate_filtered_bam_readers_from_bam_files(
                             bam_files,
@@ -165,9 +184,16 @@ fn main() {
                             filter_params.min_aligned_length_single,
                             filter_params.min_percent_identity_single,
                             filter_params.min_aligned_percent_single,
+                            filter_params.min_mapq_single,
+                            filter_params.min_as_xs_difference_single,
+                            filter_params.max_clipped_fraction_single,
                             filter_params.min_aligned_length_pair,
                             filter_params.min_percent_identity_pair,
                             filter_params.min_aligned_percent_pair,
+                            filter_params.min_mapq_pair,
+                            filter_params.min_as_xs_difference_pair,
+                            filter_params.max_clipped_fraction_pair,
+                            filter_params.pair_constraints,
                         ),
                         m,
                         &mut estimators_and_taker,
@@ -229,7 +255,16 @@ fn main() {
                     );
                 }
             } else {
+                if m.get_one::<String>("multimapper-resolution").unwrap() == "em" {
+                    error!(
+                        "EM multimapper resolution requires --bam-files, since each BAM file \
+                        is read three times. BAM files can be generated with 'coverm make', \
+                        taking care to retain secondary alignments."
+                    );
+                    process::exit(1);
+                }
                 let mapping_program = parse_mapping_program(m);
+                let sample_sheet = SampleSheet::generate_from_clap(m);
                 external_command_checker::check_for_samtools();
 
                 // If genomes defined by file, then potentially dereplicate. If
@@ -329,12 +364,17 @@ fn main() {
                         }
                     };
 
-                if filter_params.doing_filtering() {
+                if filter_params.doing_filtering()
+                    || sample_sheet
+                        .as_ref()
+                        .map_or(false, |s| s.has_read_filters())
+                {
                     debug!("Mapping and filtering..");
                     let generator_sets = get_streamed_filtered_bam_readers(
                         m,
                         mapping_program,
                         &concatenated_genomes,
+                        &sample_sheet,
                         &filter_params,
                     );
                     let mut all_generators = vec![];
@@ -361,6 +401,7 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_filter_non_type.unwrap(),
                                 ),
                                 m,
@@ -376,6 +417,7 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_filter_separator_type.unwrap(),
                                 ),
                                 m,
@@ -391,6 +433,7 @@ fn main() {
                                     m,
                                     mapping_program,
                                     &concatenated_genomes,
+                                    &sample_sheet,
                                     &genome_exclusion_genomes_and_contigs.unwrap(),
                                 ),
                                 m,
@@ -402,8 +445,12 @@ fn main() {
                         }
                     }
                 } else {
-                    let generator_sets =
-                        get_streamed_bam_readers(m, mapping_program, &concatenated_genomes);
+                    let generator_sets = get_streamed_bam_readers(
+                        m,
+                        mapping_program,
+                        &concatenated_genomes,
+                        &sample_sheet,
+                    );
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
                     for set in generator_sets {
@@ -422,6 +469,7 @@ fn main() {
                     );
                 };
             }
+            print_stream.finish();
         }
         Some("filter") => {
             let m = matches.subcommand_matches("filter").unwrap();
@@ -463,9 +511,16 @@ fn main() {
                     filter_params.min_aligned_length_single,
                     filter_params.min_percent_identity_single,
                     filter_params.min_aligned_percent_single,
+                    filter_params.min_mapq_single,
+                    filter_params.min_as_xs_difference_single,
+                    filter_params.max_clipped_fraction_single,
                     filter_params.min_aligned_length_pair,
                     filter_params.min_percent_identity_pair,
                     filter_params.min_aligned_percent_pair,
+                    filter_params.min_mapq_pair,
+                    filter_params.min_as_xs_difference_pair,
+                    filter_params.max_clipped_fraction_pair,
+                    filter_params.pair_constraints,
                     !m.get_flag("inverse"),
                 );
 
@@ -485,25 +540,52 @@ fn main() {
                     writer.write(&record).expect("Failed to write BAM record");
                 }
             }
+            let sample_names: Vec<String> = bam_files.iter().map(|b| b.to_string()).collect();
+            write_provenance(m, &sample_names, None);
         }
         Some("contig") => {
             let m = matches.subcommand_matches("contig").unwrap();
             bird_tool_utils::clap_utils::print_full_help_if_needed(m, contig_full_help());
             set_log_level(m, true);
-            let print_zeros = !m.get_flag("no-zeros");
+            // VAMB requires a row for every contig
+            let print_zeros = match binner_method(m) {
+                Some("vamb") => {
+                    if m.get_flag("no-zeros") {
+                        warn!("Ignoring --no-zeros since the vamb method prints every contig");
+                    }
+                    true
+                }
+                _ => !m.get_flag("no-zeros"),
+            };
 
-            // Add metabat filtering params since we are running contig
+            // Add binner filtering params since we are running contig
             let mut filter_params1 = FilterParameters::generate_from_clap(m);
-            filter_params1.add_metabat_filtering_if_required(m);
+            filter_params1.add_binner_filtering_if_required(m);
             let filter_params = filter_params1;
 
             let threads = *m.get_one::<u16>("threads").unwrap();
-            print_stream = OutputWriter::generate(m.get_one::<String>("output-file").map(|x| &**x));
+            print_stream = coverage_output_writer(m);
 
             let mut estimators_and_taker =
                 EstimatorsAndTaker::generate_from_clap(m, print_stream.clone());
-            estimators_and_taker =
-                estimators_and_taker.print_headers("Contig", print_stream.clone());
+            if m.contains_id("window-size") {
+                writeln!(
+                    print_stream,
+                    "Sample\tContig\tStart\tEnd\tMean\tVariance\tCovered Fraction"
+                )
+                .unwrap();
+            } else {
+                estimators_and_taker = estimators_and_taker.print_headers(
+                    if m.contains_id("regions") {
+                        "Region"
+                    } else if m.contains_id("annotation") {
+                        "Feature"
+                    } else {
+                        "Contig"
+                    },
+                    print_stream.clone(),
+                );
+            }
 
             if m.contains_id("bam-files") {
                 let bam_files: Vec<&str> = m
@@ -519,11 +601,19 @@ fn main() {
                             filter_params.min_aligned_length_single,
                             filter_params.min_percent_identity_single,
                             filter_params.min_aligned_percent_single,
+                            filter_params.min_mapq_single,
+                            filter_params.min_as_xs_difference_single,
+                            filter_params.max_clipped_fraction_single,
                             filter_params.min_aligned_length_pair,
                             filter_params.min_percent_identity_pair,
                             filter_params.min_aligned_percent_pair,
+                            filter_params.min_mapq_pair,
+                            filter_params.min_as_xs_difference_pair,
+                            filter_params.max_clipped_fraction_pair,
+                            filter_params.pair_constraints,
                         );
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         bam_readers,
                         print_zeros,
@@ -540,6 +630,7 @@ fn main() {
                             &NoExclusionGenomeFilter {},
                         );
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         bam_readers,
                         print_zeros,
@@ -551,6 +642,7 @@ fn main() {
                     let bam_readers =
                         coverm::bam_generator::generate_named_bam_readers_from_bam_files(bam_files);
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         bam_readers,
                         print_zeros,
@@ -561,14 +653,20 @@ fn main() {
                 }
             } else {
                 let mapping_program = parse_mapping_program(m);
+                let sample_sheet = SampleSheet::generate_from_clap(m);
                 external_command_checker::check_for_samtools();
 
-                if filter_params.doing_filtering() {
+                if filter_params.doing_filtering()
+                    || sample_sheet
+                        .as_ref()
+                        .map_or(false, |s| s.has_read_filters())
+                {
                     debug!("Filtering..");
                     let generator_sets = get_streamed_filtered_bam_readers(
                         m,
                         mapping_program,
                         &None,
+                        &sample_sheet,
                         &filter_params,
                     );
                     let mut all_generators = vec![];
@@ -581,6 +679,7 @@ fn main() {
                     }
                     debug!("Finished collecting generators.");
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         all_generators,
                         print_zeros,
@@ -593,9 +692,11 @@ fn main() {
                         m,
                         mapping_program,
                         &None,
+                        &sample_sheet,
                         &NoExclusionGenomeFilter {},
                     );
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         generator_sets,
                         print_zeros,
@@ -605,7 +706,8 @@ fn main() {
                     );
                 } else {
                     debug!("Not filtering..");
-                    let generator_sets = get_streamed_bam_readers(m, mapping_program, &None);
+                    let generator_sets =
+                        get_streamed_bam_readers(m, mapping_program, &None, &sample_sheet);
                     let mut all_generators = vec![];
                     let mut indices = vec![]; // Prevent indices from being dropped
                     for set in generator_sets {
@@ -615,6 +717,7 @@ fn main() {
                         }
                     }
                     run_contig(
+                        m,
                         &mut estimators_and_taker,
                         all_generators,
                         print_zeros,
@@ -624,6 +727,7 @@ fn main() {
                     );
                 }
             }
+            print_stream.finish();
         }
         Some("make") => {
             let m = matches.subcommand_matches("make").unwrap();
@@ -635,13 +739,17 @@ fn main() {
 
             let output_directory = m.get_one::<String>("output-directory").unwrap();
             setup_bam_cache_directory(output_directory);
-            let params = MappingParameters::generate_from_clap(m, mapping_program, &None);
+            let sample_sheet = SampleSheet::generate_from_clap(m);
+            let params =
+                MappingParameters::generate_from_clap(m, mapping_program, &None, &sample_sheet);
+            check_for_mapping_programs(&params);
             let mut generator_sets = vec![];
+            let mut sample_names = vec![];
             let discard_unmapped_reads = m.get_flag("discard-unmapped");
 
             for reference_wise_params in params {
                 let mut bam_readers = vec![];
-                let index = setup_mapping_index(&reference_wise_params, m, mapping_program);
+                let index = setup_mapping_index(&reference_wise_params, m);
 
                 // Ensure there is no duplication in output files e.g.
                 // https://github.com/wwood/CoverM/issues/128
@@ -651,7 +759,7 @@ fn main() {
                         generate_cached_bam_file_name(output_directory, p.reference, p.read1);
                     bam_readers.push(
                         coverm::bam_generator::generate_bam_maker_generator_from_reads(
-                            mapping_program,
+                            p.mapping_program,
                             index.as_ref(),
                             p.read1,
                             p.read2,
@@ -659,13 +767,14 @@ fn main() {
                             p.threads,
                             &name.clone(),
                             discard_unmapped_reads,
-                            p.mapping_options,
+                            p.mapping_options.as_deref(),
                         ),
                     );
                     if !unique_names.insert(name.clone()) {
                         error!("Duplicate output file name: {}", name);
                         std::process::exit(1);
                     }
+                    sample_names.push(name);
                 }
 
                 debug!("Finished BAM setup");
@@ -684,6 +793,25 @@ fn main() {
                     i += 1;
                 }
             }
+            write_provenance(m, &sample_names, None);
+        }
+        Some("merge") => {
+            let m = matches.subcommand_matches("merge").unwrap();
+            bird_tool_utils::clap_utils::print_full_help_if_needed(m, merge_full_help());
+            set_log_level(m, true);
+
+            let tables: Vec<CoverageTable> = m
+                .get_many::<String>("input-files")
+                .unwrap()
+                .map(|path| CoverageTable::from_file(path))
+                .collect();
+            print_stream = OutputWriter::generate(m.get_one::<String>("output-file").map(|x| &**x));
+            merge_coverage_tables(
+                &tables,
+                m.get_one::<String>("output-format").unwrap(),
+                &mut print_stream,
+            );
+            print_stream.finish();
         }
         Some("shell-completion") => {
             let m = matches.subcommand_matches("shell-completion").unwrap();
@@ -715,14 +843,18 @@ fn main() {
 fn setup_mapping_index(
     reference_wise_params: &SingleReferenceMappingParameters,
     m: &clap::ArgMatches,
-    mapping_program: MappingProgram,
 ) -> Box<dyn coverm::mapping_index_maintenance::MappingIndex> {
+    let mapping_program = reference_wise_params.mapping_program;
+    let index_cache_directory = m
+        .get_one::<String>("index-cache-directory")
+        .map(|s| s.as_str());
     match mapping_program {
         MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 => {
             coverm::mapping_index_maintenance::generate_bwa_index(
                 reference_wise_params.reference,
                 None,
                 mapping_program,
+                index_cache_directory,
             )
         }
         MappingProgram::MINIMAP2_SR
@@ -730,7 +862,11 @@ fn setup_mapping_index(
         | MappingProgram::MINIMAP2_HIFI
         | MappingProgram::MINIMAP2_PB
         | MappingProgram::MINIMAP2_NO_PRESET => {
-            if m.get_flag("minimap2-reference-is-index") || reference_wise_params.len() == 1 {
+            // A cached index is worth generating even for a single sample,
+            // since later runs may reuse it.
+            if m.get_flag("minimap2-reference-is-index")
+                || (reference_wise_params.len() == 1 && index_cache_directory.is_none())
+            {
                 info!("Not pre-generating minimap2 index");
                 if m.get_flag("minimap2-reference-is-index") {
                     warn!(
@@ -751,6 +887,7 @@ fn setup_mapping_index(
                             .unwrap_or(&"".to_string()),
                     ),
                     mapping_program,
+                    index_cache_directory,
                 )
             }
         }
@@ -775,6 +912,11 @@ fn setup_mapping_index(
                 ))
             }
         }
+        MappingProgram::BOWTIE2 => coverm::mapping_index_maintenance::generate_bowtie2_index(
+            reference_wise_params.reference,
+            Some(*m.get_one::<u16>("threads").unwrap()),
+            index_cache_directory,
+        ),
     }
 }
 
@@ -821,22 +963,34 @@ fn dereplicate(m: &clap::ArgMatches, genome_fasta_files: &Vec<String>) -> Vec<St
     reps
 }
 
-fn parse_mapping_program(m: &clap::ArgMatches) -> MappingProgram {
-    let mapping_program = match m.get_one::<String>("mapper").map(|x| &**x) {
-        Some("bwa-mem") => MappingProgram::BWA_MEM,
-        Some("bwa-mem2") => MappingProgram::BWA_MEM2,
-        Some("minimap2-sr") => MappingProgram::MINIMAP2_SR,
-        Some("minimap2-ont") => MappingProgram::MINIMAP2_ONT,
-        Some("minimap2-pb") => MappingProgram::MINIMAP2_PB,
-        Some("minimap2-hifi") => MappingProgram::MINIMAP2_HIFI,
-        Some("minimap2-no-preset") => MappingProgram::MINIMAP2_NO_PRESET,
-        Some("strobealign") => MappingProgram::STROBEALIGN,
-        None => DEFAULT_MAPPING_SOFTWARE_ENUM,
+fn parse_strandedness(m: &clap::ArgMatches) -> Strandedness {
+    match m.get_one::<String>("strandedness").map(|x| &**x) {
+        Some("forward") => Strandedness::Forward,
+        Some("reverse") => Strandedness::Reverse,
+        Some("unstranded") | None => Strandedness::Unstranded,
         _ => panic!(
-            "Unexpected definition for --mapper: {:?}",
-            m.get_one::<String>("mapper")
+            "Unexpected definition for --strandedness: {:?}",
+            m.get_one::<String>("strandedness")
         ),
-    };
+    }
+}
+
+fn parse_mapping_program(m: &clap::ArgMatches) -> MappingProgram {
+    match m.get_one::<String>("mapper") {
+        Some(name) => parse_mapping_program_name(name)
+            .unwrap_or_else(|| panic!("Unexpected definition for --mapper: {:?}", name)),
+        None => DEFAULT_MAPPING_SOFTWARE_ENUM,
+    }
+}
+
+/// Check that each mapping program used by any sample is installed.
+fn check_for_mapping_programs(params: &MappingParameters) {
+    for mapping_program in params.mapping_programs() {
+        check_for_mapping_program(mapping_program);
+    }
+}
+
+fn check_for_mapping_program(mapping_program: MappingProgram) {
     match mapping_program {
         MappingProgram::BWA_MEM => {
             external_command_checker::check_for_bwa();
@@ -854,8 +1008,10 @@ fn parse_mapping_program(m: &clap::ArgMatches) -> MappingProgram {
         MappingProgram::STROBEALIGN => {
             external_command_checker::check_for_strobealign();
         }
+        MappingProgram::BOWTIE2 => {
+            external_command_checker::check_for_bowtie2();
+        }
     }
-    mapping_program
 }
 
 struct EstimatorsAndTaker {
@@ -865,6 +1021,7 @@ struct EstimatorsAndTaker {
     rpkm_column: Option<usize>,
     tpm_column: Option<usize>,
     printer: CoveragePrinter,
+    strandedness: Strandedness,
 }
 
 fn extract_genomes_and_contigs_option(
@@ -919,11 +1076,35 @@ fn parse_percentage(m: &clap::ArgMatches, parameter: &str) -> f32 {
     }
 }
 
+/// Maximum fraction of a read which may be clipped, or 1.0 i.e. no maximum if
+/// the parameter was not given.
+fn parse_max_clipped_percentage(m: &clap::ArgMatches, parameter: &str) -> f32 {
+    match m.contains_id(parameter) {
+        true => parse_percentage(m, parameter),
+        false => 1.0,
+    }
+}
+
 impl EstimatorsAndTaker {
     pub fn generate_from_clap(m: &clap::ArgMatches, stream: OutputWriter) -> EstimatorsAndTaker {
         let mut estimators = vec![];
         let min_fraction_covered = parse_percentage(m, "min-covered-fraction");
-        let contig_end_exclusion = *m.get_one::<u64>("contig-end-exclusion").unwrap();
+        // BED intervals and annotated features are not whole contigs, so
+        // their ends are not excluded.
+        let contig_end_exclusion = match m.contains_id("regions")
+            || m.try_contains_id("annotation").unwrap_or(false)
+        {
+            true => {
+                if m.value_source("contig-end-exclusion")
+                    == Some(clap::parser::ValueSource::CommandLine)
+                {
+                    error!("--contig-end-exclusion cannot be used with --regions or --annotation");
+                    process::exit(1);
+                }
+                0
+            }
+            false => *m.get_one::<u64>("contig-end-exclusion").unwrap(),
+        };
 
         let methods: Vec<&str> = m
             .get_many::<String>("methods")
@@ -937,8 +1118,13 @@ impl EstimatorsAndTaker {
         let printer;
         let mut rpkm_column = None;
         let mut tpm_column = None;
+        let strandedness = parse_strandedness(m);
 
         if doing_metabat(m) {
+            if strandedness.is_stranded() {
+                error!("The metabat method cannot be used with stranded libraries");
+                process::exit(1);
+            }
             estimators.push(CoverageEstimator::new_estimator_length());
             estimators.push(CoverageEstimator::new_estimator_mean(
                 min_fraction_covered,
@@ -953,8 +1139,101 @@ impl EstimatorsAndTaker {
             debug!("Cached regular coverage taker for metabat mode being used");
             taker = CoverageTakerType::new_cached_single_float_coverage_taker(estimators.len());
             printer = CoveragePrinter::MetabatAdjustedCoveragePrinter;
+        } else if let Some(binner) = binner_method(m) {
+            if strandedness.is_stranded() {
+                error!(
+                    "The {} method cannot be used with stranded libraries",
+                    binner
+                );
+                process::exit(1);
+            }
+            // CONCOCT and MaxBin2 average over the whole contig, so only
+            // exclude contig ends if asked.
+            let binner_contig_end_exclusion = match binner {
+                "concoct" | "maxbin2"
+                    if m.value_source("contig-end-exclusion")
+                        != Some(clap::parser::ValueSource::CommandLine) =>
+                {
+                    0
+                }
+                _ => contig_end_exclusion,
+            };
+            match binner {
+                "vamb" => {
+                    // VAMB calculates depth as the mean after trimming the
+                    // 10% least and most covered positions
+                    let trim = |parameter, default| match m.value_source(parameter)
+                        == Some(clap::parser::ValueSource::CommandLine)
+                    {
+                        true => parse_percentage(m, parameter),
+                        false => default,
+                    };
+                    estimators.push(CoverageEstimator::new_estimator_trimmed_mean(
+                        trim("trim-min", 0.1),
+                        trim("trim-max", 0.9),
+                        min_fraction_covered,
+                        binner_contig_end_exclusion,
+                    ));
+                    printer = CoveragePrinter::VambCoveragePrinter;
+                }
+                "semibin" => {
+                    estimators.push(CoverageEstimator::new_estimator_mean(
+                        min_fraction_covered,
+                        binner_contig_end_exclusion,
+                        false,
+                    ));
+                    estimators.push(CoverageEstimator::new_estimator_variance(
+                        min_fraction_covered,
+                        binner_contig_end_exclusion,
+                    ));
+                    printer = CoveragePrinter::SemiBinCoveragePrinter;
+                }
+                "concoct" => {
+                    estimators.push(CoverageEstimator::new_estimator_mean(
+                        min_fraction_covered,
+                        binner_contig_end_exclusion,
+                        false,
+                    ));
+                    printer = CoveragePrinter::ConcoctCoveragePrinter;
+                }
+                "maxbin2" => {
+                    estimators.push(CoverageEstimator::new_estimator_mean(
+                        min_fraction_covered,
+                        binner_contig_end_exclusion,
+                        false,
+                    ));
+                    let abundance_file_prefix = match m.get_one::<String>("output-file") {
+                        Some(path) if path != "-" => path.clone(),
+                        _ => {
+                            error!(
+                                "The maxbin2 method requires --output-file, since \
+                                abundances are written to one file per sample \
+                                named after it"
+                            );
+                            process::exit(1);
+                        }
+                    };
+                    printer = CoveragePrinter::MaxBin2CoveragePrinter {
+                        abundance_file_prefix,
+                        abundance_file_extension: parse_compression_extension(
+                            m,
+                            "maxbin2-compression",
+                        ),
+                    };
+                }
+                _ => unreachable!(),
+            }
+
+            debug!(
+                "Cached regular coverage taker for {} mode being used",
+                binner
+            );
+            taker = CoverageTakerType::new_cached_single_float_coverage_taker(estimators.len());
         } else {
-            for (i, method) in methods.iter().enumerate() {
+            for method in methods.iter() {
+                // Index of the first column of this method, since some
+                // methods generate more than one column.
+                let i: usize = estimators.iter().map(|e| e.column_headers().len()).sum();
                 match *method {
                     "mean" => {
                         estimators.push(CoverageEstimator::new_estimator_mean(
@@ -984,6 +1263,19 @@ impl EstimatorsAndTaker {
                             min_fraction_covered,
                         ));
                     }
+                    "covered_fraction_at_depth" => {
+                        let depth_thresholds: Vec<u32> = m
+                            .get_many::<u32>("depth-thresholds")
+                            .unwrap()
+                            .cloned()
+                            .collect();
+                        estimators.push(
+                            CoverageEstimator::new_estimator_covered_fraction_at_depth(
+                                depth_thresholds,
+                                min_fraction_covered,
+                            ),
+                        );
+                    }
                     "covered_bases" => {
                         estimators.push(CoverageEstimator::new_estimator_covered_bases(
                             min_fraction_covered,
@@ -1011,6 +1303,21 @@ impl EstimatorsAndTaker {
                             contig_end_exclusion,
                         ));
                     }
+                    "median" => {
+                        estimators.push(CoverageEstimator::new_estimator_median(
+                            min_fraction_covered,
+                            contig_end_exclusion,
+                        ));
+                    }
+                    m if m.starts_with("percentile_") => {
+                        // Validated by the CLI value parser
+                        let percentile: f32 = m["percentile_".len()..].parse().unwrap();
+                        estimators.push(CoverageEstimator::new_estimator_percentile(
+                            percentile,
+                            min_fraction_covered,
+                            contig_end_exclusion,
+                        ));
+                    }
                     "length" => {
                         estimators.push(CoverageEstimator::new_estimator_length());
                     }
@@ -1029,11 +1336,85 @@ impl EstimatorsAndTaker {
                     "reads_per_base" => {
                         estimators.push(CoverageEstimator::new_estimator_reads_per_base());
                     }
+                    "nucleotide_diversity" | "snv_count" | "consensus_ani" => {
+                        let statistic = match *method {
+                            "nucleotide_diversity" => MicrodiversityStatistic::NucleotideDiversity,
+                            "snv_count" => MicrodiversityStatistic::SnvCount,
+                            _ => MicrodiversityStatistic::ConsensusAni,
+                        };
+                        estimators.push(CoverageEstimator::new_estimator_microdiversity(
+                            statistic,
+                            *m.get_one::<u32>("min-snv-coverage").unwrap(),
+                            parse_percentage(m, "min-snv-allele-frequency"),
+                            min_fraction_covered,
+                        ));
+                    }
                     _ => unreachable!(),
                 };
             }
 
+            if strandedness.is_stranded() {
+                if methods.contains(&"coverage_histogram")
+                    || !columns_to_normalise.is_empty()
+                    || rpkm_column.is_some()
+                    || tpm_column.is_some()
+                {
+                    error!(
+                        "The relative_abundance, rpkm, tpm and coverage_histogram methods \
+                        cannot currently be used with stranded libraries"
+                    );
+                    process::exit(1);
+                }
+                // Sense columns are followed by antisense columns
+                let antisense_estimators = estimators.clone();
+                estimators.extend(antisense_estimators);
+            }
+
+            if output_format == "biom" {
+                if !cfg!(feature = "hdf5") {
+                    error!(
+                        "CoverM was built without HDF5 support, which is required \
+                        for --output-format biom. Rebuild it with '--features hdf5', \
+                        or use --output-format biom-json"
+                    );
+                    process::exit(1);
+                }
+                match m.get_one::<String>("output-file") {
+                    Some(path) if path != "-" && compression_extension(path).is_empty() => {}
+                    _ => {
+                        error!(
+                            "--output-format biom requires an uncompressed --output-file, \
+                            since BIOM 2.1 tables are HDF5 files"
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+            if output_format == "biom" || output_format == "biom-json" {
+                if strandedness.is_stranded() {
+                    error!("BIOM output cannot currently be used with stranded libraries");
+                    process::exit(1);
+                }
+                if methods.iter().filter(|method| **method != "length").count() != 1
+                    || estimators.iter().any(|e| e.column_headers().len() != 1)
+                {
+                    error!(
+                        "BIOM output requires exactly one coverage method with a \
+                        single output column, optionally together with 'length'"
+                    );
+                    process::exit(1);
+                }
+            }
+
             if methods.contains(&"coverage_histogram") {
+                if output_format == "json"
+                    || output_format == "jsonl"
+                    || output_format == "biom"
+                    || output_format == "biom-json"
+                {
+                    error!("The coverage_histogram method cannot be output in JSON or BIOM format");
+                    process::exit(1);
+                }
                 if methods.len() > 1 {
                     error!("Cannot specify the coverage_histogram method with any other coverage methods");
                     process::exit(1);
@@ -1056,13 +1437,34 @@ impl EstimatorsAndTaker {
                     "Cached regular coverage taker with columns to normlise: {:?} and rpkm_column: {:?} and tpm_column: {:?}",
                     columns_to_normalise, rpkm_column, tpm_column
                 );
-                taker = CoverageTakerType::new_cached_single_float_coverage_taker(estimators.len());
+                let num_columns = estimators.iter().map(|e| e.column_headers().len()).sum();
+                taker = CoverageTakerType::new_cached_single_float_coverage_taker(num_columns);
                 printer = match output_format {
                     "sparse" => CoveragePrinter::SparseCachedCoveragePrinter,
                     "dense" => CoveragePrinter::DenseCachedCoveragePrinter {
                         entry_type: None,
                         estimator_headers: None,
                     },
+                    "json" | "jsonl" => CoveragePrinter::JsonCachedCoveragePrinter {
+                        entry_type: None,
+                        estimator_headers: None,
+                        json_lines: output_format == "jsonl",
+                    },
+                    "biom-json" => CoveragePrinter::BiomCachedCoveragePrinter {
+                        entry_type: None,
+                        estimator_headers: None,
+                        taxonomy: m
+                            .get_one::<String>("biom-taxonomy")
+                            .map(|path| read_biom_taxonomy_file(path)),
+                    },
+                    #[cfg(feature = "hdf5")]
+                    "biom" => CoveragePrinter::BiomHdf5CachedCoveragePrinter {
+                        estimator_headers: None,
+                        taxonomy: m
+                            .get_one::<String>("biom-taxonomy")
+                            .map(|path| read_biom_taxonomy_file(path)),
+                        path: m.get_one::<String>("output-file").unwrap().clone(),
+                    },
                     _ => unreachable!(),
                 }
             }
@@ -1098,6 +1500,7 @@ impl EstimatorsAndTaker {
             rpkm_column,
             tpm_column,
             printer,
+            strandedness,
         }
     }
 
@@ -1111,12 +1514,40 @@ impl EstimatorsAndTaker {
         for i in self.columns_to_normalise.iter() {
             headers[*i] = "Relative Abundance (%)".to_string();
         }
+        if self.strandedness.is_stranded() {
+            let num_sense_headers = headers.len() / 2;
+            for (i, header) in headers.iter_mut().enumerate() {
+                match i < num_sense_headers {
+                    true => header.push_str(" (sense)"),
+                    false => header.push_str(" (antisense)"),
+                }
+            }
+        }
         self.printer
             .print_headers(entry_type, headers, print_stream);
         self
     }
 }
 
+/// The extension of files compressed as chosen by a compression parameter,
+/// '.gz', '.zst' or an empty string if they are not compressed.
+fn parse_compression_extension(m: &clap::ArgMatches, parameter: &str) -> &'static str {
+    match m.get_one::<String>(parameter).unwrap().as_str() {
+        "gz" => ".gz",
+        "zst" => ".zst",
+        _ => "",
+    }
+}
+
+/// The stream coverage is printed to. BIOM 2.1 tables are instead written to
+/// the output file by the HDF5 library, so nothing is printed.
+fn coverage_output_writer(m: &clap::ArgMatches) -> OutputWriter {
+    match m.get_one::<String>("output-format").map(|f| f.as_str()) {
+        Some("biom") => OutputWriter::generate(None),
+        _ => OutputWriter::generate(m.get_one::<String>("output-file").map(|x| &**x)),
+    }
+}
+
 fn parse_separator(m: &clap::ArgMatches) -> Option<u8> {
     let single_genome = m.get_flag("single-genome");
     if single_genome {
@@ -1149,30 +1580,270 @@ fn run_genome<
     let flag_filter = FilterParameters::generate_from_clap(m).flag_filters;
     let single_genome = m.get_flag("single-genome");
     let threads = *m.get_one::<u16>("threads").unwrap();
-    let reads_mapped = match separator.is_some() || single_genome {
-        true => coverm::genome::mosdepth_genome_coverage(
+    let doing_microdiversity = estimators_and_taker
+        .estimators
+        .iter()
+        .any(|e| e.requires_base_pileup());
+    if doing_microdiversity
+        && (m.contains_id("regions")
+            || m.contains_id("per-base-depth-output")
+            || m.get_flag("sharded")
+            || estimators_and_taker.strandedness.is_stranded())
+    {
+        error!(
+            "The nucleotide_diversity, snv_count and consensus_ani methods cannot \
+            currently be used with --regions, --per-base-depth-output, --sharded \
+            or stranded libraries"
+        );
+        process::exit(1);
+    }
+    let checkpoint = checkpoint_from_clap(m);
+    let sample_names: Vec<String> = bam_generators
+        .iter()
+        .map(|g| g.stoit_name().to_string())
+        .collect();
+    let EstimatorsAndTaker {
+        ref mut taker,
+        ref mut estimators,
+        strandedness,
+        ..
+    } = *estimators_and_taker;
+    let reads_mapped = if doing_microdiversity {
+        calculate_with_checkpoint(
+            bam_generators,
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| {
+                coverm::microdiversity::microdiversity_genome_coverage(
+                    generators,
+                    |contig| {
+                        genome_of_contig(
+                            contig,
+                            single_genome,
+                            separator,
+                            genomes_and_contigs_option,
+                        )
+                    },
+                    recording_taker,
+                    estimators,
+                    print_zeros,
+                    &flag_filter,
+                    threads,
+                )
+            },
+        )
+    } else if let Some(regions_file) = m.get_one::<String>("regions") {
+        // Genome coverage is calculated over only the regions within each
+        // genome.
+        let regions = coverm::regions::Regions::from_bed_file(regions_file, false).group_by_genome(
+            |contig| genome_of_contig(contig, single_genome, separator, genomes_and_contigs_option),
+        );
+        calculate_with_checkpoint(
             bam_generators,
-            separator.unwrap(),
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| {
+                coverm::regions::region_coverage(
+                    generators,
+                    &regions,
+                    recording_taker,
+                    estimators,
+                    print_zeros,
+                    &flag_filter,
+                    threads,
+                    strandedness,
+                )
+            },
+        )
+    } else {
+        let mut per_base_depth_writer = m.get_one::<String>("per-base-depth-output").map(|dir| {
+            coverm::per_base_depth::PerBaseDepthWriter::new(
+                dir,
+                parse_compression_extension(m, "per-base-depth-compression"),
+            )
+        });
+        calculate_with_checkpoint(
+            bam_generators,
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| match separator.is_some() || single_genome {
+                true => coverm::genome::mosdepth_genome_coverage(
+                    generators,
+                    separator.unwrap(),
+                    recording_taker,
+                    print_zeros,
+                    estimators,
+                    &flag_filter,
+                    single_genome,
+                    threads,
+                    per_base_depth_writer.as_mut(),
+                    strandedness,
+                ),
+
+                false => match genomes_and_contigs_option {
+                    Some(gc) => coverm::genome::mosdepth_genome_coverage_with_contig_names(
+                        generators,
+                        gc,
+                        recording_taker,
+                        print_zeros,
+                        &flag_filter,
+                        estimators,
+                        threads,
+                        per_base_depth_writer.as_mut(),
+                        strandedness,
+                    ),
+                    None => unreachable!(),
+                },
+            },
+        )
+    };
+
+    debug!("Finalising printing ..");
+    estimators_and_taker.printer.finalise_printing(
+        &estimators_and_taker.taker,
+        print_stream,
+        Some(&reads_mapped),
+        &estimators_and_taker.columns_to_normalise,
+        estimators_and_taker.rpkm_column,
+        estimators_and_taker.tpm_column,
+    );
+    write_provenance(m, &sample_names, Some(&reads_mapped[..]));
+    if let Some(c) = checkpoint {
+        c.remove();
+    }
+}
+
+/// Checkpoint of per-sample results next to the output file, if --output-file
+/// is given.
+fn checkpoint_from_clap(m: &clap::ArgMatches) -> Option<Checkpoint> {
+    let resume = m.get_flag("resume");
+    match m.get_one::<String>("output-file") {
+        Some(output_file) if output_file != "-" => Some(Checkpoint::new(
+            output_file,
+            resume,
+            &parameters_fingerprint(m),
+        )),
+        _ => {
+            if resume {
+                error!("--resume requires --output-file to be specified");
+                process::exit(1);
+            }
+            None
+        }
+    }
+}
+
+/// Name of the genome a contig belongs to, or None if it is not part of any
+/// genome.
+fn genome_of_contig(
+    contig: &str,
+    single_genome: bool,
+    separator: Option<u8>,
+    genomes_and_contigs_option: &Option<GenomesAndContigs>,
+) -> Option<String> {
+    if single_genome {
+        Some("genome1".to_string())
+    } else if let Some(s) = separator {
+        contig.find(s as char).map(|i| contig[..i].to_string())
+    } else {
+        genomes_and_contigs_option
+            .as_ref()
+            .unwrap()
+            .genome_of_contig(&contig.to_string())
+            .cloned()
+    }
+}
+
+/// Calculate genome coverage, resolving reads that map to more than one
+/// genome by expectation-maximisation. Each BAM file is read three times.
+fn run_genome_em(
+    bam_files: Vec<&str>,
+    m: &clap::ArgMatches,
+    estimators_and_taker: &mut EstimatorsAndTaker,
+    separator: Option<u8>,
+    genomes_and_contigs_option: &Option<GenomesAndContigs>,
+    filter_params: &FilterParameters,
+    print_stream: &mut OutputWriter,
+) {
+    let print_zeros = !m.get_flag("no-zeros");
+    let single_genome = m.get_flag("single-genome");
+    let threads = *m.get_one::<u16>("threads").unwrap();
+    if m.get_flag("resume") {
+        error!("--resume cannot currently be used with EM multimapper resolution");
+        process::exit(1);
+    }
+    // Named as by generate_named_bam_readers_from_bam_files
+    let sample_names: Vec<String> = bam_files
+        .iter()
+        .map(|f| {
+            std::path::Path::new(f)
+                .file_stem()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    let contig_to_genome = |contig: &str| {
+        genome_of_contig(contig, single_genome, separator, genomes_and_contigs_option)
+    };
+    // Secondary alignments are required to find reads which map to more
+    // than one genome.
+    let flag_filters = FlagFilter {
+        include_secondary: true,
+        ..filter_params.flag_filters.clone()
+    };
+
+    let reads_mapped = if filter_params.doing_filtering() {
+        let generate_readers = || {
+            coverm::bam_generator::generate_filtered_bam_readers_from_bam_files(
+                bam_files.clone(),
+                flag_filters.clone(),
+                filter_params.min_aligned_length_single,
+                filter_params.min_percent_identity_single,
+                filter_params.min_aligned_percent_single,
+                filter_params.min_mapq_single,
+                filter_params.min_as_xs_difference_single,
+                filter_params.max_clipped_fraction_single,
+                filter_params.min_aligned_length_pair,
+                filter_params.min_percent_identity_pair,
+                filter_params.min_aligned_percent_pair,
+                filter_params.min_mapq_pair,
+                filter_params.min_as_xs_difference_pair,
+                filter_params.max_clipped_fraction_pair,
+                filter_params.pair_constraints,
+            )
+        };
+        coverm::multimapper_resolution::em_genome_coverage(
+            generate_readers()
+                .into_iter()
+                .zip(generate_readers())
+                .zip(generate_readers())
+                .map(|((a, b), c)| (a, b, c))
+                .collect(),
+            contig_to_genome,
             &mut estimators_and_taker.taker,
+            &estimators_and_taker.estimators,
             print_zeros,
-            &mut estimators_and_taker.estimators,
-            &flag_filter,
-            single_genome,
+            &flag_filters,
             threads,
-        ),
-
-        false => match genomes_and_contigs_option {
-            Some(gc) => coverm::genome::mosdepth_genome_coverage_with_contig_names(
-                bam_generators,
-                gc,
-                &mut estimators_and_taker.taker,
-                print_zeros,
-                &flag_filter,
-                &mut estimators_and_taker.estimators,
-                threads,
-            ),
-            None => unreachable!(),
-        },
+        )
+    } else {
+        let generate_readers =
+            || coverm::bam_generator::generate_named_bam_readers_from_bam_files(bam_files.clone());
+        coverm::multimapper_resolution::em_genome_coverage(
+            generate_readers()
+                .into_iter()
+                .zip(generate_readers())
+                .zip(generate_readers())
+                .map(|((a, b), c)| (a, b, c))
+                .collect(),
+            contig_to_genome,
+            &mut estimators_and_taker.taker,
+            &estimators_and_taker.estimators,
+            print_zeros,
+            &flag_filters,
+            threads,
+        )
     };
 
     debug!("Finalising printing ..");
@@ -1184,23 +1855,40 @@ fn run_genome<
         estimators_and_taker.rpkm_column,
         estimators_and_taker.tpm_column,
     );
+    write_provenance(m, &sample_names, Some(&reads_mapped[..]));
 }
 
-fn doing_metabat(m: &clap::ArgMatches) -> bool {
+/// Methods which print coverage in the input format of a genome binning
+/// program, and so cannot be combined with other methods.
+const BINNER_METHODS: &[&str] = &["metabat", "vamb", "semibin", "concoct", "maxbin2"];
+
+fn binner_method(m: &clap::ArgMatches) -> Option<&str> {
     let methods: Vec<&str> = m
         .get_many::<String>("methods")
         .unwrap()
         .map(|x| &**x)
         .collect();
-    if methods.contains(&"metabat") {
-        if methods.len() > 1 {
-            error!("Cannot specify the metabat method with any other coverage methods");
-            process::exit(1);
-        } else {
-            return true;
+    match methods
+        .iter()
+        .copied()
+        .find(|method| BINNER_METHODS.contains(method))
+    {
+        Some(method) => {
+            if methods.len() > 1 {
+                error!(
+                    "Cannot specify the {} method with any other coverage methods",
+                    method
+                );
+                process::exit(1);
+            }
+            Some(method)
         }
+        None => None,
     }
-    false
+}
+
+fn doing_metabat(m: &clap::ArgMatches) -> bool {
+    binner_method(m) == Some("metabat")
 }
 
 #[derive(Debug)]
@@ -1209,9 +1897,16 @@ struct FilterParameters {
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
+    pair_constraints: PairConstraints,
 }
 impl FilterParameters {
     pub fn generate_from_clap(m: &clap::ArgMatches) -> FilterParameters {
@@ -1224,28 +1919,111 @@ impl FilterParameters {
             min_aligned_length_single: *m.get_one::<u32>("min-read-aligned-length").unwrap_or(&0),
             min_percent_identity_single: parse_percentage(m, "min-read-percent-identity"),
             min_aligned_percent_single: parse_percentage(m, "min-read-aligned-percent"),
+            min_mapq_single: *m.get_one::<u8>("min-mapq").unwrap_or(&0),
+            min_as_xs_difference_single: *m.get_one::<u32>("min-as-xs-difference").unwrap_or(&0),
+            max_clipped_fraction_single: parse_max_clipped_percentage(
+                m,
+                "max-read-clipped-percent",
+            ),
             min_aligned_length_pair: *m
                 .get_one::<u32>("min-read-aligned-length-pair")
                 .unwrap_or(&0),
             min_percent_identity_pair: parse_percentage(m, "min-read-percent-identity-pair"),
             min_aligned_percent_pair: parse_percentage(m, "min-read-aligned-percent-pair"),
+            min_mapq_pair: *m.get_one::<u8>("min-mapq-pair").unwrap_or(&0),
+            min_as_xs_difference_pair: *m.get_one::<u32>("min-as-xs-difference-pair").unwrap_or(&0),
+            max_clipped_fraction_pair: parse_max_clipped_percentage(
+                m,
+                "max-read-clipped-percent-pair",
+            ),
+            pair_constraints: PairConstraints {
+                min_insert_size: *m.get_one::<u64>("min-insert-size").unwrap_or(&0),
+                max_insert_size: *m.get_one::<u64>("max-insert-size").unwrap_or(&0),
+                orientation: m
+                    .get_one::<String>("pair-orientation")
+                    .map(|o| PairOrientation::from_name(o).unwrap()),
+            },
         };
+        if f.pair_constraints.max_insert_size > 0
+            && f.pair_constraints.min_insert_size > f.pair_constraints.max_insert_size
+        {
+            error!("--min-insert-size cannot be greater than --max-insert-size");
+            process::exit(1);
+        }
         debug!("Filter parameters set as {:?}", f);
         f
     }
 
-    pub fn add_metabat_filtering_if_required(&mut self, m: &clap::ArgMatches) {
-        if doing_metabat(m) {
-            info!(
-                "Setting single read percent identity threshold at 0.97 for \
-                 MetaBAT adjusted coverage, and not filtering out supplementary, \
-                 secondary and improper pair alignments"
-            );
-            // we use >= where metabat uses >. Gah.
-            self.min_percent_identity_single = 0.97001;
-            self.flag_filters.include_improper_pairs = true;
-            self.flag_filters.include_supplementary = true;
-            self.flag_filters.include_secondary = true;
+    /// Filter alignments as the program whose input format a binner method
+    /// prints would when calculating coverage itself.
+    pub fn add_binner_filtering_if_required(&mut self, m: &clap::ArgMatches) {
+        match binner_method(m) {
+            Some("metabat") => {
+                info!(
+                    "Setting single read percent identity threshold at 0.97 for \
+                     MetaBAT adjusted coverage, and not filtering out supplementary, \
+                     secondary and improper pair alignments"
+                );
+                // we use >= where metabat uses >. Gah.
+                self.min_percent_identity_single = 0.97001;
+                self.flag_filters.include_improper_pairs = true;
+                self.flag_filters.include_supplementary = true;
+                self.flag_filters.include_secondary = true;
+            }
+            Some("vamb") => {
+                // VAMB recommends mapping with secondary alignments and
+                // removing supplementary ones with samtools view -F 3584
+                info!(
+                    "Not filtering out secondary and improper pair alignments, \
+                     and filtering out supplementary alignments, for VAMB"
+                );
+                self.flag_filters.include_improper_pairs = true;
+                self.flag_filters.include_supplementary = false;
+                self.flag_filters.include_secondary = true;
+            }
+            Some("semibin") => {
+                // SemiBin calculates depth with bedtools genomecov, which
+                // counts every mapped alignment
+                info!(
+                    "Not filtering out supplementary, secondary and improper \
+                     pair alignments, for SemiBin"
+                );
+                self.flag_filters.include_improper_pairs = true;
+                self.flag_filters.include_supplementary = true;
+                self.flag_filters.include_secondary = true;
+            }
+            Some(binner @ "concoct") | Some(binner @ "maxbin2") => {
+                // CONCOCT calculates depth with samtools bedcov, and MaxBin2
+                // from bowtie2 alignments, neither of which count secondary
+                // alignments
+                info!(
+                    "Not filtering out supplementary and improper pair \
+                     alignments, and filtering out secondary alignments, for {}",
+                    binner
+                );
+                self.flag_filters.include_improper_pairs = true;
+                self.flag_filters.include_supplementary = true;
+                self.flag_filters.include_secondary = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Filter parameters of a sample, with any thresholds given for it in
+    /// the sample sheet replacing those given on the command line.
+    pub fn with_sample_read_filters(&self, read_filters: &SampleReadFilters) -> FilterParameters {
+        FilterParameters {
+            flag_filters: self.flag_filters.clone(),
+            min_aligned_length_single: read_filters
+                .min_aligned_length
+                .unwrap_or(self.min_aligned_length_single),
+            min_percent_identity_single: read_filters
+                .min_percent_identity
+                .unwrap_or(self.min_percent_identity_single),
+            min_aligned_percent_single: read_filters
+                .min_aligned_percent
+                .unwrap_or(self.min_aligned_percent_single),
+            ..*self
         }
     }
 
@@ -1256,6 +2034,13 @@ impl FilterParameters {
             || self.min_aligned_percent_pair > 0.0
             || self.min_aligned_length_single > 0
             || self.min_aligned_length_pair > 0
+            || self.min_mapq_single > 0
+            || self.min_mapq_pair > 0
+            || self.min_as_xs_difference_single > 0
+            || self.min_as_xs_difference_pair > 0
+            || self.max_clipped_fraction_single < 1.0
+            || self.max_clipped_fraction_pair < 1.0
+            || !self.pair_constraints.is_empty()
     }
 }
 
@@ -1263,6 +2048,7 @@ fn get_sharded_bam_readers<'a, 'b, T>(
     m: &'a clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &'a Option<NamedTempFile>,
+    sample_sheet: &'a Option<SampleSheet>,
     genome_exclusion: &'b T,
 ) -> Vec<ShardedBamReaderGenerator<'b, T>>
 where
@@ -1274,13 +2060,15 @@ where
     }
     let discard_unmapped = m.get_flag("discard-unmapped");
     let sort_threads = *m.get_one::<u16>("threads").unwrap();
-    let params = MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile);
+    let params =
+        MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile, sample_sheet);
+    check_for_mapping_programs(&params);
     let mut bam_readers = vec![];
     let mut concatenated_reference_name: Option<String> = None;
     let mut concatenated_read_names: Option<String> = None;
 
     for reference_wise_params in params {
-        let index = setup_mapping_index(&reference_wise_params, m, mapping_program);
+        let index = setup_mapping_index(&reference_wise_params, m);
 
         let reference = reference_wise_params.reference;
         let reference_name = std::path::Path::new(reference)
@@ -1315,7 +2103,7 @@ where
         for p in reference_wise_params {
             bam_readers.push(
                 coverm::shard_bam_reader::generate_named_sharded_bam_readers_from_reads(
-                    mapping_program,
+                    p.mapping_program,
                     index.as_ref(),
                     p.read1,
                     p.read2,
@@ -1323,15 +2111,17 @@ where
                     p.threads,
                     bam_file_cache(p.read1).as_ref().map(String::as_ref),
                     discard_unmapped,
-                    p.mapping_options,
+                    p.mapping_options.as_deref(),
                 ),
             );
-            let name = &std::path::Path::new(p.read1)
-                .file_name()
-                .expect("Unable to convert read1 name to file name")
-                .to_str()
-                .expect("Unable to covert file name into str")
-                .to_string();
+            let name = match p.sample_name {
+                Some(name) => name,
+                None => std::path::Path::new(p.read1)
+                    .file_name()
+                    .expect("Unable to convert read1 name to file name")
+                    .to_str()
+                    .expect("Unable to covert file name into str"),
+            };
             concatenated_read_names = match concatenated_read_names {
                 Some(prev) => Some(format!("{}|{}", prev, name)),
                 None => Some(name.to_string()),
@@ -1357,6 +2147,7 @@ fn get_streamed_bam_readers(
     m: &clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &Option<NamedTempFile>,
+    sample_sheet: &Option<SampleSheet>,
 ) -> Vec<BamGeneratorSet<StreamingNamedBamReaderGenerator>> {
     // Check the output BAM directory actually exists and is writeable
     if m.contains_id("bam-file-cache-directory") {
@@ -1364,11 +2155,14 @@ fn get_streamed_bam_readers(
     }
     let discard_unmapped = m.get_flag("discard-unmapped");
 
-    let params = MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile);
+    let params =
+        MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile, sample_sheet);
+    check_for_mapping_programs(&params);
+    let (concurrent_mappings, mapping_threads) = concurrent_mapping_threads(m);
     let mut generator_set = vec![];
     for reference_wise_params in params {
         let mut bam_readers = vec![];
-        let index = setup_mapping_index(&reference_wise_params, m, mapping_program);
+        let index = setup_mapping_index(&reference_wise_params, m);
 
         let reference = reference_wise_params.reference;
         let bam_file_cache = |naming_readset| -> Option<String> {
@@ -1393,20 +2187,22 @@ fn get_streamed_bam_readers(
         for p in reference_wise_params {
             bam_readers.push(
                 coverm::bam_generator::generate_named_bam_readers_from_reads(
-                    mapping_program,
+                    p.mapping_program,
                     index.as_ref(),
                     p.read1,
                     p.read2,
                     p.read_format.clone(),
-                    p.threads,
+                    mapping_threads,
                     bam_file_cache(p.read1).as_ref().map(String::as_ref),
                     discard_unmapped,
-                    p.mapping_options,
+                    p.mapping_options.as_deref(),
                     reference_tempfile.is_none(),
+                    p.sample_name,
                 ),
             );
         }
 
+        MappingScheduler::schedule(&mut bam_readers, concurrent_mappings);
         debug!("Finished BAM setup");
         let to_return = BamGeneratorSet {
             generators: bam_readers,
@@ -1417,6 +2213,23 @@ fn get_streamed_bam_readers(
     generator_set
 }
 
+/// The number of samples to map at once, and the number of threads given to
+/// each of them so that together they stay within --threads.
+fn concurrent_mapping_threads(m: &clap::ArgMatches) -> (usize, u16) {
+    let threads = *m.get_one::<u16>("threads").unwrap();
+    let concurrent_mappings = *m.get_one::<u16>("concurrent-mappings").unwrap();
+    if concurrent_mappings > threads {
+        warn!(
+            "Mapping {} samples at once with only {} threads, so each is given a single thread",
+            concurrent_mappings, threads
+        );
+    }
+    (
+        concurrent_mappings as usize,
+        std::cmp::max(1, threads / concurrent_mappings),
+    )
+}
+
 fn generate_cached_bam_file_name(directory: &str, reference: &str, read1_path: &str) -> String {
     debug!(
         "Constructing BAM file cache name in directory {}, reference {}, read1_path {}",
@@ -1530,6 +2343,7 @@ fn get_streamed_filtered_bam_readers(
     m: &clap::ArgMatches,
     mapping_program: MappingProgram,
     reference_tempfile: &Option<NamedTempFile>,
+    sample_sheet: &Option<SampleSheet>,
     filter_params: &FilterParameters,
 ) -> Vec<BamGeneratorSet<StreamingFilteredNamedBamReaderGenerator>> {
     // Check the output BAM directory actually exists and is writeable
@@ -1538,11 +2352,14 @@ fn get_streamed_filtered_bam_readers(
     }
     let discard_unmapped = m.get_flag("discard-unmapped");
 
-    let params = MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile);
+    let params =
+        MappingParameters::generate_from_clap(m, mapping_program, reference_tempfile, sample_sheet);
+    check_for_mapping_programs(&params);
+    let (concurrent_mappings, mapping_threads) = concurrent_mapping_threads(m);
     let mut generator_set = vec![];
     for reference_wise_params in params {
         let mut bam_readers = vec![];
-        let index = setup_mapping_index(&reference_wise_params, m, mapping_program);
+        let index = setup_mapping_index(&reference_wise_params, m);
 
         let reference = reference_wise_params.reference;
         let bam_file_cache = |naming_readset| -> Option<String> {
@@ -1565,29 +2382,39 @@ fn get_streamed_filtered_bam_readers(
         };
 
         for p in reference_wise_params {
+            let sample_filter_params = filter_params.with_sample_read_filters(&p.read_filters);
             bam_readers.push(
                 coverm::bam_generator::generate_filtered_named_bam_readers_from_reads(
-                    mapping_program,
+                    p.mapping_program,
                     index.as_ref(),
                     p.read1,
                     p.read2,
                     p.read_format.clone(),
-                    p.threads,
+                    mapping_threads,
                     bam_file_cache(p.read1).as_ref().map(String::as_ref),
-                    filter_params.flag_filters.clone(),
-                    filter_params.min_aligned_length_single,
-                    filter_params.min_percent_identity_single,
-                    filter_params.min_aligned_percent_single,
-                    filter_params.min_aligned_length_pair,
-                    filter_params.min_percent_identity_pair,
-                    filter_params.min_aligned_percent_pair,
-                    p.mapping_options,
+                    sample_filter_params.flag_filters,
+                    sample_filter_params.min_aligned_length_single,
+                    sample_filter_params.min_percent_identity_single,
+                    sample_filter_params.min_aligned_percent_single,
+                    sample_filter_params.min_mapq_single,
+                    sample_filter_params.min_as_xs_difference_single,
+                    sample_filter_params.max_clipped_fraction_single,
+                    sample_filter_params.min_aligned_length_pair,
+                    sample_filter_params.min_percent_identity_pair,
+                    sample_filter_params.min_aligned_percent_pair,
+                    sample_filter_params.min_mapq_pair,
+                    sample_filter_params.min_as_xs_difference_pair,
+                    sample_filter_params.max_clipped_fraction_pair,
+                    sample_filter_params.pair_constraints,
+                    p.mapping_options.as_deref(),
                     discard_unmapped,
                     reference_tempfile.is_none(),
+                    p.sample_name,
                 ),
             );
         }
 
+        MappingScheduler::schedule(&mut bam_readers, concurrent_mappings);
         debug!("Finished BAM setup");
         let to_return = BamGeneratorSet {
             generators: bam_readers,
@@ -1602,6 +2429,7 @@ fn run_contig<
     R: coverm::bam_generator::NamedBamReader,
     T: coverm::bam_generator::NamedBamReaderGenerator<R>,
 >(
+    m: &clap::ArgMatches,
     estimators_and_taker: &mut EstimatorsAndTaker,
     bam_readers: Vec<T>,
     print_zeros: bool,
@@ -1609,14 +2437,116 @@ fn run_contig<
     threads: u16,
     print_stream: &mut OutputWriter,
 ) {
-    let reads_mapped = coverm::contig::contig_coverage(
-        bam_readers,
-        &mut estimators_and_taker.taker,
-        &mut estimators_and_taker.estimators,
-        print_zeros,
-        &flag_filters,
-        threads,
-    );
+    let sample_names: Vec<String> = bam_readers
+        .iter()
+        .map(|g| g.stoit_name().to_string())
+        .collect();
+    if let Some(window_size) = m.get_one::<u64>("window-size") {
+        if m.get_flag("resume") {
+            error!("--resume cannot currently be used with --window-size");
+            process::exit(1);
+        }
+        let mut bedgraph_stream = m
+            .get_one::<String>("window-bedgraph")
+            .map(|path| OutputWriter::generate(Some(path)));
+        coverm::contig::contig_window_coverage(
+            bam_readers,
+            *window_size,
+            print_stream,
+            bedgraph_stream.as_mut().map(|s| s as &mut dyn Write),
+            print_zeros,
+            &flag_filters,
+            threads,
+        );
+        if let Some(ref mut bedgraph) = bedgraph_stream {
+            bedgraph.finish();
+        }
+        write_provenance(m, &sample_names, None);
+        return;
+    }
+
+    let checkpoint = checkpoint_from_clap(m);
+    let EstimatorsAndTaker {
+        ref mut taker,
+        ref mut estimators,
+        strandedness,
+        ..
+    } = *estimators_and_taker;
+    let reads_mapped = if let Some(regions_file) = m.get_one::<String>("regions") {
+        let regions = coverm::regions::Regions::from_bed_file(
+            regions_file,
+            m.get_flag("aggregate-regions-by-name"),
+        );
+        calculate_with_checkpoint(
+            bam_readers,
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| {
+                coverm::regions::region_coverage(
+                    generators,
+                    &regions,
+                    recording_taker,
+                    estimators,
+                    print_zeros,
+                    &flag_filters,
+                    threads,
+                    strandedness,
+                )
+            },
+        )
+    } else if let Some(annotation_file) = m.get_one::<String>("annotation") {
+        let feature_types: Vec<&str> = m
+            .get_many::<String>("feature-type")
+            .unwrap()
+            .map(|x| &**x)
+            .collect();
+        let regions = coverm::regions::Regions::from_gff_file(
+            annotation_file,
+            &feature_types,
+            m.get_one::<String>("group-by-attribute").unwrap(),
+        );
+        calculate_with_checkpoint(
+            bam_readers,
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| {
+                coverm::regions::region_coverage(
+                    generators,
+                    &regions,
+                    recording_taker,
+                    estimators,
+                    print_zeros,
+                    &flag_filters,
+                    threads,
+                    strandedness,
+                )
+            },
+        )
+    } else {
+        let mut per_base_depth_writer = m.get_one::<String>("per-base-depth-output").map(|dir| {
+            coverm::per_base_depth::PerBaseDepthWriter::new(
+                dir,
+                parse_compression_extension(m, "per-base-depth-compression"),
+            )
+        });
+        calculate_with_checkpoint(
+            bam_readers,
+            taker,
+            checkpoint.as_ref(),
+            |generators, recording_taker| {
+                coverm::contig::contig_coverage(
+                    generators,
+                    recording_taker,
+                    estimators,
+                    print_zeros,
+                    &flag_filters,
+                    threads,
+                    per_base_depth_writer.as_mut(),
+                    strandedness,
+                )
+            },
+        )
+    };
 
     debug!("Finalising printing ..");
 
@@ -1628,6 +2558,10 @@ fn run_contig<
         estimators_and_taker.rpkm_column,
         estimators_and_taker.tpm_column,
     );
+    write_provenance(m, &sample_names, Some(&reads_mapped[..]));
+    if let Some(c) = checkpoint {
+        c.remove();
+    }
 }
 
 fn set_log_level(matches: &clap::ArgMatches, is_last: bool) {
@@ -0,0 +1,428 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use sha2::{Digest, Sha256};
+
+use bam_generator::*;
+use coverage_takers::*;
+use ReadsMapped;
+
+/// A call made to a CoverageTaker, which can be replayed later.
+#[derive(Debug, PartialEq)]
+enum CoverageTakerCall {
+    StartEntry(usize, String),
+    SingleCoverage(f32),
+    CoverageEntry(usize, u64),
+    FinishEntry,
+}
+
+/// Passes each call on to another CoverageTaker, remembering the calls made
+/// since the current stoit was started so that they can be checkpointed.
+pub struct RecordingCoverageTaker<'a, T: CoverageTaker> {
+    coverage_taker: &'a mut T,
+    recording: bool,
+    calls: Vec<CoverageTakerCall>,
+}
+
+impl<'a, T: CoverageTaker> RecordingCoverageTaker<'a, T> {
+    pub fn new(coverage_taker: &'a mut T, recording: bool) -> RecordingCoverageTaker<'a, T> {
+        RecordingCoverageTaker {
+            coverage_taker,
+            recording,
+            calls: vec![],
+        }
+    }
+
+    fn record(&mut self, call: CoverageTakerCall) {
+        if self.recording {
+            self.calls.push(call);
+        }
+    }
+}
+
+impl<'a, T: CoverageTaker> CoverageTaker for RecordingCoverageTaker<'a, T> {
+    fn start_stoit(&mut self, stoit_name: &str) {
+        self.calls.clear();
+        self.coverage_taker.start_stoit(stoit_name);
+    }
+    fn start_entry(&mut self, entry_order_id: usize, entry_name: &str) {
+        self.record(CoverageTakerCall::StartEntry(
+            entry_order_id,
+            entry_name.to_string(),
+        ));
+        self.coverage_taker.start_entry(entry_order_id, entry_name);
+    }
+    fn add_single_coverage(&mut self, coverage: f32) {
+        self.record(CoverageTakerCall::SingleCoverage(coverage));
+        self.coverage_taker.add_single_coverage(coverage);
+    }
+    fn add_coverage_entry(&mut self, num_reads: usize, num_bases: u64) {
+        self.record(CoverageTakerCall::CoverageEntry(num_reads, num_bases));
+        self.coverage_taker.add_coverage_entry(num_reads, num_bases);
+    }
+    fn finish_entry(&mut self) {
+        self.record(CoverageTakerCall::FinishEntry);
+        self.coverage_taker.finish_entry();
+    }
+}
+
+// Arguments which do not affect the results of a sample, or which identify
+// the samples themselves, which are checked separately.
+const ARGUMENTS_NOT_FINGERPRINTED: &[&str] = &[
+    "resume",
+    "threads",
+    "verbose",
+    "quiet",
+    "output-file",
+    "concurrent-mappings",
+    "index-cache-directory",
+    "bam-file-cache-directory",
+    "bam-files",
+    "read1",
+    "read2",
+    "coupled",
+    "interleaved",
+    "single",
+];
+
+/// Fingerprint of the parameters which determine the results of each sample
+/// e.g. the methods, references, read filtering thresholds and output
+/// format, so that results are not reused by a run with different ones.
+pub fn parameters_fingerprint(m: &clap::ArgMatches) -> String {
+    let mut parameters: Vec<String> = m
+        .ids()
+        .filter(|id| !ARGUMENTS_NOT_FINGERPRINTED.contains(&id.as_str()))
+        .filter_map(|id| match m.try_get_raw(id.as_str()) {
+            Ok(Some(values)) => Some(format!("{}={:?}", id.as_str(), values.collect::<Vec<_>>())),
+            _ => None,
+        })
+        .collect();
+    parameters.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    for parameter in parameters {
+        debug!("Checkpoint fingerprint includes {}", parameter);
+        hasher.update(parameter);
+        hasher.update("\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A directory of per-sample results, written as each sample finishes so
+/// that an interrupted run can be resumed without recalculating them.
+pub struct Checkpoint {
+    directory: PathBuf,
+}
+
+impl Checkpoint {
+    /// Use the checkpoint directory of an output file. Unless resuming with
+    /// the same parameters fingerprint, any results of a previous run are
+    /// removed.
+    pub fn new(output_file: &str, resume: bool, fingerprint: &str) -> Checkpoint {
+        let directory = PathBuf::from(format!("{}.checkpoint", output_file));
+        let fingerprint_path = directory.join("parameters");
+        let fingerprint_line = format!("fingerprint\t{}", fingerprint);
+        let same_parameters = std::fs::read_to_string(&fingerprint_path)
+            .map_or(false, |previous| previous.trim_end() == fingerprint_line);
+        if directory.exists() && !(resume && same_parameters) {
+            match resume {
+                true => warn!(
+                    "Results in checkpoint directory {} were calculated with \
+                    different parameters, so all samples are recalculated",
+                    directory.display()
+                ),
+                false => info!(
+                    "Removing results of a previous run from checkpoint directory {}",
+                    directory.display()
+                ),
+            }
+            std::fs::remove_dir_all(&directory).unwrap_or_else(|e| {
+                error!(
+                    "Failed to remove checkpoint directory {}: {}",
+                    directory.display(),
+                    e
+                );
+                process::exit(1);
+            });
+        }
+        std::fs::create_dir_all(&directory).unwrap_or_else(|e| {
+            error!(
+                "Failed to create checkpoint directory {}: {}",
+                directory.display(),
+                e
+            );
+            process::exit(1);
+        });
+        std::fs::write(&fingerprint_path, format!("{}\n", fingerprint_line)).unwrap_or_else(|e| {
+            error!(
+                "Failed to write checkpoint file {}: {}",
+                fingerprint_path.display(),
+                e
+            );
+            process::exit(1);
+        });
+        info!("Saving results of each sample to {}", directory.display());
+        Checkpoint { directory }
+    }
+
+    fn sample_path(&self, sample_index: usize) -> PathBuf {
+        self.directory.join(format!("sample_{}.tsv", sample_index))
+    }
+
+    /// Save the results of a sample. The file is written under a temporary
+    /// name first so that a partially written file is never read back.
+    pub fn save_sample<T: CoverageTaker>(
+        &self,
+        sample_index: usize,
+        stoit_name: &str,
+        recording_taker: &RecordingCoverageTaker<T>,
+        reads_mapped: &ReadsMapped,
+    ) {
+        let path = self.sample_path(sample_index);
+        let tmp_path = path.with_extension("tsv.tmp");
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(
+                writer,
+                "stoit\t{}\t{}\t{}",
+                stoit_name, reads_mapped.num_mapped_reads, reads_mapped.num_reads
+            )?;
+            for call in recording_taker.calls.iter() {
+                match call {
+                    CoverageTakerCall::StartEntry(id, name) => {
+                        writeln!(writer, "entry\t{}\t{}", id, name)?
+                    }
+                    CoverageTakerCall::SingleCoverage(coverage) => {
+                        writeln!(writer, "coverage\t{}", coverage)?
+                    }
+                    CoverageTakerCall::CoverageEntry(num_reads, num_bases) => {
+                        writeln!(writer, "coverage_entry\t{}\t{}", num_reads, num_bases)?
+                    }
+                    CoverageTakerCall::FinishEntry => writeln!(writer, "finish_entry")?,
+                }
+            }
+            writer.flush()?;
+            std::fs::rename(&tmp_path, &path)
+        };
+        write().unwrap_or_else(|e| {
+            error!("Failed to write checkpoint file {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        debug!("Saved checkpoint of sample '{}'", stoit_name);
+    }
+
+    /// If the sample was completed in a previous run, give its results to the
+    /// coverage taker and return the number of reads mapped. Returns None if
+    /// the sample has not been completed.
+    pub fn resume_sample<T: CoverageTaker>(
+        &self,
+        sample_index: usize,
+        stoit_name: &str,
+        coverage_taker: &mut T,
+    ) -> Option<ReadsMapped> {
+        let path = self.sample_path(sample_index);
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(_) => return None,
+        };
+        let mut lines = BufReader::new(file).lines().map(|l| {
+            l.unwrap_or_else(|e| {
+                error!("Failed to read checkpoint file {}: {}", path.display(), e);
+                process::exit(1);
+            })
+        });
+
+        let first_line = lines.next().unwrap_or_default();
+        let fields: Vec<&str> = first_line.split('\t').collect();
+        if fields.len() != 4 || fields[0] != "stoit" {
+            parse_failure(&path, &first_line);
+        }
+        if fields[1] != stoit_name {
+            warn!(
+                "Checkpoint file {} is for sample '{}' rather than '{}', so \
+                recalculating this sample",
+                path.display(),
+                fields[1],
+                stoit_name
+            );
+            return None;
+        }
+        let reads_mapped = match (fields[2].parse::<f64>(), fields[3].parse::<u64>()) {
+            (Ok(num_mapped_reads), Ok(num_reads)) => ReadsMapped {
+                num_mapped_reads,
+                num_reads,
+            },
+            _ => parse_failure(&path, &first_line),
+        };
+
+        coverage_taker.start_stoit(stoit_name);
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match (fields[0], fields.len()) {
+                ("entry", 3) => match fields[1].parse::<usize>() {
+                    Ok(id) => coverage_taker.start_entry(id, fields[2]),
+                    Err(_) => parse_failure(&path, &line),
+                },
+                ("coverage", 2) => match fields[1].parse::<f32>() {
+                    Ok(coverage) => coverage_taker.add_single_coverage(coverage),
+                    Err(_) => parse_failure(&path, &line),
+                },
+                ("coverage_entry", 3) => {
+                    match (fields[1].parse::<usize>(), fields[2].parse::<u64>()) {
+                        (Ok(num_reads), Ok(num_bases)) => {
+                            coverage_taker.add_coverage_entry(num_reads, num_bases)
+                        }
+                        _ => parse_failure(&path, &line),
+                    }
+                }
+                ("finish_entry", 1) => coverage_taker.finish_entry(),
+                _ => parse_failure(&path, &line),
+            }
+        }
+        info!(
+            "Using results of sample '{}' from a previous run",
+            stoit_name
+        );
+        Some(reads_mapped)
+    }
+
+    /// Remove the checkpoint directory once the output is complete.
+    pub fn remove(self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.directory) {
+            warn!(
+                "Failed to remove checkpoint directory {}: {}",
+                self.directory.display(),
+                e
+            );
+        }
+    }
+}
+
+fn parse_failure(path: &Path, line: &str) -> ! {
+    error!(
+        "Failed to parse line of checkpoint file {}: {}",
+        path.display(),
+        line
+    );
+    process::exit(1);
+}
+
+/// Calculate coverage of each sample in turn, saving the results of each
+/// sample to the checkpoint as it finishes, and reusing the results of
+/// samples which were completed in a previous run. Without a checkpoint, all
+/// samples are given to calculate at once.
+pub fn calculate_with_checkpoint<R, G, T, F>(
+    bam_generators: Vec<G>,
+    coverage_taker: &mut T,
+    checkpoint: Option<&Checkpoint>,
+    mut calculate: F,
+) -> Vec<ReadsMapped>
+where
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    T: CoverageTaker,
+    F: FnMut(Vec<G>, &mut RecordingCoverageTaker<T>) -> Vec<ReadsMapped>,
+{
+    let checkpoint = match checkpoint {
+        Some(c) => c,
+        None => {
+            let mut taker = RecordingCoverageTaker::new(coverage_taker, false);
+            return calculate(bam_generators, &mut taker);
+        }
+    };
+
+    let mut reads_mapped = vec![];
+    for (sample_index, bam_generator) in bam_generators.into_iter().enumerate() {
+        let stoit_name = bam_generator.stoit_name().to_string();
+        if let Some(previous) = checkpoint.resume_sample(sample_index, &stoit_name, coverage_taker)
+        {
+            reads_mapped.push(previous);
+            continue;
+        }
+        let mut taker = RecordingCoverageTaker::new(coverage_taker, true);
+        let sample_reads_mapped = calculate(vec![bam_generator], &mut taker);
+        checkpoint.save_sample(sample_index, &stoit_name, &taker, &sample_reads_mapped[0]);
+        reads_mapped.extend(sample_reads_mapped);
+    }
+    reads_mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_save_and_resume() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output_file = td.path().join("output.tsv");
+        let checkpoint = Checkpoint::new(output_file.to_str().unwrap(), false, "a");
+
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: 1.5,
+            num_reads: 4,
+        };
+        {
+            let mut taker = RecordingCoverageTaker::new(&mut c, true);
+            taker.start_stoit("sample1");
+            taker.start_entry(0, "contig1");
+            taker.add_single_coverage(1.1);
+            taker.add_single_coverage(f32::NAN);
+            taker.finish_entry();
+            checkpoint.save_sample(0, "sample1", &taker, &reads_mapped);
+        }
+
+        let checkpoint = Checkpoint::new(output_file.to_str().unwrap(), true, "a");
+        let mut resumed = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        assert_eq!(None, checkpoint.resume_sample(1, "sample2", &mut resumed));
+        assert_eq!(
+            None,
+            checkpoint.resume_sample(0, "other_sample", &mut resumed)
+        );
+        assert_eq!(
+            Some(reads_mapped),
+            checkpoint.resume_sample(0, "sample1", &mut resumed)
+        );
+        let ecs: Vec<EntryAndCoverages> = resumed.generate_iterator().collect();
+        assert_eq!(1, ecs.len());
+        assert_eq!(1.1, ecs[0].coverages[0]);
+        assert!(ecs[0].coverages[1].is_nan());
+
+        checkpoint.remove();
+        assert!(!td.path().join("output.tsv.checkpoint").exists());
+    }
+
+    #[test]
+    fn test_checkpoint_with_different_parameters_discarded() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output_file = td.path().join("output.tsv");
+        let checkpoint = Checkpoint::new(output_file.to_str().unwrap(), false, "a");
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(1);
+        {
+            let mut taker = RecordingCoverageTaker::new(&mut c, true);
+            taker.start_stoit("sample1");
+            taker.start_entry(0, "contig1");
+            taker.add_single_coverage(1.1);
+            taker.finish_entry();
+            checkpoint.save_sample(
+                0,
+                "sample1",
+                &taker,
+                &ReadsMapped {
+                    num_mapped_reads: 1.0,
+                    num_reads: 2,
+                },
+            );
+        }
+
+        let checkpoint = Checkpoint::new(output_file.to_str().unwrap(), true, "b");
+        let mut resumed = CoverageTakerType::new_cached_single_float_coverage_taker(1);
+        assert_eq!(None, checkpoint.resume_sample(0, "sample1", &mut resumed));
+    }
+}
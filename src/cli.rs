@@ -20,9 +20,79 @@ const MAPPING_SOFTWARE_LIST: &[&str] = &[
     "minimap2-hifi",
     "minimap2-no-preset",
     "strobealign",
+    "bowtie2",
 ];
 const DEFAULT_MAPPING_SOFTWARE: &str = "minimap2-sr";
 
+const GENOME_METHODS_LIST: &[&str] = &[
+    "relative_abundance",
+    "mean",
+    "trimmed_mean",
+    "coverage_histogram",
+    "covered_fraction",
+    "covered_fraction_at_depth",
+    "covered_bases",
+    "variance",
+    "median",
+    "length",
+    "count",
+    "reads_per_base",
+    "rpkm",
+    "tpm",
+    "nucleotide_diversity",
+    "snv_count",
+    "consensus_ani",
+];
+const CONTIG_METHODS_LIST: &[&str] = &[
+    "mean",
+    "trimmed_mean",
+    "coverage_histogram",
+    "covered_fraction",
+    "covered_fraction_at_depth",
+    "covered_bases",
+    "variance",
+    "median",
+    "length",
+    "count",
+    "metabat",
+    "vamb",
+    "semibin",
+    "concoct",
+    "maxbin2",
+    "reads_per_base",
+    "rpkm",
+    "tpm",
+];
+
+// Methods are either one of a fixed list, or percentile_N where N is a
+// number between 0 and 100.
+fn parse_method(method: &str, allowed: &[&str]) -> std::result::Result<String, String> {
+    if allowed.contains(&method) {
+        return Ok(method.to_string());
+    }
+    if let Some(percentile) = method.strip_prefix("percentile_") {
+        return match percentile.parse::<f32>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => Ok(method.to_string()),
+            _ => Err(format!(
+                "percentile must be a number between 0 and 100, found '{}'",
+                percentile
+            )),
+        };
+    }
+    Err(format!(
+        "possible values: {}, percentile_N",
+        allowed.join(", ")
+    ))
+}
+
+fn parse_genome_method(method: &str) -> std::result::Result<String, String> {
+    parse_method(method, GENOME_METHODS_LIST)
+}
+
+fn parse_contig_method(method: &str) -> std::result::Result<String, String> {
+    parse_method(method, CONTIG_METHODS_LIST)
+}
+
 lazy_static! {
     pub static ref COVERM_CLUSTER_COMMAND_DEFINITION: GalahClustererCommandDefinition = {
         galah::cluster_argument_parsing::GalahClustererCommandDefinition {
@@ -81,6 +151,14 @@ fn add_mapping_options(manual: Manual) -> Manual {
                         &monospace_roff("minimap2-no-preset"),
                         &format!("minimap2 with no '{}' option", &monospace_roff("-x"))
                     ],
+                    &[
+                        &monospace_roff("strobealign"),
+                        "strobealign using default parameters"
+                    ],
+                    &[
+                        &monospace_roff("bowtie2"),
+                        "bowtie2 in its default end-to-end mode"
+                    ],
                 ])
             )))
             .option(Opt::new("PARAMS").long("--minimap2-params").help(&format!(
@@ -109,6 +187,19 @@ fn add_mapping_options(manual: Manual) -> Manual {
             ))
             .flag(Flag::new().long("--strobealign-use-index").help(
                 "Use a pregenerated index (one that has been created with 'strobealign --create-index'). The --reference option should be specified as the original FASTA file i.e. 'ref.fna' not 'ref.fna.r100.sti' [default: not set]",
+            ))
+            .option(Opt::new("PARAMS").long("--bowtie2-params").help(
+                "Extra parameters to provide to bowtie2 when mapping, \
+        e.g. '--very-sensitive'. Note that usage of this parameter \
+        has security implications if untrusted input is specified. \
+        [default: none]",
+            ))
+            .option(Opt::new("DIRECTORY").long("--index-cache-directory").help(
+                "Store generated BWA, BWA-MEM2, minimap2 and bowtie2 indices \
+        in this directory, named by a hash of the reference contents, the \
+        mapper, its version and indexing parameters. Later runs with the \
+        same reference reuse the stored index instead of generating it \
+        again. [default: not set]",
             )),
     )
 }
@@ -175,6 +266,80 @@ fn add_thresholding_options(manual: Manual) -> Manual {
                         default_roff("0")
                     )),
             )
+            .option(Opt::new("FLOAT").long("--max-read-clipped-percent").help(
+                "Exclude reads by percent of bases soft- or hard-clipped \
+                e.g. 10 means at most 10% of the read's bases may be clipped. \
+                Clipped bases which would run off either end of the contig \
+                are not counted. [default: no maximum]",
+            ))
+            .option(
+                Opt::new("FLOAT")
+                    .long("--max-read-clipped-percent-pair")
+                    .help(
+                        "Exclude pairs by percent of bases soft- or \
+                        hard-clipped, as for --max-read-clipped-percent. \
+                        Implies --proper-pairs-only. [default: no maximum]",
+                    ),
+            )
+            .option(Opt::new("INT").long("--min-mapq").help(&format!(
+                "Exclude reads with a smaller mapping quality (MAPQ). {}",
+                default_roff("0")
+            )))
+            .option(
+                Opt::new("INT")
+                    .long("--min-as-xs-difference")
+                    .help(&format!(
+                        "Exclude reads whose alignment score (AS tag) exceeds the \
+                score of their best suboptimal alignment (XS tag) by less \
+                than this, i.e. reads which could be placed almost as well \
+                elsewhere. Reads without a suboptimal score are not \
+                excluded, so this has no effect for mappers which do not \
+                report XS, such as minimap2 and strobealign, and a warning is \
+                given when no read has one. {}",
+                        default_roff("0")
+                    )),
+            )
+            .option(Opt::new("INT").long("--min-mapq-pair").help(&format!(
+                "Exclude pairs where neither read has at least this mapping \
+                quality. Implies --proper-pairs-only. {}",
+                default_roff("0")
+            )))
+            .option(
+                Opt::new("INT")
+                    .long("--min-as-xs-difference-pair")
+                    .help(&format!(
+                        "Exclude pairs where neither read has an alignment \
+                score exceeding that of its best suboptimal alignment by \
+                at least this. Implies --proper-pairs-only. {}",
+                        default_roff("0")
+                    )),
+            )
+            .option(Opt::new("INT").long("--min-insert-size").help(&format!(
+                "Exclude pairs with a smaller insert size, as pairs \
+                which are discordant. When this, --max-insert-size or \
+                --pair-orientation is given, pairs are judged by them rather \
+                than by the mapper's proper pair flag, and pairs with reads \
+                mapped to different contigs are also discordant. {}",
+                default_roff("0")
+            )))
+            .option(Opt::new("INT").long("--max-insert-size").help(
+                "Exclude pairs with a larger insert size, as pairs which are \
+                discordant. [default: no maximum]",
+            ))
+            .option(
+                Opt::new("ORIENTATION")
+                    .long("--pair-orientation")
+                    .help(&format!(
+                        "Exclude pairs whose reads are not in this orientation, as \
+                pairs which are discordant: '{}' for the leftmost read on the \
+                forward strand and the other on the reverse, '{}' for the \
+                reverse, or '{}' for both on the same strand. \
+                [default: any orientation]",
+                        monospace_roff("fr"),
+                        monospace_roff("rf"),
+                        monospace_roff("ff")
+                    )),
+            )
             .flag(
                 Flag::new()
                     .long("--proper-pairs-only")
@@ -221,6 +386,19 @@ fn read_mapping_params_section() -> Section {
                 .long("--single")
                 .help("Unpaired FASTA/Q files(s) for mapping. These may be gzipped or not."),
         )
+        .option(Opt::new("PATH").long("--sample-sheet").help(
+            "Tab-separated file with a header line, describing one \
+        sample per line instead of specifying reads with -1, -2, -c, \
+        --interleaved or --single. The 'sample' column names the sample \
+        in the output. Reads are given in either the 'read1' and 'read2' \
+        columns, the 'interleaved' column or the 'single' column, with \
+        the other columns left empty. An optional 'mapper' column gives \
+        the mapping software to use for each sample, overriding --mapper, \
+        so that e.g. short and long read samples can be profiled together. \
+        Optional 'min-read-percent-identity', 'min-read-aligned-percent' and \
+        'min-read-aligned-length' columns similarly override those options \
+        for each sample.",
+        ))
 }
 
 fn add_help_options(manual: Manual) -> Manual {
@@ -341,7 +519,10 @@ pub fn filter_full_help() -> Manual {
             Opt::new("PATH ..")
                 .short("-o")
                 .long("--output-bam-files")
-                .help(" Path to corresponding output file(s). [required]"),
+                .help(
+                    " Path to corresponding output file(s). How each was produced \
+                    is recorded in PATH.provenance.json. [required]",
+                ),
         );
     manual = add_thresholding_options(manual);
     manual = manual.option(Opt::new("INT").short("-t").long("--threads").help(&format!(
@@ -410,10 +591,10 @@ pub fn make_full_help() -> Manual {
                         index \
                         (with {}), \
                         strobealign index (with {}), \
-                        or BWA index stem (with {}). [required]",
+                        or BWA or bowtie2 index stem (with {}). [required]",
                 monospace_roff("--minimap2-reference-is-index"),
                 monospace_roff("--strobealign-use-index"),
-                monospace_roff("-p bwa-mem/bwa-mem2"),
+                monospace_roff("-p bwa-mem/bwa-mem2/bowtie2"),
             ),
         )),
     );
@@ -423,7 +604,8 @@ pub fn make_full_help() -> Manual {
     manual = manual.custom(
             Section::new("Output")    
         .option(Opt::new("DIR").short("-o").long("--output-directory").help(
-            "Where generated BAM files will go. The directory will be created if it does not exist. [required]",
+            "Where generated BAM files will go. The directory will be created if it does not exist. \
+            How the BAM files were produced is recorded in DIR.provenance.json. [required]",
         ))
         .flag(
             Flag::new()
@@ -455,6 +637,57 @@ pub fn make_full_help() -> Manual {
     manual
 }
 
+pub fn merge_full_help() -> Manual {
+    let mut manual = Manual::new("coverm merge")
+        .about(format!(
+            "Merge coverage tables from separate runs (version: {})",
+            crate_version!()
+        ))
+        .author(Author::new(crate::AUTHOR).email("benjwoodcroft near gmail.com"))
+        .description(
+            "coverm merge combines sparse or dense tables output by coverm genome or \
+        coverm contig on different samples into a single table. The tables must \
+        have been generated with the same methods. In dense output, entries missing \
+        from a sample are given zero coverage.\n\n\
+        Relative abundance, RPKM and TPM are normalised within each sample, so \
+        the values of each sample are output as they were calculated.",
+        );
+
+    manual = manual.custom(Section::new("Input").option(
+        Opt::new("PATH ..").short("-i").long("--input-files").help(
+            "Sparse or dense coverage tables to merge, which may be \
+                gzip or zstd compressed. [required]",
+        ),
+    ));
+
+    manual = manual.custom(
+        Section::new("Output")
+            .option(Opt::new("FILE").short("-o").long("--output-file").help(
+                "Output merged coverage values to this file, or '-' for STDOUT. \
+                Files ending in .gz or .zst are gzip or zstd compressed. \
+                [default: output to STDOUT]",
+            ))
+            .option(Opt::new("FORMAT").long("--output-format").help(&format!(
+                "Shape of output: 'sparse' for long format, \
+                'dense' for species-by-site. {}",
+                default_roff("dense")
+            ))),
+    );
+
+    manual = manual.example(
+        Example::new()
+            .text("Merge the genome coverage of two batches of samples into one table")
+            .command("coverm merge -i batch1.tsv batch2.tsv -o all_samples.tsv"),
+    );
+
+    let mut general_section = Section::new("General options");
+    general_section = add_help_options_to_section(general_section);
+    general_section = add_verbosity_flags_to_section(general_section);
+    manual = manual.custom(general_section);
+
+    manual
+}
+
 pub fn contig_full_help() -> Manual {
     let mut manual = Manual::new("coverm contig")
         .about(format!("Calculate read coverage per-contig (version {})",crate_version!()))
@@ -492,14 +725,14 @@ pub fn contig_full_help() -> Manual {
                     index \
                     (with {}), \
                     strobealign index (with {}), \
-                    or BWA index stem (with {}). \
+                    or BWA or bowtie2 index stem (with {}). \
                     If multiple references FASTA files are \
                     provided and {} is specified, \
                     then reads will be mapped to references \
                     separately as sharded BAMs. [required unless {} is specified]",
                 monospace_roff("--minimap2-reference-is-index"),
                 monospace_roff("--strobealign-use-index"),
-                monospace_roff("-p bwa-mem/bwa-mem2"),
+                monospace_roff("-p bwa-mem/bwa-mem2/bowtie2"),
                 monospace_roff("--sharded"),
                 monospace_roff("-b/--bam-files")
             ),
@@ -526,15 +759,27 @@ pub fn contig_full_help() -> Manual {
 
                     &[&monospace_roff("coverage_histogram"), "Histogram of coverage depths"],
                     &[&monospace_roff("covered_bases"), "Number of bases covered by 1 or more reads"],
+                    &[&monospace_roff("covered_fraction_at_depth"), &format!("Fraction of bases covered by at least N reads, for each N given by {}", &monospace_roff("--depth-thresholds"))],
                     &[&monospace_roff("variance"), "Variance of coverage depths"],
+                    &[&monospace_roff("median"), "Median coverage depth, counting uncovered positions as zero"],
+                    &[&monospace_roff("percentile_N"), &format!("Nth percentile of coverage depths e.g. {} for the 90th percentile", &monospace_roff("percentile_90"))],
                     &[&monospace_roff("length"), "Length of each contig in base pairs"],
                     &[&monospace_roff("count"), "Number of reads aligned to each contig. Note that supplementary alignments are not counted."],
                     &[&monospace_roff("metabat"), "(\"MetaBAT adjusted coverage\") Coverage as defined in Kang et al 2015 https://doi.org/10.7717/peerj.1165"],
+                    &[&monospace_roff("vamb"), "Contig by sample table of trimmed mean depth (10% trimmed unless --trim-min/--trim-max are given), for VAMB's --abundance_tsv. Secondary but not supplementary alignments are counted, and every contig is printed."],
+                    &[&monospace_roff("semibin"), "Table of mean and variance of depth in each sample, as calculated by SemiBin. Secondary and supplementary alignments are counted."],
+                    &[&monospace_roff("concoct"), "Coverage table as produced by CONCOCT's concoct_coverage_table.py. Supplementary but not secondary alignments are counted. Contig ends are included unless --contig-end-exclusion is given."],
+                    &[&monospace_roff("maxbin2"), "Mean depth of each sample written to OUTPUT_FILE.SAMPLE.abund, compressed according to --maxbin2-compression, with OUTPUT_FILE listing these files for MaxBin2's -abund_list. Supplementary but not secondary alignments are counted. Contig ends are included unless --contig-end-exclusion is given."],
                     &[&monospace_roff("reads_per_base"), "Number of reads aligned divided by the length of the contig"],
                     &[&monospace_roff("rpkm"), "Reads mapped per kilobase of contig, per million mapped reads"],
                     &[&monospace_roff("tpm"), "Transcripts Per Million as described in Li et al 2010 https://doi.org/10.1093/bioinformatics/btp692"],
                 ]),
             )))
+            .option(Opt::new("FORMAT").long("--maxbin2-compression").help(&format!(
+                "Compression of the abundance files of the maxbin2 method: \
+                'none', 'gz' for gzip or 'zst' for zstd {}",
+                default_roff("none")
+            )))
             .option(Opt::new("FRACTION").long("--min-covered-fraction").help(
                 &format!("Contigs with less covered bases than this are \
                 reported as having zero coverage. \
@@ -553,24 +798,127 @@ pub fn contig_full_help() -> Manual {
             .option(Opt::new("FRACTION").long("--trim-max").help(
                 &format!("Maximum fraction for trimmed_mean \
                 calculations {}", default_roff("95"))
-            )),
+            ))
+            .option(Opt::new("INT ..").long("--depth-thresholds").help(
+                &format!("Minimum depths at which bases are considered \
+                covered in covered_fraction_at_depth calculations, \
+                one column is output for each {}", default_roff("1 5 10 30"))
+            ))
+            .option(Opt::new("STRANDEDNESS").long("--strandedness").help(&format!(
+                "Library strandedness of RNA-seq reads: 'forward' if \
+                the first read of each pair has the same orientation as the \
+                transcript, 'reverse' if it has the opposite orientation \
+                (e.g. dUTP libraries), or 'unstranded'. When stranded, each \
+                method is reported in separate sense and antisense columns, \
+                relative to the reference strand or to the strand of each \
+                feature when --annotation or --regions is used. Cannot be \
+                used with relative_abundance, rpkm, tpm or coverage_histogram \
+                methods {}",
+                default_roff("unstranded")
+            )))
+            .option(Opt::new("BED").long("--regions").help(
+                "Only calculate coverage over the intervals in this \
+                BED file, reporting each interval as a separate row named \
+                'contig:start-end'. Contig ends are not excluded, so \
+                cannot be used with --contig-end-exclusion. [default: not \
+                used]",
+            ))
+            .flag(Flag::new().long("--aggregate-regions-by-name").help(
+                "Report intervals with the same name (4th column of \
+                the --regions BED file) as a single row. [default: not set]",
+            ))
+            .option(Opt::new("GFF").long("--annotation").help(
+                "Calculate coverage of the features in this GFF3 or \
+                GTF file instead of each contig, e.g. to summarise \
+                metatranscriptomic reads per gene or per function. \
+                Features are grouped into rows by --group-by-attribute, \
+                and reads overlapping several features of a group are \
+                counted once. Contig ends are not excluded, so cannot be \
+                used with --contig-end-exclusion. [default: not used]",
+            ))
+            .option(Opt::new("TYPE ..").long("--feature-type").help(&format!(
+                "Feature types (3rd column of the --annotation file) to \
+                include {}",
+                default_roff("CDS")
+            )))
+            .option(Opt::new("ATTRIBUTE").long("--group-by-attribute").help(&format!(
+                "Attribute of each feature used to name its row e.g. \
+                ID, gene_id or KO. Features without this attribute are \
+                ignored, and features with several comma-separated values \
+                are included in each corresponding row {}",
+                default_roff("ID")
+            ))),
     );
 
     manual = manual.custom(
         Section::new("Output")
             .option(Opt::new("FILE").short("-o").long("--output-file").help(
                 "Output coverage values to this file, or '-' for STDOUT. \
+                Files ending in .gz or .zst are gzip or zstd compressed. \
+                How the file was produced, including the command line, \
+                program versions, reference checksums and read counts of \
+                each sample, is recorded in OUTPUT_FILE.provenance.json, \
+                and likewise alongside any other output files or directories. \
                 [default: output to STDOUT]",
             ))
+            .flag(Flag::new().long("--resume").help(
+                "With --output-file, the results of each sample are saved \
+                to OUTPUT_FILE.checkpoint as they are completed. Rerun with \
+                --resume to skip samples completed by an interrupted run. \
+                Checkpoints made with different parameters are discarded. \
+                Not available with --window-size or EM multimapper \
+                resolution. [default: not set]",
+            ))
             .option(Opt::new("FORMAT").long("--output-format").help(
                 "Shape of output: 'sparse' for long format, \
-    'dense' for species-by-site. \
-    [default: dense]",
+    'dense' for species-by-site, 'json' for a single JSON document, \
+    'jsonl' for JSON Lines with one record per sample and contig, \
+    'biom' for a BIOM 2.1 (HDF5) table of a single coverage method, \
+    optionally with 'length', written to --output-file, or 'biom-json' for \
+    the same table in the BIOM 1.0 (JSON) format. 'biom' requires CoverM to \
+    be built with the hdf5 feature. JSON records include each sample's \
+    mapped and total read counts. [default: dense]",
+            ))
+            .option(Opt::new("FILE").long("--biom-taxonomy").help(
+                "Tab-separated file of contig name and semicolon-separated \
+    taxonomy, included as observation metadata with --output-format biom or \
+    biom-json. [default: not used]",
             ))
             .flag(Flag::new().long("--no-zeros").help(
                 "Omit printing of genomes that have zero \
         coverage. [default: not set]",
             ))
+            .option(Opt::new("DIRECTORY").long("--per-base-depth-output").help(
+                "Also write the read depth at each position of each \
+                contig with mapped reads to a bedGraph file in this \
+                directory, one file per sample. Adjacent positions with the \
+                same depth are merged. The directory may or may not exist. \
+                [default: not used]",
+            ))
+            .option(
+                Opt::new("FORMAT")
+                    .long("--per-base-depth-compression")
+                    .help(&format!(
+                        "Compression of --per-base-depth-output files: 'none', \
+                'gz' for gzip or 'zst' for zstd {}",
+                        default_roff("none")
+                    )),
+            )
+            .option(Opt::new("INT").long("--window-size").help(
+                "Instead of calculating coverage of each contig, \
+                calculate the mean, variance and covered fraction of \
+                coverage in windows of this many base pairs along each \
+                contig. Output is in long format, with the start \
+                (0-based) and end of each window. The last window of each \
+                contig may be shorter. --contig-end-exclusion and \
+                --min-covered-fraction are not applied. \
+                [default: not set]",
+            ))
+            .option(Opt::new("FILE").long("--window-bedgraph").help(
+                "Also output the mean coverage of each window to this \
+                file in bedGraph format, with one track per sample. \
+                Requires --window-size. [default: not set]",
+            ))
             .option(
                 Opt::new("DIRECTORY")
                     .long("--bam-file-cache-directory")
@@ -608,13 +956,25 @@ pub fn contig_full_help() -> Manual {
                 --bam-file-cache-directory saved_bam_files",
             ),
     );
-
-    let mut general_section = Section::new("General options").option(
-        Opt::new("INT")
-            .short("-t")
-            .long("--threads")
-            .help("Number of threads for mapping, sorting and reading. [default: 1]"),
+    manual = manual.example(
+        Example::new()
+            .text("Calculate coverage in 1kb windows along each contig from a sorted BAM file")
+            .command("coverm contig --window-size 1000 --bam-files my.bam"),
     );
+
+    let mut general_section = Section::new("General options")
+        .option(
+            Opt::new("INT")
+                .short("-t")
+                .long("--threads")
+                .help("Number of threads for mapping, sorting and reading. [default: 1]"),
+        )
+        .option(Opt::new("INT").long("--concurrent-mappings").help(&format!(
+            "Number of samples to map at once, sharing the --threads \
+        budget. The next samples are mapped while the coverage of the current \
+        sample is calculated. {}",
+            default_roff("1")
+        )));
     general_section = add_help_options_to_section(general_section);
     general_section = add_verbosity_flags_to_section(general_section);
     manual = manual.custom(general_section);
@@ -665,7 +1025,7 @@ pub fn genome_full_help() -> Manual {
                     index \
                     (with {}), \
                     strobealign index (with {}), \
-                    or BWA index stem (with {}). \
+                    or BWA or bowtie2 index stem (with {}). \
                     If multiple reference FASTA files are \
                     provided and {} is specified, \
                     then reads will be mapped to references \
@@ -675,7 +1035,7 @@ pub fn genome_full_help() -> Manual {
                     be optionally specified if an alternate reference sequence set is desired.",
                     monospace_roff("--minimap2-reference-is-index"),
                     monospace_roff("--strobealign-use-index"),
-                    monospace_roff("-p bwa-mem/bwa-mem2"),
+                    monospace_roff("-p bwa-mem/bwa-mem2/bowtie2"),
                     monospace_roff("--sharded"),
                     bold("NOTE"),
                     monospace_roff("--genome-fasta-files"),
@@ -767,12 +1127,18 @@ pub fn genome_full_help() -> Manual {
                         &monospace_roff("--trim-max"))],    
                     &[&monospace_roff("coverage_histogram"), "Histogram of coverage depths"],
                     &[&monospace_roff("covered_bases"), "Number of bases covered by 1 or more reads"],
+                    &[&monospace_roff("covered_fraction_at_depth"), &format!("Fraction of bases covered by at least N reads, for each N given by {}", &monospace_roff("--depth-thresholds"))],
                     &[&monospace_roff("variance"), "Variance of coverage depths"],
+                    &[&monospace_roff("median"), "Median coverage depth, counting uncovered positions as zero"],
+                    &[&monospace_roff("percentile_N"), &format!("Nth percentile of coverage depths e.g. {} for the 90th percentile", &monospace_roff("percentile_90"))],
                     &[&monospace_roff("length"), "Length of each genome in base pairs"],
                     &[&monospace_roff("count"), "Number of reads aligned to each genome. Note that supplementary alignments are not counted."],
                     &[&monospace_roff("reads_per_base"), "Number of reads aligned divided by the length of the genome"],
                     &[&monospace_roff("rpkm"), "Reads mapped per kilobase of genome, per million mapped reads"],
                     &[&monospace_roff("tpm"), "Transcripts Per Million as described in Li et al 2010 https://doi.org/10.1093/bioinformatics/btp692"],
+                    &[&monospace_roff("nucleotide_diversity"), &format!("Mean nucleotide diversity (pi) over positions covered by at least {} reads", &monospace_roff("--min-snv-coverage"))],
+                    &[&monospace_roff("snv_count"), "Number of positions where a base other than the reference base is above the minimum allele frequency. Requires MD tags in the BAM file."],
+                    &[&monospace_roff("consensus_ani"), "Fraction of sufficiently covered positions where the most common base in the reads is the reference base. Requires MD tags in the BAM file."],
                 ])
             )))
             .option(Opt::new("FRACTION").long("--min-covered-fraction").help(
@@ -793,6 +1159,51 @@ pub fn genome_full_help() -> Manual {
             .option(Opt::new("FRACTION").long("--trim-max").help(
                 &format!("Maximum fraction for trimmed_mean \
                 calculations {}", default_roff("95"))
+            ))
+            .option(Opt::new("INT ..").long("--depth-thresholds").help(
+                &format!("Minimum depths at which bases are considered \
+                covered in covered_fraction_at_depth calculations, \
+                one column is output for each {}", default_roff("1 5 10 30"))
+            ))
+            .option(Opt::new("INT").long("--min-snv-coverage").help(
+                &format!("Minimum number of reads covering a position for \
+                it to be considered in nucleotide_diversity, snv_count and \
+                consensus_ani calculations {}", default_roff("5"))
+            ))
+            .option(Opt::new("FRACTION").long("--min-snv-allele-frequency").help(
+                &format!("Minimum frequency of a non-reference base for a \
+                position to be counted in snv_count {}", default_roff("5"))
+            ))
+            .option(Opt::new("STRANDEDNESS").long("--strandedness").help(&format!(
+                "Library strandedness of RNA-seq reads: 'forward' if \
+                the first read of each pair has the same orientation as the \
+                transcript, 'reverse' if it has the opposite orientation \
+                (e.g. dUTP libraries), or 'unstranded'. When stranded, each \
+                method is reported in separate sense and antisense columns, \
+                relative to the reference strand or to the strand of each \
+                feature when --annotation or --regions is used. Cannot be \
+                used with relative_abundance, rpkm, tpm or coverage_histogram \
+                methods {}",
+                default_roff("unstranded")
+            )))
+            .option(Opt::new("MODE").long("--multimapper-resolution").help(&format!(
+                "How to treat reads which map to more than one genome. \
+                'primary' uses only the primary alignment of each read. 'em' \
+                instead distributes each read across the genomes it maps to \
+                (taken from its primary and secondary alignments) in \
+                proportion to genome abundances estimated by \
+                expectation-maximisation. 'em' requires --bam-files which \
+                retain secondary alignments, and supports only the \
+                relative_abundance, mean, covered_fraction, count, rpkm, tpm, \
+                length and reads_per_base methods {}",
+                default_roff("primary")
+            )))
+            .option(Opt::new("BED").long("--regions").help(
+                "Only calculate coverage over the intervals in this \
+                BED file, so that each genome's coverage is calculated from \
+                the intervals on its contigs only. Contig ends are not \
+                excluded, so cannot be used with --contig-end-exclusion. \
+                [default: not used]",
             )),
     );
 
@@ -800,17 +1211,58 @@ pub fn genome_full_help() -> Manual {
         Section::new("Output")
             .option(Opt::new("FILE").short("-o").long("--output-file").help(
                 "Output coverage values to this file, or '-' for STDOUT. \
+                Files ending in .gz or .zst are gzip or zstd compressed. \
+                How the file was produced, including the command line, \
+                program versions, reference checksums and read counts of \
+                each sample, is recorded in OUTPUT_FILE.provenance.json, \
+                and likewise alongside any other output files or directories. \
                 [default: output to STDOUT]",
             ))
+            .flag(Flag::new().long("--resume").help(
+                "With --output-file, the results of each sample are saved \
+                to OUTPUT_FILE.checkpoint as they are completed. Rerun with \
+                --resume to skip samples completed by an interrupted run. \
+                Checkpoints made with different parameters are discarded. \
+                Not available with --window-size or EM multimapper \
+                resolution. [default: not set]",
+            ))
             .option(Opt::new("FORMAT").long("--output-format").help(&format!(
                 "Shape of output: 'sparse' for long format, \
-            'dense' for species-by-site. {}",
+            'dense' for species-by-site, 'json' for a single JSON document, \
+            'jsonl' for JSON Lines with one record per sample and genome, \
+            'biom' for a BIOM 2.1 (HDF5) table of a single coverage method, \
+            optionally with 'length', written to --output-file, or 'biom-json' \
+            for the same table in the BIOM 1.0 (JSON) format. 'biom' requires \
+            CoverM to be built with the hdf5 feature. JSON records include \
+            each sample's mapped and total read counts. {}",
                 default_roff("dense")
             )))
+            .option(Opt::new("FILE").long("--biom-taxonomy").help(
+                "Tab-separated file of genome name and semicolon-separated \
+            taxonomy e.g. a GTDB-Tk summary's first two columns, included as \
+            observation metadata with --output-format biom or biom-json. \
+            [default: not used]",
+            ))
             .flag(Flag::new().long("--no-zeros").help(
                 "Omit printing of genomes that have zero \
             coverage. [default: not set]",
             ))
+            .option(Opt::new("DIRECTORY").long("--per-base-depth-output").help(
+                "Also write the read depth at each position of each \
+                contig with mapped reads to a bedGraph file in this \
+                directory, one file per sample. Adjacent positions with the \
+                same depth are merged. The directory may or may not exist. \
+                [default: not used]",
+            ))
+            .option(
+                Opt::new("FORMAT")
+                    .long("--per-base-depth-compression")
+                    .help(&format!(
+                        "Compression of --per-base-depth-output files: 'none', \
+                'gz' for gzip or 'zst' for zstd {}",
+                        default_roff("none")
+                    )),
+            )
             .option(
                 Opt::new("DIRECTORY")
                     .long("--bam-file-cache-directory")
@@ -857,12 +1309,17 @@ pub fn genome_full_help() -> Manual {
             ),
     );
 
-    let mut general_section = Section::new("General options").option(
-        Opt::new("INT").short("-t").long("--threads").help(&format!(
+    let mut general_section = Section::new("General options")
+        .option(Opt::new("INT").short("-t").long("--threads").help(&format!(
             "Number of threads for mapping, sorting and reading. {}",
             default_roff("1")
-        )),
-    );
+        )))
+        .option(Opt::new("INT").long("--concurrent-mappings").help(&format!(
+            "Number of samples to map at once, sharing the --threads \
+        budget. The next samples are mapped while the coverage of the current \
+        sample is calculated. {}",
+            default_roff("1")
+        )));
     general_section = add_help_options_to_section(general_section);
     general_section = add_verbosity_flags_to_section(general_section);
     manual = manual.custom(general_section);
@@ -962,6 +1419,23 @@ See coverm filter --full-help for further options and further detail.
                  threads for output compression:"
             ),
         );
+        static ref MERGE_HELP: String = format!(
+            "
+                            {}
+                     {}
+
+{}
+
+  coverm merge -i batch1.tsv batch2.tsv -o all_samples.tsv
+
+See coverm merge --full-help for further options and further detail.
+",
+            ansi_term::Colour::Green.paint("coverm merge"),
+            ansi_term::Colour::Green.paint("Merge coverage tables from separate runs"),
+            ansi_term::Colour::Purple.paint(
+                "Example: Merge the genome coverage of two batches of samples into one table:"
+            ),
+        );
         static ref MAKE_HELP: String = format!(
             "
                             {}
@@ -1002,6 +1476,7 @@ Main subcommands:
 
 Less used utility subcommands:
 \tmake\tGenerate BAM files through alignment
+\tmerge\tMerge coverage tables from separate runs
 \tfilter\tRemove (or only keep) alignments with insufficient identity
 \tcluster\tDereplicate and cluster genomes
 \tshell-completion
@@ -1059,6 +1534,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1077,6 +1553,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1093,6 +1570,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1108,6 +1586,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1123,11 +1602,24 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "interleaved",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
                         .conflicts_with("bam-files"),
                 )
+                .arg(
+                    Arg::new("sample-sheet")
+                        .long("sample-sheet")
+                        .conflicts_with_all([
+                            "bam-files",
+                            "read1",
+                            "read2",
+                            "coupled",
+                            "interleaved",
+                            "single",
+                        ]),
+                )
                 .arg(
                     Arg::new("reference")
                         .short('r')
@@ -1148,6 +1640,12 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .value_parser(clap::value_parser!(u16))
                         .default_value("1"),
                 )
+                .arg(
+                    Arg::new("concurrent-mappings")
+                        .long("concurrent-mappings")
+                        .value_parser(clap::value_parser!(u16).range(1..))
+                        .default_value("1"),
+                )
                 .arg(
                     Arg::new("mapper")
                         .short('p')
@@ -1187,6 +1685,17 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("reference")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("bowtie2-params")
+                        .long("bowtie2-params")
+                        .long("bowtie2-parameters")
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::new("index-cache-directory")
+                        .long("index-cache-directory")
+                        .conflicts_with("minimap2-reference-is-index"),
+                )
                 // TODO: Relax this for autoconcatenation
                 .arg(
                     Arg::new("discard-unmapped")
@@ -1338,6 +1847,54 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .value_parser(clap::value_parser!(f32))
                         .requires("proper-pairs-only"),
                 )
+                .arg(
+                    Arg::new("max-read-clipped-percent")
+                        .long("max-read-clipped-percent")
+                        .value_parser(clap::value_parser!(f32)),
+                )
+                .arg(
+                    Arg::new("max-read-clipped-percent-pair")
+                        .long("max-read-clipped-percent-pair")
+                        .value_parser(clap::value_parser!(f32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-mapq")
+                        .long("min-mapq")
+                        .value_parser(clap::value_parser!(u8)),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference")
+                        .long("min-as-xs-difference")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("min-mapq-pair")
+                        .long("min-mapq-pair")
+                        .value_parser(clap::value_parser!(u8))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference-pair")
+                        .long("min-as-xs-difference-pair")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-insert-size")
+                        .long("min-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("max-insert-size")
+                        .long("max-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("pair-orientation")
+                        .long("pair-orientation")
+                        .value_parser(["fr", "rf", "ff"]),
+                )
                 .arg(
                     Arg::new("methods")
                         .short('m')
@@ -1345,20 +1902,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("methods")
                         .action(clap::ArgAction::Append)
                         .num_args(1..)
-                        .value_parser([
-                            "relative_abundance",
-                            "mean",
-                            "trimmed_mean",
-                            "coverage_histogram",
-                            "covered_fraction",
-                            "covered_bases",
-                            "variance",
-                            "length",
-                            "count",
-                            "reads_per_base",
-                            "rpkm",
-                            "tpm",
-                        ])
+                        .value_parser(parse_genome_method)
                         .default_value("relative_abundance"),
                 )
                 .arg(
@@ -1373,6 +1917,25 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .default_value("95")
                         .value_parser(clap::value_parser!(f32)),
                 )
+                .arg(
+                    Arg::new("depth-thresholds")
+                        .long("depth-thresholds")
+                        .num_args(1..)
+                        .default_values(["1", "5", "10", "30"])
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("min-snv-coverage")
+                        .long("min-snv-coverage")
+                        .default_value("5")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("min-snv-allele-frequency")
+                        .long("min-snv-allele-frequency")
+                        .default_value("5")
+                        .value_parser(clap::value_parser!(f32)),
+                )
                 .arg(
                     Arg::new("min-covered-fraction")
                         .long("min-covered-fraction")
@@ -1390,6 +1953,36 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("no-zeros")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(Arg::new("per-base-depth-output").long("per-base-depth-output"))
+                .arg(
+                    Arg::new("per-base-depth-compression")
+                        .long("per-base-depth-compression")
+                        .value_parser(["none", "gz", "zst"])
+                        .default_value("none"),
+                )
+                .arg(
+                    Arg::new("regions")
+                        .long("regions")
+                        .conflicts_with("per-base-depth-output"),
+                )
+                .arg(
+                    Arg::new("strandedness")
+                        .long("strandedness")
+                        .value_parser(["forward", "reverse", "unstranded"])
+                        .conflicts_with("per-base-depth-output"),
+                )
+                .arg(
+                    Arg::new("multimapper-resolution")
+                        .long("multimapper-resolution")
+                        .value_parser(["primary", "em"])
+                        .default_value("primary")
+                        .conflicts_with_all([
+                            "sharded",
+                            "regions",
+                            "per-base-depth-output",
+                            "strandedness",
+                        ]),
+                )
                 .arg(
                     Arg::new("proper-pairs-only")
                         .long("proper-pairs-only")
@@ -1406,12 +1999,18 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .action(clap::ArgAction::SetTrue),
                 )
                 .arg(Arg::new("output-file").long("output-file").short('o'))
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .action(clap::ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("output-format")
                         .long("output-format")
-                        .value_parser(["sparse", "dense"])
+                        .value_parser(["sparse", "dense", "json", "jsonl", "biom", "biom-json"])
                         .default_value("dense"),
                 )
+                .arg(Arg::new("biom-taxonomy").long("biom-taxonomy"))
                 .arg(
                     Arg::new("dereplicate")
                         .long("dereplicate")
@@ -1557,6 +2156,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1573,6 +2173,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1589,6 +2190,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1604,6 +2206,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
@@ -1619,11 +2222,24 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "interleaved",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ])
                         .conflicts_with("bam-files"),
                 )
+                .arg(
+                    Arg::new("sample-sheet")
+                        .long("sample-sheet")
+                        .conflicts_with_all([
+                            "bam-files",
+                            "read1",
+                            "read2",
+                            "coupled",
+                            "interleaved",
+                            "single",
+                        ]),
+                )
                 .arg(
                     Arg::new("reference")
                         .short('r')
@@ -1645,6 +2261,12 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .default_value("1")
                         .value_parser(clap::value_parser!(u16)),
                 )
+                .arg(
+                    Arg::new("concurrent-mappings")
+                        .long("concurrent-mappings")
+                        .value_parser(clap::value_parser!(u16).range(1..))
+                        .default_value("1"),
+                )
                 .arg(
                     Arg::new("mapper")
                         .short('p')
@@ -1684,6 +2306,17 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .requires("reference")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("bowtie2-params")
+                        .long("bowtie2-params")
+                        .long("bowtie2-parameters")
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::new("index-cache-directory")
+                        .long("index-cache-directory")
+                        .conflicts_with("minimap2-reference-is-index"),
+                )
                 .arg(
                     Arg::new("discard-unmapped")
                         .long("discard-unmapped")
@@ -1723,29 +2356,70 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .value_parser(clap::value_parser!(f32))
                         .requires("proper-pairs-only"),
                 )
+                .arg(
+                    Arg::new("max-read-clipped-percent")
+                        .long("max-read-clipped-percent")
+                        .value_parser(clap::value_parser!(f32)),
+                )
+                .arg(
+                    Arg::new("max-read-clipped-percent-pair")
+                        .long("max-read-clipped-percent-pair")
+                        .value_parser(clap::value_parser!(f32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-mapq")
+                        .long("min-mapq")
+                        .value_parser(clap::value_parser!(u8)),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference")
+                        .long("min-as-xs-difference")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("min-mapq-pair")
+                        .long("min-mapq-pair")
+                        .value_parser(clap::value_parser!(u8))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference-pair")
+                        .long("min-as-xs-difference-pair")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-insert-size")
+                        .long("min-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("max-insert-size")
+                        .long("max-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("pair-orientation")
+                        .long("pair-orientation")
+                        .value_parser(["fr", "rf", "ff"]),
+                )
                 .arg(
                     Arg::new("methods")
                         .short('m')
                         .long("method")
                         .long("methods")
-                        .value_parser([
-                            "mean",
-                            "trimmed_mean",
-                            "coverage_histogram",
-                            "covered_fraction",
-                            "covered_bases",
-                            "variance",
-                            "length",
-                            "count",
-                            "metabat",
-                            "reads_per_base",
-                            "rpkm",
-                            "tpm",
-                        ])
+                        .value_parser(parse_contig_method)
                         .default_value("mean")
                         .action(clap::ArgAction::Append)
                         .num_args(1..),
                 )
+                .arg(
+                    Arg::new("maxbin2-compression")
+                        .long("maxbin2-compression")
+                        .value_parser(["none", "gz", "zst"])
+                        .default_value("none"),
+                )
                 .arg(
                     Arg::new("min-covered-fraction")
                         .long("min-covered-fraction")
@@ -1770,11 +2444,75 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .default_value("95")
                         .value_parser(clap::value_parser!(f32)),
                 )
+                .arg(
+                    Arg::new("depth-thresholds")
+                        .long("depth-thresholds")
+                        .num_args(1..)
+                        .default_values(["1", "5", "10", "30"])
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
                 .arg(
                     Arg::new("no-zeros")
                         .long("no-zeros")
                         .action(clap::ArgAction::SetTrue),
                 )
+                .arg(
+                    Arg::new("window-size")
+                        .long("window-size")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .conflicts_with_all(["methods", "output-format"]),
+                )
+                .arg(
+                    Arg::new("window-bedgraph")
+                        .long("window-bedgraph")
+                        .requires("window-size"),
+                )
+                .arg(
+                    Arg::new("per-base-depth-output")
+                        .long("per-base-depth-output")
+                        .conflicts_with("window-size"),
+                )
+                .arg(
+                    Arg::new("per-base-depth-compression")
+                        .long("per-base-depth-compression")
+                        .value_parser(["none", "gz", "zst"])
+                        .default_value("none"),
+                )
+                .arg(
+                    Arg::new("regions")
+                        .long("regions")
+                        .conflicts_with_all(["window-size", "per-base-depth-output"]),
+                )
+                .arg(
+                    Arg::new("aggregate-regions-by-name")
+                        .long("aggregate-regions-by-name")
+                        .requires("regions")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("strandedness")
+                        .long("strandedness")
+                        .value_parser(["forward", "reverse", "unstranded"])
+                        .conflicts_with_all(["window-size", "per-base-depth-output"]),
+                )
+                .arg(
+                    Arg::new("annotation")
+                        .long("annotation")
+                        .conflicts_with_all(["regions", "window-size", "per-base-depth-output"]),
+                )
+                .arg(
+                    Arg::new("feature-type")
+                        .long("feature-type")
+                        .num_args(1..)
+                        .requires("annotation")
+                        .default_value("CDS"),
+                )
+                .arg(
+                    Arg::new("group-by-attribute")
+                        .long("group-by-attribute")
+                        .requires("annotation")
+                        .default_value("ID"),
+                )
                 .arg(
                     Arg::new("proper-pairs-only")
                         .long("proper-pairs-only")
@@ -1791,12 +2529,18 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .action(clap::ArgAction::SetTrue),
                 )
                 .arg(Arg::new("output-file").long("output-file").short('o'))
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .action(clap::ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("output-format")
                         .long("output-format")
-                        .value_parser(["sparse", "dense"])
+                        .value_parser(["sparse", "dense", "json", "jsonl", "biom", "biom-json"])
                         .default_value("dense"),
-                ),
+                )
+                .arg(Arg::new("biom-taxonomy").long("biom-taxonomy")),
         )
         .subcommand(
             Command::new("filter") // Do not use add_clap_verbosity_flags since -v shouldn't be used here, specify manually below
@@ -1866,6 +2610,54 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .value_parser(clap::value_parser!(f32))
                         .requires("proper-pairs-only"),
                 )
+                .arg(
+                    Arg::new("max-read-clipped-percent")
+                        .long("max-read-clipped-percent")
+                        .value_parser(clap::value_parser!(f32)),
+                )
+                .arg(
+                    Arg::new("max-read-clipped-percent-pair")
+                        .long("max-read-clipped-percent-pair")
+                        .value_parser(clap::value_parser!(f32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-mapq")
+                        .long("min-mapq")
+                        .value_parser(clap::value_parser!(u8)),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference")
+                        .long("min-as-xs-difference")
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("min-mapq-pair")
+                        .long("min-mapq-pair")
+                        .value_parser(clap::value_parser!(u8))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-as-xs-difference-pair")
+                        .long("min-as-xs-difference-pair")
+                        .value_parser(clap::value_parser!(u32))
+                        .requires("proper-pairs-only"),
+                )
+                .arg(
+                    Arg::new("min-insert-size")
+                        .long("min-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("max-insert-size")
+                        .long("max-insert-size")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("pair-orientation")
+                        .long("pair-orientation")
+                        .value_parser(["fr", "rf", "ff"]),
+                )
                 .arg(
                     Arg::new("proper-pairs-only")
                         .long("proper-pairs-only")
@@ -1932,6 +2724,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ]),
@@ -1946,6 +2739,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "coupled",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ]),
@@ -1960,6 +2754,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "interleaved",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ]),
@@ -1973,6 +2768,7 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "single",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ]),
@@ -1986,10 +2782,16 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                             "read1",
                             "coupled",
                             "interleaved",
+                            "sample-sheet",
                             "full-help",
                             "full-help-roff",
                         ]),
                 )
+                .arg(
+                    Arg::new("sample-sheet")
+                        .long("sample-sheet")
+                        .conflicts_with_all(["read1", "read2", "coupled", "interleaved", "single"]),
+                )
                 .arg(
                     Arg::new("reference")
                         .short('r')
@@ -2049,6 +2851,47 @@ Ben J. Woodcroft <benjwoodcroft near gmail.com>
                         .long("strobealign-use-index")
                         .requires("reference")
                         .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("bowtie2-params")
+                        .long("bowtie2-params")
+                        .long("bowtie2-parameters")
+                        .allow_hyphen_values(true),
+                )
+                .arg(
+                    Arg::new("index-cache-directory")
+                        .long("index-cache-directory")
+                        .conflicts_with("minimap2-reference-is-index"),
+                ),
+        )
+        .subcommand(
+            add_clap_verbosity_flags(Command::new("merge"))
+                .about("Merge coverage tables from separate runs")
+                .override_help(MERGE_HELP.as_str())
+                .arg(
+                    Arg::new("full-help")
+                        .long("full-help")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("full-help-roff")
+                        .long("full-help-roff")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("input-files")
+                        .short('i')
+                        .long("input-files")
+                        .action(clap::ArgAction::Append)
+                        .num_args(1..)
+                        .required_unless_present_any(["full-help", "full-help-roff"]),
+                )
+                .arg(Arg::new("output-file").long("output-file").short('o'))
+                .arg(
+                    Arg::new("output-format")
+                        .long("output-format")
+                        .value_parser(["sparse", "dense"])
+                        .default_value("dense"),
                 ),
         )
         .subcommand(
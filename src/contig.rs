@@ -1,4 +1,5 @@
 use std;
+use std::io::Write;
 
 use rust_htslib::bam;
 use rust_htslib::bam::record::Cigar;
@@ -7,9 +8,12 @@ use bam_generator::*;
 use coverage_takers::*;
 use mosdepth_genome_coverage_estimators::*;
 use nm;
+use per_base_depth::PerBaseDepthWriter;
+use strandedness::*;
 use FlagFilter;
 use ReadsMapped;
 
+#[allow(clippy::too_many_arguments)]
 pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: CoverageTaker>(
     bam_readers: Vec<G>,
     coverage_taker: &mut T,
@@ -17,6 +21,8 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
     print_zero_coverage_contigs: bool,
     flag_filters: &FlagFilter,
     threads: u16,
+    mut per_base_depth_writer: Option<&mut PerBaseDepthWriter>,
+    strandedness: Strandedness,
 ) -> Vec<ReadsMapped> {
     let mut reads_mapped_vector = vec![];
     for bam_generator in bam_readers {
@@ -25,25 +31,34 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
 
         let stoit_name = &(bam_generated.name().to_string());
         coverage_taker.start_stoit(stoit_name);
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.start_stoit(stoit_name);
+        }
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut last_tid: i32 = -2; // no such tid in a real BAM file
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut antisense_ups_and_downs: Vec<i32> = Vec::new();
         let header = bam_generated.header().clone();
         let target_names = header.target_names();
 
         let mut num_mapped_reads_total: u64 = 0;
         let mut num_mapped_reads_in_current_contig: u64 = 0;
+        let mut num_antisense_reads_in_current_contig: u64 = 0;
         let mut total_indels_in_current_contig: u64 = 0;
         let mut total_edit_distance_in_current_contig: u64 = 0;
+        let mut num_antisense_mismatches_in_current_contig: u64 = 0;
 
         let mut process_previous_contigs =
             |last_tid,
              tid,
              coverage_estimators: &mut Vec<CoverageEstimator>,
              ups_and_downs: &[i32],
+             antisense_ups_and_downs: &[i32],
              num_mapped_reads_in_current_contig,
+             num_antisense_reads_in_current_contig,
              total_edit_distance_in_current_contig,
              total_indels_in_current_contig,
+             num_antisense_mismatches_in_current_contig,
              num_mapped_reads_total: &mut u64| {
                 if last_tid != -2 {
                     debug!(
@@ -54,13 +69,19 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
                         total_edit_distance_in_current_contig,
                         total_indels_in_current_contig
                     );
-                    for estimator in coverage_estimators.iter_mut() {
-                        estimator.add_contig(
-                            ups_and_downs,
-                            num_mapped_reads_in_current_contig,
-                            total_edit_distance_in_current_contig - total_indels_in_current_contig,
-                        )
+                    if let Some(ref mut writer) = per_base_depth_writer {
+                        writer.add_contig(target_names[last_tid as usize], ups_and_downs);
                     }
+                    add_contig_to_estimators(
+                        coverage_estimators,
+                        strandedness,
+                        ups_and_downs,
+                        antisense_ups_and_downs,
+                        num_mapped_reads_in_current_contig,
+                        num_antisense_reads_in_current_contig,
+                        total_edit_distance_in_current_contig - total_indels_in_current_contig,
+                        num_antisense_mismatches_in_current_contig,
+                    );
                     let coverages: Vec<f32> = coverage_estimators
                         .iter_mut()
                         .map(|estimator| estimator.calculate_coverage(&[0]))
@@ -135,69 +156,58 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
                         tid,
                         coverage_estimators,
                         &ups_and_downs,
+                        &antisense_ups_and_downs,
                         num_mapped_reads_in_current_contig,
+                        num_antisense_reads_in_current_contig,
                         total_edit_distance_in_current_contig,
                         total_indels_in_current_contig,
+                        num_antisense_mismatches_in_current_contig,
                         &mut num_mapped_reads_total,
                     );
                     ups_and_downs =
                         vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                    if strandedness.is_stranded() {
+                        antisense_ups_and_downs = vec![0; ups_and_downs.len()];
+                    }
                     debug!(
                         "Working on new reference {}",
                         std::str::from_utf8(target_names[tid as usize]).unwrap()
                     );
                     last_tid = tid;
                     num_mapped_reads_in_current_contig = 0;
+                    num_antisense_reads_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
+                    num_antisense_mismatches_in_current_contig = 0;
                 }
 
+                let is_antisense = strandedness.is_antisense(&record);
                 if !record.is_supplementary() && !record.is_secondary() {
                     num_mapped_reads_in_current_contig += 1;
+                    if is_antisense {
+                        num_antisense_reads_in_current_contig += 1;
+                    }
                 }
+                let pileup = match is_antisense {
+                    true => &mut antisense_ups_and_downs,
+                    false => &mut ups_and_downs,
+                };
 
-                // for each chunk of the cigar string
                 trace!(
                     "read name {:?}",
                     std::str::from_utf8(record.qname()).unwrap()
                 );
-                let mut cursor: usize = record.pos() as usize;
-                for cig in record.cigar().iter() {
-                    trace!("Found cigar {:} from {}", cig, cursor);
-                    match cig {
-                        Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
-                            // if M, X, or = increment start and decrement end index
-                            trace!(
-                                "Adding M, X, or = at {} and {}",
-                                cursor,
-                                cursor + cig.len() as usize
-                            );
-                            ups_and_downs[cursor] += 1;
-                            let final_pos = cursor + cig.len() as usize;
-                            if final_pos < ups_and_downs.len() {
-                                // True unless the read hits the contig end.
-                                ups_and_downs[final_pos] -= 1;
-                            }
-                            cursor += cig.len() as usize;
-                        }
-                        Cigar::Del(_) => {
-                            cursor += cig.len() as usize;
-                            total_indels_in_current_contig += cig.len() as u64;
-                        }
-                        Cigar::RefSkip(_) => {
-                            // if D or N, move the cursor
-                            cursor += cig.len() as usize;
-                        }
-                        Cigar::Ins(_) => {
-                            total_indels_in_current_contig += cig.len() as u64;
-                        }
-                        Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
-                    }
-                }
+                let indels = add_record_to_ups_and_downs(&record, pileup);
+                total_indels_in_current_contig += indels;
 
                 // Determine the number of mismatching bases in this read by
                 // looking at the NM tag.
-                total_edit_distance_in_current_contig += nm(&record);
+                let edit_distance = nm(&record);
+                total_edit_distance_in_current_contig += edit_distance;
+                if is_antisense {
+                    num_antisense_mismatches_in_current_contig +=
+                        edit_distance.saturating_sub(indels);
+                }
 
                 trace!("At end of loop")
             }
@@ -208,14 +218,20 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
             target_names.len() as i32,
             coverage_estimators,
             &ups_and_downs,
+            &antisense_ups_and_downs,
             num_mapped_reads_in_current_contig,
+            num_antisense_reads_in_current_contig,
             total_edit_distance_in_current_contig,
             total_indels_in_current_contig,
+            num_antisense_mismatches_in_current_contig,
             &mut num_mapped_reads_total,
         );
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.finish_stoit();
+        }
 
         let reads_mapped = ReadsMapped {
-            num_mapped_reads: num_mapped_reads_total,
+            num_mapped_reads: num_mapped_reads_total as f64,
             num_reads: bam_generated.num_detected_primary_alignments(),
         };
         info!(
@@ -224,7 +240,7 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
             reads_mapped.num_mapped_reads,
             reads_mapped.num_reads,
             2,
-            (reads_mapped.num_mapped_reads * 100) as f64 / reads_mapped.num_reads as f64
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
         );
         reads_mapped_vector.push(reads_mapped);
 
@@ -241,6 +257,219 @@ pub fn contig_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: Cove
     reads_mapped_vector
 }
 
+/// Add the aligned bases of a record to the ups and downs of its contig, i.e.
+/// increment the depth at the start of each M, X or = run and decrement it
+/// after the end. Returns the number of inserted and deleted bases.
+fn add_record_to_ups_and_downs(record: &bam::Record, ups_and_downs: &mut [i32]) -> u64 {
+    let mut indels: u64 = 0;
+    let mut cursor: usize = record.pos() as usize;
+    for cig in record.cigar().iter() {
+        trace!("Found cigar {:} from {}", cig, cursor);
+        match cig {
+            Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                // if M, X, or = increment start and decrement end index
+                trace!(
+                    "Adding M, X, or = at {} and {}",
+                    cursor,
+                    cursor + cig.len() as usize
+                );
+                ups_and_downs[cursor] += 1;
+                let final_pos = cursor + cig.len() as usize;
+                if final_pos < ups_and_downs.len() {
+                    // True unless the read hits the contig end.
+                    ups_and_downs[final_pos] -= 1;
+                }
+                cursor += cig.len() as usize;
+            }
+            Cigar::Del(_) => {
+                cursor += cig.len() as usize;
+                indels += cig.len() as u64;
+            }
+            Cigar::RefSkip(_) => {
+                // if D or N, move the cursor
+                cursor += cig.len() as usize;
+            }
+            Cigar::Ins(_) => {
+                indels += cig.len() as u64;
+            }
+            Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    indels
+}
+
+/// Calculate the mean, variance and covered fraction of read depth in
+/// fixed-size windows along each contig. One line is printed to print_stream
+/// per window, and the mean of each window is optionally also printed in
+/// bedGraph format, with one track per sample.
+pub fn contig_window_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>>(
+    bam_readers: Vec<G>,
+    window_size: u64,
+    print_stream: &mut dyn Write,
+    mut bedgraph_stream: Option<&mut dyn Write>,
+    print_zero_coverage_contigs: bool,
+    flag_filters: &FlagFilter,
+    threads: u16,
+) -> Vec<ReadsMapped> {
+    let mut reads_mapped_vector = vec![];
+    for bam_generator in bam_readers {
+        let mut bam_generated = bam_generator.start();
+        bam_generated.set_threads(threads as usize);
+
+        let stoit_name = &(bam_generated.name().to_string());
+        if let Some(ref mut bedgraph) = bedgraph_stream {
+            writeln!(bedgraph, "track type=bedGraph name=\"{}\"", stoit_name).unwrap();
+        }
+        let mut record: bam::record::Record = bam::record::Record::new();
+        let mut last_tid: i32 = -2; // no such tid in a real BAM file
+        let mut ups_and_downs: Vec<i32> = Vec::new();
+        let header = bam_generated.header().clone();
+        let target_names = header.target_names();
+        let mut num_mapped_reads_total: u64 = 0;
+
+        let mut process_previous_contigs =
+            |last_tid: i32,
+             tid: i32,
+             ups_and_downs: &[i32],
+             bedgraph_stream: &mut Option<&mut dyn Write>| {
+                if last_tid != -2 {
+                    print_contig_windows(
+                        stoit_name,
+                        std::str::from_utf8(target_names[last_tid as usize]).unwrap(),
+                        ups_and_downs,
+                        window_size as usize,
+                        print_stream,
+                        bedgraph_stream,
+                    );
+                }
+                if print_zero_coverage_contigs {
+                    let mut my_tid = match last_tid {
+                        -2 => 0,
+                        _ => last_tid + 1,
+                    };
+                    while my_tid < tid {
+                        print_contig_windows(
+                            stoit_name,
+                            std::str::from_utf8(target_names[my_tid as usize]).unwrap(),
+                            &vec![0; header.target_len(my_tid as u32).unwrap() as usize],
+                            window_size as usize,
+                            print_stream,
+                            bedgraph_stream,
+                        );
+                        my_tid += 1;
+                    }
+                }
+            };
+
+        loop {
+            match bam_generated.read(&mut record) {
+                None => {
+                    break;
+                }
+                Some(Ok(())) => {}
+                Some(e) => {
+                    panic!("Error reading BAM record: {:?}", e)
+                }
+            }
+
+            if !flag_filters.passes(&record) || record.is_unmapped() {
+                continue;
+            }
+            let tid = record.tid();
+            if tid != last_tid {
+                if tid < last_tid {
+                    error!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                    panic!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                }
+                process_previous_contigs(last_tid, tid, &ups_and_downs, &mut bedgraph_stream);
+                ups_and_downs =
+                    vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                last_tid = tid;
+            }
+
+            if !record.is_supplementary() && !record.is_secondary() {
+                num_mapped_reads_total += 1;
+            }
+
+            add_record_to_ups_and_downs(&record, &mut ups_and_downs);
+        }
+
+        process_previous_contigs(
+            last_tid,
+            target_names.len() as i32,
+            &ups_and_downs,
+            &mut bedgraph_stream,
+        );
+
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: num_mapped_reads_total as f64,
+            num_reads: bam_generated.num_detected_primary_alignments(),
+        };
+        info!(
+            "In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
+            stoit_name,
+            reads_mapped.num_mapped_reads,
+            reads_mapped.num_reads,
+            2,
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
+        );
+        reads_mapped_vector.push(reads_mapped);
+
+        bam_generated.finish();
+    }
+    reads_mapped_vector
+}
+
+fn print_contig_windows(
+    stoit_name: &str,
+    contig_name: &str,
+    ups_and_downs: &[i32],
+    window_size: usize,
+    print_stream: &mut dyn Write,
+    bedgraph_stream: &mut Option<&mut dyn Write>,
+) {
+    let mut cumulative_sum: i32 = 0;
+    let mut start = 0;
+    while start < ups_and_downs.len() {
+        let end = std::cmp::min(start + window_size, ups_and_downs.len());
+        // Welford's algorithm, to avoid overflow and cancellation in deep
+        // windows
+        let mut num_bases: u64 = 0;
+        let mut mean: f64 = 0.0;
+        let mut sum_squared_deviations: f64 = 0.0;
+        let mut num_covered_bases: u64 = 0;
+        for current in ups_and_downs[start..end].iter() {
+            cumulative_sum += current;
+            let depth = cumulative_sum as f64;
+            num_bases += 1;
+            let delta = depth - mean;
+            mean += delta / num_bases as f64;
+            sum_squared_deviations += delta * (depth - mean);
+            if cumulative_sum > 0 {
+                num_covered_bases += 1;
+            }
+        }
+        // Sample variance, as for the variance coverage method
+        let variance = match num_bases < 2 {
+            true => 0.0,
+            false => sum_squared_deviations / (num_bases - 1) as f64,
+        };
+        let mean = mean as f32;
+        let variance = variance as f32;
+        let covered_fraction = num_covered_bases as f32 / num_bases as f32;
+        writeln!(
+            print_stream,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            stoit_name, contig_name, start, end, mean, variance, covered_fraction
+        )
+        .unwrap();
+        if let Some(ref mut bedgraph) = bedgraph_stream {
+            writeln!(bedgraph, "{}\t{}\t{}\t{}", contig_name, start, end, mean).unwrap();
+        }
+        start = end;
+    }
+}
+
 fn print_previous_zero_coverage_contigs<T: CoverageTaker>(
     last_tid: i32,
     current_tid: i32,
@@ -305,6 +534,8 @@ mod tests {
                 print_zero_coverage_contigs,
                 &flag_filters,
                 1,
+                None,
+                Strandedness::Unstranded,
             );
         }
         let mut buf = vec![];
@@ -402,6 +633,7 @@ mod tests {
                 false,
                 None,
                 true,
+                None,
             )],
             &mut vec![CoverageEstimator::new_estimator_mean(0.0, 0, false)],
             false,
@@ -528,6 +760,159 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_median_and_percentile_estimators() {
+        test_with_stream(
+            "2seqs.reads_for_seq1\tseq1\t1\t2\n2seqs.reads_for_seq1\tseq2\t0\t0\n",
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            &mut vec![
+                CoverageEstimator::new_estimator_median(0.0, 0),
+                CoverageEstimator::new_estimator_percentile(75.0, 0.0, 0),
+            ],
+            true,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_median_and_percentile_estimators_contig_end_exclusion() {
+        test_with_stream(
+            "7seqs.reads_for_seq1_and_seq2\tgenome2~seq1\t1\t3\n\
+             7seqs.reads_for_seq1_and_seq2\tgenome5~seq2\t1\t2\n",
+            generate_named_bam_readers_from_bam_files(vec![
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+            ]),
+            &mut vec![
+                CoverageEstimator::new_estimator_median(0.0, 75),
+                CoverageEstimator::new_estimator_percentile(90.0, 0.0, 75),
+            ],
+            false,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_covered_fraction_at_depth_estimator() {
+        test_with_stream(
+            "2seqs.reads_for_seq1\tseq1\t0.727\t0.352\t0.121\t0\n\
+             2seqs.reads_for_seq1\tseq2\t0\t0\t0\t0\n",
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            &mut vec![CoverageEstimator::new_estimator_covered_fraction_at_depth(
+                vec![1, 2, 3, 5],
+                0.0,
+            )],
+            true,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_covered_fraction_at_depth_estimator_min_covered_fraction() {
+        test_with_stream(
+            "2seqs.reads_for_seq1\tseq1\t0\t0\t0\t0\n\
+             2seqs.reads_for_seq1\tseq2\t0\t0\t0\t0\n",
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            &mut vec![CoverageEstimator::new_estimator_covered_fraction_at_depth(
+                vec![1, 2, 3, 5],
+                0.8,
+            )],
+            true,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_stranded_coverage() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    OutputWriter::generate(Some(tf.path().to_str().unwrap())),
+                );
+            let estimators = vec![
+                CoverageEstimator::new_estimator_read_count(),
+                CoverageEstimator::new_estimator_mean(0.0, 0, false),
+            ];
+            contig_coverage(
+                generate_named_bam_readers_from_bam_files(vec![
+                    "tests/data/2seqs.reads_for_seq1.bam",
+                ]),
+                &mut coverage_taker,
+                &mut [estimators.clone(), estimators].concat(),
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+                None,
+                Strandedness::Forward,
+            );
+        }
+        assert_eq!(
+            "2seqs.reads_for_seq1\tseq1\t6\t0.6\t6\t0.6\n\
+             2seqs.reads_for_seq1\tseq2\t0\t0\t0\t0\n",
+            std::fs::read_to_string(tf.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_window_coverage() {
+        let mut output: Vec<u8> = vec![];
+        let mut bedgraph: Vec<u8> = vec![];
+        contig_window_coverage(
+            generate_named_bam_readers_from_bam_files(vec!["tests/data/2seqs.reads_for_seq1.bam"]),
+            400,
+            &mut output,
+            Some(&mut bedgraph),
+            true,
+            &FlagFilter {
+                include_improper_pairs: true,
+                include_secondary: false,
+                include_supplementary: false,
+            },
+            1,
+        );
+        assert_eq!(
+            "2seqs.reads_for_seq1\tseq1\t0\t400\t1.085\t0.8849875\t0.6975\n\
+             2seqs.reads_for_seq1\tseq1\t400\t800\t1.405\t0.73280704\t0.855\n\
+             2seqs.reads_for_seq1\tseq1\t800\t1000\t1.02\t1.3764824\t0.53\n\
+             2seqs.reads_for_seq1\tseq2\t0\t400\t0\t0\t0\n\
+             2seqs.reads_for_seq1\tseq2\t400\t800\t0\t0\t0\n\
+             2seqs.reads_for_seq1\tseq2\t800\t1000\t0\t0\t0\n",
+            str::from_utf8(&output).unwrap()
+        );
+        assert_eq!(
+            "track type=bedGraph name=\"2seqs.reads_for_seq1\"\n\
+             seq1\t0\t400\t1.085\n\
+             seq1\t400\t800\t1.405\n\
+             seq1\t800\t1000\t1.02\n\
+             seq2\t0\t400\t0\n\
+             seq2\t400\t800\t0\n\
+             seq2\t800\t1000\t0\n",
+            str::from_utf8(&bedgraph).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_window_coverage_deep() {
+        let mut output: Vec<u8> = vec![];
+        print_contig_windows(
+            "sample",
+            "contig",
+            &[3_000_001, 0, 0, -1, 0, 0],
+            3,
+            &mut output,
+            &mut None,
+        );
+        assert_eq!(
+            "sample\tcontig\t0\t3\t3000001\t0\t1\n\
+             sample\tcontig\t3\t6\t3000000\t0\t1\n",
+            str::from_utf8(&output).unwrap()
+        );
+    }
+
     #[test]
     fn test_reads_not_counting_when_sufficient_min_covered() {
         // In the past this threw up a underflow error
@@ -543,7 +928,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 24,
+                num_mapped_reads: 24.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -564,7 +949,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 0,
+                num_mapped_reads: 0.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -592,6 +977,7 @@ mod tests {
                 false,
                 None,
                 true,
+                None,
             )],
             &mut vec![CoverageEstimator::new_estimator_reads_per_base()],
             true,
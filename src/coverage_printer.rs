@@ -1,8 +1,12 @@
 use std;
-use std::io::Write;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
 use std::process;
 
+use compression_extension;
 use coverage_takers::*;
+use json;
 use OutputWriter;
 use ReadsMapped;
 
@@ -14,6 +18,37 @@ pub enum CoveragePrinter {
         estimator_headers: Option<Vec<String>>,
     },
     MetabatAdjustedCoveragePrinter,
+    VambCoveragePrinter,
+    SemiBinCoveragePrinter,
+    ConcoctCoveragePrinter,
+    MaxBin2CoveragePrinter {
+        // Each sample's abundances are written to a file named with this
+        // prefix, and the list of these files to the print stream
+        abundance_file_prefix: String,
+        // '.gz' or '.zst' if the abundance files are compressed, otherwise
+        // empty
+        abundance_file_extension: &'static str,
+    },
+    JsonCachedCoveragePrinter {
+        entry_type: Option<String>,
+        estimator_headers: Option<Vec<String>>,
+        // One record per line rather than a single document
+        json_lines: bool,
+    },
+    BiomCachedCoveragePrinter {
+        entry_type: Option<String>,
+        estimator_headers: Option<Vec<String>>,
+        // Taxonomy of each entry, added as observation metadata
+        taxonomy: Option<HashMap<String, Vec<String>>>,
+    },
+    #[cfg(feature = "hdf5")]
+    BiomHdf5CachedCoveragePrinter {
+        estimator_headers: Option<Vec<String>>,
+        taxonomy: Option<HashMap<String, Vec<String>>>,
+        // The table is written to this file by the HDF5 library rather than
+        // to the print stream
+        path: String,
+    },
 }
 
 impl CoveragePrinter {
@@ -54,6 +89,132 @@ impl CoveragePrinter {
                     tpm_column,
                 );
             }
+            CoveragePrinter::JsonCachedCoveragePrinter {
+                entry_type,
+                estimator_headers,
+                json_lines,
+            } => {
+                print_json_cached_coverage_taker(
+                    entry_type.as_ref().unwrap(),
+                    estimator_headers.as_ref().unwrap(),
+                    cached_coverage_taker,
+                    print_stream,
+                    reads_mapped_per_sample,
+                    columns_to_normalise,
+                    rpkm_column,
+                    tpm_column,
+                    *json_lines,
+                );
+            }
+            CoveragePrinter::BiomCachedCoveragePrinter {
+                entry_type,
+                estimator_headers,
+                taxonomy,
+            } => {
+                print_biom_cached_coverage_taker(
+                    entry_type.as_ref().unwrap(),
+                    estimator_headers.as_ref().unwrap(),
+                    cached_coverage_taker,
+                    print_stream,
+                    reads_mapped_per_sample,
+                    columns_to_normalise,
+                    rpkm_column,
+                    tpm_column,
+                    taxonomy.as_ref(),
+                    &json::utc_timestamp_now(),
+                );
+            }
+            #[cfg(feature = "hdf5")]
+            CoveragePrinter::BiomHdf5CachedCoveragePrinter {
+                estimator_headers,
+                taxonomy,
+                path,
+            } => {
+                write_biom_hdf5_cached_coverage_taker(
+                    path,
+                    estimator_headers.as_ref().unwrap(),
+                    cached_coverage_taker,
+                    reads_mapped_per_sample,
+                    columns_to_normalise,
+                    rpkm_column,
+                    tpm_column,
+                    taxonomy.as_ref(),
+                    &json::utc_timestamp_now(),
+                );
+            }
+            CoveragePrinter::VambCoveragePrinter => {
+                let (stoit_names, entries) = dense_coverage_matrix(cached_coverage_taker);
+                write!(print_stream, "contigname").unwrap();
+                for stoit in stoit_names.iter() {
+                    write!(print_stream, "\t{}", stoit).unwrap();
+                }
+                writeln!(print_stream).unwrap();
+                for (entry_name, coverages) in entries {
+                    write!(print_stream, "{}", entry_name).unwrap();
+                    for c in coverages.iter() {
+                        write!(print_stream, "\t{}", c[0]).unwrap();
+                    }
+                    writeln!(print_stream).unwrap();
+                }
+            }
+            CoveragePrinter::SemiBinCoveragePrinter => {
+                let (stoit_names, entries) = dense_coverage_matrix(cached_coverage_taker);
+                write!(print_stream, "contig").unwrap();
+                for stoit in stoit_names.iter() {
+                    write!(print_stream, "\t{}_cov\t{}_var", stoit, stoit).unwrap();
+                }
+                writeln!(print_stream).unwrap();
+                for (entry_name, coverages) in entries {
+                    write!(print_stream, "{}", entry_name).unwrap();
+                    for c in coverages.iter() {
+                        write!(print_stream, "\t{}\t{}", c[0], c[1]).unwrap();
+                    }
+                    writeln!(print_stream).unwrap();
+                }
+            }
+            CoveragePrinter::ConcoctCoveragePrinter => {
+                let (stoit_names, entries) = dense_coverage_matrix(cached_coverage_taker);
+                write!(print_stream, "contig").unwrap();
+                for stoit in stoit_names.iter() {
+                    write!(print_stream, "\tcov_mean_sample_{}", stoit).unwrap();
+                }
+                writeln!(print_stream).unwrap();
+                for (entry_name, coverages) in entries {
+                    write!(print_stream, "{}", entry_name).unwrap();
+                    for c in coverages.iter() {
+                        write!(print_stream, "\t{}", c[0]).unwrap();
+                    }
+                    writeln!(print_stream).unwrap();
+                }
+            }
+            CoveragePrinter::MaxBin2CoveragePrinter {
+                abundance_file_prefix,
+                abundance_file_extension,
+            } => {
+                let (stoit_names, entries) = dense_coverage_matrix(cached_coverage_taker);
+                // Abundance files are named without the compression extension
+                // of the output file
+                let stem = &abundance_file_prefix[..abundance_file_prefix.len()
+                    - compression_extension(abundance_file_prefix).len()];
+                for (stoit_i, stoit) in stoit_names.iter().enumerate() {
+                    // Sample names may be paths, so flatten them
+                    let path = format!(
+                        "{}.{}.abund{}",
+                        stem,
+                        stoit.replace('/', "_"),
+                        abundance_file_extension
+                    );
+                    info!("Writing MaxBin2 abundance file {}", path);
+                    let mut abundance_file = OutputWriter::generate(Some(&path));
+                    for (entry_name, coverages) in entries.iter() {
+                        writeln!(abundance_file, "{}\t{}", entry_name, coverages[stoit_i][0])
+                            .unwrap();
+                    }
+                    abundance_file.finish();
+                    // The print stream lists the files, for MaxBin2's -abund_list
+                    writeln!(print_stream, "{}", path).unwrap();
+                }
+            }
             CoveragePrinter::MetabatAdjustedCoveragePrinter => {
                 // Print header e.g.
                 // contigName      contigLen       totalAvgDepth   2seqs.bad_read.1.bam    2seqs.bad_read.1.bam-var
@@ -138,6 +299,16 @@ impl CoveragePrinter {
             CoveragePrinter::DenseCachedCoveragePrinter {
                 ref mut entry_type,
                 ref mut estimator_headers,
+            }
+            | CoveragePrinter::JsonCachedCoveragePrinter {
+                ref mut entry_type,
+                ref mut estimator_headers,
+                ..
+            }
+            | CoveragePrinter::BiomCachedCoveragePrinter {
+                ref mut entry_type,
+                ref mut estimator_headers,
+                ..
             } => {
                 *entry_type = Some(entry_type_str.to_string());
                 *estimator_headers = Some(
@@ -147,8 +318,51 @@ impl CoveragePrinter {
                         .collect(),
                 );
             }
-            CoveragePrinter::MetabatAdjustedCoveragePrinter => {}
+            #[cfg(feature = "hdf5")]
+            CoveragePrinter::BiomHdf5CachedCoveragePrinter {
+                ref mut estimator_headers,
+                ..
+            } => {
+                *estimator_headers = Some(estimator_headers_vec);
+            }
+            CoveragePrinter::MetabatAdjustedCoveragePrinter
+            | CoveragePrinter::VambCoveragePrinter
+            | CoveragePrinter::SemiBinCoveragePrinter
+            | CoveragePrinter::ConcoctCoveragePrinter
+            | CoveragePrinter::MaxBin2CoveragePrinter { .. } => {}
+        }
+    }
+}
+
+/// Coverages of each entry in each sample, in entry order, for the printers
+/// which print a contig-by-sample table. Entries not present in a sample are
+/// given zero coverage.
+fn dense_coverage_matrix(
+    cached_coverage_taker: &CoverageTakerType,
+) -> (&Vec<String>, Vec<(&str, Vec<Vec<f32>>)>) {
+    match cached_coverage_taker {
+        CoverageTakerType::CachedSingleFloatCoverageTaker {
+            stoit_names,
+            entry_names,
+            num_coverages,
+            ..
+        } => {
+            let mut coverages: Vec<Option<Vec<Vec<f32>>>> = vec![None; entry_names.len()];
+            for ecs in cached_coverage_taker.generate_iterator() {
+                coverages[ecs.entry_index]
+                    .get_or_insert_with(|| vec![vec![0.0; *num_coverages]; stoit_names.len()])
+                    [ecs.stoit_index] = ecs.coverages;
+            }
+            let entries = entry_names
+                .iter()
+                .zip(coverages)
+                .filter_map(|(name, entry_coverages)| {
+                    entry_coverages.map(|c| (name.as_ref().unwrap().as_str(), c))
+                })
+                .collect();
+            (stoit_names, entries)
         }
+        _ => unreachable!(),
     }
 }
 
@@ -285,7 +499,7 @@ pub fn print_sparse_cached_coverage_taker(
                                 write!(
                                     print_stream,
                                     "\t{}",
-                                    match num_mapped_reads == 0 {
+                                    match num_mapped_reads == 0.0 {
                                         true => 0.0,
                                         false => coverages[i] / num_mapped_reads as f32,
                                     }
@@ -302,7 +516,7 @@ pub fn print_sparse_cached_coverage_taker(
                                 write!(
                                     print_stream,
                                     "\t{}",
-                                    match num_mapped_reads == 0 {
+                                    match num_mapped_reads == 0.0 {
                                         true => 0.0,
                                         // TPM can be calculated from RPKM - see
                                         // https://haroldpimentel.wordpress.com/2014/05/08/what-the-fpkm-a-review-rna-seq-expression-units/
@@ -500,7 +714,7 @@ pub fn print_dense_cached_coverage_taker(
                             write!(
                                 print_stream,
                                 "\t{}",
-                                match num_mapped_reads == 0 {
+                                match num_mapped_reads == 0.0 {
                                     true => 0.0,
                                     false => coverages[i] / num_mapped_reads as f32,
                                 }
@@ -516,7 +730,7 @@ pub fn print_dense_cached_coverage_taker(
                             write!(
                                 print_stream,
                                 "\t{}",
-                                match num_mapped_reads == 0 {
+                                match num_mapped_reads == 0.0 {
                                     true => 0.0,
                                     // TPM can be calculated from RPKM - see
                                     // https://haroldpimentel.wordpress.com/2014/05/08/what-the-fpkm-a-review-rna-seq-expression-units/
@@ -540,6 +754,594 @@ pub fn print_dense_cached_coverage_taker(
     }
 }
 
+/// Coverage values of each entry of a sample, after normalisation as for
+/// the other printers. An 'unmapped' entry is included first if any columns
+/// are normalised, with None for the columns which are not.
+struct NormalisedSample<'a> {
+    stoit_name: &'a str,
+    reads_mapped: Option<&'a ReadsMapped>,
+    entries: Vec<(&'a str, Vec<Option<f32>>)>,
+}
+
+fn normalise_cached_coverage_taker<'a>(
+    cached_coverage_taker: &'a CoverageTakerType,
+    reads_mapped_per_sample: Option<&'a Vec<ReadsMapped>>,
+    columns_to_normalise: &[usize],
+    rpkm_column: Option<usize>,
+    tpm_column: Option<usize>,
+) -> Vec<NormalisedSample<'a>> {
+    let (stoit_names, entry_names, num_coverages) = match &cached_coverage_taker {
+        CoverageTakerType::CachedSingleFloatCoverageTaker {
+            stoit_names,
+            entry_names,
+            num_coverages,
+            ..
+        } => (stoit_names, entry_names, *num_coverages),
+        _ => unreachable!(),
+    };
+
+    let mut stoit_by_entry_by_coverage: Vec<Vec<EntryAndCoverages>> =
+        stoit_names.iter().map(|_| vec![]).collect();
+    for ecs in cached_coverage_taker.generate_iterator() {
+        stoit_by_entry_by_coverage[ecs.stoit_index].push(ecs);
+    }
+
+    let mut samples = vec![];
+    for (stoit_i, entries) in stoit_by_entry_by_coverage.iter().enumerate() {
+        let reads_mapped = reads_mapped_per_sample.map(|rm| &rm[stoit_i]);
+        let fraction_mapped = reads_mapped
+            .map(|r| r.num_mapped_reads as f32 / r.num_reads as f32)
+            .unwrap_or(1.0);
+        let num_mapped_reads = reads_mapped.map(|r| r.num_mapped_reads).unwrap_or(0.0);
+
+        let mut coverage_totals: Vec<f32> = vec![0.0; num_coverages];
+        for ecs in entries {
+            for (i, total) in coverage_totals.iter_mut().enumerate() {
+                *total += ecs.coverages[i];
+            }
+        }
+
+        let mut normalised_entries = vec![];
+        if !columns_to_normalise.is_empty() {
+            normalised_entries.push((
+                "unmapped",
+                (0..num_coverages)
+                    .map(|i| match columns_to_normalise.contains(&i) {
+                        true => Some(100.0 * (1.0 - fraction_mapped)),
+                        false => None,
+                    })
+                    .collect(),
+            ));
+        }
+        for ecs in entries {
+            let values: Vec<Option<f32>> = ecs
+                .coverages
+                .iter()
+                .enumerate()
+                .map(|(i, coverage)| {
+                    Some(if columns_to_normalise.contains(&i) {
+                        coverage / coverage_totals[i] * 100.0 * fraction_mapped
+                    } else if rpkm_column == Some(i) {
+                        match num_mapped_reads == 0.0 {
+                            true => 0.0,
+                            false => coverage / num_mapped_reads as f32,
+                        }
+                    } else if tpm_column == Some(i) {
+                        match num_mapped_reads == 0.0 {
+                            true => 0.0,
+                            // TPM can be calculated from RPKM
+                            false => {
+                                (coverage.ln() - coverage_totals[i].ln()).exp()
+                                    * (10u64.pow(6) as f32)
+                            }
+                        }
+                    } else {
+                        *coverage
+                    })
+                })
+                .collect();
+            normalised_entries.push((
+                entry_names[ecs.entry_index].as_ref().unwrap().as_str(),
+                values,
+            ));
+        }
+        samples.push(NormalisedSample {
+            stoit_name: &stoit_names[stoit_i],
+            reads_mapped,
+            entries: normalised_entries,
+        });
+    }
+    samples
+}
+
+/// Print each entry of each sample as a JSON object, with its coverage
+/// values keyed by column header, either as a single JSON document or as
+/// JSON Lines with one entry per line. Any 'unmapped' entry has null values
+/// for columns which are not normalised.
+#[allow(clippy::too_many_arguments)]
+pub fn print_json_cached_coverage_taker(
+    entry_type: &str,
+    estimator_headers: &[String],
+    cached_coverage_taker: &CoverageTakerType,
+    print_stream: &mut dyn std::io::Write,
+    reads_mapped_per_sample: Option<&Vec<ReadsMapped>>,
+    columns_to_normalise: &[usize],
+    rpkm_column: Option<usize>,
+    tpm_column: Option<usize>,
+    json_lines: bool,
+) {
+    let entry_key = entry_type.to_lowercase();
+    let values_object = |values: &[Option<f32>]| -> String {
+        json::object(
+            &estimator_headers
+                .iter()
+                .zip(values.iter())
+                .map(|(header, value)| {
+                    (
+                        header.as_str(),
+                        match value {
+                            Some(v) => json::number(*v),
+                            None => "null".to_string(),
+                        },
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    let mut sample_objects = vec![];
+    for sample in normalise_cached_coverage_taker(
+        cached_coverage_taker,
+        reads_mapped_per_sample,
+        columns_to_normalise,
+        rpkm_column,
+        tpm_column,
+    ) {
+        // Each entry as a list of key and value pairs
+        let entry_objects: Vec<Vec<(String, String)>> = sample
+            .entries
+            .iter()
+            .map(|(entry_name, values)| {
+                vec![
+                    (entry_key.clone(), json::string(entry_name)),
+                    ("values".to_string(), values_object(values)),
+                ]
+            })
+            .collect();
+        let sample_totals: Vec<(String, String)> = match sample.reads_mapped {
+            Some(r) => vec![
+                ("mapped_reads".to_string(), json::number(r.num_mapped_reads)),
+                ("total_reads".to_string(), r.num_reads.to_string()),
+            ],
+            None => vec![],
+        };
+        let sample_name = ("sample".to_string(), json::string(sample.stoit_name));
+
+        if json_lines {
+            for entry_object in entry_objects {
+                let mut record = vec![sample_name.clone()];
+                record.extend(entry_object);
+                record.extend(sample_totals.iter().cloned());
+                writeln!(print_stream, "{}", json::object(&record)).unwrap();
+            }
+        } else {
+            let mut sample_object = vec![sample_name];
+            sample_object.extend(sample_totals);
+            sample_object.push((
+                "entries".to_string(),
+                json::array(
+                    &entry_objects
+                        .iter()
+                        .map(|e| json::object(e))
+                        .collect::<Vec<_>>(),
+                ),
+            ));
+            sample_objects.push(json::object(&sample_object));
+        }
+    }
+
+    if !json_lines {
+        writeln!(
+            print_stream,
+            "{}",
+            json::object(&[
+                ("entry_type", json::string(entry_type)),
+                ("samples", json::array(&sample_objects)),
+            ])
+        )
+        .unwrap();
+    }
+}
+
+/// Read a taxonomy file for BIOM output, where each line is an entry name
+/// and its taxonomy separated by a tab, with ranks separated by semicolons
+/// e.g. 'genome1<TAB>d__Bacteria; p__Firmicutes'.
+pub fn read_biom_taxonomy_file(path: &str) -> HashMap<String, Vec<String>> {
+    let file = File::open(path).unwrap_or_else(|e| {
+        error!("Failed to open taxonomy file {}: {}", path, e);
+        process::exit(1);
+    });
+    let mut taxonomy = HashMap::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| {
+            error!("Failed to read line from taxonomy file {}: {}", path, e);
+            process::exit(1);
+        });
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 2 {
+            error!(
+                "Line {} of taxonomy file {} does not have exactly 2 tab-separated fields: {}",
+                i + 1,
+                path,
+                line
+            );
+            process::exit(1);
+        }
+        taxonomy.insert(
+            fields[0].to_string(),
+            fields[1]
+                .split(';')
+                .map(|rank| rank.trim().to_string())
+                .filter(|rank| !rank.is_empty())
+                .collect(),
+        );
+    }
+    debug!("Read taxonomy of {} entries from {}", taxonomy.len(), path);
+    taxonomy
+}
+
+/// A BIOM table of the single column which is not 'Length', with entries as
+/// observations and samples as columns.
+struct BiomTable<'a> {
+    observation_ids: Vec<&'a str>,
+    // Length of each entry, if calculated
+    observation_lengths: Vec<Option<f32>>,
+    sample_ids: Vec<&'a str>,
+    // Nonzero values as (observation index, sample index, value), in sample
+    // order
+    data: Vec<(usize, usize, f32)>,
+}
+
+fn biom_table<'a>(
+    estimator_headers: &[String],
+    cached_coverage_taker: &'a CoverageTakerType,
+    reads_mapped_per_sample: Option<&'a Vec<ReadsMapped>>,
+    columns_to_normalise: &[usize],
+    rpkm_column: Option<usize>,
+    tpm_column: Option<usize>,
+) -> BiomTable<'a> {
+    let length_column = estimator_headers.iter().position(|h| h == "Length");
+    let value_column = match estimator_headers.iter().position(|h| h != "Length") {
+        Some(i) => i,
+        None => {
+            error!("BIOM output requires a coverage method other than length");
+            process::exit(1);
+        }
+    };
+    debug!(
+        "Making BIOM table of {} column",
+        estimator_headers[value_column]
+    );
+
+    let samples = normalise_cached_coverage_taker(
+        cached_coverage_taker,
+        reads_mapped_per_sample,
+        columns_to_normalise,
+        rpkm_column,
+        tpm_column,
+    );
+
+    // Entries in order of first appearance, since each sample may not have
+    // every entry.
+    let mut table = BiomTable {
+        observation_ids: vec![],
+        observation_lengths: vec![],
+        sample_ids: samples.iter().map(|sample| sample.stoit_name).collect(),
+        data: vec![],
+    };
+    let mut observation_indices: HashMap<&str, usize> = HashMap::new();
+    for (sample_i, sample) in samples.into_iter().enumerate() {
+        for (entry_name, values) in sample.entries {
+            let observation_i = match observation_indices.get(entry_name) {
+                Some(i) => *i,
+                None => {
+                    observation_indices.insert(entry_name, table.observation_ids.len());
+                    table.observation_ids.push(entry_name);
+                    table
+                        .observation_lengths
+                        .push(length_column.and_then(|i| values[i]));
+                    table.observation_ids.len() - 1
+                }
+            };
+            if let Some(value) = values[value_column] {
+                if value != 0.0 {
+                    table.data.push((observation_i, sample_i, value));
+                }
+            }
+        }
+    }
+    table
+}
+
+/// Print a BIOM table in the JSON based BIOM 1.0 format, with entries as
+/// observations and samples as columns. The matrix holds the single column
+/// which is not 'Length'. The length of each entry, if calculated, and its
+/// taxonomy, if given, are included as observation metadata.
+#[allow(clippy::too_many_arguments)]
+pub fn print_biom_cached_coverage_taker(
+    entry_type: &str,
+    estimator_headers: &[String],
+    cached_coverage_taker: &CoverageTakerType,
+    print_stream: &mut dyn std::io::Write,
+    reads_mapped_per_sample: Option<&Vec<ReadsMapped>>,
+    columns_to_normalise: &[usize],
+    rpkm_column: Option<usize>,
+    tpm_column: Option<usize>,
+    taxonomy: Option<&HashMap<String, Vec<String>>>,
+    date: &str,
+) {
+    debug!("Printing BIOM table with {} entries", entry_type);
+    let table = biom_table(
+        estimator_headers,
+        cached_coverage_taker,
+        reads_mapped_per_sample,
+        columns_to_normalise,
+        rpkm_column,
+        tpm_column,
+    );
+
+    let rows: Vec<String> = table
+        .observation_ids
+        .iter()
+        .zip(table.observation_lengths.iter())
+        .map(|(id, length)| {
+            let mut metadata = vec![];
+            if let Some(length) = length {
+                metadata.push(("length", json::number(*length)));
+            }
+            if let Some(lineage) = taxonomy.and_then(|t| t.get(*id)) {
+                metadata.push((
+                    "taxonomy",
+                    json::array(
+                        &lineage
+                            .iter()
+                            .map(|rank| json::string(rank))
+                            .collect::<Vec<_>>(),
+                    ),
+                ));
+            }
+            json::object(&[
+                ("id", json::string(id)),
+                (
+                    "metadata",
+                    match metadata.is_empty() {
+                        true => "null".to_string(),
+                        false => json::object(&metadata),
+                    },
+                ),
+            ])
+        })
+        .collect();
+    let columns: Vec<String> = table
+        .sample_ids
+        .iter()
+        .map(|id| json::object(&[("id", json::string(id)), ("metadata", "null".to_string())]))
+        .collect();
+    let data: Vec<String> = table
+        .data
+        .iter()
+        .map(|(observation_i, sample_i, value)| {
+            json::array(&[
+                observation_i.to_string(),
+                sample_i.to_string(),
+                json::number(*value),
+            ])
+        })
+        .collect();
+
+    writeln!(
+        print_stream,
+        "{}",
+        json::object(&[
+            ("id", "null".to_string()),
+            (
+                "format",
+                json::string("Biological Observation Matrix 1.0.0")
+            ),
+            ("format_url", json::string("http://biom-format.org")),
+            ("type", json::string("OTU table")),
+            (
+                "generated_by",
+                json::string(&format!("CoverM {}", env!("CARGO_PKG_VERSION"))),
+            ),
+            ("date", json::string(date)),
+            ("rows", json::array(&rows)),
+            ("columns", json::array(&columns)),
+            ("matrix_type", json::string("sparse")),
+            ("matrix_element_type", json::string("float")),
+            (
+                "shape",
+                json::array(&[rows.len().to_string(), columns.len().to_string()]),
+            ),
+            ("data", json::array(&data)),
+        ])
+    )
+    .unwrap();
+}
+
+/// Write a BIOM table in the HDF5 based BIOM 2.1 format to a file, with
+/// entries as observations and samples as columns. As for the BIOM 1.0
+/// format, the length of each entry, if calculated, and its taxonomy, if
+/// given, are included as observation metadata.
+#[cfg(feature = "hdf5")]
+#[allow(clippy::too_many_arguments)]
+pub fn write_biom_hdf5_cached_coverage_taker(
+    path: &str,
+    estimator_headers: &[String],
+    cached_coverage_taker: &CoverageTakerType,
+    reads_mapped_per_sample: Option<&Vec<ReadsMapped>>,
+    columns_to_normalise: &[usize],
+    rpkm_column: Option<usize>,
+    tpm_column: Option<usize>,
+    taxonomy: Option<&HashMap<String, Vec<String>>>,
+    date: &str,
+) {
+    let table = biom_table(
+        estimator_headers,
+        cached_coverage_taker,
+        reads_mapped_per_sample,
+        columns_to_normalise,
+        rpkm_column,
+        tpm_column,
+    );
+    debug!("Writing BIOM 2.1 table to {}", path);
+    if let Err(e) = write_biom_hdf5(path, &table, taxonomy, date) {
+        error!("Failed to write BIOM file {}: {}", path, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "hdf5")]
+fn biom_hdf5_string(s: &str) -> hdf5::Result<hdf5::types::VarLenUnicode> {
+    s.parse::<hdf5::types::VarLenUnicode>()
+        .map_err(|e| hdf5::Error::from(format!("Invalid string '{}': {}", s, e)))
+}
+
+#[cfg(feature = "hdf5")]
+fn write_biom_hdf5(
+    path: &str,
+    table: &BiomTable,
+    taxonomy: Option<&HashMap<String, Vec<String>>>,
+    date: &str,
+) -> hdf5::Result<()> {
+    let file = hdf5::File::create(path)?;
+    for (name, value) in [
+        ("id", "No Table ID".to_string()),
+        ("type", "OTU table".to_string()),
+        ("format-url", "http://biom-format.org".to_string()),
+        (
+            "generated-by",
+            format!("CoverM {}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("creation-date", date.to_string()),
+    ] {
+        file.new_attr::<hdf5::types::VarLenUnicode>()
+            .create(name)?
+            .write_scalar(&biom_hdf5_string(&value)?)?;
+    }
+    file.new_attr_builder()
+        .with_data(&[2i32, 1][..])
+        .create("format-version")?;
+    file.new_attr_builder()
+        .with_data(
+            &[
+                table.observation_ids.len() as i32,
+                table.sample_ids.len() as i32,
+            ][..],
+        )
+        .create("shape")?;
+    file.new_attr::<i32>()
+        .create("nnz")?
+        .write_scalar(&(table.data.len() as i32))?;
+
+    // The matrix is stored compressed by row under observation, and by
+    // column under sample.
+    write_biom_hdf5_axis(
+        &file,
+        "observation",
+        &table.observation_ids,
+        table.data.clone(),
+    )?;
+    write_biom_hdf5_axis(
+        &file,
+        "sample",
+        &table.sample_ids,
+        table.data.iter().map(|(o, s, v)| (*s, *o, *v)).collect(),
+    )?;
+
+    let metadata = file.group("observation/metadata")?;
+    if let Some(lengths) = table
+        .observation_lengths
+        .iter()
+        .map(|l| l.map(|l| l as f64))
+        .collect::<Option<Vec<f64>>>()
+    {
+        metadata
+            .new_dataset_builder()
+            .with_data(&lengths[..])
+            .create("length")?;
+    }
+    if let Some(taxonomy) = taxonomy {
+        // Ranks are padded with empty strings to the longest lineage
+        let lineages: Vec<&[String]> = table
+            .observation_ids
+            .iter()
+            .map(|id| taxonomy.get(*id).map_or(&[][..], |l| &l[..]))
+            .collect();
+        let num_ranks = lineages.iter().map(|l| l.len()).max().unwrap_or(0);
+        if num_ranks > 0 {
+            let mut ranks = Vec::with_capacity(lineages.len() * num_ranks);
+            for lineage in lineages.iter() {
+                for i in 0..num_ranks {
+                    ranks.push(biom_hdf5_string(lineage.get(i).map_or("", |r| r.as_str()))?);
+                }
+            }
+            metadata
+                .new_dataset::<hdf5::types::VarLenUnicode>()
+                .shape((lineages.len(), num_ranks))
+                .create("taxonomy")?
+                .write_raw(&ranks[..])?;
+        }
+    }
+    file.flush()
+}
+
+/// Write the ids of one axis of a BIOM table and the matrix compressed along
+/// it, given as (index on this axis, index on the other axis, value).
+#[cfg(feature = "hdf5")]
+fn write_biom_hdf5_axis(
+    file: &hdf5::File,
+    axis: &str,
+    ids: &[&str],
+    mut values: Vec<(usize, usize, f32)>,
+) -> hdf5::Result<()> {
+    values.sort_by_key(|(major, minor, _)| (*major, *minor));
+    let group = file.create_group(axis)?;
+    let ids = ids
+        .iter()
+        .map(|id| biom_hdf5_string(id))
+        .collect::<hdf5::Result<Vec<_>>>()?;
+    group
+        .new_dataset_builder()
+        .with_data(&ids[..])
+        .create("ids")?;
+    group.create_group("metadata")?;
+    group.create_group("group-metadata")?;
+
+    let matrix = group.create_group("matrix")?;
+    let data: Vec<f64> = values.iter().map(|(_, _, v)| *v as f64).collect();
+    let indices: Vec<i32> = values.iter().map(|(_, minor, _)| *minor as i32).collect();
+    let indptr: Vec<i32> = (0..=ids.len())
+        .map(|major| values.partition_point(|(m, _, _)| *m < major) as i32)
+        .collect();
+    matrix
+        .new_dataset_builder()
+        .with_data(&data[..])
+        .create("data")?;
+    matrix
+        .new_dataset_builder()
+        .with_data(&indices[..])
+        .create("indices")?;
+    matrix
+        .new_dataset_builder()
+        .with_data(&indptr[..])
+        .create("indptr")?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -587,7 +1389,7 @@ mod tests {
             &c,
             &mut stream,
             Some(&vec![ReadsMapped {
-                num_mapped_reads: 1,
+                num_mapped_reads: 1.0,
                 num_reads: 2,
             }]),
             &vec![0],
@@ -602,6 +1404,234 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dense_cached_printer_fractional_mapped_reads() {
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "contig1");
+        c.add_single_coverage(1.0);
+        c.add_single_coverage(75000000.0);
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(3.0);
+        c.add_single_coverage(300000000.0);
+        let mut stream = Cursor::new(Vec::new());
+        print_dense_cached_coverage_taker(
+            "Contig",
+            &vec!["Relative Abundance (%)".to_string(), "RPKM".to_string()],
+            &c,
+            &mut stream,
+            // Weighted reads, e.g. from multimapper resolution
+            Some(&vec![ReadsMapped {
+                num_mapped_reads: 1.5,
+                num_reads: 4,
+            }]),
+            &vec![0],
+            Some(1),
+            None,
+        );
+        assert_eq!(
+            "Contig\tstoit1 Relative Abundance (%)\tstoit1 RPKM\n\
+                    unmapped\t62.5\tNA\n\
+                    contig1\t9.375\t50000000\n\
+                    contig2\t28.125\t200000000\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json_cached_printer_normalised() {
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "genome1");
+        c.add_single_coverage(1.1);
+        c.add_single_coverage(1.2);
+        let reads_mapped = vec![ReadsMapped {
+            num_mapped_reads: 1.0,
+            num_reads: 2,
+        }];
+        let headers = vec!["Relative Abundance (%)".to_string(), "Mean".to_string()];
+
+        let mut stream = Cursor::new(Vec::new());
+        print_json_cached_coverage_taker(
+            "Genome",
+            &headers,
+            &c,
+            &mut stream,
+            Some(&reads_mapped),
+            &[0],
+            None,
+            None,
+            true,
+        );
+        assert_eq!(
+            "{\"sample\":\"stoit1\",\"genome\":\"unmapped\",\
+            \"values\":{\"Relative Abundance (%)\":50,\"Mean\":null},\
+            \"mapped_reads\":1,\"total_reads\":2}\n\
+            {\"sample\":\"stoit1\",\"genome\":\"genome1\",\
+            \"values\":{\"Relative Abundance (%)\":50,\"Mean\":1.2},\
+            \"mapped_reads\":1,\"total_reads\":2}\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+
+        let mut stream = Cursor::new(Vec::new());
+        print_json_cached_coverage_taker(
+            "Genome",
+            &headers,
+            &c,
+            &mut stream,
+            Some(&reads_mapped),
+            &[0],
+            None,
+            None,
+            false,
+        );
+        assert_eq!(
+            "{\"entry_type\":\"Genome\",\"samples\":[{\"sample\":\"stoit1\",\
+            \"mapped_reads\":1,\"total_reads\":2,\"entries\":[\
+            {\"genome\":\"unmapped\",\"values\":{\"Relative Abundance (%)\":50,\"Mean\":null}},\
+            {\"genome\":\"genome1\",\"values\":{\"Relative Abundance (%)\":50,\"Mean\":1.2}}]}]}\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_biom_cached_printer() {
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "genome1");
+        c.add_single_coverage(1.1);
+        c.add_single_coverage(100.0);
+        c.start_entry(1, "genome2");
+        c.add_single_coverage(0.0);
+        c.add_single_coverage(200.0);
+        c.start_stoit("stoit2");
+        c.start_entry(1, "genome2");
+        c.add_single_coverage(2.5);
+        c.add_single_coverage(200.0);
+        let mut taxonomy = HashMap::new();
+        taxonomy.insert(
+            "genome2".to_string(),
+            vec!["d__Bacteria".to_string(), "p__Firmicutes".to_string()],
+        );
+
+        let mut stream = Cursor::new(Vec::new());
+        print_biom_cached_coverage_taker(
+            "Genome",
+            &["Mean".to_string(), "Length".to_string()],
+            &c,
+            &mut stream,
+            None,
+            &[],
+            None,
+            None,
+            Some(&taxonomy),
+            "2021-03-01T12:00:00Z",
+        );
+        assert_eq!(
+            format!(
+                "{{\"id\":null,\"format\":\"Biological Observation Matrix 1.0.0\",\
+                \"format_url\":\"http://biom-format.org\",\"type\":\"OTU table\",\
+                \"generated_by\":\"CoverM {}\",\"date\":\"2021-03-01T12:00:00Z\",\
+                \"rows\":[{{\"id\":\"genome1\",\"metadata\":{{\"length\":100}}}},\
+                {{\"id\":\"genome2\",\"metadata\":{{\"length\":200,\
+                \"taxonomy\":[\"d__Bacteria\",\"p__Firmicutes\"]}}}}],\
+                \"columns\":[{{\"id\":\"stoit1\",\"metadata\":null}},\
+                {{\"id\":\"stoit2\",\"metadata\":null}}],\
+                \"matrix_type\":\"sparse\",\"matrix_element_type\":\"float\",\
+                \"shape\":[2,2],\"data\":[[0,0,1.1],[1,1,2.5]]}}\n",
+                env!("CARGO_PKG_VERSION")
+            ),
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn test_biom_hdf5_cached_printer() {
+        use hdf5::types::VarLenUnicode;
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "genome1");
+        c.add_single_coverage(1.5);
+        c.add_single_coverage(100.0);
+        c.start_entry(1, "genome2");
+        c.add_single_coverage(0.0);
+        c.add_single_coverage(200.0);
+        c.start_stoit("stoit2");
+        c.start_entry(0, "genome1");
+        c.add_single_coverage(0.5);
+        c.add_single_coverage(100.0);
+        c.start_entry(1, "genome2");
+        c.add_single_coverage(2.5);
+        c.add_single_coverage(200.0);
+        let mut taxonomy = HashMap::new();
+        taxonomy.insert(
+            "genome2".to_string(),
+            vec!["d__Bacteria".to_string(), "p__Firmicutes".to_string()],
+        );
+
+        let td = tempfile::TempDir::new().unwrap();
+        let path = td.path().join("table.biom");
+        let path = path.to_str().unwrap();
+        write_biom_hdf5_cached_coverage_taker(
+            path,
+            &["Mean".to_string(), "Length".to_string()],
+            &c,
+            None,
+            &[],
+            None,
+            None,
+            Some(&taxonomy),
+            "2021-03-01T12:00:00Z",
+        );
+
+        let file = hdf5::File::open(path).unwrap();
+        let strings = |dataset: &str| -> Vec<String> {
+            file.dataset(dataset)
+                .unwrap()
+                .read_raw::<VarLenUnicode>()
+                .unwrap()
+                .iter()
+                .map(|s| s.as_str().to_string())
+                .collect()
+        };
+        let ints = |dataset: &str| file.dataset(dataset).unwrap().read_raw::<i32>().unwrap();
+        let floats = |dataset: &str| file.dataset(dataset).unwrap().read_raw::<f64>().unwrap();
+        assert_eq!(
+            vec![2, 1],
+            file.attr("format-version")
+                .unwrap()
+                .read_raw::<i32>()
+                .unwrap()
+        );
+        assert_eq!(
+            vec![2, 2],
+            file.attr("shape").unwrap().read_raw::<i32>().unwrap()
+        );
+        assert_eq!(3, file.attr("nnz").unwrap().read_scalar::<i32>().unwrap());
+        assert_eq!(
+            "2021-03-01T12:00:00Z",
+            file.attr("creation-date")
+                .unwrap()
+                .read_scalar::<VarLenUnicode>()
+                .unwrap()
+                .as_str()
+        );
+        assert_eq!(vec!["genome1", "genome2"], strings("observation/ids"));
+        assert_eq!(vec!["stoit1", "stoit2"], strings("sample/ids"));
+        assert_eq!(vec![1.5, 0.5, 2.5], floats("observation/matrix/data"));
+        assert_eq!(vec![0, 1, 1], ints("observation/matrix/indices"));
+        assert_eq!(vec![0, 2, 3], ints("observation/matrix/indptr"));
+        assert_eq!(vec![1.5, 0.5, 2.5], floats("sample/matrix/data"));
+        assert_eq!(vec![0, 0, 1], ints("sample/matrix/indices"));
+        assert_eq!(vec![0, 1, 3], ints("sample/matrix/indptr"));
+        assert_eq!(vec![100.0, 200.0], floats("observation/metadata/length"));
+        assert_eq!(
+            vec!["", "", "d__Bacteria", "p__Firmicutes"],
+            strings("observation/metadata/taxonomy")
+        );
+    }
+
     #[test]
     fn test_metabat_mode_printer_easy() {
         let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(3);
@@ -648,4 +1678,109 @@ mod tests {
              contig2\t1025\t12.1\t2.1\t2.2\t22.1\t22.2\n",
              str::from_utf8(&buf).unwrap());
     }
+    fn binner_test_coverage_taker() -> CoverageTakerType {
+        let mut c = CoverageTakerType::new_cached_single_float_coverage_taker(2);
+        c.start_stoit("stoit1");
+        c.start_entry(0, "contig1");
+        c.add_single_coverage(1.1);
+        c.add_single_coverage(1.2);
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(2.1);
+        c.add_single_coverage(2.2);
+        c.start_stoit("stoit2");
+        c.start_entry(1, "contig2");
+        c.add_single_coverage(22.1);
+        c.add_single_coverage(22.2);
+        c
+    }
+
+    fn print_with(mut printer: CoveragePrinter, c: &CoverageTakerType) -> String {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        printer.finalise_printing(
+            c,
+            &mut OutputWriter::generate(Some(t)),
+            None,
+            &vec![],
+            None,
+            None,
+        );
+        std::fs::read_to_string(tf.path()).unwrap()
+    }
+
+    #[test]
+    fn test_binner_printers() {
+        let c = binner_test_coverage_taker();
+        assert_eq!(
+            "contigname\tstoit1\tstoit2\n\
+            contig1\t1.1\t0\n\
+            contig2\t2.1\t22.1\n",
+            print_with(CoveragePrinter::VambCoveragePrinter, &c)
+        );
+        assert_eq!(
+            "contig\tstoit1_cov\tstoit1_var\tstoit2_cov\tstoit2_var\n\
+            contig1\t1.1\t1.2\t0\t0\n\
+            contig2\t2.1\t2.2\t22.1\t22.2\n",
+            print_with(CoveragePrinter::SemiBinCoveragePrinter, &c)
+        );
+        assert_eq!(
+            "contig\tcov_mean_sample_stoit1\tcov_mean_sample_stoit2\n\
+            contig1\t1.1\t0\n\
+            contig2\t2.1\t22.1\n",
+            print_with(CoveragePrinter::ConcoctCoveragePrinter, &c)
+        );
+    }
+
+    #[test]
+    fn test_maxbin2_printer() {
+        let c = binner_test_coverage_taker();
+        let td = tempfile::TempDir::new().unwrap();
+        let prefix = td.path().join("abund").to_str().unwrap().to_string();
+        let list = print_with(
+            CoveragePrinter::MaxBin2CoveragePrinter {
+                abundance_file_prefix: prefix.clone(),
+                abundance_file_extension: "",
+            },
+            &c,
+        );
+        assert_eq!(
+            format!("{}.stoit1.abund\n{}.stoit2.abund\n", prefix, prefix),
+            list
+        );
+        assert_eq!(
+            "contig1\t1.1\ncontig2\t2.1\n",
+            std::fs::read_to_string(format!("{}.stoit1.abund", prefix)).unwrap()
+        );
+        assert_eq!(
+            "contig1\t0\ncontig2\t22.1\n",
+            std::fs::read_to_string(format!("{}.stoit2.abund", prefix)).unwrap()
+        );
+        // Abundance files are compressed independently of the output file
+        let gz_prefix = td.path().join("abund.gz").to_str().unwrap().to_string();
+        print_with(
+            CoveragePrinter::MaxBin2CoveragePrinter {
+                abundance_file_prefix: gz_prefix,
+                abundance_file_extension: "",
+            },
+            &c,
+        );
+        assert_eq!(
+            "contig1\t1.1\ncontig2\t2.1\n",
+            std::fs::read_to_string(format!("{}.stoit1.abund", prefix)).unwrap()
+        );
+        print_with(
+            CoveragePrinter::MaxBin2CoveragePrinter {
+                abundance_file_prefix: prefix.clone(),
+                abundance_file_extension: ".gz",
+            },
+            &c,
+        );
+        let mut decompressed = String::new();
+        flate2::read::MultiGzDecoder::new(
+            File::open(format!("{}.stoit1.abund.gz", prefix)).unwrap(),
+        )
+        .read_to_string(&mut decompressed)
+        .unwrap();
+        assert_eq!("contig1\t1.1\ncontig2\t2.1\n", decompressed);
+    }
 }
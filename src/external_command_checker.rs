@@ -1,7 +1,43 @@
 use bird_tool_utils::external_command_checker::*;
 
+use provenance::record_program_version;
+
+/// Record the version reported by a program for the provenance file. The
+/// first line of its standard output is used, or for programs which report
+/// their version in their usage, the line of standard error starting with
+/// 'Version:'.
+fn record_version(program: &str, args: &[&str]) {
+    let output = match std::process::Command::new(program).args(args).output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to determine version of {}: {}", program, e);
+            return;
+        }
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let first_line = |s: &str| {
+        s.lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .map(|l| l.to_string())
+    };
+    let version = first_line(&stdout)
+        .or_else(|| {
+            stderr
+                .lines()
+                .find_map(|l| l.strip_prefix("Version:"))
+                .map(|v| v.trim().to_string())
+        })
+        .or_else(|| first_line(&stderr))
+        .unwrap_or_default();
+    debug!("Detected {} version '{}'", program, version);
+    record_program_version(program, &version);
+}
+
 pub fn check_for_bwa() {
     check_for_external_command_presence("BWA", "which bwa").expect("Failed to find installed BWA");
+    record_version("bwa", &[]);
 }
 
 pub fn check_for_bwa_mem2() {
@@ -9,6 +45,7 @@ pub fn check_for_bwa_mem2() {
         .expect("Failed to find installed BWA");
     default_version_check("bwa-mem2", "2.0", false, Some("bwa-mem2 version"))
         .expect("Failed to find sufficient version of bwa-mem2");
+    record_version("bwa-mem2", &["version"]);
 }
 
 pub fn check_for_minimap2() {
@@ -16,6 +53,15 @@ pub fn check_for_minimap2() {
         .expect("Failed to find installed minimap2");
     default_version_check("minimap2", "2.24-r1122", false, None)
         .expect("Failed to find sufficient version of minimap2");
+    record_version("minimap2", &["--version"]);
+}
+
+pub fn check_for_bowtie2() {
+    check_for_external_command_presence("bowtie2", "which bowtie2")
+        .expect("Failed to find installed bowtie2");
+    check_for_external_command_presence("bowtie2-build", "which bowtie2-build")
+        .expect("Failed to find installed bowtie2-build");
+    record_version("bowtie2", &["--version"]);
 }
 
 pub fn check_for_samtools() {
@@ -23,6 +69,7 @@ pub fn check_for_samtools() {
         .expect("Failed to find installed samtools");
     default_version_check("samtools", "1.9", false, None)
         .expect("Failed to find sufficient version of samtools");
+    record_version("samtools", &["--version"]);
 }
 
 pub fn check_for_strobealign() {
@@ -30,4 +77,5 @@ pub fn check_for_strobealign() {
         .expect("Failed to find installed strobealign");
     default_version_check("strobealign", "0.11.0", false, None)
         .expect("Failed to find sufficient version of strobealign");
+    record_version("strobealign", &["--version"]);
 }
@@ -1,7 +1,9 @@
 use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::str;
+use std::sync::Once;
 
+use aux_integer;
 use nm;
 use FlagFilter;
 
@@ -10,6 +12,65 @@ use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::Read;
 use rust_htslib::errors::Result as HtslibResult;
 
+/// Relative orientation of the reads of a pair, from the leftmost read to the
+/// rightmost e.g. FR when the leftmost read is on the forward strand and the
+/// rightmost on the reverse strand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PairOrientation {
+    FR,
+    RF,
+    FF,
+}
+
+impl PairOrientation {
+    pub fn from_name(name: &str) -> Option<PairOrientation> {
+        match name.to_lowercase().as_str() {
+            "fr" => Some(PairOrientation::FR),
+            "rf" => Some(PairOrientation::RF),
+            "ff" => Some(PairOrientation::FF),
+            _ => None,
+        }
+    }
+
+    fn of_pair(record1: &bam::Record, record2: &bam::Record) -> PairOrientation {
+        let (left, right) = match record1.pos() <= record2.pos() {
+            true => (record1, record2),
+            false => (record2, record1),
+        };
+        match (left.is_reverse(), right.is_reverse()) {
+            (false, true) => PairOrientation::FR,
+            (true, false) => PairOrientation::RF,
+            _ => PairOrientation::FF,
+        }
+    }
+}
+
+/// Constraints on the placement of the reads of a pair relative to each
+/// other. When any are set, pairs are judged by these constraints rather than
+/// by the mapper's proper pair flag, and pairs which do not satisfy them, or
+/// whose reads are mapped to different contigs, are discordant.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PairConstraints {
+    pub min_insert_size: u64,
+    // 0 for no maximum
+    pub max_insert_size: u64,
+    pub orientation: Option<PairOrientation>,
+}
+
+impl PairConstraints {
+    pub fn is_empty(&self) -> bool {
+        self.min_insert_size == 0 && self.max_insert_size == 0 && self.orientation.is_none()
+    }
+
+    fn pair_is_concordant(&self, record1: &bam::Record, record2: &bam::Record) -> bool {
+        let insert_size = record1.insert_size().unsigned_abs();
+        let orientation = PairOrientation::of_pair(record1, record2);
+        insert_size >= self.min_insert_size
+            && (self.max_insert_size == 0 || insert_size <= self.max_insert_size)
+            && self.orientation.map_or(true, |o| o == orientation)
+    }
+}
+
 pub struct ReferenceSortedBamFilter {
     first_set: BTreeMap<Rc<String>, Rc<bam::Record>>,
     current_reference: i32,
@@ -19,10 +80,19 @@ pub struct ReferenceSortedBamFilter {
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
     filter_pairs: bool,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
+    pair_constraints: PairConstraints,
+    num_discordant_pairs: u64,
+    found_suboptimal_score: bool,
     pub num_detected_primary_alignments: u64,
     flag_filters: FlagFilter,
     filter_out: bool, // true if we are filtering out reads
@@ -36,17 +106,31 @@ impl ReferenceSortedBamFilter {
         min_aligned_length_single: u32,
         min_percent_identity_single: f32,
         min_aligned_percent_single: f32,
+        min_mapq_single: u8,
+        min_as_xs_difference_single: u32,
+        max_clipped_fraction_single: f32,
         min_aligned_length_pair: u32,
         min_percent_identity_pair: f32,
         min_aligned_percent_pair: f32,
+        min_mapq_pair: u8,
+        min_as_xs_difference_pair: u32,
+        max_clipped_fraction_pair: f32,
+        pair_constraints: PairConstraints,
         filter_out: bool,
     ) -> ReferenceSortedBamFilter {
         let filtering_single = min_aligned_length_single > 0
             || min_percent_identity_single > 0.0
-            || min_aligned_percent_single > 0.0;
+            || min_aligned_percent_single > 0.0
+            || min_mapq_single > 0
+            || min_as_xs_difference_single > 0
+            || max_clipped_fraction_single < 1.0;
         let filtering_pairs = min_aligned_length_pair > 0
             || min_percent_identity_pair > 0.0
-            || min_aligned_percent_pair > 0.0;
+            || min_aligned_percent_pair > 0.0
+            || min_mapq_pair > 0
+            || min_as_xs_difference_pair > 0
+            || max_clipped_fraction_pair < 1.0
+            || !pair_constraints.is_empty();
 
         ReferenceSortedBamFilter {
             first_set: BTreeMap::new(),
@@ -57,10 +141,19 @@ impl ReferenceSortedBamFilter {
             min_aligned_length_single,
             min_percent_identity_single,
             min_aligned_percent_single,
+            min_mapq_single,
+            min_as_xs_difference_single,
+            max_clipped_fraction_single,
             filter_pairs: filtering_pairs,
             min_aligned_length_pair,
             min_percent_identity_pair,
             min_aligned_percent_pair,
+            min_mapq_pair,
+            min_as_xs_difference_pair,
+            max_clipped_fraction_pair,
+            pair_constraints,
+            num_discordant_pairs: 0,
+            found_suboptimal_score: false,
             num_detected_primary_alignments: 0,
             flag_filters,
             filter_out,
@@ -75,6 +168,7 @@ impl ReferenceSortedBamFilter {
             loop {
                 let res = self.reader.read(record);
                 if res.is_none() {
+                    self.warn_if_no_suboptimal_scores();
                     return res;
                 }
                 if !record.is_supplementary() && !record.is_secondary() {
@@ -87,11 +181,21 @@ impl ReferenceSortedBamFilter {
                     && (self.flag_filters.include_supplementary || !record.is_supplementary())
                     && (self.flag_filters.include_secondary || !record.is_secondary());
                 if passes_filter1 {
+                    self.note_suboptimal_score(record);
+                    let contig_length = self
+                        .reader
+                        .header()
+                        .target_len(record.tid() as u32)
+                        .unwrap();
                     let passes_filter2 = single_read_passes_filter(
                         record,
+                        contig_length,
                         self.min_aligned_length_single,
                         self.min_percent_identity_single,
                         self.min_aligned_percent_single,
+                        self.min_mapq_single,
+                        self.min_as_xs_difference_single,
+                        self.max_clipped_fraction_single,
                     );
                     if (passes_filter2 && self.filter_out) || (!passes_filter2 && !self.filter_out)
                     {
@@ -124,7 +228,21 @@ impl ReferenceSortedBamFilter {
                 if record.is_secondary() || record.is_supplementary() {
                     continue;
                 }
-                if !record.is_proper_pair() {
+                let pair_candidate = match self.pair_constraints.is_empty() {
+                    true => record.is_proper_pair(),
+                    false => {
+                        let candidate = record.is_paired()
+                            && !record.is_mate_unmapped()
+                            && record.mtid() == record.tid()
+                            && (self.flag_filters.include_improper_pairs
+                                || record.is_proper_pair());
+                        if !candidate && record.is_first_in_template() {
+                            self.num_discordant_pairs += 1;
+                        }
+                        candidate
+                    }
+                };
+                if !pair_candidate {
                     if self.filter_out {
                         continue;
                     } else {
@@ -171,27 +289,52 @@ impl ReferenceSortedBamFilter {
                     }
                     Some(record1) => {
                         debug!("Testing qname2 {}", qname);
+                        self.note_suboptimal_score(&record1);
+                        self.note_suboptimal_score(record);
+                        let concordant = self.pair_constraints.is_empty()
+                            || self.pair_constraints.pair_is_concordant(record, &record1);
+                        if !concordant {
+                            self.num_discordant_pairs += 1;
+                        }
+                        let contig_length = self
+                            .reader
+                            .header()
+                            .target_len(record.tid() as u32)
+                            .unwrap();
                         // if filtering single and paired reads then
                         // both must pass QC, as well as the pair
                         // together.
-                        let passes_filter = (!self.filter_single_reads
-                            || (single_read_passes_filter(
-                                &record1,
-                                self.min_aligned_length_single,
-                                self.min_percent_identity_single,
-                                self.min_aligned_percent_single,
-                            ) && single_read_passes_filter(
-                                record,
-                                self.min_aligned_length_single,
-                                self.min_percent_identity_single,
-                                self.min_aligned_percent_single,
-                            )))
+                        let passes_filter = concordant
+                            && (!self.filter_single_reads
+                                || (single_read_passes_filter(
+                                    &record1,
+                                    contig_length,
+                                    self.min_aligned_length_single,
+                                    self.min_percent_identity_single,
+                                    self.min_aligned_percent_single,
+                                    self.min_mapq_single,
+                                    self.min_as_xs_difference_single,
+                                    self.max_clipped_fraction_single,
+                                ) && single_read_passes_filter(
+                                    record,
+                                    contig_length,
+                                    self.min_aligned_length_single,
+                                    self.min_percent_identity_single,
+                                    self.min_aligned_percent_single,
+                                    self.min_mapq_single,
+                                    self.min_as_xs_difference_single,
+                                    self.max_clipped_fraction_single,
+                                )))
                             && read_pair_passes_filter(
                                 record,
                                 &record1,
+                                contig_length,
                                 self.min_aligned_length_pair,
                                 self.min_percent_identity_pair,
                                 self.min_aligned_percent_pair,
+                                self.min_mapq_pair,
+                                self.min_as_xs_difference_pair,
+                                self.max_clipped_fraction_pair,
                             );
                         if (passes_filter && self.filter_out)
                             || (!passes_filter && !self.filter_out)
@@ -211,6 +354,17 @@ impl ReferenceSortedBamFilter {
             }
 
             // No more records, we are finished.
+            if !self.pair_constraints.is_empty() {
+                info!(
+                    "Found {} discordant read pairs, which were {}",
+                    self.num_discordant_pairs,
+                    match self.filter_out {
+                        true => "excluded",
+                        false => "kept",
+                    }
+                );
+            }
+            self.warn_if_no_suboptimal_scores();
             None
         } else {
             record.clone_from(self.known_next_read.as_ref().unwrap());
@@ -219,6 +373,29 @@ impl ReferenceSortedBamFilter {
         }
     }
 
+    fn note_suboptimal_score(&mut self, record: &bam::Record) {
+        if !self.found_suboptimal_score && aux_integer(record, b"XS").is_some() {
+            self.found_suboptimal_score = true;
+        }
+    }
+
+    /// Warn, once per run, when reads are filtered on their AS-XS difference
+    /// but the mapper reported no suboptimal scores, so none were excluded.
+    fn warn_if_no_suboptimal_scores(&self) {
+        static WARNING: Once = Once::new();
+        if (self.min_as_xs_difference_single > 0 || self.min_as_xs_difference_pair > 0)
+            && !self.found_suboptimal_score
+        {
+            WARNING.call_once(|| {
+                warn!(
+                    "No mapping records carried a suboptimal alignment score (XS tag), \
+                     so no reads were excluded by --min-as-xs-difference or \
+                     --min-as-xs-difference-pair"
+                )
+            });
+        }
+    }
+
     pub fn set_threads(&mut self, n_threads: usize) {
         if n_threads > 1 {
             self.reader.set_threads(n_threads - 1).unwrap();
@@ -226,12 +403,102 @@ impl ReferenceSortedBamFilter {
     }
 }
 
+/// Difference between the alignment score of a read (AS tag) and the score of
+/// its best suboptimal alignment (XS tag). Ok(None) if the aligner reported
+/// no suboptimal alignment, Err if the score of the read itself is missing.
+fn as_xs_difference(record: &bam::Record) -> Result<Option<i64>, String> {
+    match aux_integer(record, b"XS") {
+        None => Ok(None),
+        Some(suboptimal) => match aux_integer(record, b"AS") {
+            Some(best) => Ok(Some(best - suboptimal)),
+            None => Err(format!(
+                "Mapping record '{}' has an 'XS' auxiliary tag but no 'AS' tag",
+                String::from_utf8_lossy(record.qname())
+            )),
+        },
+    }
+}
+
+/// Whether a read, or the better placed read of a pair, is confidently
+/// placed according to its MAPQ and the difference between its best and
+/// suboptimal alignment scores.
+fn placement_passes_filter(
+    records: &[&bam::Record],
+    min_mapq: u8,
+    min_as_xs_difference: u32,
+) -> bool {
+    if min_mapq > 0 && records.iter().all(|r| r.mapq() < min_mapq) {
+        return false;
+    }
+    if min_as_xs_difference > 0 {
+        let differences: Vec<Result<Option<i64>, String>> =
+            records.iter().map(|r| as_xs_difference(r)).collect();
+        debug!("AS-XS differences {:?}", differences);
+        // Reads without a suboptimal alignment are uniquely placed, and
+        // reads without their own score cannot be judged so do not pass.
+        return differences.iter().any(|d| match d {
+            Ok(d) => d.map_or(true, |d| d >= min_as_xs_difference as i64),
+            Err(_) => false,
+        });
+    }
+    true
+}
+
+/// Number of soft- or hard-clipped bases at either end of a read which cannot
+/// be explained by the read running off the end of its contig, and the length
+/// of the read including hard-clipped bases. Clipped bases which would have
+/// aligned within the contig are unexplained.
+fn unexplained_clipping(record: &bam::Record, contig_length: u64) -> (u64, u64) {
+    let cigar = record.cigar();
+    let clip_length = |ops: &mut dyn Iterator<Item = &Cigar>| -> u64 {
+        ops.map_while(|cig| match cig {
+            Cigar::SoftClip(i) | Cigar::HardClip(i) => Some(*i as u64),
+            _ => None,
+        })
+        .sum()
+    };
+    let leading = clip_length(&mut cigar.iter());
+    let trailing = clip_length(&mut cigar.iter().rev());
+    let hard_clipped: u64 = cigar
+        .iter()
+        .map(|cig| match cig {
+            Cigar::HardClip(i) => *i as u64,
+            _ => 0,
+        })
+        .sum();
+
+    // Only the clipped bases that would have fallen beyond the ends of the
+    // contig are explained by it.
+    let unexplained_leading = leading.min(record.pos() as u64);
+    let unexplained_trailing = trailing.min(contig_length.saturating_sub(cigar.end_pos() as u64));
+    if unexplained_leading + unexplained_trailing < leading + trailing {
+        debug!(
+            "{} clipped bases explained by contig ends",
+            leading + trailing - unexplained_leading - unexplained_trailing
+        );
+    }
+    let clipped = unexplained_leading + unexplained_trailing;
+    (clipped, record.seq().len() as u64 + hard_clipped)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn single_read_passes_filter(
     record: &bam::Record,
+    contig_length: u64,
     min_aligned_length_single: u32,
     min_percent_identity_single: f32,
     min_aligned_percent_single: f32,
+    min_mapq_single: u8,
+    min_as_xs_difference_single: u32,
+    max_clipped_fraction_single: f32,
 ) -> bool {
+    if !placement_passes_filter(&[record], min_mapq_single, min_as_xs_difference_single) {
+        return false;
+    }
+    let (clipped, read_length) = unexplained_clipping(record, contig_length);
+    if clipped as f32 / read_length as f32 > max_clipped_fraction_single {
+        return false;
+    }
     let edit_distance1 = nm(record);
 
     let mut aligned: u32 = 0;
@@ -257,13 +524,32 @@ fn single_read_passes_filter(
         && 1.0 - edit_distance1 as f32 / aligned as f32 >= min_percent_identity_single;
 }
 
+#[allow(clippy::too_many_arguments)]
 fn read_pair_passes_filter(
     record1: &bam::Record,
     record2: &bam::Record,
+    contig_length: u64,
     min_aligned_length_pair: u32,
     min_percent_identity_pair: f32,
     min_aligned_percent_pair: f32,
+    min_mapq_pair: u8,
+    min_as_xs_difference_pair: u32,
+    max_clipped_fraction_pair: f32,
 ) -> bool {
+    if !placement_passes_filter(
+        &[record1, record2],
+        min_mapq_pair,
+        min_as_xs_difference_pair,
+    ) {
+        return false;
+    }
+    let (clipped1, read_length1) = unexplained_clipping(record1, contig_length);
+    let (clipped2, read_length2) = unexplained_clipping(record2, contig_length);
+    if (clipped1 + clipped2) as f32 / (read_length1 + read_length2) as f32
+        > max_clipped_fraction_pair
+    {
+        return false;
+    }
     let edit_distance1 = nm(record1);
     let edit_distance2 = nm(record2);
 
@@ -322,9 +608,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             90,
             0.99,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         );
         let queries = vec![
@@ -354,9 +647,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             90,
             0.99,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         );
         let queries: Vec<&str> = vec![];
@@ -382,9 +682,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             250,
             0.99,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         ); // perc too high
         let queries = vec!["2", "2", "3", "3"];
@@ -406,9 +713,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             300,
             0.98,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         ); // aligned length too high
         let queries = vec!["2", "2", "3", "3"];
@@ -430,8 +744,15 @@ mod tests {
             0.0,
             0.0,
             0,
+            0,
+            1.0,
+            0,
             0.98,
             0.94,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         ); // aligned percent too high
         let queries = vec!["2", "2", "3", "3"];
@@ -452,9 +773,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             299,
             0.98,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         );
         let queries = vec!["1", "1", "2", "2"];
@@ -478,9 +806,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             250,
             0.99,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         ); // perc too high
         let queries = vec!["1", "1"];
@@ -502,9 +837,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             300,
             0.98,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         ); // aligned length too high
         let queries = vec!["1", "1"];
@@ -526,8 +868,15 @@ mod tests {
             0.0,
             0.0,
             0,
+            0,
+            1.0,
+            0,
             0.98,
             0.94,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         ); // aligned percent too high
         let queries = vec!["1", "1"];
@@ -548,9 +897,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             299,
             0.98,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         );
         let queries: Vec<&str> = vec![];
@@ -575,8 +931,15 @@ mod tests {
             0.99,
             0.0,
             0,
+            0,
+            1.0,
+            0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         ); // perc too high
         assert!(sorted.filter_single_reads);
@@ -604,8 +967,15 @@ mod tests {
             0.99,
             0.0,
             0,
+            0,
+            1.0,
+            0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         ); // perc too high
         assert!(sorted.filter_single_reads);
@@ -632,9 +1002,16 @@ mod tests {
             0,
             0.95,
             0.0,
+            0,
+            0,
+            1.0,
             300,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         ); // perc OK, but pair fails on length
         assert!(sorted.filter_single_reads);
@@ -661,9 +1038,16 @@ mod tests {
             0,
             0.95,
             0.0,
+            0,
+            0,
+            1.0,
             300,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             false,
         ); // perc OK, but pair fails on length
         assert!(sorted.filter_single_reads);
@@ -677,6 +1061,147 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_filter_mapq_and_as_xs_difference() {
+        // All reads have MAPQ 60 and XS 0. The first read of pair 1 has AS
+        // 135, all others have AS 150.
+        let filter = |min_mapq_single: u8,
+                      min_as_xs_difference_single: u32,
+                      min_mapq_pair: u8,
+                      min_as_xs_difference_pair: u32| {
+            ReferenceSortedBamFilter::new(
+                bam::Reader::from_path("tests/data/2seqs.bad_read.1.bam").unwrap(),
+                FlagFilter {
+                    include_improper_pairs: false,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                0,
+                0.0,
+                0.0,
+                min_mapq_single,
+                min_as_xs_difference_single,
+                1.0,
+                0,
+                0.0,
+                0.0,
+                min_mapq_pair,
+                min_as_xs_difference_pair,
+                1.0,
+                PairConstraints::default(),
+                true,
+            )
+        };
+        let mut record = bam::record::Record::new();
+
+        let mut sorted = filter(60, 0, 0, 0);
+        assert!(sorted.filter_single_reads);
+        sorted.read(&mut record).expect("").expect("");
+        assert_eq!("1", str::from_utf8(record.qname()).unwrap());
+        assert!(filter(61, 0, 0, 0).read(&mut record).is_none());
+
+        let mut sorted = filter(0, 140, 0, 0);
+        for i in ["2", "3", "4", "1"] {
+            sorted.read(&mut record).expect("").expect("");
+            assert_eq!(i, str::from_utf8(record.qname()).unwrap());
+        }
+
+        // Pairs pass when either read is confidently placed
+        let mut sorted = filter(0, 0, 0, 140);
+        assert!(!sorted.filter_single_reads);
+        assert!(sorted.filter_pairs);
+        for i in ["1", "1", "2", "2"] {
+            sorted.read(&mut record).expect("").expect("");
+            assert_eq!(i, str::from_utf8(record.qname()).unwrap());
+        }
+        assert!(filter(0, 0, 0, 151).read(&mut record).is_none());
+        assert!(filter(0, 0, 61, 0).read(&mut record).is_none());
+    }
+
+    #[test]
+    fn test_filter_pair_constraints() {
+        // All pairs are FR with an insert size of 500.
+        let filter =
+            |min_insert_size: u64, max_insert_size: u64, orientation: Option<PairOrientation>| {
+                ReferenceSortedBamFilter::new(
+                    bam::Reader::from_path("tests/data/2seqs.bad_read.1.bam").unwrap(),
+                    FlagFilter {
+                        include_improper_pairs: false,
+                        include_secondary: false,
+                        include_supplementary: false,
+                    },
+                    0,
+                    0.0,
+                    0.0,
+                    0,
+                    0,
+                    1.0,
+                    0,
+                    0.0,
+                    0.0,
+                    0,
+                    0,
+                    1.0,
+                    PairConstraints {
+                        min_insert_size,
+                        max_insert_size,
+                        orientation,
+                    },
+                    true,
+                )
+            };
+        let mut record = bam::record::Record::new();
+
+        let mut sorted = filter(500, 500, Some(PairOrientation::FR));
+        assert!(sorted.filter_pairs);
+        for i in ["1", "1", "2", "2"] {
+            sorted.read(&mut record).expect("").expect("");
+            assert_eq!(i, str::from_utf8(record.qname()).unwrap());
+        }
+        assert!(filter(0, 0, Some(PairOrientation::RF))
+            .read(&mut record)
+            .is_none());
+        assert!(filter(0, 0, Some(PairOrientation::FF))
+            .read(&mut record)
+            .is_none());
+        assert!(filter(501, 0, None).read(&mut record).is_none());
+        assert!(filter(0, 499, None).read(&mut record).is_none());
+    }
+
+    #[test]
+    fn test_as_xs_difference() {
+        let record = |tags: &[(&str, i32)]| {
+            let mut record = bam::Record::new();
+            record.set(b"read", None, b"A", &[30]);
+            for (tag, value) in tags {
+                record
+                    .push_aux(tag.as_bytes(), bam::record::Aux::I32(*value))
+                    .unwrap();
+            }
+            record
+        };
+        assert_eq!(
+            Ok(Some(10)),
+            as_xs_difference(&record(&[("AS", 150), ("XS", 140)]))
+        );
+        assert_eq!(Ok(None), as_xs_difference(&record(&[("AS", 150)])));
+        // minimap2 chaining scores are not alignment scores, so are not used
+        assert_eq!(
+            Ok(None),
+            as_xs_difference(&record(&[("AS", 150), ("s1", 100), ("s2", 80)]))
+        );
+        assert!(as_xs_difference(&record(&[("XS", 140)])).is_err());
+        assert!(!placement_passes_filter(&[&record(&[("XS", 140)])], 0, 1));
+
+        // Spliced aligners write XS as a strand tag, not a score
+        let mut spliced = record(&[("AS", 150)]);
+        spliced
+            .push_aux(b"XS", bam::record::Aux::Char(b'+'))
+            .unwrap();
+        assert_eq!(Ok(None), as_xs_difference(&spliced));
+        assert!(placement_passes_filter(&[&spliced], 0, 1));
+    }
+
     #[test]
     fn test_first_encountered_read_having_negative_insert_length() {
         // Rare, I think.
@@ -692,9 +1217,16 @@ mod tests {
             0,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
             1,
             0.0,
             0.0,
+            0,
+            0,
+            1.0,
+            PairConstraints::default(),
             true,
         );
         assert!(!sorted.filter_single_reads);
@@ -706,4 +1238,80 @@ mod tests {
         }
         assert_eq!(11192, num_passing);
     }
+
+    #[test]
+    fn test_unexplained_clipping() {
+        use rust_htslib::bam::record::CigarString;
+        let record = |pos: i64, cigar: Vec<Cigar>| {
+            let aligned_length: u32 = cigar
+                .iter()
+                .map(|cig| match cig {
+                    Cigar::HardClip(_) => 0,
+                    _ => cig.len(),
+                })
+                .sum();
+            let seq = vec![b'A'; aligned_length as usize];
+            let qual = vec![30; seq.len()];
+            let mut record = bam::Record::new();
+            record.set(b"read", Some(&CigarString(cigar)), &seq, &qual);
+            record.set_pos(pos);
+            record
+        };
+        // Clipping within the contig is counted
+        assert_eq!(
+            (10, 100),
+            unexplained_clipping(
+                &record(100, vec![Cigar::SoftClip(10), Cigar::Match(90)]),
+                1000
+            )
+        );
+        assert_eq!(
+            (10, 100),
+            unexplained_clipping(
+                &record(800, vec![Cigar::Match(90), Cigar::SoftClip(10)]),
+                1000
+            )
+        );
+        // Clipping where the read runs off the contig is not
+        assert_eq!(
+            (0, 100),
+            unexplained_clipping(
+                &record(0, vec![Cigar::SoftClip(10), Cigar::Match(90)]),
+                1000
+            )
+        );
+        assert_eq!(
+            (0, 100),
+            unexplained_clipping(
+                &record(910, vec![Cigar::Match(90), Cigar::SoftClip(10)]),
+                1000
+            )
+        );
+        // except for the bases which would still fall within the contig
+        assert_eq!(
+            (5, 100),
+            unexplained_clipping(
+                &record(5, vec![Cigar::SoftClip(10), Cigar::Match(90)]),
+                1000
+            )
+        );
+        assert_eq!(
+            (5, 100),
+            unexplained_clipping(
+                &record(905, vec![Cigar::Match(90), Cigar::SoftClip(10)]),
+                1000
+            )
+        );
+        // Hard-clipped bases count towards the read length
+        assert_eq!(
+            (10, 100),
+            unexplained_clipping(
+                &record(
+                    100,
+                    vec![Cigar::HardClip(5), Cigar::Match(90), Cigar::SoftClip(5)]
+                ),
+                1000
+            )
+        );
+    }
 }
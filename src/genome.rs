@@ -13,6 +13,8 @@ use coverage_takers::*;
 use genomes_and_contigs::find_first;
 use genomes_and_contigs::GenomesAndContigs;
 use mosdepth_genome_coverage_estimators::*;
+use per_base_depth::PerBaseDepthWriter;
+use strandedness::*;
 use ReadsMapped;
 
 pub fn mosdepth_genome_coverage_with_contig_names<
@@ -27,6 +29,8 @@ pub fn mosdepth_genome_coverage_with_contig_names<
     flag_filters: &FlagFilter,
     coverage_estimators: &mut [CoverageEstimator],
     threads: u16,
+    mut per_base_depth_writer: Option<&mut PerBaseDepthWriter>,
+    strandedness: Strandedness,
 ) -> Vec<ReadsMapped> {
     let mut reads_mapped_vector = vec![];
     let mut is_first_bam = true;
@@ -37,6 +41,9 @@ pub fn mosdepth_genome_coverage_with_contig_names<
         let stoit_name = &(bam_generated.name().to_string());
         debug!("Working on stoit {}", stoit_name);
         coverage_taker.start_stoit(stoit_name);
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.start_stoit(stoit_name);
+        }
         let header = bam_generated.header().clone();
         let target_names = header.target_names();
 
@@ -105,11 +112,14 @@ pub fn mosdepth_genome_coverage_with_contig_names<
         let mut last_tid: u32 = 0;
         let mut doing_first = true;
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut antisense_ups_and_downs: Vec<i32> = Vec::new();
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut seen_ref_ids = BTreeSet::new();
         let mut num_mapped_reads_in_current_contig: u64 = 0;
+        let mut num_antisense_reads_in_current_contig: u64 = 0;
         let mut total_edit_distance_in_current_contig: u64 = 0;
         let mut total_indels_in_current_contig: u64 = 0;
+        let mut num_antisense_mismatches_in_current_contig: u64 = 0;
         loop {
             match bam_generated.read(&mut record) {
                 None => {
@@ -145,24 +155,33 @@ pub fn mosdepth_genome_coverage_with_contig_names<
                                 "Found {} reads mapped to tid {}",
                                 num_mapped_reads_in_current_contig, last_tid
                             );
-                            for ref mut coverage_estimator in
-                                per_genome_coverage_estimators[genome_index].iter_mut()
-                            {
-                                coverage_estimator.add_contig(
-                                    &ups_and_downs,
-                                    num_mapped_reads_in_current_contig,
-                                    total_edit_distance_in_current_contig
-                                        - total_indels_in_current_contig,
-                                );
+                            if let Some(ref mut writer) = per_base_depth_writer {
+                                writer.add_contig(target_names[last_tid as usize], &ups_and_downs);
                             }
+                            add_contig_to_estimators(
+                                &mut per_genome_coverage_estimators[genome_index],
+                                strandedness,
+                                &ups_and_downs,
+                                &antisense_ups_and_downs,
+                                num_mapped_reads_in_current_contig,
+                                num_antisense_reads_in_current_contig,
+                                total_edit_distance_in_current_contig
+                                    - total_indels_in_current_contig,
+                                num_antisense_mismatches_in_current_contig,
+                            );
                         }
                     }
 
                     ups_and_downs =
                         vec![0; header.target_len(tid).expect("Corrupt BAM file?") as usize];
+                    if strandedness.is_stranded() {
+                        antisense_ups_and_downs = vec![0; ups_and_downs.len()];
+                    }
                     num_mapped_reads_in_current_contig = 0;
+                    num_antisense_reads_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
+                    num_antisense_mismatches_in_current_contig = 0;
                     last_tid = tid;
                     seen_ref_ids.insert(tid);
                 }
@@ -175,10 +194,19 @@ pub fn mosdepth_genome_coverage_with_contig_names<
                     Some(genome_index) => {
                         reads_mapped_in_each_genome[genome_index] += 1;
                         num_mapped_reads_in_current_contig += 1;
+                        let is_antisense = strandedness.is_antisense(&record);
+                        let pileup = match is_antisense {
+                            true => {
+                                num_antisense_reads_in_current_contig += 1;
+                                &mut antisense_ups_and_downs
+                            }
+                            false => &mut ups_and_downs,
+                        };
                         trace!(
                             "read name {:?}",
                             std::str::from_utf8(record.qname()).unwrap()
                         );
+                        let previous_indels = total_indels_in_current_contig;
                         let mut cursor: usize = record.pos() as usize;
                         for cig in record.cigar().iter() {
                             trace!("Found cigar {:} from {}", cig, cursor);
@@ -190,11 +218,11 @@ pub fn mosdepth_genome_coverage_with_contig_names<
                                         cursor,
                                         cursor + cig.len() as usize
                                     );
-                                    ups_and_downs[cursor] += 1;
+                                    pileup[cursor] += 1;
                                     let final_pos = cursor + cig.len() as usize;
-                                    if final_pos < ups_and_downs.len() {
+                                    if final_pos < pileup.len() {
                                         // True unless the read hits the contig end.
-                                        ups_and_downs[final_pos] -= 1;
+                                        pileup[final_pos] -= 1;
                                     }
                                     cursor += cig.len() as usize;
                                 }
@@ -214,7 +242,12 @@ pub fn mosdepth_genome_coverage_with_contig_names<
 
                         // Determine the number of mismatching bases in this read by
                         // looking at the NM tag.
-                        total_edit_distance_in_current_contig += nm(&record);
+                        let edit_distance = nm(&record);
+                        total_edit_distance_in_current_contig += edit_distance;
+                        if is_antisense {
+                            num_antisense_mismatches_in_current_contig += edit_distance
+                                .saturating_sub(total_indels_in_current_contig - previous_indels);
+                        }
                     }
                 }
             }
@@ -230,15 +263,19 @@ pub fn mosdepth_genome_coverage_with_contig_names<
         } else {
             // Record the last contig
             if let Some(genome_index) = reference_number_to_genome_index[last_tid as usize] {
-                for ref mut coverage_estimator in
-                    per_genome_coverage_estimators[genome_index].iter_mut()
-                {
-                    coverage_estimator.add_contig(
-                        &ups_and_downs,
-                        num_mapped_reads_in_current_contig,
-                        total_edit_distance_in_current_contig - total_indels_in_current_contig,
-                    )
+                if let Some(ref mut writer) = per_base_depth_writer {
+                    writer.add_contig(target_names[last_tid as usize], &ups_and_downs);
                 }
+                add_contig_to_estimators(
+                    &mut per_genome_coverage_estimators[genome_index],
+                    strandedness,
+                    &ups_and_downs,
+                    &antisense_ups_and_downs,
+                    num_mapped_reads_in_current_contig,
+                    num_antisense_reads_in_current_contig,
+                    total_edit_distance_in_current_contig - total_indels_in_current_contig,
+                    num_antisense_mismatches_in_current_contig,
+                );
             }
 
             // Print the coverages of each genome
@@ -300,8 +337,12 @@ pub fn mosdepth_genome_coverage_with_contig_names<
             }
         }
 
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.finish_stoit();
+        }
+
         let reads_mapped = ReadsMapped {
-            num_mapped_reads: num_mapped_reads_total,
+            num_mapped_reads: num_mapped_reads_total as f64,
             num_reads: bam_generated.num_detected_primary_alignments(),
         };
         info!(
@@ -310,7 +351,7 @@ pub fn mosdepth_genome_coverage_with_contig_names<
             reads_mapped.num_mapped_reads,
             reads_mapped.num_reads,
             2,
-            (reads_mapped.num_mapped_reads * 100) as f64 / reads_mapped.num_reads as f64
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
         );
         reads_mapped_vector.push(reads_mapped);
 
@@ -328,11 +369,15 @@ struct UnobservedLengthAndFirstTid {
 #[allow(clippy::too_many_arguments)]
 fn print_last_genomes<T: CoverageTaker>(
     num_mapped_reads_in_current_contig: u64,
+    num_antisense_reads_in_current_contig: u64,
     last_genome: Option<&[u8]>,
     unobserved_contig_length_and_first_tid: &mut UnobservedLengthAndFirstTid,
     ups_and_downs: &[i32],
+    antisense_ups_and_downs: &[i32],
+    strandedness: Strandedness,
     total_edit_distance_in_current_contig: u64,
     total_indels_in_current_contig: u64,
+    num_antisense_mismatches_in_current_contig: u64,
     current_genome: &[u8],
     coverage_estimators: &mut Vec<CoverageEstimator>,
     coverage_taker: &mut T,
@@ -344,13 +389,16 @@ fn print_last_genomes<T: CoverageTaker>(
     tid_to_print_zeros_to: u32,
 ) -> bool {
     //    debug!("ups_and_downs {:?}", &ups_and_downs);
-    for coverage_estimator in coverage_estimators.iter_mut() {
-        coverage_estimator.add_contig(
-            ups_and_downs,
-            num_mapped_reads_in_current_contig,
-            total_edit_distance_in_current_contig - total_indels_in_current_contig,
-        );
-    }
+    add_contig_to_estimators(
+        coverage_estimators,
+        strandedness,
+        ups_and_downs,
+        antisense_ups_and_downs,
+        num_mapped_reads_in_current_contig,
+        num_antisense_reads_in_current_contig,
+        total_edit_distance_in_current_contig - total_indels_in_current_contig,
+        num_antisense_mismatches_in_current_contig,
+    );
 
     // Determine coverage of previous genome
     let coverages: Vec<f32> = coverage_estimators
@@ -425,6 +473,8 @@ pub fn mosdepth_genome_coverage<
     flag_filters: &FlagFilter,
     single_genome: bool,
     threads: u16,
+    mut per_base_depth_writer: Option<&mut PerBaseDepthWriter>,
+    strandedness: Strandedness,
 ) -> Vec<ReadsMapped> {
     let mut reads_mapped_vector = vec![];
     debug!(
@@ -438,6 +488,9 @@ pub fn mosdepth_genome_coverage<
         let stoit_name = &(bam_generated.name().to_string());
         debug!("Working on stoit {}", stoit_name);
         coverage_taker.start_stoit(stoit_name);
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.start_stoit(stoit_name);
+        }
         let header = bam_generated.header().clone();
         let target_names = header.target_names();
 
@@ -502,12 +555,15 @@ pub fn mosdepth_genome_coverage<
             first_tid: 0,
         };
         let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut antisense_ups_and_downs: Vec<i32> = Vec::new();
         let mut record: bam::record::Record = bam::record::Record::new();
         let mut num_mapped_reads_total: u64 = 0;
         let mut num_mapped_reads_in_current_contig: u64 = 0;
+        let mut num_antisense_reads_in_current_contig: u64 = 0;
         let mut num_mapped_reads_in_current_genome: u64 = 0;
         let mut total_edit_distance_in_current_contig: u64 = 0;
         let mut total_indels_in_current_contig: u64 = 0;
+        let mut num_antisense_mismatches_in_current_contig: u64 = 0;
         loop {
             match bam_generated.read(&mut record) {
                 None => {
@@ -545,6 +601,11 @@ pub fn mosdepth_genome_coverage<
                         error!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
                         panic!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
                     }
+                    if !doing_first {
+                        if let Some(ref mut writer) = per_base_depth_writer {
+                            writer.add_contig(target_names[last_tid as usize], &ups_and_downs);
+                        }
+                    }
                     if doing_first {
                         for ref mut coverage_estimator in coverage_estimators.iter_mut() {
                             coverage_estimator.setup()
@@ -578,14 +639,16 @@ pub fn mosdepth_genome_coverage<
                             "Found {} reads mapped to tid {}",
                             num_mapped_reads_in_current_contig, last_tid
                         );
-                        for ref mut coverage_estimator in coverage_estimators.iter_mut() {
-                            coverage_estimator.add_contig(
-                                &ups_and_downs,
-                                num_mapped_reads_in_current_contig,
-                                total_edit_distance_in_current_contig
-                                    - total_indels_in_current_contig,
-                            );
-                        }
+                        add_contig_to_estimators(
+                            coverage_estimators,
+                            strandedness,
+                            &ups_and_downs,
+                            &antisense_ups_and_downs,
+                            num_mapped_reads_in_current_contig,
+                            num_antisense_reads_in_current_contig,
+                            total_edit_distance_in_current_contig - total_indels_in_current_contig,
+                            num_antisense_mismatches_in_current_contig,
+                        );
                         // Collect the length of reference sequences from this
                         // genome that had no hits that were just skipped over.
                         debug!("Filling unobserved from {} to {}", last_tid, tid);
@@ -618,11 +681,15 @@ pub fn mosdepth_genome_coverage<
 
                         let positive_coverage = print_last_genomes(
                             num_mapped_reads_in_current_contig,
+                            num_antisense_reads_in_current_contig,
                             last_genome,
                             &mut unobserved_contig_length_and_first_tid,
                             &ups_and_downs,
+                            &antisense_ups_and_downs,
+                            strandedness,
                             total_edit_distance_in_current_contig,
                             total_indels_in_current_contig,
+                            num_antisense_mismatches_in_current_contig,
                             current_genome,
                             coverage_estimators,
                             coverage_taker,
@@ -656,9 +723,14 @@ pub fn mosdepth_genome_coverage<
 
                     ups_and_downs =
                         vec![0; header.target_len(tid).expect("Corrupt BAM file?") as usize];
+                    if strandedness.is_stranded() {
+                        antisense_ups_and_downs = vec![0; ups_and_downs.len()];
+                    }
                     num_mapped_reads_in_current_contig = 0;
+                    num_antisense_reads_in_current_contig = 0;
                     total_edit_distance_in_current_contig = 0;
                     total_indels_in_current_contig = 0;
+                    num_antisense_mismatches_in_current_contig = 0;
                     last_tid = tid;
                 }
 
@@ -668,12 +740,21 @@ pub fn mosdepth_genome_coverage<
                     "read name {:?}",
                     std::str::from_utf8(record.qname()).unwrap()
                 );
+                let is_antisense = strandedness.is_antisense(&record);
                 if !record.is_supplementary() {
                     // Supplementary reads are marked primary, so exclude
                     // supplementary mappings to avoid double counting.
                     num_mapped_reads_in_current_contig += 1;
                     num_mapped_reads_in_current_genome += 1;
+                    if is_antisense {
+                        num_antisense_reads_in_current_contig += 1;
+                    }
                 }
+                let pileup = match is_antisense {
+                    true => &mut antisense_ups_and_downs,
+                    false => &mut ups_and_downs,
+                };
+                let previous_indels = total_indels_in_current_contig;
                 let mut cursor: usize = record.pos() as usize;
                 for cig in record.cigar().iter() {
                     trace!("Found cigar {:} from {}", cig, cursor);
@@ -685,11 +766,11 @@ pub fn mosdepth_genome_coverage<
                                 cursor,
                                 cursor + cig.len() as usize
                             );
-                            ups_and_downs[cursor] += 1;
+                            pileup[cursor] += 1;
                             let final_pos = cursor + cig.len() as usize;
-                            if final_pos < ups_and_downs.len() {
+                            if final_pos < pileup.len() {
                                 // True unless the read hits the contig end.
-                                ups_and_downs[final_pos] -= 1;
+                                pileup[final_pos] -= 1;
                             }
                             cursor += cig.len() as usize;
                         }
@@ -709,7 +790,12 @@ pub fn mosdepth_genome_coverage<
 
                 // Determine the number of mismatching bases in this read by
                 // looking at the NM tag.
-                total_edit_distance_in_current_contig += nm(&record);
+                let edit_distance = nm(&record);
+                total_edit_distance_in_current_contig += edit_distance;
+                if is_antisense {
+                    num_antisense_mismatches_in_current_contig += edit_distance
+                        .saturating_sub(total_indels_in_current_contig - previous_indels);
+                }
             }
         }
 
@@ -730,6 +816,9 @@ pub fn mosdepth_genome_coverage<
                 "Found {} reads mapped to tid {}",
                 num_mapped_reads_in_current_contig, last_tid
             );
+            if let Some(ref mut writer) = per_base_depth_writer {
+                writer.add_contig(target_names[last_tid as usize], &ups_and_downs);
+            }
             // Collect the length of refs from the end of the last genome that had no hits
             debug!(
                 "Filling unobserved from {} to end for {:?}",
@@ -745,11 +834,15 @@ pub fn mosdepth_genome_coverage<
 
             let positive_coverage = print_last_genomes(
                 num_mapped_reads_in_current_contig,
+                num_antisense_reads_in_current_contig,
                 last_genome,
                 &mut unobserved_contig_length_and_first_tid,
                 &ups_and_downs,
+                &antisense_ups_and_downs,
+                strandedness,
                 total_edit_distance_in_current_contig,
                 total_indels_in_current_contig,
+                num_antisense_mismatches_in_current_contig,
                 b"",
                 coverage_estimators,
                 coverage_taker,
@@ -765,8 +858,12 @@ pub fn mosdepth_genome_coverage<
             }
         }
 
+        if let Some(ref mut writer) = per_base_depth_writer {
+            writer.finish_stoit();
+        }
+
         let reads_mapped = ReadsMapped {
-            num_mapped_reads: num_mapped_reads_total,
+            num_mapped_reads: num_mapped_reads_total as f64,
             num_reads: bam_generated.num_detected_primary_alignments(),
         };
         info!(
@@ -775,7 +872,7 @@ pub fn mosdepth_genome_coverage<
             reads_mapped.num_mapped_reads,
             reads_mapped.num_reads,
             2,
-            (reads_mapped.num_mapped_reads * 100) as f64 / reads_mapped.num_reads as f64
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
         );
         reads_mapped_vector.push(reads_mapped);
 
@@ -958,6 +1055,8 @@ mod tests {
                 &flags,
                 single_genome,
                 1,
+                None,
+                Strandedness::Unstranded,
             );
         }
         let mut buf = vec![];
@@ -1002,6 +1101,8 @@ mod tests {
                 &flags,
                 single_genome,
                 1,
+                None,
+                Strandedness::Unstranded,
             );
         }
         let mut buf = vec![];
@@ -1042,6 +1143,8 @@ mod tests {
                 &flags,
                 coverage_estimators,
                 1,
+                None,
+                Strandedness::Unstranded,
             );
         }
         let mut buf = vec![];
@@ -1084,6 +1187,8 @@ mod tests {
                 &flags,
                 coverage_estimators,
                 1,
+                None,
+                Strandedness::Unstranded,
             );
         }
         let mut buf = vec![];
@@ -1545,7 +1650,7 @@ mod tests {
                 CoverageEstimator::new_estimator_variance(0.1,0)]);
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 24,
+                num_mapped_reads: 24.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -1582,7 +1687,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 12,
+                num_mapped_reads: 12.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -1605,7 +1710,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 20,
+                num_mapped_reads: 20.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -1833,11 +1938,11 @@ mod tests {
         assert_eq!(
             vec!(
                 ReadsMapped {
-                    num_mapped_reads: 12,
+                    num_mapped_reads: 12.0,
                     num_reads: 12
                 },
                 ReadsMapped {
-                    num_mapped_reads: 24,
+                    num_mapped_reads: 24.0,
                     num_reads: 24
                 }
             ),
@@ -1879,11 +1984,11 @@ mod tests {
         assert_eq!(
             vec!(
                 ReadsMapped {
-                    num_mapped_reads: 12,
+                    num_mapped_reads: 12.0,
                     num_reads: 12
                 },
                 ReadsMapped {
-                    num_mapped_reads: 24,
+                    num_mapped_reads: 24.0,
                     num_reads: 24
                 }
             ),
@@ -1920,11 +2025,11 @@ mod tests {
         assert_eq!(
             vec!(
                 ReadsMapped {
-                    num_mapped_reads: 0,
+                    num_mapped_reads: 0.0,
                     num_reads: 12
                 },
                 ReadsMapped {
-                    num_mapped_reads: 0,
+                    num_mapped_reads: 0.0,
                     num_reads: 24
                 }
             ),
@@ -1966,7 +2071,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 24,
+                num_mapped_reads: 24.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -1988,7 +2093,7 @@ mod tests {
         );
         assert_eq!(
             vec!(ReadsMapped {
-                num_mapped_reads: 0,
+                num_mapped_reads: 0.0,
                 num_reads: 24
             }),
             reads_mapped
@@ -0,0 +1,157 @@
+// Minimal JSON writing, for the structured output formats.
+
+use std::fmt::Display;
+
+/// Quote and escape a string as a JSON string.
+pub fn string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Format a number as JSON, or null if it is NaN or infinite since JSON
+/// cannot represent those.
+pub fn number<T: Display + Into<f64> + Copy>(n: T) -> String {
+    match n.into().is_finite() {
+        true => n.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+/// Format a JSON object from keys and already formatted JSON values.
+pub fn object<K: AsRef<str>, V: AsRef<str>>(pairs: &[(K, V)]) -> String {
+    format!(
+        "{{{}}}",
+        pairs
+            .iter()
+            .map(|(k, v)| format!("{}:{}", string(k.as_ref()), v.as_ref()))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Format a JSON array from already formatted JSON values.
+pub fn array<V: AsRef<str>>(values: &[V]) -> String {
+    format!(
+        "[{}]",
+        values
+            .iter()
+            .map(|v| v.as_ref())
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Format a time, given as seconds since the Unix epoch, as an ISO 8601
+/// UTC timestamp e.g. 2021-03-01T12:00:00Z.
+pub fn utc_timestamp(seconds_since_epoch: u64) -> String {
+    let days = (seconds_since_epoch / 86400) as i64;
+    let seconds_of_day = seconds_since_epoch % 86400;
+
+    // Convert days since the epoch to a calendar date, following Howard
+    // Hinnant's civil_from_days algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// The current time as an ISO 8601 UTC timestamp.
+pub fn utc_timestamp_now() -> String {
+    utc_timestamp(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_formatting() {
+        assert_eq!("\"a\\\"b\\\\c\\n\\u0001\"", string("a\"b\\c\n\u{1}"));
+        assert_eq!("1.5", number(1.5f32));
+        assert_eq!("null", number(f32::NAN));
+        assert_eq!(
+            "{\"x\":[1,null],\"y\":\"z\"}",
+            object(&[
+                ("x", array(&[number(1.0f64), number(f64::INFINITY)])),
+                ("y", string("z")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_string_escaping_control_characters() {
+        for c in 0u32..0x20 {
+            let expected = match c {
+                0x09 => "\"\\t\"".to_string(),
+                0x0a => "\"\\n\"".to_string(),
+                0x0d => "\"\\r\"".to_string(),
+                _ => format!("\"\\u{:04x}\"", c),
+            };
+            assert_eq!(
+                expected,
+                string(&std::char::from_u32(c).unwrap().to_string())
+            );
+        }
+        assert_eq!("\"\\u001f\"", string("\u{1f}"));
+        // DEL is not a control character in JSON
+        assert_eq!("\"\u{7f}\"", string("\u{7f}"));
+    }
+
+    #[test]
+    fn test_string_non_ascii() {
+        // JSON is UTF-8, so non-ASCII characters are written as is
+        assert_eq!("\"génome_α\"", string("génome_α"));
+        assert_eq!("\"样品😀\"", string("样品😀"));
+        assert_eq!("\"a\u{2028}b\"", string("a\u{2028}b"));
+        assert_eq!(
+            "{\"échantillon\":\"ü\\\"\"}",
+            object(&[("échantillon", string("ü\""))])
+        );
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!("1970-01-01T00:00:00Z", utc_timestamp(0));
+        assert_eq!("2000-02-29T23:59:59Z", utc_timestamp(951868799));
+        assert_eq!("2009-02-13T23:31:30Z", utc_timestamp(1234567890));
+        assert_eq!("2021-03-01T12:00:00Z", utc_timestamp(1614600000));
+        // 2100 is not a leap year
+        assert_eq!("2100-02-28T23:59:59Z", utc_timestamp(4107542399));
+        assert_eq!("2100-03-01T00:00:00Z", utc_timestamp(4107542400));
+        assert_eq!("9999-12-31T23:59:59Z", utc_timestamp(253402300799));
+    }
+}
@@ -1,4 +1,5 @@
 pub mod bam_generator;
+pub mod checkpoint;
 pub mod cli;
 pub mod contig;
 pub mod coverage_printer;
@@ -9,12 +10,22 @@ pub mod genome;
 pub mod genome_exclusion;
 pub mod genome_parsing;
 pub mod genomes_and_contigs;
+pub mod json;
 pub mod mapping_index_maintenance;
 pub mod mapping_parameters;
+pub mod merge;
+pub mod microdiversity;
 pub mod mosdepth_genome_coverage_estimators;
+pub mod multimapper_resolution;
+pub mod per_base_depth;
+pub mod provenance;
+pub mod regions;
+pub mod sample_sheet;
 pub mod shard_bam_reader;
+pub mod strandedness;
 
 use rust_htslib::bam::record::Record;
+use std::io::Write;
 use std::sync::Arc;
 
 extern crate bio;
@@ -22,6 +33,7 @@ extern crate bio;
 extern crate log;
 
 extern crate env_logger;
+extern crate flate2;
 extern crate nix;
 extern crate rand;
 extern crate rust_htslib;
@@ -36,9 +48,13 @@ extern crate lazy_static;
 extern crate bird_tool_utils;
 extern crate bird_tool_utils_man;
 extern crate galah;
+#[cfg(feature = "hdf5")]
+extern crate hdf5;
 extern crate needletail;
 extern crate roff;
+extern crate sha2;
 extern crate version_compare;
+extern crate zstd;
 
 pub const CONCATENATED_FASTA_FILE_SEPARATOR: &str = "~";
 
@@ -49,7 +65,8 @@ pub const AUTHOR_AND_EMAIL: &str =
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct ReadsMapped {
-    num_mapped_reads: u64,
+    // Fractional when reads are weighted, e.g. by multimapper resolution
+    num_mapped_reads: f64,
     num_reads: u64,
 }
 
@@ -75,8 +92,85 @@ impl FlagFilter {
     }
 }
 
+/// The extension of a path which determines its compression, '.gz' or
+/// '.zst', or an empty string if it is not compressed.
+pub fn compression_extension(path: &str) -> &'static str {
+    [".gz", ".zst"]
+        .iter()
+        .find(|ext| path.ends_with(*ext))
+        .copied()
+        .unwrap_or("")
+}
+
+/// An output file, compressed according to its extension.
+pub enum OutputFile {
+    Uncompressed(std::fs::File),
+    Gzip(flate2::write::GzEncoder<std::fs::File>),
+    Zstd(zstd::stream::write::Encoder<'static, std::fs::File>),
+}
+
+impl OutputFile {
+    /// Create a file, gzip compressed if the path ends in .gz and zstd
+    /// compressed if it ends in .zst.
+    pub fn create(path: &std::path::Path) -> std::io::Result<OutputFile> {
+        let file = std::fs::File::create(path)?;
+        Ok(match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => {
+                debug!("Gzip compressing output to {:?}", path);
+                OutputFile::Gzip(flate2::write::GzEncoder::new(
+                    file,
+                    flate2::Compression::default(),
+                ))
+            }
+            Some("zst") => {
+                debug!("Zstd compressing output to {:?}", path);
+                OutputFile::Zstd(zstd::stream::write::Encoder::new(file, 0)?)
+            }
+            _ => OutputFile::Uncompressed(file),
+        })
+    }
+
+    /// Write any remaining compressed data and the end of the compressed
+    /// stream. Further writes are not possible.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Uncompressed(f) => f.flush(),
+            OutputFile::Gzip(f) => f.try_finish(),
+            OutputFile::Zstd(f) => f.do_finish(),
+        }
+    }
+}
+
+impl std::io::Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Uncompressed(f) => f.write(buf),
+            OutputFile::Gzip(f) => f.write(buf),
+            OutputFile::Zstd(f) => f.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Uncompressed(f) => f.flush(),
+            OutputFile::Gzip(f) => f.flush(),
+            OutputFile::Zstd(f) => f.flush(),
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    // The zstd encoder does not finish the stream itself when dropped. This is
+    // a fallback, since errors here can only be logged, so finish() should be
+    // called once writing is done.
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Failed to finish writing output file: {}", e);
+        }
+    }
+}
+
 pub struct OutputWriter {
-    pub output_file: Option<Arc<std::sync::Mutex<std::fs::File>>>,
+    pub output_file: Option<Arc<std::sync::Mutex<OutputFile>>>,
 }
 
 impl OutputWriter {
@@ -92,7 +186,7 @@ impl OutputWriter {
                     info!("Writing output to file: {}", file_path);
                     OutputWriter {
                         output_file: Some(Arc::new(std::sync::Mutex::new(
-                            std::fs::File::create(path).unwrap_or_else(|_| {
+                            OutputFile::create(path).unwrap_or_else(|_| {
                                 panic!("Failed to create output file: {}", file_path)
                             }),
                         ))),
@@ -105,6 +199,19 @@ impl OutputWriter {
             }
         }
     }
+
+    /// Finish writing the output file, exiting if it could not be completed,
+    /// e.g. because the disk is full. Further writes are not possible.
+    pub fn finish(&mut self) {
+        let result = match &self.output_file {
+            None => std::io::stdout().flush(),
+            Some(f) => f.lock().expect("failed to unlock output file").finish(),
+        };
+        if let Err(e) = result {
+            error!("Failed to finish writing output: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 impl std::io::Write for OutputWriter {
@@ -175,3 +282,61 @@ fn aux_as(record: &rust_htslib::bam::Record) -> i64 {
         }
     }
 }
+
+/// The value of an integer auxiliary tag of a record, or None if the record
+/// does not have the tag or it is not an integer, such as the XS:A strand tag
+/// written by spliced aligners like HISAT2 and STAR.
+fn aux_integer(record: &rust_htslib::bam::Record, tag: &[u8]) -> Option<i64> {
+    use rust_htslib::bam::record::Aux;
+    match record.aux(tag) {
+        Ok(Aux::I8(v)) => Some(v as i64),
+        Ok(Aux::U8(v)) => Some(v as i64),
+        Ok(Aux::I16(v)) => Some(v as i64),
+        Ok(Aux::U16(v)) => Some(v as i64),
+        Ok(Aux::I32(v)) => Some(v as i64),
+        Ok(Aux::U32(v)) => Some(v as i64),
+        Ok(_) | Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_compressed_output_writer() {
+        let td = tempfile::TempDir::new().unwrap();
+        for name in ["out.tsv", "out.tsv.gz", "out.tsv.zst"] {
+            let path = td.path().join(name);
+            let mut writer = OutputWriter::generate(Some(path.to_str().unwrap()));
+            let mut writer_clone = writer.clone();
+            writeln!(writer, "Contig\tMean").unwrap();
+            writeln!(writer_clone, "contig1\t1.5").unwrap();
+            drop(writer);
+            drop(writer_clone);
+
+            let file = std::fs::File::open(&path).unwrap();
+            let mut contents = String::new();
+            match name {
+                "out.tsv.gz" => {
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_string(&mut contents)
+                        .unwrap();
+                }
+                "out.tsv.zst" => {
+                    zstd::stream::read::Decoder::new(file)
+                        .unwrap()
+                        .read_to_string(&mut contents)
+                        .unwrap();
+                }
+                _ => {
+                    std::io::BufReader::new(file)
+                        .read_to_string(&mut contents)
+                        .unwrap();
+                }
+            }
+            assert_eq!("Contig\tMean\ncontig1\t1.5\n", contents);
+        }
+    }
+}
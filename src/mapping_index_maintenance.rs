@@ -6,8 +6,10 @@ use std::path::Path;
 use std::process;
 
 use bam_generator::MappingProgram;
+use provenance::record_reference;
 use CONCATENATED_FASTA_FILE_SEPARATOR;
 
+use sha2::{Digest, Sha256};
 use tempdir::TempDir;
 use tempfile::{Builder, NamedTempFile};
 
@@ -57,96 +59,13 @@ impl TemporaryIndexStruct {
                 .expect("Failed to glean file stem from reference DB. Strange."),
         );
 
-        info!(
-            "Generating {:?} index for {} ..",
-            mapping_program, reference_path
+        generate_index(
+            mapping_program,
+            reference_path,
+            &index_path,
+            num_threads,
+            index_creation_options,
         );
-        let mut cmd = match mapping_program {
-            MappingProgram::BWA_MEM => std::process::Command::new("bwa"),
-            MappingProgram::BWA_MEM2 => std::process::Command::new("bwa-mem2"),
-            MappingProgram::MINIMAP2_SR
-            | MappingProgram::MINIMAP2_ONT
-            | MappingProgram::MINIMAP2_PB
-            | MappingProgram::MINIMAP2_HIFI
-            | MappingProgram::MINIMAP2_NO_PRESET => std::process::Command::new("minimap2"),
-            MappingProgram::STROBEALIGN => std::process::Command::new("strobealign"),
-        };
-        match &mapping_program {
-            MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 => {
-                cmd.arg("index")
-                    .arg("-p")
-                    .arg(&index_path)
-                    .arg(reference_path);
-            }
-            MappingProgram::MINIMAP2_SR
-            | MappingProgram::MINIMAP2_ONT
-            | MappingProgram::MINIMAP2_HIFI
-            | MappingProgram::MINIMAP2_PB
-            | MappingProgram::MINIMAP2_NO_PRESET => {
-                match &mapping_program {
-                    MappingProgram::MINIMAP2_SR => {
-                        cmd.arg("-x").arg("sr");
-                    }
-                    MappingProgram::MINIMAP2_ONT => {
-                        cmd.arg("-x").arg("map-ont");
-                    }
-                    MappingProgram::MINIMAP2_HIFI => {
-                        cmd.arg("-x").arg("map-hifi");
-                    }
-                    MappingProgram::MINIMAP2_PB => {
-                        cmd.arg("-x").arg("map-pb");
-                    }
-                    MappingProgram::MINIMAP2_NO_PRESET
-                    | MappingProgram::BWA_MEM
-                    | MappingProgram::BWA_MEM2
-                    | MappingProgram::STROBEALIGN => {}
-                };
-                if let Some(t) = num_threads {
-                    cmd.arg("-t").arg(&format!("{}", t));
-                }
-                cmd.arg("-d").arg(&index_path).arg(reference_path);
-            }
-            MappingProgram::STROBEALIGN => {
-                warn!("STROBEALIGN pre-indexing is not supported currently, so skipping index generation.");
-            }
-        };
-        if let Some(params) = index_creation_options {
-            for s in params.split_whitespace() {
-                cmd.arg(s);
-            }
-        };
-        // Some BWA versions output log info to stdout. Ignore this.
-        cmd.stdout(std::process::Stdio::piped());
-        cmd.stderr(std::process::Stdio::piped());
-        debug!("Running DB indexing command: {:?}", cmd);
-
-        let mut process = cmd
-            .spawn()
-            .unwrap_or_else(|_| panic!("Failed to start {:?} index process", mapping_program));
-        let es = process.wait().unwrap_or_else(|_| {
-            panic!(
-                "Failed to glean exitstatus from failing {:?} index process",
-                mapping_program
-            )
-        });
-        if !es.success() {
-            error!("Error when running {:?} index process.", mapping_program);
-            let mut err = String::new();
-            process
-                .stderr
-                .unwrap_or_else(|| {
-                    panic!(
-                        "Failed to grab stderr from failed {:?} index process",
-                        mapping_program
-                    )
-                })
-                .read_to_string(&mut err)
-                .expect("Failed to read stderr into string");
-            error!("The STDERR was: {:?}", err);
-            error!("Cannot continue after {:?} index failed.", mapping_program);
-            process::exit(1);
-        }
-        info!("Finished generating {:?} index.", mapping_program);
         return TemporaryIndexStruct {
             index_path_internal: index_path.to_string_lossy().to_string(),
             tempdir: td,
@@ -167,6 +86,317 @@ impl Drop for TemporaryIndexStruct {
     }
 }
 
+/// Run the index generation command of a mapping program, writing the index
+/// with the given path as its stem.
+fn generate_index(
+    mapping_program: MappingProgram,
+    reference_path: &str,
+    index_path: &Path,
+    num_threads: Option<u16>,
+    index_creation_options: Option<&str>,
+) {
+    info!(
+        "Generating {:?} index for {} ..",
+        mapping_program, reference_path
+    );
+    let mut cmd = match mapping_program {
+        MappingProgram::BWA_MEM => std::process::Command::new("bwa"),
+        MappingProgram::BWA_MEM2 => std::process::Command::new("bwa-mem2"),
+        MappingProgram::MINIMAP2_SR
+        | MappingProgram::MINIMAP2_ONT
+        | MappingProgram::MINIMAP2_PB
+        | MappingProgram::MINIMAP2_HIFI
+        | MappingProgram::MINIMAP2_NO_PRESET => std::process::Command::new("minimap2"),
+        MappingProgram::STROBEALIGN => std::process::Command::new("strobealign"),
+        MappingProgram::BOWTIE2 => std::process::Command::new("bowtie2-build"),
+    };
+    match &mapping_program {
+        MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 => {
+            cmd.arg("index")
+                .arg("-p")
+                .arg(index_path)
+                .arg(reference_path);
+        }
+        MappingProgram::MINIMAP2_SR
+        | MappingProgram::MINIMAP2_ONT
+        | MappingProgram::MINIMAP2_HIFI
+        | MappingProgram::MINIMAP2_PB
+        | MappingProgram::MINIMAP2_NO_PRESET => {
+            match &mapping_program {
+                MappingProgram::MINIMAP2_SR => {
+                    cmd.arg("-x").arg("sr");
+                }
+                MappingProgram::MINIMAP2_ONT => {
+                    cmd.arg("-x").arg("map-ont");
+                }
+                MappingProgram::MINIMAP2_HIFI => {
+                    cmd.arg("-x").arg("map-hifi");
+                }
+                MappingProgram::MINIMAP2_PB => {
+                    cmd.arg("-x").arg("map-pb");
+                }
+                MappingProgram::MINIMAP2_NO_PRESET
+                | MappingProgram::BWA_MEM
+                | MappingProgram::BWA_MEM2
+                | MappingProgram::STROBEALIGN
+                | MappingProgram::BOWTIE2 => {}
+            };
+            if let Some(t) = num_threads {
+                cmd.arg("-t").arg(&format!("{}", t));
+            }
+            cmd.arg("-d").arg(index_path).arg(reference_path);
+        }
+        MappingProgram::STROBEALIGN => {
+            warn!("STROBEALIGN pre-indexing is not supported currently, so skipping index generation.");
+        }
+        MappingProgram::BOWTIE2 => {
+            cmd.arg("--quiet");
+            if let Some(t) = num_threads {
+                cmd.arg("--threads").arg(&format!("{}", t));
+            }
+            cmd.arg(reference_path).arg(index_path);
+        }
+    };
+    if let Some(params) = index_creation_options {
+        for s in params.split_whitespace() {
+            cmd.arg(s);
+        }
+    };
+    // Some BWA versions output log info to stdout. Ignore this.
+    cmd.stdout(std::process::Stdio::piped());
+    cmd.stderr(std::process::Stdio::piped());
+    debug!("Running DB indexing command: {:?}", cmd);
+
+    let mut process = cmd
+        .spawn()
+        .unwrap_or_else(|_| panic!("Failed to start {:?} index process", mapping_program));
+    let es = process.wait().unwrap_or_else(|_| {
+        panic!(
+            "Failed to glean exitstatus from failing {:?} index process",
+            mapping_program
+        )
+    });
+    if !es.success() {
+        error!("Error when running {:?} index process.", mapping_program);
+        let mut err = String::new();
+        process
+            .stderr
+            .unwrap_or_else(|| {
+                panic!(
+                    "Failed to grab stderr from failed {:?} index process",
+                    mapping_program
+                )
+            })
+            .read_to_string(&mut err)
+            .expect("Failed to read stderr into string");
+        error!("The STDERR was: {:?}", err);
+        error!("Cannot continue after {:?} index failed.", mapping_program);
+        process::exit(1);
+    }
+    info!("Finished generating {:?} index.", mapping_program);
+}
+
+/// Stem of index files within each entry of an index cache directory.
+const CACHED_INDEX_STEM: &str = "index";
+
+/// An index stored in an index cache directory, so that it can be reused by
+/// later runs. The cache directory has one entry for each combination of
+/// reference contents, mapping program, mapping program version and index
+/// creation options.
+pub struct CachedIndexStruct {
+    #[allow(dead_code)] // field is never used, it just needs to be kept in scope.
+    tempdir: TempDir,
+    index_path_internal: String,
+}
+
+impl CachedIndexStruct {
+    pub fn new(
+        index_cache_directory: &str,
+        mapping_program: MappingProgram,
+        reference_path: &str,
+        num_threads: Option<u16>,
+        index_creation_options: Option<&str>,
+    ) -> CachedIndexStruct {
+        let cache_directory = Path::new(index_cache_directory);
+        std::fs::create_dir_all(cache_directory).unwrap_or_else(|e| {
+            error!(
+                "Failed to create index cache directory {}: {}",
+                index_cache_directory, e
+            );
+            process::exit(1);
+        });
+        let entry = cache_directory.join(index_cache_key(
+            mapping_program,
+            reference_path,
+            index_creation_options,
+        ));
+
+        if entry.is_dir() {
+            info!(
+                "Using cached {:?} index {} for {}",
+                mapping_program,
+                entry.display(),
+                reference_path
+            );
+        } else {
+            // Build in a temporary directory and then move it into place, so
+            // that an incomplete index is never found in the cache.
+            let build_directory = TempDir::new_in(cache_directory, ".coverm-index-build")
+                .expect("Unable to create temporary directory in index cache directory");
+            generate_index(
+                mapping_program,
+                reference_path,
+                &build_directory.path().join(CACHED_INDEX_STEM),
+                num_threads,
+                index_creation_options,
+            );
+            let build_path = build_directory.into_path();
+            if let Err(e) = std::fs::rename(&build_path, &entry) {
+                // Another process may have cached the same index meanwhile
+                if entry.is_dir() {
+                    std::fs::remove_dir_all(&build_path).ok();
+                } else {
+                    error!(
+                        "Failed to move index into index cache directory as {}: {}",
+                        entry.display(),
+                        e
+                    );
+                    process::exit(1);
+                }
+            }
+            info!("Cached {:?} index as {}", mapping_program, entry.display());
+        }
+
+        // Stoit names are derived from the index file name, so link to the
+        // cached index files using the reference's file name.
+        let td = TempDir::new("coverm-cached-index").expect("Unable to create temporary directory");
+        let reference_name = Path::new(reference_path)
+            .file_name()
+            .expect("Failed to glean file stem from reference DB. Strange.")
+            .to_str()
+            .expect("Unable to covert file name into str");
+        let entry_files = std::fs::read_dir(&entry).unwrap_or_else(|e| {
+            error!("Failed to read cached index {}: {}", entry.display(), e);
+            process::exit(1);
+        });
+        for file in entry_files {
+            let file = file.expect("Failed to read cached index directory entry");
+            let file_name = file.file_name();
+            if let Some(suffix) = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(CACHED_INDEX_STEM))
+            {
+                std::os::unix::fs::symlink(
+                    file.path(),
+                    td.path().join(format!("{}{}", reference_name, suffix)),
+                )
+                .expect("Failed to link to cached index file");
+            }
+        }
+        CachedIndexStruct {
+            index_path_internal: td.path().join(reference_name).to_string_lossy().to_string(),
+            tempdir: td,
+        }
+    }
+}
+impl MappingIndex for CachedIndexStruct {
+    fn index_path(&self) -> &String {
+        &self.index_path_internal
+    }
+}
+
+/// Name of the index cache entry of a reference, a hash of the reference's
+/// contents along with the mapping program, its version and the index
+/// creation options.
+fn index_cache_key(
+    mapping_program: MappingProgram,
+    reference_path: &str,
+    index_creation_options: Option<&str>,
+) -> String {
+    let mut hasher = Sha256::new();
+    let mut reference = std::fs::File::open(reference_path).unwrap_or_else(|e| {
+        error!("Failed to open reference {}: {}", reference_path, e);
+        process::exit(1);
+    });
+    debug!("Hashing reference {} for the index cache", reference_path);
+    std::io::copy(&mut reference, &mut hasher).unwrap_or_else(|e| {
+        error!("Failed to read reference {}: {}", reference_path, e);
+        process::exit(1);
+    });
+    let mapping_program_name = format!("{:?}", mapping_program).to_lowercase();
+    let version = mapping_program_version(mapping_program);
+    for field in [
+        mapping_program_name.as_str(),
+        version.as_str(),
+        index_creation_options.unwrap_or(""),
+    ] {
+        hasher.update(b"\0");
+        hasher.update(field.as_bytes());
+    }
+    format!(
+        "{}-{}",
+        mapping_program_name,
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    )
+}
+
+/// The version report of a mapping program, so that an index is not reused
+/// by a different version.
+fn mapping_program_version(mapping_program: MappingProgram) -> String {
+    let (command, args): (&str, &[&str]) = match mapping_program {
+        // bwa has no version option, but reports its version in its usage
+        MappingProgram::BWA_MEM => ("bwa", &[]),
+        MappingProgram::BWA_MEM2 => ("bwa-mem2", &["version"]),
+        MappingProgram::MINIMAP2_SR
+        | MappingProgram::MINIMAP2_ONT
+        | MappingProgram::MINIMAP2_HIFI
+        | MappingProgram::MINIMAP2_PB
+        | MappingProgram::MINIMAP2_NO_PRESET => ("minimap2", &["--version"]),
+        MappingProgram::STROBEALIGN => ("strobealign", &["--version"]),
+        MappingProgram::BOWTIE2 => ("bowtie2-build", &["--version"]),
+    };
+    match std::process::Command::new(command).args(args).output() {
+        Ok(output) => {
+            String::from_utf8_lossy(&output.stdout).to_string()
+                + &String::from_utf8_lossy(&output.stderr)
+        }
+        Err(e) => {
+            error!("Failed to determine version of {}: {}", command, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Generate an index in the index cache directory if one is given, otherwise
+/// in a temporary directory.
+fn generate_temporary_or_cached_index(
+    mapping_program: MappingProgram,
+    reference_path: &str,
+    num_threads: Option<u16>,
+    index_creation_options: Option<&str>,
+    index_cache_directory: Option<&str>,
+) -> Box<dyn MappingIndex> {
+    match index_cache_directory {
+        Some(directory) => Box::new(CachedIndexStruct::new(
+            directory,
+            mapping_program,
+            reference_path,
+            num_threads,
+            index_creation_options,
+        )),
+        None => Box::new(TemporaryIndexStruct::new(
+            mapping_program,
+            reference_path,
+            num_threads,
+            index_creation_options,
+        )),
+    }
+}
+
 fn check_for_bwa_index_existence(reference_path: &str, mapping_program: &MappingProgram) -> bool {
     let bwa_extensions = match mapping_program {
         MappingProgram::BWA_MEM => vec!["amb", "ann", "bwt", "pac", "sa"],
@@ -190,6 +420,28 @@ fn check_for_bwa_index_existence(reference_path: &str, mapping_program: &Mapping
     }
 }
 
+/// Whether a complete bowtie2 index exists with the reference path as its
+/// stem, either small (.bt2) or large (.bt2l).
+fn check_for_bowtie2_index_existence(reference_path: &str) -> bool {
+    let suffixes = ["1", "2", "3", "4", "rev.1", "rev.2"];
+    for extension in ["bt2", "bt2l"] {
+        let num_existing = suffixes
+            .iter()
+            .filter(|suffix| {
+                std::path::Path::new(&format!("{}.{}.{}", reference_path, suffix, extension))
+                    .exists()
+            })
+            .count();
+        if num_existing == suffixes.len() {
+            return true;
+        } else if num_existing > 0 {
+            error!("bowtie2 index appears to be incomplete, cannot continue.");
+            process::exit(1);
+        }
+    }
+    false
+}
+
 /// Check that a reference exists, or that a corresponding index exists.
 pub fn check_reference_existence(reference_path: &str, mapping_program: &MappingProgram) {
     let ref_path = std::path::Path::new(reference_path);
@@ -205,6 +457,11 @@ pub fn check_reference_existence(reference_path: &str, mapping_program: &Mapping
         | MappingProgram::MINIMAP2_PB
         | MappingProgram::MINIMAP2_NO_PRESET
         | MappingProgram::STROBEALIGN => {}
+        MappingProgram::BOWTIE2 => {
+            if check_for_bowtie2_index_existence(reference_path) {
+                return;
+            }
+        }
     };
 
     if !ref_path.exists() {
@@ -224,17 +481,19 @@ pub fn generate_bwa_index(
     reference_path: &str,
     index_creation_parameters: Option<&str>,
     mapping_program: MappingProgram,
+    index_cache_directory: Option<&str>,
 ) -> Box<dyn MappingIndex> {
     if check_for_bwa_index_existence(reference_path, &mapping_program) {
         info!("BWA index appears to be complete, so going ahead and using it.");
         Box::new(VanillaIndexStruct::new(reference_path))
     } else {
-        Box::new(TemporaryIndexStruct::new(
+        generate_temporary_or_cached_index(
             mapping_program,
             reference_path,
             None,
             index_creation_parameters,
-        ))
+            index_cache_directory,
+        )
     }
 }
 
@@ -243,13 +502,34 @@ pub fn generate_minimap2_index(
     num_threads: Option<u16>,
     index_creation_parameters: Option<&str>,
     mapping_program: MappingProgram,
+    index_cache_directory: Option<&str>,
 ) -> Box<dyn MappingIndex> {
-    Box::new(TemporaryIndexStruct::new(
+    generate_temporary_or_cached_index(
         mapping_program,
         reference_path,
         num_threads,
         index_creation_parameters,
-    ))
+        index_cache_directory,
+    )
+}
+
+pub fn generate_bowtie2_index(
+    reference_path: &str,
+    num_threads: Option<u16>,
+    index_cache_directory: Option<&str>,
+) -> Box<dyn MappingIndex> {
+    if check_for_bowtie2_index_existence(reference_path) {
+        info!("bowtie2 index appears to be complete, so going ahead and using it.");
+        Box::new(VanillaIndexStruct::new(reference_path))
+    } else {
+        generate_temporary_or_cached_index(
+            MappingProgram::BOWTIE2,
+            reference_path,
+            num_threads,
+            None,
+            index_cache_directory,
+        )
+    }
 }
 
 pub fn generate_concatenated_fasta_file(fasta_file_paths: &Vec<String>) -> NamedTempFile {
@@ -265,6 +545,7 @@ pub fn generate_concatenated_fasta_file(fasta_file_paths: &Vec<String>) -> Named
 
         // NOTE: A lot of this code is shared with genome_parsing#read_genome_fasta_files
         for file in fasta_file_paths {
+            record_reference(file);
             let mut something_written = false;
             let path = std::path::Path::new(file);
             let mut reader = parse_fastx_file(path)
@@ -4,23 +4,35 @@ use tempfile::NamedTempFile;
 
 use bam_generator::MappingProgram;
 use mapping_index_maintenance::check_reference_existence;
+use provenance::record_reference;
+use sample_sheet::{SampleReadFilters, SampleSheet};
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum ReadFormat {
     Coupled,
     Interleaved,
     Single,
 }
 
+/// The reads of one sample, and how they are to be mapped.
+#[derive(Clone)]
+struct SampleReads<'a> {
+    read_format: ReadFormat,
+    read1: &'a str,
+    read2: Option<&'a str>,
+    sample_name: Option<&'a str>,
+    mapping_program: MappingProgram,
+    read_filters: SampleReadFilters,
+}
+
 pub struct MappingParameters<'a> {
     references: Vec<&'a str>,
     threads: u16,
-    read1: Vec<&'a str>,
-    read2: Vec<&'a str>,
-    interleaved: Vec<&'a str>,
-    unpaired: Vec<&'a str>,
+    samples: Vec<SampleReads<'a>>,
+    // Each mapping program used, together with its options
+    mapping_programs: Vec<(MappingProgram, Option<String>)>,
     iter_reference_index: usize,
-    mapping_options: Option<&'a str>,
+    iter_mapping_program_index: usize,
 }
 
 impl<'a> MappingParameters<'a> {
@@ -28,6 +40,7 @@ impl<'a> MappingParameters<'a> {
         m: &'a clap::ArgMatches,
         mapping_program: MappingProgram,
         reference_tempfile: &'a Option<NamedTempFile>,
+        sample_sheet: &'a Option<SampleSheet>,
     ) -> MappingParameters<'a> {
         let mut read1: Vec<_> = vec![];
         let mut read2: Vec<_> = vec![];
@@ -95,11 +108,48 @@ impl<'a> MappingParameters<'a> {
                 .collect();
         }
 
-        match mapping_program {
-            MappingProgram::MINIMAP2_ONT
-            | MappingProgram::MINIMAP2_PB
-            | MappingProgram::MINIMAP2_HIFI => {
-                if !read1.is_empty() || !interleaved.is_empty() {
+        // Samples given on the command line are named after their read files,
+        // and mapped with --mapper.
+        let command_line_sample =
+            |read_format: ReadFormat, read1: &'a str, read2: Option<&'a str>| SampleReads {
+                read_format,
+                read1,
+                read2,
+                sample_name: None,
+                mapping_program,
+                read_filters: SampleReadFilters::default(),
+            };
+        let mut samples: Vec<SampleReads> = vec![];
+        for (r1, r2) in read1.iter().zip(read2.iter()) {
+            samples.push(command_line_sample(ReadFormat::Coupled, *r1, Some(*r2)));
+        }
+        for i in interleaved.iter() {
+            samples.push(command_line_sample(ReadFormat::Interleaved, *i, None));
+        }
+        for s in unpaired.iter() {
+            samples.push(command_line_sample(ReadFormat::Single, *s, None));
+        }
+        if let Some(sheet) = sample_sheet {
+            for entry in sheet.entries.iter() {
+                samples.push(SampleReads {
+                    read_format: entry.read_format.clone(),
+                    read1: entry.read1.as_str(),
+                    read2: entry.read2.as_ref().map(|r| r.as_str()),
+                    sample_name: Some(entry.sample.as_str()),
+                    mapping_program: entry.mapper.unwrap_or(mapping_program),
+                    read_filters: entry.read_filters,
+                });
+            }
+        }
+
+        for sample in samples.iter() {
+            match (sample.mapping_program, &sample.read_format) {
+                (
+                    MappingProgram::MINIMAP2_ONT
+                    | MappingProgram::MINIMAP2_PB
+                    | MappingProgram::MINIMAP2_HIFI,
+                    ReadFormat::Coupled | ReadFormat::Interleaved,
+                ) => {
                     error!(
                         "Paired-end read input specified to be mapped \
                         with minimap2-ont, minimap2-pb, or minimap2-hifi which is presumably \
@@ -109,30 +159,24 @@ impl<'a> MappingParameters<'a> {
                     );
                     process::exit(1);
                 }
+                _ => {}
             }
-            _ => {}
         }
 
-        let mapping_parameters_arg = match mapping_program {
-            MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 => "bwa-params",
-            MappingProgram::MINIMAP2_SR
-            | MappingProgram::MINIMAP2_ONT
-            | MappingProgram::MINIMAP2_HIFI
-            | MappingProgram::MINIMAP2_PB
-            | MappingProgram::MINIMAP2_NO_PRESET => "minimap2-params",
-            MappingProgram::STROBEALIGN => "strobealign-params",
-        };
-        let mapping_options = match m.contains_id(mapping_parameters_arg) {
-            true => {
-                let params = m.get_one::<String>(mapping_parameters_arg);
-                params
+        let mut mapping_programs: Vec<(MappingProgram, Option<String>)> = vec![];
+        for sample in samples.iter() {
+            if !mapping_programs
+                .iter()
+                .any(|(p, _)| *p == sample.mapping_program)
+            {
+                let mapping_options = mapping_options_from_clap(m, sample.mapping_program);
+                debug!(
+                    "Setting mapper {:?} options as '{:?}'",
+                    sample.mapping_program, mapping_options
+                );
+                mapping_programs.push((sample.mapping_program, mapping_options));
             }
-            false => None,
-        };
-        debug!(
-            "Setting mapper {:?} options as '{:?}'",
-            mapping_program, mapping_options
-        );
+        }
 
         return MappingParameters {
             references: match reference_tempfile {
@@ -142,7 +186,10 @@ impl<'a> MappingParameters<'a> {
                         .collect::<Vec<_>>()
                         .into_iter()
                         .map(|r| {
-                            check_reference_existence(r, &mapping_program);
+                            for (program, _) in mapping_programs.iter() {
+                                check_reference_existence(r, program);
+                            }
+                            record_reference(r);
                             r.as_str()
                         })
                         .collect(),
@@ -150,12 +197,10 @@ impl<'a> MappingParameters<'a> {
                 },
             },
             threads: *m.get_one::<u16>("threads").unwrap(),
-            read1,
-            read2,
-            interleaved,
-            unpaired,
+            samples,
+            mapping_programs,
             iter_reference_index: 0,
-            mapping_options: mapping_options.map(|x| &**x),
+            iter_mapping_program_index: 0,
         };
     }
 
@@ -164,33 +209,84 @@ impl<'a> MappingParameters<'a> {
     pub fn readsets(&self) -> Vec<(&str, Option<&str>)> {
         let mut to_return: Vec<(&str, Option<&str>)> = vec![];
 
-        for (r1, r2) in self.read1.iter().zip(self.read2.iter()) {
-            to_return.push((r1, Some(r2)))
-        }
-        for s in self.unpaired.iter() {
-            to_return.push((s, None))
+        for sample in self.samples.iter() {
+            match sample.read_format {
+                ReadFormat::Coupled | ReadFormat::Single => {
+                    to_return.push((sample.read1, sample.read2))
+                }
+                ReadFormat::Interleaved => {}
+            }
         }
         to_return
     }
+
+    /// Each mapping program used by at least one sample.
+    pub fn mapping_programs(&self) -> Vec<MappingProgram> {
+        self.mapping_programs.iter().map(|(p, _)| *p).collect()
+    }
+}
+
+/// Options given on the command line for a mapping program, if any, together
+/// with any required by the coverage methods requested.
+fn mapping_options_from_clap(
+    m: &clap::ArgMatches,
+    mapping_program: MappingProgram,
+) -> Option<String> {
+    let mapping_parameters_arg = match mapping_program {
+        MappingProgram::BWA_MEM | MappingProgram::BWA_MEM2 => "bwa-params",
+        MappingProgram::MINIMAP2_SR
+        | MappingProgram::MINIMAP2_ONT
+        | MappingProgram::MINIMAP2_HIFI
+        | MappingProgram::MINIMAP2_PB
+        | MappingProgram::MINIMAP2_NO_PRESET => "minimap2-params",
+        MappingProgram::STROBEALIGN => "strobealign-params",
+        MappingProgram::BOWTIE2 => "bowtie2-params",
+    };
+    let options = match m.contains_id(mapping_parameters_arg) {
+        true => m.get_one::<String>(mapping_parameters_arg).cloned(),
+        false => None,
+    };
+    match mapping_program {
+        // minimap2 only generates the MD tags needed to determine reference
+        // bases when asked.
+        MappingProgram::MINIMAP2_SR
+        | MappingProgram::MINIMAP2_ONT
+        | MappingProgram::MINIMAP2_HIFI
+        | MappingProgram::MINIMAP2_PB
+        | MappingProgram::MINIMAP2_NO_PRESET
+            if reference_bases_required(m) =>
+        {
+            Some(format!("{} --MD", options.unwrap_or_default()))
+        }
+        _ => options,
+    }
+}
+
+/// Whether any of the requested coverage methods compare reads to the
+/// reference, and so require MD tags.
+fn reference_bases_required(m: &clap::ArgMatches) -> bool {
+    match m.try_get_many::<String>("methods") {
+        Ok(Some(mut methods)) => {
+            methods.any(|method| method == "snv_count" || method == "consensus_ani")
+        }
+        _ => false,
+    }
 }
 
+/// The samples which are mapped to one reference with one mapping program.
 pub struct SingleReferenceMappingParameters<'a> {
     pub reference: &'a str,
+    pub mapping_program: MappingProgram,
     threads: u16,
-    read1: Vec<&'a str>,
-    read2: Vec<&'a str>,
-    interleaved: Vec<&'a str>,
-    unpaired: Vec<&'a str>,
-    mapping_options: Option<&'a str>,
+    samples: Vec<SampleReads<'a>>,
+    mapping_options: Option<String>,
 
-    iter_read_pair_index: usize,
-    iter_interleaved_index: usize,
-    iter_unpaired_index: usize,
+    iter_sample_index: usize,
 }
 
 impl<'a> SingleReferenceMappingParameters<'a> {
     pub fn len(&self) -> usize {
-        self.read1.len() + self.interleaved.len() + self.unpaired.len()
+        self.samples.len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -202,20 +298,26 @@ impl<'a> Iterator for MappingParameters<'a> {
     type Item = SingleReferenceMappingParameters<'a>;
 
     fn next(&mut self) -> Option<SingleReferenceMappingParameters<'a>> {
-        if self.iter_reference_index < self.references.len() {
-            let i = self.iter_reference_index;
+        if self.iter_mapping_program_index >= self.mapping_programs.len() {
+            self.iter_mapping_program_index = 0;
             self.iter_reference_index += 1;
+        }
+        if self.iter_reference_index < self.references.len() && !self.mapping_programs.is_empty() {
+            let (mapping_program, mapping_options) =
+                self.mapping_programs[self.iter_mapping_program_index].clone();
+            self.iter_mapping_program_index += 1;
             Some(SingleReferenceMappingParameters {
-                reference: self.references[i],
+                reference: self.references[self.iter_reference_index],
+                mapping_program,
                 threads: self.threads,
-                read1: self.read1.clone(),
-                read2: self.read2.clone(),
-                interleaved: self.interleaved.clone(),
-                unpaired: self.unpaired.clone(),
-                mapping_options: self.mapping_options,
-                iter_read_pair_index: 0,
-                iter_interleaved_index: 0,
-                iter_unpaired_index: 0,
+                samples: self
+                    .samples
+                    .iter()
+                    .filter(|s| s.mapping_program == mapping_program)
+                    .cloned()
+                    .collect(),
+                mapping_options,
+                iter_sample_index: 0,
             })
         } else {
             None
@@ -227,41 +329,22 @@ impl<'a> Iterator for SingleReferenceMappingParameters<'a> {
     type Item = OneSampleMappingParameters<'a>;
 
     fn next(&mut self) -> Option<OneSampleMappingParameters<'a>> {
-        if self.iter_read_pair_index < self.read1.len() {
-            let i = self.iter_read_pair_index;
-            self.iter_read_pair_index += 1;
+        if self.iter_sample_index < self.samples.len() {
+            let sample = &self.samples[self.iter_sample_index];
+            self.iter_sample_index += 1;
             Some(OneSampleMappingParameters {
                 reference: self.reference,
-                read_format: ReadFormat::Coupled,
-                read1: self.read1[i],
-                read2: Some(self.read2[i]),
+                read_format: sample.read_format.clone(),
+                read1: sample.read1,
+                read2: sample.read2,
+                sample_name: sample.sample_name,
+                mapping_program: sample.mapping_program,
+                read_filters: sample.read_filters,
                 threads: self.threads,
-                mapping_options: self.mapping_options,
+                mapping_options: self.mapping_options.clone(),
             })
-        } else if self.iter_interleaved_index < self.interleaved.len() {
-            let i = self.iter_interleaved_index;
-            self.iter_interleaved_index += 1;
-            return Some(OneSampleMappingParameters {
-                reference: self.reference,
-                read_format: ReadFormat::Interleaved,
-                read1: self.interleaved[i],
-                read2: None,
-                threads: self.threads,
-                mapping_options: self.mapping_options,
-            });
-        } else if self.iter_unpaired_index < self.unpaired.len() {
-            let i = self.iter_unpaired_index;
-            self.iter_unpaired_index += 1;
-            return Some(OneSampleMappingParameters {
-                reference: self.reference,
-                read_format: ReadFormat::Single,
-                read1: self.unpaired[i],
-                read2: None,
-                threads: self.threads,
-                mapping_options: self.mapping_options,
-            });
         } else {
-            return None;
+            None
         }
     }
 }
@@ -271,6 +354,11 @@ pub struct OneSampleMappingParameters<'a> {
     pub read_format: ReadFormat,
     pub read1: &'a str,
     pub read2: Option<&'a str>,
+    /// Name given to the sample in a sample sheet, if any
+    pub sample_name: Option<&'a str>,
+    pub mapping_program: MappingProgram,
+    /// Read filtering thresholds which override those on the command line
+    pub read_filters: SampleReadFilters,
     pub threads: u16,
-    pub mapping_options: Option<&'a str>,
+    pub mapping_options: Option<String>,
 }
@@ -0,0 +1,519 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+use microdiversity::MicrodiversityStatistic;
+use mosdepth_genome_coverage_estimators::*;
+
+/// Column headers of CoverM methods which do not depend on a parameter.
+fn fixed_method_headers() -> Vec<String> {
+    let estimators = vec![
+        CoverageEstimator::new_estimator_mean(0.0, 0, false),
+        CoverageEstimator::new_estimator_trimmed_mean(0.0, 0.0, 0.0, 0),
+        CoverageEstimator::new_estimator_pileup_counts(0.0, 0),
+        CoverageEstimator::new_estimator_covered_fraction(0.0),
+        CoverageEstimator::new_estimator_covered_bases(0.0),
+        CoverageEstimator::new_estimator_rpkm(0.0),
+        CoverageEstimator::new_estimator_tpm(0.0),
+        CoverageEstimator::new_estimator_variance(0.0, 0),
+        CoverageEstimator::new_estimator_median(0.0, 0),
+        CoverageEstimator::new_estimator_length(),
+        CoverageEstimator::new_estimator_read_count(),
+        CoverageEstimator::new_estimator_reads_per_base(),
+        CoverageEstimator::new_estimator_microdiversity(
+            MicrodiversityStatistic::NucleotideDiversity,
+            0,
+            0.0,
+            0.0,
+        ),
+        CoverageEstimator::new_estimator_microdiversity(
+            MicrodiversityStatistic::SnvCount,
+            0,
+            0.0,
+            0.0,
+        ),
+        CoverageEstimator::new_estimator_microdiversity(
+            MicrodiversityStatistic::ConsensusAni,
+            0,
+            0.0,
+            0.0,
+        ),
+    ];
+    // Relative abundance is a normalised copy of another method's column
+    let mut headers = vec!["Relative Abundance (%)".to_string()];
+    headers.extend(estimators.iter().flat_map(|e| e.column_headers()));
+    headers
+}
+
+/// Coverage values of each entry of each sample, as read from a sparse or
+/// dense CoverM output file. Values are None where NA was printed, as in the
+/// non-normalised columns of 'unmapped' entries.
+#[derive(Debug, PartialEq)]
+pub struct CoverageTable {
+    pub entry_type: String,
+    pub headers: Vec<String>,
+    pub samples: Vec<SampleCoverages>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SampleCoverages {
+    pub sample: String,
+    pub entries: Vec<(String, Vec<Option<f32>>)>,
+}
+
+impl CoverageTable {
+    /// Read a sparse or dense table, which may be gzip or zstd compressed.
+    pub fn from_file(path: &str) -> CoverageTable {
+        let file = File::open(path).unwrap_or_else(|e| {
+            error!("Failed to open coverage table {}: {}", path, e);
+            process::exit(1);
+        });
+        let reader: Box<dyn BufRead> = if path.ends_with(".gz") {
+            Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+        } else if path.ends_with(".zst") {
+            Box::new(BufReader::new(
+                zstd::stream::read::Decoder::new(file).unwrap_or_else(|e| {
+                    error!("Failed to read zstd compressed file {}: {}", path, e);
+                    process::exit(1);
+                }),
+            ))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        CoverageTable::from_reader(reader, path)
+    }
+
+    fn from_reader<R: BufRead>(reader: R, path: &str) -> CoverageTable {
+        let mut lines = reader.lines().enumerate().map(|(i, line)| {
+            (
+                i + 1,
+                line.unwrap_or_else(|e| {
+                    error!("Failed to read line from coverage table {}: {}", path, e);
+                    process::exit(1);
+                }),
+            )
+        });
+        let header = match lines.next() {
+            Some((_, header)) => header,
+            None => {
+                error!("Coverage table {} is empty", path);
+                process::exit(1);
+            }
+        };
+        let header_fields: Vec<&str> = header.split('\t').collect();
+        if header_fields.len() < 2 {
+            error!(
+                "Unable to parse header of coverage table {}: {}",
+                path, header
+            );
+            process::exit(1);
+        }
+        let parse_values = |fields: &[&str], line_number: usize| -> Vec<Option<f32>> {
+            fields
+                .iter()
+                .map(|field| match *field {
+                    "NA" => None,
+                    _ => Some(field.parse::<f32>().unwrap_or_else(|_| {
+                        error!(
+                            "Failed to parse value '{}' on line {} of coverage table {}",
+                            field, line_number, path
+                        );
+                        process::exit(1);
+                    })),
+                })
+                .collect()
+        };
+
+        let mut samples: Vec<SampleCoverages> = vec![];
+        let entry_type;
+        let headers: Vec<String>;
+        if header_fields[0] == "Sample" {
+            debug!("Reading sparse coverage table {}", path);
+            entry_type = header_fields[1].to_string();
+            headers = header_fields[2..].iter().map(|h| h.to_string()).collect();
+            let mut sample_indices: HashMap<String, usize> = HashMap::new();
+            for (line_number, line) in lines {
+                if line.is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() != header_fields.len() {
+                    error!(
+                        "Line {} of coverage table {} has {} fields, expected {}",
+                        line_number,
+                        path,
+                        fields.len(),
+                        header_fields.len()
+                    );
+                    process::exit(1);
+                }
+                let sample_index =
+                    *sample_indices
+                        .entry(fields[0].to_string())
+                        .or_insert_with(|| {
+                            samples.push(SampleCoverages {
+                                sample: fields[0].to_string(),
+                                entries: vec![],
+                            });
+                            samples.len() - 1
+                        });
+                samples[sample_index].entries.push((
+                    fields[1].to_string(),
+                    parse_values(&fields[2..], line_number),
+                ));
+            }
+        } else {
+            debug!("Reading dense coverage table {}", path);
+            entry_type = header_fields[0].to_string();
+            // Columns are grouped by sample, each with the same methods
+            let mut column_methods: Vec<String> = vec![];
+            for column_header in &header_fields[1..] {
+                let (sample, method) = match split_dense_column_header(column_header) {
+                    Some(split) => split,
+                    None => {
+                        error!(
+                            "Unable to determine the sample and method of column '{}' \
+                            in coverage table {}",
+                            column_header, path
+                        );
+                        process::exit(1);
+                    }
+                };
+                if samples.last().map(|s| s.sample.as_str()) != Some(sample) {
+                    samples.push(SampleCoverages {
+                        sample: sample.to_string(),
+                        entries: vec![],
+                    });
+                }
+                column_methods.push(method.to_string());
+            }
+            let num_methods = column_methods.len() / samples.len();
+            headers = column_methods[0..num_methods].to_vec();
+            if column_methods.len() % samples.len() != 0
+                || column_methods
+                    .iter()
+                    .enumerate()
+                    .any(|(i, method)| *method != headers[i % num_methods])
+            {
+                error!(
+                    "Samples in coverage table {} do not all have the same methods",
+                    path
+                );
+                process::exit(1);
+            }
+            for (line_number, line) in lines {
+                if line.is_empty() {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() != header_fields.len() {
+                    error!(
+                        "Line {} of coverage table {} has {} fields, expected {}",
+                        line_number,
+                        path,
+                        fields.len(),
+                        header_fields.len()
+                    );
+                    process::exit(1);
+                }
+                let values = parse_values(&fields[1..], line_number);
+                for (sample, sample_values) in samples.iter_mut().zip(values.chunks(num_methods)) {
+                    sample
+                        .entries
+                        .push((fields[0].to_string(), sample_values.to_vec()));
+                }
+            }
+        }
+        debug!(
+            "Read {} samples with methods {:?} from {}",
+            samples.len(),
+            headers,
+            path
+        );
+        CoverageTable {
+            entry_type,
+            headers,
+            samples,
+        }
+    }
+}
+
+/// Split the header of a dense table column e.g. 'sample1 Mean' into the
+/// sample name and the method header, or None if no method is recognised.
+fn split_dense_column_header(column_header: &str) -> Option<(&str, &str)> {
+    let base = column_header
+        .strip_suffix(" (sense)")
+        .or_else(|| column_header.strip_suffix(" (antisense)"))
+        .unwrap_or(column_header);
+
+    let mut method_starts: Vec<usize> = fixed_method_headers()
+        .iter()
+        .filter(|method| {
+            base.len() > method.len()
+                && base.ends_with(*method)
+                && base[..base.len() - method.len()].ends_with(' ')
+        })
+        .map(|method| base.len() - method.len())
+        .collect();
+    if let Some(i) = base.rfind(" Covered Fraction >= ") {
+        let threshold = &base[i + " Covered Fraction >= ".len()..];
+        if threshold
+            .strip_suffix('X')
+            .map_or(false, |d| d.parse::<u32>().is_ok())
+        {
+            method_starts.push(i + 1);
+        }
+    }
+    if let Some(i) = base.rfind(" Percentile ") {
+        if base[i + " Percentile ".len()..].parse::<f32>().is_ok() {
+            method_starts.push(i + 1);
+        }
+    }
+    // Prefer the longest method e.g. 'Trimmed Mean' over 'Mean'
+    method_starts
+        .into_iter()
+        .min()
+        .map(|start| (&column_header[..start - 1], &column_header[start..]))
+}
+
+/// Merge coverage tables of different samples into one table, which is
+/// printed in sparse or dense format. Each sample appears in only one table,
+/// so the values of each sample are printed as they were calculated,
+/// including those of relative abundance, RPKM and TPM, which are normalised
+/// within each sample. In dense output, entries missing from a sample are
+/// given zero coverage and the length of that entry in other samples.
+pub fn merge_coverage_tables(
+    tables: &[CoverageTable],
+    output_format: &str,
+    print_stream: &mut dyn std::io::Write,
+) {
+    let entry_type = &tables[0].entry_type;
+    let headers = &tables[0].headers;
+    for table in &tables[1..] {
+        if table.entry_type != *entry_type || table.headers != *headers {
+            error!(
+                "Coverage tables cannot be merged since they have different entry types \
+                or methods: {} {:?} and {} {:?}",
+                entry_type, headers, table.entry_type, table.headers
+            );
+            process::exit(1);
+        }
+    }
+    let length_column = headers.iter().position(|h| h == "Length");
+
+    // Entries in order of first appearance, and their lengths since these
+    // are the same in each sample
+    let mut entry_names: Vec<&str> = vec![];
+    let mut entry_indices: HashMap<&str, usize> = HashMap::new();
+    let mut entry_lengths: HashMap<&str, Option<f32>> = HashMap::new();
+    let mut sample_names: HashSet<&str> = HashSet::new();
+    for sample in tables.iter().flat_map(|t| t.samples.iter()) {
+        if !sample_names.insert(&sample.sample) {
+            error!(
+                "The sample '{}' is in more than one coverage table",
+                sample.sample
+            );
+            process::exit(1);
+        }
+        for (entry, values) in sample.entries.iter() {
+            if entry == "unmapped" {
+                continue;
+            }
+            if !entry_indices.contains_key(entry.as_str()) {
+                entry_indices.insert(entry.as_str(), entry_names.len());
+                entry_names.push(entry);
+            }
+            if let Some(length_column) = length_column {
+                entry_lengths.insert(entry.as_str(), values[length_column]);
+            }
+        }
+    }
+    debug!(
+        "Merging {} samples with {} entries",
+        sample_names.len(),
+        entry_names.len()
+    );
+
+    // Values of each entry of each sample, and of the unmapped entry
+    let mut sample_entry_values: Vec<Vec<Option<&Vec<Option<f32>>>>> = vec![];
+    let mut sample_unmapped_values: Vec<Option<&Vec<Option<f32>>>> = vec![];
+    for sample in tables.iter().flat_map(|t| t.samples.iter()) {
+        let mut entry_values: Vec<Option<&Vec<Option<f32>>>> = vec![None; entry_names.len()];
+        let mut unmapped_values = None;
+        for (entry, values) in sample.entries.iter() {
+            let previous = match entry.as_str() {
+                "unmapped" => unmapped_values.replace(values),
+                _ => entry_values[entry_indices[entry.as_str()]].replace(values),
+            };
+            if previous.is_some() {
+                error!(
+                    "The {} '{}' appears more than once for sample '{}'",
+                    entry_type.to_lowercase(),
+                    entry,
+                    sample.sample
+                );
+                process::exit(1);
+            }
+        }
+        sample_entry_values.push(entry_values);
+        sample_unmapped_values.push(unmapped_values);
+    }
+
+    let write_values = |print_stream: &mut dyn std::io::Write, values: &[Option<f32>]| {
+        for value in values {
+            match value {
+                Some(value) => write!(print_stream, "\t{}", value).unwrap(),
+                None => write!(print_stream, "\tNA").unwrap(),
+            }
+        }
+    };
+    match output_format {
+        "sparse" => {
+            write!(print_stream, "Sample\t{}", entry_type).unwrap();
+            for h in headers {
+                write!(print_stream, "\t{}", h).unwrap();
+            }
+            writeln!(print_stream).unwrap();
+            for sample in tables.iter().flat_map(|t| t.samples.iter()) {
+                for (entry, values) in sample.entries.iter() {
+                    write!(print_stream, "{}\t{}", sample.sample, entry).unwrap();
+                    write_values(print_stream, values);
+                    writeln!(print_stream).unwrap();
+                }
+            }
+        }
+        "dense" => {
+            write!(print_stream, "{}", entry_type).unwrap();
+            for sample in tables.iter().flat_map(|t| t.samples.iter()) {
+                for h in headers {
+                    write!(print_stream, "\t{} {}", sample.sample, h).unwrap();
+                }
+            }
+            writeln!(print_stream).unwrap();
+
+            let missing_values = vec![None; headers.len()];
+            if sample_unmapped_values.iter().any(|v| v.is_some()) {
+                write!(print_stream, "unmapped").unwrap();
+                for unmapped_values in sample_unmapped_values.iter() {
+                    write_values(print_stream, unmapped_values.unwrap_or(&missing_values));
+                }
+                writeln!(print_stream).unwrap();
+            }
+            for (entry_i, entry_name) in entry_names.iter().enumerate() {
+                write!(print_stream, "{}", entry_name).unwrap();
+                for entry_values in sample_entry_values.iter() {
+                    match entry_values[entry_i] {
+                        Some(values) => write_values(print_stream, values),
+                        None => {
+                            let zeros: Vec<Option<f32>> = (0..headers.len())
+                                .map(|column| match Some(column) == length_column {
+                                    true => entry_lengths[entry_name],
+                                    false => Some(0.0),
+                                })
+                                .collect();
+                            write_values(print_stream, &zeros);
+                        }
+                    }
+                }
+                writeln!(print_stream).unwrap();
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::str;
+
+    #[test]
+    fn test_split_dense_column_header() {
+        assert_eq!(
+            Some(("sample 1", "Trimmed Mean")),
+            split_dense_column_header("sample 1 Trimmed Mean")
+        );
+        assert_eq!(
+            Some(("s1", "Covered Fraction >= 10X")),
+            split_dense_column_header("s1 Covered Fraction >= 10X")
+        );
+        assert_eq!(
+            Some(("s1", "Percentile 90")),
+            split_dense_column_header("s1 Percentile 90")
+        );
+        assert_eq!(
+            Some(("s1", "Mean (antisense)")),
+            split_dense_column_header("s1 Mean (antisense)")
+        );
+        assert_eq!(None, split_dense_column_header("Mean"));
+        assert_eq!(None, split_dense_column_header("s1 Unknown"));
+    }
+
+    #[test]
+    fn test_merge_sparse_and_dense_tables() {
+        let sparse = CoverageTable::from_reader(
+            Cursor::new(
+                "Sample\tGenome\tRelative Abundance (%)\tRead Count\tLength\n\
+                s1\tunmapped\t50\tNA\tNA\n\
+                s1\tg1\t50\t10\t100\n",
+            ),
+            "sparse.tsv",
+        );
+        let dense = CoverageTable::from_reader(
+            Cursor::new(
+                "Genome\ts2 Relative Abundance (%)\ts2 Read Count\ts2 Length\n\
+                unmapped\t25\tNA\tNA\n\
+                g2\t75\t30\t300\n",
+            ),
+            "dense.tsv",
+        );
+        assert_eq!(
+            vec!["Relative Abundance (%)", "Read Count", "Length"],
+            dense.headers
+        );
+
+        let mut stream = Cursor::new(Vec::new());
+        merge_coverage_tables(&[sparse, dense], "dense", &mut stream);
+        assert_eq!(
+            "Genome\ts1 Relative Abundance (%)\ts1 Read Count\ts1 Length\t\
+            s2 Relative Abundance (%)\ts2 Read Count\ts2 Length\n\
+            unmapped\t50\tNA\tNA\t25\tNA\tNA\n\
+            g1\t50\t10\t100\t0\t0\t100\n\
+            g2\t0\t0\t300\t75\t30\t300\n",
+            str::from_utf8(stream.get_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_single_table_is_identity() {
+        let sparse = "Sample\tGenome\tRelative Abundance (%)\tMean\tRPKM\tTPM\n\
+            s1\tunmapped\t40.5\tNA\tNA\tNA\n\
+            s1\tg1\t59.5\t1.2\t1503.7594\t1000000\n\
+            s2\tunmapped\t100\tNA\tNA\tNA\n\
+            s2\tg2\t0\t0.05\t0\t0\n";
+        let mut stream = Cursor::new(Vec::new());
+        merge_coverage_tables(
+            &[CoverageTable::from_reader(
+                Cursor::new(sparse),
+                "sparse.tsv",
+            )],
+            "sparse",
+            &mut stream,
+        );
+        assert_eq!(sparse, str::from_utf8(stream.get_ref()).unwrap());
+
+        let dense = "Contig\ts1 Mean\ts1 Covered Fraction >= 5X\ts1 Length\t\
+            s2 Mean\ts2 Covered Fraction >= 5X\ts2 Length\n\
+            c1\t1.5\t0.25\t100\t0\t0\t100\n\
+            c2\t0\t0\t300\t2.25\t0.5\t300\n";
+        let mut stream = Cursor::new(Vec::new());
+        merge_coverage_tables(
+            &[CoverageTable::from_reader(Cursor::new(dense), "dense.tsv")],
+            "dense",
+            &mut stream,
+        );
+        assert_eq!(dense, str::from_utf8(stream.get_ref()).unwrap());
+    }
+}
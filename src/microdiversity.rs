@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::process;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::record::Cigar;
+
+use bam_generator::*;
+use coverage_takers::*;
+use mosdepth_genome_coverage_estimators::*;
+use FlagFilter;
+use ReadsMapped;
+
+/// Number of reads with each of A, C, G and T at a position.
+pub type BaseCounts = [u32; 4];
+
+/// Per-genome statistic calculated from the base composition of aligned
+/// reads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MicrodiversityStatistic {
+    /// Mean over positions of the probability that two randomly chosen
+    /// reads differ (π)
+    NucleotideDiversity,
+    /// Number of positions where a non-reference base is above the minimum
+    /// allele frequency
+    SnvCount,
+    /// Fraction of positions where the most common base in the reads is the
+    /// reference base
+    ConsensusAni,
+}
+
+impl MicrodiversityStatistic {
+    /// Whether the statistic compares reads to the reference, and so
+    /// requires MD tags.
+    pub fn requires_reference_bases(&self) -> bool {
+        *self != MicrodiversityStatistic::NucleotideDiversity
+    }
+}
+
+/// Base composition of the reads aligned to a single contig.
+pub struct ContigBasePileup {
+    base_counts: Vec<BaseCounts>,
+    // Reference base at each position as derived from MD tags, or 0 if not
+    // known.
+    reference_bases: Vec<u8>,
+}
+
+impl ContigBasePileup {
+    pub fn new(contig_length: usize) -> ContigBasePileup {
+        ContigBasePileup {
+            base_counts: vec![[0; 4]; contig_length],
+            reference_bases: vec![0; contig_length],
+        }
+    }
+
+    /// Add the aligned bases of a record. If require_reference_bases is
+    /// true, then exit with an error if the record has no MD tag.
+    pub fn add_record(&mut self, record: &bam::Record, require_reference_bases: bool) {
+        // Secondary alignments may have no SEQ, so there are no bases to add.
+        if record.seq_len() == 0 {
+            return;
+        }
+        let md_reference_bases = match record.aux(b"MD") {
+            Ok(Aux::String(md)) => Some(parse_md(md.as_bytes())),
+            _ => {
+                if require_reference_bases {
+                    error!(
+                        "Mapping record '{}' does not have an 'MD' auxiliary tag, \
+                        which is required to determine the reference base at each \
+                        position for the snv_count and consensus_ani methods. MD tags \
+                        are generated by bwa-mem, bowtie2 and minimap2 (which CoverM \
+                        runs with --MD when these methods are used), or can be added \
+                        with 'samtools calmd'.",
+                        String::from_utf8_lossy(record.qname())
+                    );
+                    process::exit(1);
+                }
+                None
+            }
+        };
+        let sequence = record.seq().as_bytes();
+        let mut reference_cursor = record.pos() as usize;
+        let mut query_cursor: usize = 0;
+        let mut aligned_index: usize = 0;
+        for cig in record.cigar().iter() {
+            match cig {
+                Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                    for _ in 0..cig.len() {
+                        let read_base = sequence[query_cursor].to_ascii_uppercase();
+                        if let Some(i) = base_index(read_base) {
+                            self.base_counts[reference_cursor][i] += 1;
+                        }
+                        if let Some(ref md) = md_reference_bases {
+                            let reference_base = match md.get(aligned_index) {
+                                Some(Some(mismatched)) => *mismatched,
+                                Some(None) => read_base,
+                                None => 0,
+                            };
+                            if base_index(reference_base).is_some() {
+                                self.reference_bases[reference_cursor] = reference_base;
+                            }
+                        }
+                        reference_cursor += 1;
+                        query_cursor += 1;
+                        aligned_index += 1;
+                    }
+                }
+                Cigar::Del(_) | Cigar::RefSkip(_) => {
+                    reference_cursor += cig.len() as usize;
+                }
+                Cigar::Ins(_) | Cigar::SoftClip(_) => {
+                    query_cursor += cig.len() as usize;
+                }
+                Cigar::HardClip(_) | Cigar::Pad(_) => {}
+            }
+        }
+    }
+}
+
+/// Microdiversity counts accumulated over the positions of a genome.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MicrodiversityTotals {
+    // Positions with at least the minimum coverage
+    num_positions_considered: u64,
+    total_diversity: f64,
+    num_snvs: u64,
+    // Positions with at least the minimum coverage and a known reference
+    // base
+    num_positions_compared: u64,
+    num_consensus_matches: u64,
+}
+
+impl MicrodiversityTotals {
+    pub fn add_pileup(
+        &mut self,
+        pileup: &ContigBasePileup,
+        min_coverage: u32,
+        min_allele_frequency: f32,
+    ) {
+        for (counts, reference_base) in pileup.base_counts.iter().zip(pileup.reference_bases.iter())
+        {
+            let coverage: u32 = counts.iter().sum();
+            if coverage == 0 || coverage < min_coverage {
+                continue;
+            }
+            self.num_positions_considered += 1;
+            self.total_diversity += 1.0
+                - counts
+                    .iter()
+                    .map(|c| (*c as f64 / coverage as f64).powi(2))
+                    .sum::<f64>();
+
+            if let Some(reference_index) = base_index(*reference_base) {
+                self.num_positions_compared += 1;
+                let is_snv = counts.iter().enumerate().any(|(i, c)| {
+                    i != reference_index && *c as f32 / coverage as f32 >= min_allele_frequency
+                });
+                if is_snv {
+                    self.num_snvs += 1;
+                }
+                // Ties are resolved in favour of the reference
+                let max_count = counts.iter().max().unwrap();
+                if counts[reference_index] == *max_count {
+                    self.num_consensus_matches += 1;
+                }
+            }
+        }
+    }
+
+    pub fn calculate(&self, statistic: MicrodiversityStatistic) -> f32 {
+        match statistic {
+            MicrodiversityStatistic::NucleotideDiversity => match self.num_positions_considered {
+                0 => 0.0,
+                n => (self.total_diversity / n as f64) as f32,
+            },
+            MicrodiversityStatistic::SnvCount => self.num_snvs as f32,
+            MicrodiversityStatistic::ConsensusAni => match self.num_positions_compared {
+                0 => 0.0,
+                n => self.num_consensus_matches as f32 / n as f32,
+            },
+        }
+    }
+}
+
+fn base_index(base: u8) -> Option<usize> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Expand an MD tag into the reference base at each aligned (M, X or =)
+/// position, where None means the reference base is the same as the read's.
+/// Deleted reference bases are skipped.
+fn parse_md(md: &[u8]) -> Vec<Option<u8>> {
+    let mut reference_bases = vec![];
+    let mut num_matches: usize = 0;
+    let mut in_deletion = false;
+    for c in md {
+        if c.is_ascii_digit() {
+            num_matches = num_matches * 10 + (c - b'0') as usize;
+            in_deletion = false;
+        } else {
+            reference_bases.extend(std::iter::repeat(None).take(num_matches));
+            num_matches = 0;
+            if *c == b'^' {
+                in_deletion = true;
+            } else if !in_deletion {
+                reference_bases.push(Some(c.to_ascii_uppercase()));
+            }
+        }
+    }
+    reference_bases.extend(std::iter::repeat(None).take(num_matches));
+    reference_bases
+}
+
+/// Calculate genome coverage alongside microdiversity statistics, which
+/// require the base composition at each position rather than just the read
+/// depth. Coverage estimators other than microdiversity estimators are
+/// calculated as usual.
+pub fn microdiversity_genome_coverage<
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    T: CoverageTaker,
+    F: Fn(&str) -> Option<String>,
+>(
+    bam_readers: Vec<G>,
+    genome_of_contig: F,
+    coverage_taker: &mut T,
+    coverage_estimators: &[CoverageEstimator],
+    print_zero_coverage_genomes: bool,
+    flag_filters: &FlagFilter,
+    threads: u16,
+) -> Vec<ReadsMapped> {
+    let require_reference_bases = coverage_estimators
+        .iter()
+        .any(|e| e.requires_reference_bases());
+    // Genome indices are kept consistent across samples
+    let mut genome_names: Vec<String> = vec![];
+    let mut genome_name_to_index: HashMap<String, usize> = HashMap::new();
+    let mut reads_mapped_vector = vec![];
+
+    for bam_generator in bam_readers {
+        let mut bam_generated = bam_generator.start();
+        bam_generated.set_threads(threads as usize);
+        let stoit_name = bam_generated.name().to_string();
+        debug!("Working on stoit {}", stoit_name);
+        let header = bam_generated.header().clone();
+
+        let tid_to_genome: Vec<Option<usize>> = header
+            .target_names()
+            .iter()
+            .map(|name| {
+                genome_of_contig(
+                    std::str::from_utf8(name).expect("UTF8 encoding error in BAM header file"),
+                )
+                .map(|genome| match genome_name_to_index.get(&genome) {
+                    Some(i) => *i,
+                    None => {
+                        genome_names.push(genome.clone());
+                        genome_name_to_index.insert(genome, genome_names.len() - 1);
+                        genome_names.len() - 1
+                    }
+                })
+            })
+            .collect();
+        let mut per_genome_coverage_estimators: Vec<Vec<CoverageEstimator>> = genome_names
+            .iter()
+            .map(|_| {
+                coverage_estimators
+                    .iter()
+                    .map(|e| {
+                        let mut e = e.clone();
+                        e.setup();
+                        e
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut seen_tids = vec![false; tid_to_genome.len()];
+        let mut reads_mapped_in_each_genome: Vec<u64> = vec![0; genome_names.len()];
+
+        let add_contig = |estimators: &mut [CoverageEstimator],
+                          ups_and_downs: &[i32],
+                          pileup: &ContigBasePileup,
+                          num_mapped_reads: u64| {
+            for estimator in estimators.iter_mut() {
+                estimator.add_contig(ups_and_downs, num_mapped_reads as f64, 0);
+                estimator.add_contig_base_pileup(pileup);
+            }
+        };
+
+        coverage_taker.start_stoit(&stoit_name);
+        let mut record = bam::Record::new();
+        let mut last_tid: i32 = -1;
+        let mut ups_and_downs: Vec<i32> = vec![];
+        let mut pileup = ContigBasePileup::new(0);
+        let mut num_mapped_reads_in_current_contig: u64 = 0;
+        loop {
+            match bam_generated.read(&mut record) {
+                None => {
+                    break;
+                }
+                Some(Ok(())) => {}
+                Some(e) => {
+                    panic!("Error reading BAM record: {:?}", e)
+                }
+            }
+            if !flag_filters.passes(&record) || record.is_unmapped() {
+                continue;
+            }
+            let tid = record.tid();
+            let genome = match tid_to_genome[tid as usize] {
+                Some(g) => g,
+                None => continue,
+            };
+            if tid != last_tid {
+                if tid < last_tid {
+                    error!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                    panic!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                }
+                if last_tid != -1 {
+                    if let Some(g) = tid_to_genome[last_tid as usize] {
+                        add_contig(
+                            &mut per_genome_coverage_estimators[g],
+                            &ups_and_downs,
+                            &pileup,
+                            num_mapped_reads_in_current_contig,
+                        );
+                    }
+                }
+                let contig_length =
+                    header.target_len(tid as u32).expect("Corrupt BAM file?") as usize;
+                ups_and_downs = vec![0; contig_length];
+                pileup = ContigBasePileup::new(contig_length);
+                num_mapped_reads_in_current_contig = 0;
+                seen_tids[tid as usize] = true;
+                last_tid = tid;
+            }
+
+            if !record.is_supplementary() {
+                num_mapped_reads_in_current_contig += 1;
+                reads_mapped_in_each_genome[genome] += 1;
+            }
+            let mut cursor: usize = record.pos() as usize;
+            for cig in record.cigar().iter() {
+                match cig {
+                    Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                        ups_and_downs[cursor] += 1;
+                        let final_pos = cursor + cig.len() as usize;
+                        if final_pos < ups_and_downs.len() {
+                            // True unless the read hits the contig end.
+                            ups_and_downs[final_pos] -= 1;
+                        }
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Del(_) | Cigar::RefSkip(_) => {
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
+                }
+            }
+            pileup.add_record(&record, require_reference_bases);
+        }
+        if last_tid != -1 {
+            if let Some(g) = tid_to_genome[last_tid as usize] {
+                add_contig(
+                    &mut per_genome_coverage_estimators[g],
+                    &ups_and_downs,
+                    &pileup,
+                    num_mapped_reads_in_current_contig,
+                );
+            }
+        }
+
+        // Contigs without any mapped reads
+        let mut unobserved_lengths: Vec<Vec<u64>> = vec![vec![]; genome_names.len()];
+        let mut genome_lengths: Vec<u64> = vec![0; genome_names.len()];
+        for (tid, genome) in tid_to_genome.iter().enumerate() {
+            if let Some(g) = genome {
+                let length = header.target_len(tid as u32).unwrap();
+                genome_lengths[*g] += length;
+                if !seen_tids[tid] {
+                    unobserved_lengths[*g].push(length);
+                }
+            }
+        }
+
+        let mut num_mapped_reads_total: u64 = 0;
+        for (i, estimators) in per_genome_coverage_estimators.iter_mut().enumerate() {
+            if genome_lengths[i] == 0 {
+                // Genome is not a part of this sample's reference
+                continue;
+            }
+            let coverages: Vec<f32> = estimators
+                .iter_mut()
+                .map(|e| e.calculate_coverage(&unobserved_lengths[i]))
+                .collect();
+            let any_nonzero_coverage = coverages.iter().any(|c| *c > 0.0);
+            if any_nonzero_coverage {
+                num_mapped_reads_total += reads_mapped_in_each_genome[i];
+            }
+            if print_zero_coverage_genomes || any_nonzero_coverage {
+                coverage_taker.start_entry(i, &genome_names[i]);
+                for (estimator, coverage) in estimators.iter().zip(coverages.iter()) {
+                    if *coverage > 0.0 {
+                        estimator.print_coverage(coverage, coverage_taker);
+                    } else {
+                        estimator.print_zero_coverage(coverage_taker, genome_lengths[i]);
+                    }
+                }
+                coverage_taker.finish_entry();
+            }
+        }
+
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: num_mapped_reads_total as f64,
+            num_reads: bam_generated.num_detected_primary_alignments(),
+        };
+        info!(
+            "In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
+            stoit_name,
+            reads_mapped.num_mapped_reads,
+            reads_mapped.num_reads,
+            2,
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
+        );
+        reads_mapped_vector.push(reads_mapped);
+
+        bam_generated.finish();
+    }
+    reads_mapped_vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OutputWriter;
+
+    #[test]
+    fn test_parse_md() {
+        assert_eq!(vec![None, None, None], parse_md(b"3"));
+        assert_eq!(
+            vec![None, Some(b'A'), None, None, Some(b'G')],
+            parse_md(b"1A2^CT0G0")
+        );
+    }
+
+    #[test]
+    fn test_microdiversity_genome_coverage() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    OutputWriter::generate(Some(tf.path().to_str().unwrap())),
+                );
+            microdiversity_genome_coverage(
+                generate_named_bam_readers_from_bam_files(vec!["tests/data/microdiversity.sam"]),
+                |contig| contig.find('~').map(|i| contig[..i].to_string()),
+                &mut coverage_taker,
+                &[
+                    CoverageEstimator::new_estimator_microdiversity(
+                        MicrodiversityStatistic::NucleotideDiversity,
+                        4,
+                        0.05,
+                        0.0,
+                    ),
+                    CoverageEstimator::new_estimator_microdiversity(
+                        MicrodiversityStatistic::SnvCount,
+                        4,
+                        0.05,
+                        0.0,
+                    ),
+                    CoverageEstimator::new_estimator_microdiversity(
+                        MicrodiversityStatistic::ConsensusAni,
+                        4,
+                        0.05,
+                        0.0,
+                    ),
+                    CoverageEstimator::new_estimator_read_count(),
+                ],
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+            );
+        }
+        // In g1, 3 of 4 reads have G rather than the reference A at one of
+        // the 10 covered positions.
+        assert_eq!(
+            "microdiversity\tg1\t0.0375\t1\t0.9\t4\n\
+            microdiversity\tg2\t0\t0\t1\t4\n",
+            std::fs::read_to_string(tf.path()).unwrap()
+        );
+    }
+}
@@ -1,12 +1,18 @@
 use coverage_takers::CoverageTaker;
+use microdiversity::*;
+
+/// Weighted depths below this are treated as zero, so that floating point
+/// error does not cause positions to be counted as covered.
+const MIN_WEIGHTED_DEPTH: f64 = 1e-9;
 
 #[derive(Clone, Debug)]
 pub enum CoverageEstimator {
     MeanGenomeCoverageEstimator {
-        total_count: u64,
+        // Fractional when alignments are weighted
+        total_count: f64,
         total_bases: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         total_mismatches: u64,
         min_fraction_covered_bases: f32,
         contig_end_exclusion: u64,
@@ -16,7 +22,7 @@ pub enum CoverageEstimator {
         counts: Vec<u64>,
         observed_contig_length: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         min: f32,
         max: f32,
         min_fraction_covered_bases: f32,
@@ -26,58 +32,87 @@ pub enum CoverageEstimator {
         counts: Vec<u64>,
         observed_contig_length: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         min_fraction_covered_bases: f32,
         contig_end_exclusion: u64,
     },
     CoverageFractionGenomeCoverageEstimator {
         total_bases: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
+        min_fraction_covered_bases: f32,
+    },
+    CoverageFractionAtDepthGenomeCoverageEstimator {
+        total_bases: u64,
+        num_covered_bases: u64,
+        num_covered_bases_at_depth: Vec<u64>,
+        // Fraction covered at each depth threshold, as printed
+        fractions_at_depth: Vec<f32>,
+        num_mapped_reads: f64,
+        depth_thresholds: Vec<u32>,
         min_fraction_covered_bases: f32,
     },
     NumCoveredBasesCoverageEstimator {
         total_bases: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         min_fraction_covered_bases: f32,
     },
     RPKMCoverageEstimator {
         total_bases: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         min_fraction_covered_bases: f32,
     },
     TPMCoverageEstimator {
         total_bases: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
         min_fraction_covered_bases: f32,
     },
     VarianceGenomeCoverageEstimator {
         counts: Vec<u64>,
         observed_contig_length: u64,
         num_covered_bases: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
+        min_fraction_covered_bases: f32,
+        contig_end_exclusion: u64,
+    },
+    PercentileGenomeCoverageEstimator {
+        counts: Vec<u64>,
+        observed_contig_length: u64,
+        num_covered_bases: u64,
+        num_mapped_reads: f64,
+        percentile: f32,
         min_fraction_covered_bases: f32,
         contig_end_exclusion: u64,
     },
     ReferenceLengthCalculator {
         observed_contig_length: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
     },
     ReadCountCalculator {
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
     },
     ReadsPerBaseCalculator {
         observed_contig_length: u64,
-        num_mapped_reads: u64,
+        num_mapped_reads: f64,
+    },
+    MicrodiversityEstimator {
+        statistic: MicrodiversityStatistic,
+        totals: MicrodiversityTotals,
+        total_bases: u64,
+        num_covered_bases: u64,
+        num_mapped_reads: f64,
+        min_coverage: u32,
+        min_allele_frequency: f32,
+        min_fraction_covered_bases: f32,
     },
 }
 
 impl CoverageEstimator {
-    pub fn column_headers(&self) -> Vec<&str> {
-        match self {
+    pub fn column_headers(&self) -> Vec<String> {
+        let headers = match self {
             CoverageEstimator::MeanGenomeCoverageEstimator { .. } => vec!["Mean"],
             CoverageEstimator::TrimmedMeanGenomeCoverageEstimator { .. } => vec!["Trimmed Mean"],
             CoverageEstimator::PileupCountsGenomeCoverageEstimator { .. } => {
@@ -86,13 +121,65 @@ impl CoverageEstimator {
             CoverageEstimator::CoverageFractionGenomeCoverageEstimator { .. } => {
                 vec!["Covered Fraction"]
             }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                depth_thresholds,
+                ..
+            } => {
+                return depth_thresholds
+                    .iter()
+                    .map(|d| format!("Covered Fraction >= {}X", d))
+                    .collect();
+            }
             CoverageEstimator::NumCoveredBasesCoverageEstimator { .. } => vec!["Covered Bases"],
             CoverageEstimator::RPKMCoverageEstimator { .. } => vec!["RPKM"],
             CoverageEstimator::TPMCoverageEstimator { .. } => vec!["TPM"],
             CoverageEstimator::VarianceGenomeCoverageEstimator { .. } => vec!["Variance"],
+            CoverageEstimator::PercentileGenomeCoverageEstimator { percentile, .. } => {
+                return match *percentile == 50.0 {
+                    true => vec!["Median".to_string()],
+                    false => vec![format!("Percentile {}", percentile)],
+                };
+            }
             CoverageEstimator::ReferenceLengthCalculator { .. } => vec!["Length"],
             CoverageEstimator::ReadCountCalculator { .. } => vec!["Read Count"],
             CoverageEstimator::ReadsPerBaseCalculator { .. } => vec!["Reads per base"],
+            CoverageEstimator::MicrodiversityEstimator { statistic, .. } => match statistic {
+                MicrodiversityStatistic::NucleotideDiversity => vec!["Nucleotide Diversity"],
+                MicrodiversityStatistic::SnvCount => vec!["SNV Count"],
+                MicrodiversityStatistic::ConsensusAni => vec!["Consensus ANI"],
+            },
+        };
+        headers.iter().map(|h| h.to_string()).collect()
+    }
+
+    /// Whether this estimator needs the reference base at each position,
+    /// which is taken from MD tags.
+    pub fn requires_reference_bases(&self) -> bool {
+        match self {
+            CoverageEstimator::MicrodiversityEstimator { statistic, .. } => {
+                statistic.requires_reference_bases()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether this estimator needs the base composition of aligned reads,
+    /// provided through add_contig_base_pileup.
+    pub fn requires_base_pileup(&self) -> bool {
+        matches!(self, CoverageEstimator::MicrodiversityEstimator { .. })
+    }
+
+    /// Add the base composition of a contig, which was previously given to
+    /// add_contig. Ignored by estimators which only use read depth.
+    pub fn add_contig_base_pileup(&mut self, pileup: &ContigBasePileup) {
+        if let CoverageEstimator::MicrodiversityEstimator {
+            ref mut totals,
+            min_coverage,
+            min_allele_frequency,
+            ..
+        } = self
+        {
+            totals.add_pileup(pileup, *min_coverage, *min_allele_frequency);
         }
     }
 }
@@ -104,10 +191,10 @@ impl CoverageEstimator {
         exclude_mismatches: bool,
     ) -> CoverageEstimator {
         CoverageEstimator::MeanGenomeCoverageEstimator {
-            total_count: 0,
+            total_count: 0.0,
             total_bases: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             total_mismatches: 0,
             min_fraction_covered_bases,
             contig_end_exclusion,
@@ -124,7 +211,7 @@ impl CoverageEstimator {
             counts: vec![],
             observed_contig_length: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             min_fraction_covered_bases,
             min,
             max,
@@ -139,7 +226,7 @@ impl CoverageEstimator {
             counts: vec![],
             observed_contig_length: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             min_fraction_covered_bases,
             contig_end_exclusion,
         }
@@ -148,7 +235,21 @@ impl CoverageEstimator {
         CoverageEstimator::CoverageFractionGenomeCoverageEstimator {
             total_bases: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
+            min_fraction_covered_bases,
+        }
+    }
+    pub fn new_estimator_covered_fraction_at_depth(
+        depth_thresholds: Vec<u32>,
+        min_fraction_covered_bases: f32,
+    ) -> CoverageEstimator {
+        CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+            total_bases: 0,
+            num_covered_bases: 0,
+            num_covered_bases_at_depth: vec![0; depth_thresholds.len()],
+            fractions_at_depth: vec![0.0; depth_thresholds.len()],
+            num_mapped_reads: 0.0,
+            depth_thresholds,
             min_fraction_covered_bases,
         }
     }
@@ -156,7 +257,7 @@ impl CoverageEstimator {
         CoverageEstimator::RPKMCoverageEstimator {
             total_bases: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             min_fraction_covered_bases,
         }
     }
@@ -164,7 +265,7 @@ impl CoverageEstimator {
         CoverageEstimator::TPMCoverageEstimator {
             total_bases: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             min_fraction_covered_bases,
         }
     }
@@ -172,7 +273,7 @@ impl CoverageEstimator {
         CoverageEstimator::NumCoveredBasesCoverageEstimator {
             total_bases: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
             min_fraction_covered_bases,
         }
     }
@@ -184,7 +285,32 @@ impl CoverageEstimator {
             counts: vec![],
             observed_contig_length: 0,
             num_covered_bases: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
+            min_fraction_covered_bases,
+            contig_end_exclusion,
+        }
+    }
+    pub fn new_estimator_median(
+        min_fraction_covered_bases: f32,
+        contig_end_exclusion: u64,
+    ) -> CoverageEstimator {
+        CoverageEstimator::new_estimator_percentile(
+            50.0,
+            min_fraction_covered_bases,
+            contig_end_exclusion,
+        )
+    }
+    pub fn new_estimator_percentile(
+        percentile: f32,
+        min_fraction_covered_bases: f32,
+        contig_end_exclusion: u64,
+    ) -> CoverageEstimator {
+        CoverageEstimator::PercentileGenomeCoverageEstimator {
+            counts: vec![],
+            observed_contig_length: 0,
+            num_covered_bases: 0,
+            num_mapped_reads: 0.0,
+            percentile,
             min_fraction_covered_bases,
             contig_end_exclusion,
         }
@@ -192,18 +318,35 @@ impl CoverageEstimator {
     pub fn new_estimator_length() -> CoverageEstimator {
         CoverageEstimator::ReferenceLengthCalculator {
             observed_contig_length: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
         }
     }
     pub fn new_estimator_read_count() -> CoverageEstimator {
         CoverageEstimator::ReadCountCalculator {
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
         }
     }
     pub fn new_estimator_reads_per_base() -> CoverageEstimator {
         CoverageEstimator::ReadsPerBaseCalculator {
             observed_contig_length: 0,
-            num_mapped_reads: 0,
+            num_mapped_reads: 0.0,
+        }
+    }
+    pub fn new_estimator_microdiversity(
+        statistic: MicrodiversityStatistic,
+        min_coverage: u32,
+        min_allele_frequency: f32,
+        min_fraction_covered_bases: f32,
+    ) -> CoverageEstimator {
+        CoverageEstimator::MicrodiversityEstimator {
+            statistic,
+            totals: MicrodiversityTotals::default(),
+            total_bases: 0,
+            num_covered_bases: 0,
+            num_mapped_reads: 0.0,
+            min_coverage,
+            min_allele_frequency,
+            min_fraction_covered_bases,
         }
     }
 
@@ -229,7 +372,14 @@ impl CoverageEstimator {
 pub trait MosdepthGenomeCoverageEstimator {
     fn setup(&mut self);
 
-    fn add_contig(&mut self, ups_and_downs: &[i32], num_mapped_reads: u64, total_mismatches: u64);
+    /// Add the pileup of a contig. num_mapped_reads may be fractional when
+    /// reads are weighted, e.g. when a read is shared between references.
+    fn add_contig(&mut self, ups_and_downs: &[i32], num_mapped_reads: f64, total_mismatches: u64);
+
+    /// Add the pileup of a contig where each alignment contributes its
+    /// weight to the depth rather than 1. Only supported by the estimators
+    /// accepted by multimapper_resolution::check_estimators_supported.
+    fn add_weighted_contig(&mut self, ups_and_downs: &[f64], num_mapped_reads: f64);
 
     fn calculate_coverage(&mut self, unobserved_contig_lengths: &[u64]) -> f32;
 
@@ -239,7 +389,7 @@ pub trait MosdepthGenomeCoverageEstimator {
 
     fn copy(&self) -> CoverageEstimator;
 
-    fn num_mapped_reads(&self) -> u64;
+    fn num_mapped_reads(&self) -> f64;
 }
 
 impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
@@ -254,10 +404,10 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut total_mismatches,
                 ..
             } => {
-                *total_count = 0;
+                *total_count = 0.0;
                 *total_bases = 0;
                 *num_covered_bases = 0;
-                *num_mapped_reads = 0;
+                *num_mapped_reads = 0.0;
                 *total_mismatches = 0;
             }
             CoverageEstimator::TrimmedMeanGenomeCoverageEstimator {
@@ -280,11 +430,18 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_covered_bases,
                 ref mut num_mapped_reads,
                 ..
+            }
+            | CoverageEstimator::PercentileGenomeCoverageEstimator {
+                ref mut observed_contig_length,
+                ref mut counts,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
             } => {
                 *counts = vec![];
                 *observed_contig_length = 0;
                 *num_covered_bases = 0;
-                *num_mapped_reads = 0;
+                *num_mapped_reads = 0.0;
             }
             CoverageEstimator::CoverageFractionGenomeCoverageEstimator {
                 ref mut total_bases,
@@ -312,7 +469,25 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             } => {
                 *total_bases = 0;
                 *num_covered_bases = 0;
-                *num_mapped_reads = 0;
+                *num_mapped_reads = 0.0;
+            }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_covered_bases_at_depth,
+                ref mut fractions_at_depth,
+                ref mut num_mapped_reads,
+                ..
+            } => {
+                *total_bases = 0;
+                *num_covered_bases = 0;
+                for n in num_covered_bases_at_depth.iter_mut() {
+                    *n = 0;
+                }
+                for f in fractions_at_depth.iter_mut() {
+                    *f = 0.0;
+                }
+                *num_mapped_reads = 0.0;
             }
             CoverageEstimator::ReferenceLengthCalculator {
                 ref mut observed_contig_length,
@@ -323,12 +498,24 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_mapped_reads,
             } => {
                 *observed_contig_length = 0;
-                *num_mapped_reads = 0;
+                *num_mapped_reads = 0.0;
             }
             CoverageEstimator::ReadCountCalculator {
                 ref mut num_mapped_reads,
             } => {
-                *num_mapped_reads = 0;
+                *num_mapped_reads = 0.0;
+            }
+            CoverageEstimator::MicrodiversityEstimator {
+                ref mut totals,
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
+            } => {
+                *totals = MicrodiversityTotals::default();
+                *total_bases = 0;
+                *num_covered_bases = 0;
+                *num_mapped_reads = 0.0;
             }
         }
     }
@@ -336,7 +523,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
     fn add_contig(
         &mut self,
         ups_and_downs: &[i32],
-        num_mapped_reads_in_contig: u64,
+        num_mapped_reads_in_contig: f64,
         total_mismatches_in_contig: u64,
     ) {
         match self {
@@ -368,7 +555,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                         if cumulative_sum > 0 {
                             *num_covered_bases += 1
                         }
-                        *total_count += cumulative_sum as u64;
+                        *total_count += cumulative_sum as f64;
                     }
                 }
                 debug!(
@@ -400,6 +587,14 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_mapped_reads,
                 contig_end_exclusion,
                 ..
+            }
+            | CoverageEstimator::PercentileGenomeCoverageEstimator {
+                ref mut counts,
+                ref mut observed_contig_length,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                contig_end_exclusion,
+                ..
             } => {
                 *num_mapped_reads = num_mapped_reads_in_contig;
                 let len1 = ups_and_downs.len();
@@ -460,6 +655,12 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 ref mut num_covered_bases,
                 ref mut num_mapped_reads,
                 ..
+            }
+            | CoverageEstimator::MicrodiversityEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
             } => {
                 *num_mapped_reads += num_mapped_reads_in_contig;
                 let len = ups_and_downs.len();
@@ -473,6 +674,113 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     }
                 }
             }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_covered_bases_at_depth,
+                ref mut num_mapped_reads,
+                depth_thresholds,
+                ..
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+                *total_bases += ups_and_downs.len() as u64;
+                let mut cumulative_sum: i32 = 0;
+
+                for current in ups_and_downs.iter() {
+                    cumulative_sum += current;
+                    if cumulative_sum > 0 {
+                        *num_covered_bases += 1;
+                        for (i, depth) in depth_thresholds.iter().enumerate() {
+                            if cumulative_sum as u32 >= *depth {
+                                num_covered_bases_at_depth[i] += 1
+                            }
+                        }
+                    }
+                }
+            }
+            CoverageEstimator::ReferenceLengthCalculator {
+                ref mut observed_contig_length,
+                ref mut num_mapped_reads,
+            }
+            | CoverageEstimator::ReadsPerBaseCalculator {
+                ref mut observed_contig_length,
+                ref mut num_mapped_reads,
+            } => {
+                *observed_contig_length += ups_and_downs.len() as u64;
+                *num_mapped_reads += num_mapped_reads_in_contig;
+            }
+            CoverageEstimator::ReadCountCalculator {
+                ref mut num_mapped_reads,
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+            }
+        }
+    }
+
+    fn add_weighted_contig(&mut self, ups_and_downs: &[f64], num_mapped_reads_in_contig: f64) {
+        match self {
+            CoverageEstimator::MeanGenomeCoverageEstimator {
+                ref mut total_count,
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                contig_end_exclusion,
+                ..
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+                let len = ups_and_downs.len();
+                match *contig_end_exclusion * 2 < len as u64 {
+                    true => *total_bases += len as u64 - 2 * *contig_end_exclusion,
+                    false => {
+                        debug!("Contig too short - less than twice the contig-end-exclusion");
+                        return; //contig is all ends, too short
+                    }
+                }
+                let mut cumulative_sum: f64 = 0.0;
+                let start_from = *contig_end_exclusion as usize;
+                let end_at = len - *contig_end_exclusion as usize - 1;
+                for (i, current) in ups_and_downs.iter().enumerate() {
+                    cumulative_sum += current;
+                    if i >= start_from && i <= end_at && cumulative_sum > MIN_WEIGHTED_DEPTH {
+                        *num_covered_bases += 1;
+                        *total_count += cumulative_sum;
+                    }
+                }
+            }
+            CoverageEstimator::CoverageFractionGenomeCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
+            }
+            | CoverageEstimator::NumCoveredBasesCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
+            }
+            | CoverageEstimator::RPKMCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
+            }
+            | CoverageEstimator::TPMCoverageEstimator {
+                ref mut total_bases,
+                ref mut num_covered_bases,
+                ref mut num_mapped_reads,
+                ..
+            } => {
+                *num_mapped_reads += num_mapped_reads_in_contig;
+                *total_bases += ups_and_downs.len() as u64;
+                let mut cumulative_sum: f64 = 0.0;
+                for current in ups_and_downs.iter() {
+                    cumulative_sum += current;
+                    if cumulative_sum > MIN_WEIGHTED_DEPTH {
+                        *num_covered_bases += 1
+                    }
+                }
+            }
             CoverageEstimator::ReferenceLengthCalculator {
                 ref mut observed_contig_length,
                 ref mut num_mapped_reads,
@@ -489,6 +797,10 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             } => {
                 *num_mapped_reads += num_mapped_reads_in_contig;
             }
+            _ => unreachable!(
+                "The {:?} method cannot be calculated from weighted alignments",
+                self.column_headers()
+            ),
         }
     }
 
@@ -527,7 +839,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     0.0
                 } else {
                     let calculated_coverage = match exclude_mismatches {
-                        true => (*total_count - *total_mismatches) as f32,
+                        true => (*total_count - *total_mismatches as f64) as f32,
                         false => *total_count as f32,
                     } / final_total_bases as f32;
                     debug!("Found mean coverage {}", calculated_coverage);
@@ -667,6 +979,38 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     *num_covered_bases as f32 / final_total_bases as f32
                 }
             }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                total_bases,
+                num_covered_bases,
+                num_covered_bases_at_depth,
+                ref mut fractions_at_depth,
+                num_mapped_reads: _,
+                depth_thresholds: _,
+                min_fraction_covered_bases,
+            } => {
+                let final_total_bases: u64 =
+                    *total_bases + unobserved_contig_lengths.iter().sum::<u64>();
+                // The per-threshold fractions are stored so they can be
+                // printed later.
+                if final_total_bases == 0
+                    || (*num_covered_bases as f32 / final_total_bases as f32)
+                        < *min_fraction_covered_bases
+                {
+                    for f in fractions_at_depth.iter_mut() {
+                        *f = 0.0;
+                    }
+                } else {
+                    for (f, n) in fractions_at_depth
+                        .iter_mut()
+                        .zip(num_covered_bases_at_depth.iter())
+                    {
+                        *f = *n as f32 / final_total_bases as f32;
+                    }
+                }
+                // Return the highest of the fractions, so that the coverage
+                // is only zero when all thresholds are.
+                fractions_at_depth.iter().cloned().fold(0.0, f32::max)
+            }
             CoverageEstimator::NumCoveredBasesCoverageEstimator {
                 total_bases,
                 num_covered_bases,
@@ -703,9 +1047,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     debug!("RPKM: {} {}", num_mapped_reads, final_total_bases);
                     match final_total_bases == 0 {
                         true => 0.0,
-                        false => {
-                            (*num_mapped_reads * (10u64.pow(9))) as f32 / final_total_bases as f32
-                        }
+                        false => (*num_mapped_reads * 1e9) as f32 / final_total_bases as f32,
                     }
                 }
             }
@@ -729,8 +1071,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     match final_total_bases == 0 {
                         true => 0.0,
                         false => {
-                            ((*num_mapped_reads as f64).ln() - (final_total_bases as f64).ln())
-                                .exp() as f32
+                            (num_mapped_reads.ln() - (final_total_bases as f64).ln()).exp() as f32
                         }
                     }
                 }
@@ -785,6 +1126,46 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     }
                 }
             }
+            CoverageEstimator::PercentileGenomeCoverageEstimator {
+                counts,
+                observed_contig_length,
+                num_covered_bases,
+                num_mapped_reads: _,
+                percentile,
+                min_fraction_covered_bases,
+                contig_end_exclusion,
+            } => {
+                let unobserved_contig_length = CoverageEstimator::calculate_unobserved_bases(
+                    unobserved_contig_lengths,
+                    *contig_end_exclusion,
+                );
+                let total_bases = *observed_contig_length + unobserved_contig_length;
+                debug!("Calculating percentile {} with num_covered_bases {}, observed_length {}, unobserved_length {:?} and counts {:?}",
+                       percentile, num_covered_bases, observed_contig_length, unobserved_contig_lengths, counts);
+                if total_bases == 0
+                    || *num_covered_bases == 0
+                    || (*num_covered_bases as f32 / total_bases as f32)
+                        < *min_fraction_covered_bases
+                {
+                    0.0
+                } else {
+                    // Nearest-rank percentile, counting unobserved bases as
+                    // having zero coverage. Counts are not modified so that
+                    // this estimator can be calculated more than once.
+                    let rank =
+                        ((*percentile as f64 / 100.0 * total_bases as f64).ceil() as u64).max(1);
+                    let mut num_accounted_for = unobserved_contig_length;
+                    let mut found = 0;
+                    for (depth, num_covered) in counts.iter().enumerate() {
+                        num_accounted_for += *num_covered;
+                        if num_accounted_for >= rank {
+                            found = depth;
+                            break;
+                        }
+                    }
+                    found as f32
+                }
+            }
             CoverageEstimator::ReferenceLengthCalculator {
                 observed_contig_length,
                 ..
@@ -798,6 +1179,25 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                     / (*observed_contig_length + unobserved_contig_lengths.iter().sum::<u64>())
                         as f32
             }
+            CoverageEstimator::MicrodiversityEstimator {
+                statistic,
+                totals,
+                total_bases,
+                num_covered_bases,
+                min_fraction_covered_bases,
+                ..
+            } => {
+                let final_total_bases: u64 =
+                    *total_bases + unobserved_contig_lengths.iter().sum::<u64>();
+                if final_total_bases == 0
+                    || (*num_covered_bases as f32 / final_total_bases as f32)
+                        < *min_fraction_covered_bases
+                {
+                    0.0
+                } else {
+                    totals.calculate(*statistic)
+                }
+            }
         }
     }
 
@@ -867,6 +1267,14 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 num_mapped_reads: _,
                 min_fraction_covered_bases,
             } => CoverageEstimator::new_estimator_tpm(*min_fraction_covered_bases),
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                depth_thresholds,
+                min_fraction_covered_bases,
+                ..
+            } => CoverageEstimator::new_estimator_covered_fraction_at_depth(
+                depth_thresholds.clone(),
+                *min_fraction_covered_bases,
+            ),
             CoverageEstimator::VarianceGenomeCoverageEstimator {
                 observed_contig_length: _,
                 counts: _,
@@ -878,6 +1286,19 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 *min_fraction_covered_bases,
                 *contig_end_exclusion,
             ),
+            CoverageEstimator::PercentileGenomeCoverageEstimator {
+                counts: _,
+                observed_contig_length: _,
+                num_covered_bases: _,
+                num_mapped_reads: _,
+                percentile,
+                min_fraction_covered_bases,
+                contig_end_exclusion,
+            } => CoverageEstimator::new_estimator_percentile(
+                *percentile,
+                *min_fraction_covered_bases,
+                *contig_end_exclusion,
+            ),
             CoverageEstimator::ReferenceLengthCalculator { .. } => {
                 CoverageEstimator::new_estimator_length()
             }
@@ -887,6 +1308,18 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             CoverageEstimator::ReadsPerBaseCalculator { .. } => {
                 CoverageEstimator::new_estimator_reads_per_base()
             }
+            CoverageEstimator::MicrodiversityEstimator {
+                statistic,
+                min_coverage,
+                min_allele_frequency,
+                min_fraction_covered_bases,
+                ..
+            } => CoverageEstimator::new_estimator_microdiversity(
+                *statistic,
+                *min_coverage,
+                *min_allele_frequency,
+                *min_fraction_covered_bases,
+            ),
         }
     }
 
@@ -899,11 +1332,21 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             | CoverageEstimator::RPKMCoverageEstimator { .. }
             | CoverageEstimator::TPMCoverageEstimator { .. }
             | CoverageEstimator::VarianceGenomeCoverageEstimator { .. }
+            | CoverageEstimator::PercentileGenomeCoverageEstimator { .. }
             | CoverageEstimator::ReferenceLengthCalculator { .. }
             | CoverageEstimator::ReadCountCalculator { .. }
-            | CoverageEstimator::ReadsPerBaseCalculator { .. } => {
+            | CoverageEstimator::ReadsPerBaseCalculator { .. }
+            | CoverageEstimator::MicrodiversityEstimator { .. } => {
                 coverage_taker.add_single_coverage(*coverage);
             }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                fractions_at_depth,
+                ..
+            } => {
+                for fraction in fractions_at_depth.iter() {
+                    coverage_taker.add_single_coverage(*fraction);
+                }
+            }
             CoverageEstimator::PileupCountsGenomeCoverageEstimator { counts, .. } => {
                 debug!("{:?}", counts);
                 for (i, num_covered) in counts.iter().enumerate() {
@@ -932,10 +1375,20 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             | CoverageEstimator::RPKMCoverageEstimator { .. }
             | CoverageEstimator::TPMCoverageEstimator { .. }
             | CoverageEstimator::VarianceGenomeCoverageEstimator { .. }
+            | CoverageEstimator::PercentileGenomeCoverageEstimator { .. }
             | CoverageEstimator::ReadCountCalculator { .. }
-            | CoverageEstimator::ReadsPerBaseCalculator { .. } => {
+            | CoverageEstimator::ReadsPerBaseCalculator { .. }
+            | CoverageEstimator::MicrodiversityEstimator { .. } => {
                 coverage_taker.add_single_coverage(0.0);
             }
+            CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                depth_thresholds,
+                ..
+            } => {
+                for _ in depth_thresholds {
+                    coverage_taker.add_single_coverage(0.0);
+                }
+            }
             CoverageEstimator::PileupCountsGenomeCoverageEstimator { .. } => {}
             CoverageEstimator::ReferenceLengthCalculator { .. } => {
                 coverage_taker.add_single_coverage(entry_length as f32);
@@ -943,7 +1396,7 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
         }
     }
 
-    fn num_mapped_reads(&self) -> u64 {
+    fn num_mapped_reads(&self) -> f64 {
         match self {
             CoverageEstimator::MeanGenomeCoverageEstimator {
                 total_count: _,
@@ -972,6 +1425,12 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 num_mapped_reads,
                 ..
             }
+            | CoverageEstimator::CoverageFractionAtDepthGenomeCoverageEstimator {
+                total_bases: _,
+                num_covered_bases: _,
+                num_mapped_reads,
+                ..
+            }
             | CoverageEstimator::NumCoveredBasesCoverageEstimator {
                 total_bases: _,
                 num_covered_bases: _,
@@ -997,6 +1456,13 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
                 num_mapped_reads,
                 ..
             }
+            | CoverageEstimator::PercentileGenomeCoverageEstimator {
+                counts: _,
+                observed_contig_length: _,
+                num_covered_bases: _,
+                num_mapped_reads,
+                ..
+            }
             | CoverageEstimator::ReferenceLengthCalculator {
                 observed_contig_length: _,
                 num_mapped_reads,
@@ -1005,7 +1471,55 @@ impl MosdepthGenomeCoverageEstimator for CoverageEstimator {
             | CoverageEstimator::ReadsPerBaseCalculator {
                 observed_contig_length: _,
                 num_mapped_reads,
+            }
+            | CoverageEstimator::MicrodiversityEstimator {
+                num_mapped_reads, ..
             } => *num_mapped_reads,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate_with_fractional_reads(mut estimator: CoverageEstimator) -> f32 {
+        estimator.setup();
+        // Weighted reads shared with other references
+        estimator.add_contig(&[1, 0, 0, -1], 0.5, 0);
+        estimator.add_contig(&[1, -1, 0, 0], 0.25, 0);
+        assert_eq!(0.75, estimator.num_mapped_reads());
+        estimator.calculate_coverage(&[])
+    }
+
+    #[test]
+    fn test_fractional_read_count() {
+        assert_eq!(
+            0.75,
+            estimate_with_fractional_reads(CoverageEstimator::new_estimator_read_count())
+        );
+    }
+
+    #[test]
+    fn test_fractional_reads_per_base() {
+        assert_eq!(
+            0.09375,
+            estimate_with_fractional_reads(CoverageEstimator::new_estimator_reads_per_base())
+        );
+    }
+
+    #[test]
+    fn test_fractional_rpkm() {
+        // Divided by the number of mapped reads in the sample when printed
+        assert_eq!(
+            93750000.0,
+            estimate_with_fractional_reads(CoverageEstimator::new_estimator_rpkm(0.0))
+        );
+    }
+
+    #[test]
+    fn test_fractional_tpm() {
+        let tpm = estimate_with_fractional_reads(CoverageEstimator::new_estimator_tpm(0.0));
+        assert!((tpm - 0.09375).abs() < 1e-6);
+    }
+}
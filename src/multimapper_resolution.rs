@@ -0,0 +1,434 @@
+use std::collections::{HashMap, HashSet};
+use std::process;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+
+use bam_generator::*;
+use coverage_takers::*;
+use mosdepth_genome_coverage_estimators::*;
+use FlagFilter;
+use ReadsMapped;
+
+/// Maximum number of iterations of the expectation-maximisation algorithm.
+const MAX_EM_ITERATIONS: usize = 10000;
+/// The EM algorithm stops once no genome's abundance changes by more than
+/// this between iterations.
+const EM_CONVERGENCE_THRESHOLD: f64 = 1e-12;
+
+/// The genomes a read aligns to, and the number of alignments to each, in
+/// order of genome index.
+type AlignmentClass = Vec<(usize, u32)>;
+
+/// Check that each of the coverage estimators can be calculated from
+/// weighted alignments, exiting with an error if not.
+pub fn check_estimators_supported(coverage_estimators: &[CoverageEstimator]) {
+    for estimator in coverage_estimators {
+        match estimator {
+            CoverageEstimator::MeanGenomeCoverageEstimator { .. }
+            | CoverageEstimator::CoverageFractionGenomeCoverageEstimator { .. }
+            | CoverageEstimator::RPKMCoverageEstimator { .. }
+            | CoverageEstimator::TPMCoverageEstimator { .. }
+            | CoverageEstimator::ReferenceLengthCalculator { .. }
+            | CoverageEstimator::ReadCountCalculator { .. }
+            | CoverageEstimator::ReadsPerBaseCalculator { .. } => {}
+            _ => {
+                error!(
+                    "The '{}' method cannot be used with EM multimapper resolution. \
+                    Only the relative_abundance, mean, covered_fraction, count, \
+                    rpkm, tpm, length and reads_per_base methods are supported.",
+                    estimator.column_headers().join(", ")
+                );
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Calculate the coverage of each genome, distributing reads that align to
+/// more than one genome according to genome abundances estimated by
+/// expectation-maximisation. Each set of readers must read the same BAM
+/// file: the first is used to find reads with secondary alignments, the
+/// second to collect the genomes those reads align to, and the third to
+/// calculate coverage. Only reads with secondary alignments are held in
+/// memory. Secondary alignments are always used, regardless of flag_filters.
+#[allow(clippy::too_many_arguments)]
+pub fn em_genome_coverage<
+    R: NamedBamReader,
+    G: NamedBamReaderGenerator<R>,
+    T: CoverageTaker,
+    F: Fn(&str) -> Option<String>,
+>(
+    bam_reader_sets: Vec<(G, G, G)>,
+    genome_of_contig: F,
+    coverage_taker: &mut T,
+    coverage_estimators: &[CoverageEstimator],
+    print_zero_coverage_genomes: bool,
+    flag_filters: &FlagFilter,
+    threads: u16,
+) -> Vec<ReadsMapped> {
+    check_estimators_supported(coverage_estimators);
+    let flag_filters = FlagFilter {
+        include_secondary: true,
+        ..flag_filters.clone()
+    };
+    // Genome indices are kept consistent across samples
+    let mut genome_names: Vec<String> = vec![];
+    let mut genome_name_to_index: HashMap<String, usize> = HashMap::new();
+
+    let mut reads_mapped_vector = vec![];
+    for (secondary_generator, assignment_generator, coverage_generator) in bam_reader_sets {
+        // First pass: find the reads with secondary alignments
+        let mut bam_generated = secondary_generator.start();
+        bam_generated.set_threads(threads as usize);
+        let stoit_name = bam_generated.name().to_string();
+        debug!(
+            "Finding reads with secondary alignments in stoit {}",
+            stoit_name
+        );
+        let header = bam_generated.header().clone();
+        let target_names = header.target_names();
+
+        let mut tid_to_genome: Vec<Option<usize>> = vec![];
+        for name in target_names.iter() {
+            let contig = std::str::from_utf8(name).expect("UTF8 encoding error in BAM header file");
+            tid_to_genome.push(genome_of_contig(contig).map(|genome| {
+                *genome_name_to_index
+                    .entry(genome.clone())
+                    .or_insert_with(|| {
+                        genome_names.push(genome);
+                        genome_names.len() - 1
+                    })
+            }));
+        }
+        let mut genome_lengths: Vec<u64> = vec![0; genome_names.len()];
+        for (tid, genome) in tid_to_genome.iter().enumerate() {
+            if let Some(g) = genome {
+                genome_lengths[*g] += header.target_len(tid as u32).unwrap();
+            }
+        }
+
+        let mut record = bam::record::Record::new();
+        let read_next = |bam_generated: &mut R, record: &mut bam::Record| -> bool {
+            loop {
+                match bam_generated.read(record) {
+                    None => return false,
+                    Some(Ok(())) => {}
+                    Some(e) => {
+                        panic!("Error reading BAM record: {:?}", e)
+                    }
+                }
+                if flag_filters.passes(record) && !record.is_unmapped() {
+                    return true;
+                }
+            }
+        };
+        let mut multimapped_reads: HashSet<Vec<u8>> = HashSet::new();
+        while read_next(&mut bam_generated, &mut record) {
+            if record.is_secondary() && tid_to_genome[record.tid() as usize].is_some() {
+                multimapped_reads.insert(read_key(&record));
+            }
+        }
+        bam_generated.finish();
+
+        // Second pass: reads without secondary alignments are assigned wholly
+        // to their genome, and other reads are grouped by the genomes they
+        // align to.
+        let mut bam_generated = assignment_generator.start();
+        bam_generated.set_threads(threads as usize);
+        let mut unique_counts: Vec<f64> = vec![0.0; genome_names.len()];
+        let mut read_alignments: HashMap<Vec<u8>, AlignmentClass> = HashMap::new();
+        while read_next(&mut bam_generated, &mut record) {
+            if record.is_supplementary() {
+                continue;
+            }
+            if let Some(genome) = tid_to_genome[record.tid() as usize] {
+                let key = read_key(&record);
+                if !multimapped_reads.contains(&key) {
+                    unique_counts[genome] += 1.0;
+                    continue;
+                }
+                let alignments = read_alignments.entry(key).or_default();
+                match alignments.iter_mut().find(|(g, _)| *g == genome) {
+                    Some(alignment) => alignment.1 += 1,
+                    None => alignments.push((genome, 1)),
+                }
+            }
+        }
+        bam_generated.finish();
+        drop(multimapped_reads);
+
+        let mut classes: Vec<(AlignmentClass, u64)> = vec![];
+        let mut class_to_index: HashMap<AlignmentClass, usize> = HashMap::new();
+        let mut read_to_class: HashMap<Vec<u8>, usize> = HashMap::new();
+        for (read, mut alignments) in read_alignments {
+            if alignments.len() == 1 && alignments[0].1 == 1 {
+                unique_counts[alignments[0].0] += 1.0;
+                continue;
+            }
+            alignments.sort_unstable();
+            let index = *class_to_index.entry(alignments.clone()).or_insert_with(|| {
+                classes.push((alignments, 0));
+                classes.len() - 1
+            });
+            classes[index].1 += 1;
+            read_to_class.insert(read, index);
+        }
+        info!(
+            "In sample '{}', found {} reads aligning to more than one place, in {} distinct sets of genomes",
+            stoit_name,
+            read_to_class.len(),
+            classes.len()
+        );
+        let class_weights = expectation_maximisation(&unique_counts, &classes, &genome_lengths);
+
+        // Third pass: calculate coverage with weighted alignments
+        let mut bam_generated = coverage_generator.start();
+        bam_generated.set_threads(threads as usize);
+        coverage_taker.start_stoit(&stoit_name);
+        let mut genome_estimators: Vec<Vec<CoverageEstimator>> = vec![];
+        for _ in 0..genome_names.len() {
+            let mut estimators = coverage_estimators.to_vec();
+            for estimator in estimators.iter_mut() {
+                estimator.setup();
+            }
+            genome_estimators.push(estimators);
+        }
+        let mut tid_observed: Vec<bool> = vec![false; target_names.len()];
+        let mut genome_num_reads: Vec<f64> = vec![0.0; genome_names.len()];
+        let mut last_tid: i32 = -1;
+        let mut ups_and_downs: Vec<f64> = vec![];
+        let mut num_reads_in_contig: f64 = 0.0;
+        let mut add_previous_contig =
+            |last_tid: i32, ups_and_downs: &[f64], num_reads_in_contig: f64| {
+                if last_tid != -1 {
+                    if let Some(g) = tid_to_genome[last_tid as usize] {
+                        for estimator in genome_estimators[g].iter_mut() {
+                            estimator.add_weighted_contig(ups_and_downs, num_reads_in_contig);
+                        }
+                        genome_num_reads[g] += num_reads_in_contig;
+                    }
+                }
+            };
+        while read_next(&mut bam_generated, &mut record) {
+            let tid = record.tid();
+            let genome = match tid_to_genome[tid as usize] {
+                Some(g) => g,
+                None => continue,
+            };
+            if tid != last_tid {
+                if tid < last_tid {
+                    error!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                    panic!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                }
+                add_previous_contig(last_tid, &ups_and_downs, num_reads_in_contig);
+                ups_and_downs =
+                    vec![0.0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                num_reads_in_contig = 0.0;
+                tid_observed[tid as usize] = true;
+                last_tid = tid;
+            }
+
+            let weight = match read_to_class.get(&read_key(&record)) {
+                Some(class) => {
+                    let (ref alignments, _) = classes[*class];
+                    let i = alignments.iter().position(|(g, _)| *g == genome).unwrap();
+                    class_weights[*class][i] / alignments[i].1 as f64
+                }
+                None => 1.0,
+            };
+            if !record.is_supplementary() {
+                num_reads_in_contig += weight;
+            }
+            let mut cursor: usize = record.pos() as usize;
+            for cig in record.cigar().iter() {
+                match cig {
+                    Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                        ups_and_downs[cursor] += weight;
+                        let final_pos = cursor + cig.len() as usize;
+                        if final_pos < ups_and_downs.len() {
+                            // True unless the read hits the contig end.
+                            ups_and_downs[final_pos] -= weight;
+                        }
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Del(_) | Cigar::RefSkip(_) => {
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
+                }
+            }
+        }
+        add_previous_contig(last_tid, &ups_and_downs, num_reads_in_contig);
+
+        let mut num_mapped_reads_total: f64 = 0.0;
+        for (i, estimators) in genome_estimators.iter_mut().enumerate() {
+            if genome_lengths[i] == 0 {
+                // Genome is not a part of this sample's reference
+                continue;
+            }
+            let unobserved_contig_lengths: Vec<u64> = tid_to_genome
+                .iter()
+                .enumerate()
+                .filter(|(tid, genome)| **genome == Some(i) && !tid_observed[*tid])
+                .map(|(tid, _)| header.target_len(tid as u32).unwrap())
+                .collect();
+            let coverages: Vec<f32> = estimators
+                .iter_mut()
+                .map(|estimator| estimator.calculate_coverage(&unobserved_contig_lengths))
+                .collect();
+            let any_nonzero_coverage = coverages.iter().any(|c| *c > 0.0);
+            if any_nonzero_coverage {
+                num_mapped_reads_total += genome_num_reads[i];
+            }
+            if print_zero_coverage_genomes || any_nonzero_coverage {
+                coverage_taker.start_entry(i, &genome_names[i]);
+                for (coverage, estimator) in coverages.iter().zip(estimators.iter()) {
+                    estimator.print_coverage(coverage, coverage_taker);
+                }
+                coverage_taker.finish_entry();
+            }
+        }
+
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: num_mapped_reads_total,
+            num_reads: bam_generated.num_detected_primary_alignments(),
+        };
+        info!(
+            "In sample '{}', found {} reads mapped out of {} total ({:.*}%)",
+            stoit_name,
+            reads_mapped.num_mapped_reads,
+            reads_mapped.num_reads,
+            2,
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
+        );
+        reads_mapped_vector.push(reads_mapped);
+
+        bam_generated.finish();
+    }
+    reads_mapped_vector
+}
+
+/// Identifies a read, distinguishing the two reads of a pair.
+fn read_key(record: &bam::Record) -> Vec<u8> {
+    let mut key = record.qname().to_vec();
+    key.push(record.is_last_in_template() as u8);
+    key
+}
+
+/// Estimate the fraction of reads derived from each genome, and return the
+/// weight of an alignment of each class to each of its genomes. The
+/// likelihood of a read deriving from a genome is proportional to the
+/// abundance of the genome divided by its length.
+fn expectation_maximisation(
+    unique_counts: &[f64],
+    classes: &[(AlignmentClass, u64)],
+    genome_lengths: &[u64],
+) -> Vec<Vec<f64>> {
+    let total_reads: f64 =
+        unique_counts.iter().sum::<f64>() + classes.iter().map(|(_, n)| *n as f64).sum::<f64>();
+    let num_genomes = unique_counts.len();
+    let mut abundances: Vec<f64> = vec![1.0 / num_genomes as f64; num_genomes];
+
+    let class_weights = |abundances: &[f64]| -> Vec<Vec<f64>> {
+        classes
+            .iter()
+            .map(|(alignments, _)| {
+                let likelihoods: Vec<f64> = alignments
+                    .iter()
+                    .map(|(g, n)| *n as f64 * abundances[*g] / genome_lengths[*g] as f64)
+                    .collect();
+                let total: f64 = likelihoods.iter().sum();
+                match total > 0.0 {
+                    true => likelihoods.iter().map(|l| l / total).collect(),
+                    false => vec![1.0 / alignments.len() as f64; alignments.len()],
+                }
+            })
+            .collect()
+    };
+
+    for iteration in 0..MAX_EM_ITERATIONS {
+        let weights = class_weights(&abundances);
+        let mut counts = unique_counts.to_vec();
+        for ((alignments, num_reads), class_weights) in classes.iter().zip(weights.iter()) {
+            for ((g, _), weight) in alignments.iter().zip(class_weights.iter()) {
+                counts[*g] += *num_reads as f64 * weight;
+            }
+        }
+        let new_abundances: Vec<f64> = counts.iter().map(|c| c / total_reads).collect();
+        let max_change = abundances
+            .iter()
+            .zip(new_abundances.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        abundances = new_abundances;
+        if max_change < EM_CONVERGENCE_THRESHOLD {
+            debug!("EM converged after {} iterations", iteration + 1);
+            break;
+        }
+    }
+    debug!("EM estimated genome abundances {:?}", abundances);
+
+    // Return the weight of a single alignment to each genome of each class
+    class_weights(&abundances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OutputWriter;
+
+    #[test]
+    fn test_expectation_maximisation() {
+        // 6 reads unique to genome 0, 2 unique to genome 1 and 4 shared, so
+        // that at convergence genome 0 has 9 reads and genome 1 has 3.
+        let weights =
+            expectation_maximisation(&[6.0, 2.0], &[(vec![(0, 1), (1, 1)], 4)], &[100, 100]);
+        assert!((weights[0][0] - 0.75).abs() < 1e-9);
+        assert!((weights[0][1] - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_em_genome_coverage() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let path = "tests/data/em_multimappers.sam";
+        let reads_mapped;
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    OutputWriter::generate(Some(tf.path().to_str().unwrap())),
+                );
+            reads_mapped = em_genome_coverage(
+                generate_named_bam_readers_from_bam_files(vec![path])
+                    .into_iter()
+                    .zip(generate_named_bam_readers_from_bam_files(vec![path]))
+                    .zip(generate_named_bam_readers_from_bam_files(vec![path]))
+                    .map(|((a, b), c)| (a, b, c))
+                    .collect(),
+                |contig| contig.find('~').map(|i| contig[..i].to_string()),
+                &mut coverage_taker,
+                &[
+                    CoverageEstimator::new_estimator_mean(0.0, 0, false),
+                    CoverageEstimator::new_estimator_read_count(),
+                ],
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+            );
+        }
+        assert_eq!(
+            "em_multimappers\tg1\t0.9\t9\n\
+            em_multimappers\tg2\t0.3\t3\n\
+            em_multimappers\tg3\t0\t0\n",
+            std::fs::read_to_string(tf.path()).unwrap()
+        );
+        // Shared reads are counted fractionally, but still sum to 12.
+        assert_eq!(1, reads_mapped.len());
+        assert!((reads_mapped[0].num_mapped_reads - 12.0).abs() < 1e-6);
+        assert_eq!(12, reads_mapped[0].num_reads);
+    }
+}
@@ -0,0 +1,153 @@
+use std;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+use OutputFile;
+
+/// Writes the read depth at each position of each sample to a separate
+/// bedGraph file in an output directory. Adjacent positions with the same
+/// depth are merged into a single interval.
+pub struct PerBaseDepthWriter {
+    output_directory: PathBuf,
+    compression_extension: String,
+    current_writer: Option<BufWriter<OutputFile>>,
+}
+
+impl PerBaseDepthWriter {
+    /// Files are compressed if compression_extension is '.gz' or '.zst'.
+    pub fn new(output_directory: &str, compression_extension: &str) -> PerBaseDepthWriter {
+        std::fs::create_dir_all(output_directory).unwrap_or_else(|e| {
+            error!(
+                "Failed to create per-base depth output directory {}: {}",
+                output_directory, e
+            );
+            process::exit(1);
+        });
+        PerBaseDepthWriter {
+            output_directory: PathBuf::from(output_directory),
+            compression_extension: compression_extension.to_string(),
+            current_writer: None,
+        }
+    }
+
+    /// Start writing a new bedGraph file for the given sample. Any '/'
+    /// characters in the sample name are replaced with '_' to create the file
+    /// name.
+    pub fn start_stoit(&mut self, stoit_name: &str) {
+        self.finish_stoit();
+        let path = self.output_directory.join(format!(
+            "{}.bedgraph{}",
+            stoit_name.replace('/', "_"),
+            self.compression_extension
+        ));
+        info!("Writing per-base depth to {}", path.display());
+        let file = OutputFile::create(&path).unwrap_or_else(|e| {
+            error!(
+                "Failed to create per-base depth file {}: {}",
+                path.display(),
+                e
+            );
+            process::exit(1);
+        });
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "track type=bedGraph name=\"{}\"", stoit_name).unwrap();
+        self.current_writer = Some(writer);
+    }
+
+    /// Write the depth intervals of a contig, given the changes in depth at
+    /// each position as accumulated by the coverage calculation.
+    pub fn add_contig(&mut self, contig_name: &[u8], ups_and_downs: &[i32]) {
+        let writer = match self.current_writer {
+            Some(ref mut w) => w,
+            None => unreachable!(),
+        };
+        let contig_name = std::str::from_utf8(contig_name).unwrap();
+        let mut depth: i32 = 0;
+        let mut run_start: usize = 0;
+        for (i, current) in ups_and_downs.iter().enumerate() {
+            if *current != 0 {
+                if i > run_start {
+                    writeln!(writer, "{}\t{}\t{}\t{}", contig_name, run_start, i, depth).unwrap();
+                }
+                depth += current;
+                run_start = i;
+            }
+        }
+        if ups_and_downs.len() > run_start {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                contig_name,
+                run_start,
+                ups_and_downs.len(),
+                depth
+            )
+            .unwrap();
+        }
+    }
+
+    pub fn finish_stoit(&mut self) {
+        if let Some(writer) = self.current_writer.take() {
+            let result = writer
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|mut file| file.finish());
+            if let Err(e) = result {
+                error!("Failed to finish writing per-base depth file: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_length_encoding() {
+        let td = tempfile::TempDir::new().unwrap();
+        let dir = td.path().join("depths");
+        let mut writer = PerBaseDepthWriter::new(dir.to_str().unwrap(), "");
+        writer.start_stoit("sample/1");
+        // depths 0 0 1 1 2 1 0 0
+        writer.add_contig(b"contig1", &[0, 0, 1, 0, 1, -1, -1, 0]);
+        // depths 1 1 1
+        writer.add_contig(b"contig2", &[1, 0, 0]);
+        writer.finish_stoit();
+
+        assert_eq!(
+            "track type=bedGraph name=\"sample/1\"\n\
+            contig1\t0\t2\t0\n\
+            contig1\t2\t4\t1\n\
+            contig1\t4\t5\t2\n\
+            contig1\t5\t6\t1\n\
+            contig1\t6\t8\t0\n\
+            contig2\t0\t3\t1\n",
+            std::fs::read_to_string(dir.join("sample_1.bedgraph")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compressed_output() {
+        use std::io::Read;
+        let td = tempfile::TempDir::new().unwrap();
+        let mut writer = PerBaseDepthWriter::new(td.path().to_str().unwrap(), ".gz");
+        writer.start_stoit("sample");
+        writer.add_contig(b"contig1", &[1, 0, 0]);
+        writer.finish_stoit();
+
+        let mut decompressed = String::new();
+        flate2::read::MultiGzDecoder::new(
+            std::fs::File::open(td.path().join("sample.bedgraph.gz")).unwrap(),
+        )
+        .read_to_string(&mut decompressed)
+        .unwrap();
+        assert_eq!(
+            "track type=bedGraph name=\"sample\"\n\
+            contig1\t0\t3\t1\n",
+            decompressed
+        );
+    }
+}
@@ -0,0 +1,247 @@
+// Provenance of an output table, describing how it was produced.
+
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+use json;
+use ReadsMapped;
+
+lazy_static! {
+    // Versions of external programs as detected when checking for them.
+    static ref PROGRAM_VERSIONS: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+    // Reference FASTA files, or indices, which reads were mapped to.
+    static ref REFERENCES: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+/// Record the version of an external program, for the provenance file.
+pub fn record_program_version(program: &str, version: &str) {
+    let mut versions = PROGRAM_VERSIONS.lock().unwrap();
+    if !versions.iter().any(|(p, _)| p == program) {
+        versions.push((program.to_string(), version.to_string()));
+    }
+}
+
+/// Record a reference that reads are mapped to, for the provenance file.
+pub fn record_reference(path: &str) {
+    let mut references = REFERENCES.lock().unwrap();
+    if !references.iter().any(|r| r == path) {
+        references.push(path.to_string());
+    }
+}
+
+/// Path of the provenance file of an output file or directory.
+pub fn provenance_path(output_file: &str) -> String {
+    format!("{}.provenance.json", output_file.trim_end_matches('/'))
+}
+
+/// Output files and directories of a subcommand, other than STDOUT.
+fn output_paths(m: &clap::ArgMatches) -> Vec<String> {
+    let mut paths = vec![];
+    for id in [
+        "output-file",
+        "window-bedgraph",
+        "per-base-depth-output",
+        "output-directory",
+        "output-bam-files",
+    ] {
+        if let Ok(Some(values)) = m.try_get_many::<String>(id) {
+            paths.extend(values.filter(|v| *v != "-").cloned());
+        }
+    }
+    paths
+}
+
+/// Write the provenance of each output file or directory, alongside it.
+/// Read counts are given for each sample when they are known.
+pub fn write_provenance(
+    m: &clap::ArgMatches,
+    sample_names: &[String],
+    reads_mapped: Option<&[ReadsMapped]>,
+) {
+    let output_paths = output_paths(m);
+    if output_paths.is_empty() {
+        return;
+    }
+
+    let command_line: Vec<String> = std::env::args().map(|a| json::string(&a)).collect();
+
+    let mut parameters = vec![];
+    for id in m.ids() {
+        if let Ok(Some(values)) = m.try_get_raw(id.as_str()) {
+            let values: Vec<String> = values.map(|v| json::string(&v.to_string_lossy())).collect();
+            parameters.push((id.as_str().to_string(), json::array(&values)));
+        }
+    }
+
+    let program_versions: Vec<(String, String)> = PROGRAM_VERSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(p, v)| (p.clone(), json::string(v)))
+        .collect();
+
+    let references: Vec<String> = REFERENCES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|r| reference_json(r))
+        .collect();
+
+    let samples: Vec<String> = sample_names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let counts = reads_mapped.and_then(|r| r.get(i));
+            json::object(&[
+                ("sample", json::string(name)),
+                (
+                    "mapped_reads",
+                    counts
+                        .map(|r| json::number(r.num_mapped_reads))
+                        .unwrap_or_else(|| "null".to_string()),
+                ),
+                (
+                    "total_reads",
+                    counts
+                        .map(|r| r.num_reads.to_string())
+                        .unwrap_or_else(|| "null".to_string()),
+                ),
+            ])
+        })
+        .collect();
+
+    let provenance = json::object(&[
+        ("coverm_version", json::string(env!("CARGO_PKG_VERSION"))),
+        ("created", json::string(&json::utc_timestamp_now())),
+        ("command_line", json::array(&command_line)),
+        ("parameters", json::object(&parameters)),
+        ("external_programs", json::object(&program_versions)),
+        ("references", json::array(&references)),
+        ("samples", json::array(&samples)),
+    ]);
+
+    for output_path in output_paths {
+        let path = provenance_path(&output_path);
+        debug!("Writing provenance to {}", path);
+        let mut file = std::fs::File::create(&path).unwrap_or_else(|e| {
+            error!("Failed to create provenance file {}: {}", path, e);
+            process::exit(1);
+        });
+        writeln!(file, "{}", provenance).unwrap_or_else(|e| {
+            error!("Failed to write provenance file {}: {}", path, e);
+            process::exit(1);
+        });
+    }
+}
+
+/// Description of a reference. References may be given as the prefix of a
+/// mapping index rather than a FASTA file, in which case the checksums of
+/// the index files are given instead.
+fn reference_json(path: &str) -> String {
+    if Path::new(path).is_file() {
+        return json::object(&[("path", json::string(path)), ("sha256", sha256_json(path))]);
+    }
+    let prefix = Path::new(path);
+    let index_prefix = format!(
+        "{}.",
+        prefix
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default()
+    );
+    let directory = match prefix.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let mut index_files: Vec<String> = match std::fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.is_file()
+                    && p.file_name()
+                        .map_or(false, |f| f.to_string_lossy().starts_with(&index_prefix))
+            })
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    index_files.sort();
+    let index_files: Vec<String> = index_files
+        .iter()
+        .map(|f| json::object(&[("path", json::string(f)), ("sha256", sha256_json(f))]))
+        .collect();
+    json::object(&[
+        ("path", json::string(path)),
+        ("sha256", "null".to_string()),
+        ("index_files", json::array(&index_files)),
+    ])
+}
+
+fn sha256_json(path: &str) -> String {
+    match sha256_of_file(path) {
+        Ok(sha256) => json::string(&sha256),
+        Err(e) => {
+            warn!("Failed to calculate checksum of {}: {}", path, e);
+            "null".to_string()
+        }
+    }
+}
+
+fn sha256_of_file(path: &str) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = std::fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provenance_path() {
+        assert_eq!("out.tsv.gz.provenance.json", provenance_path("out.tsv.gz"));
+    }
+
+    #[test]
+    fn test_sha256_of_file() {
+        let mut tf = tempfile::NamedTempFile::new().unwrap();
+        write!(tf, "abc").unwrap();
+        tf.flush().unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            sha256_of_file(tf.path().to_str().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reference_json_of_index_prefix() {
+        let td = tempfile::TempDir::new().unwrap();
+        let prefix = td.path().join("ref.fna");
+        std::fs::write(td.path().join("ref.fna.amb"), "abc").unwrap();
+        std::fs::write(td.path().join("other.fna.amb"), "abc").unwrap();
+        let prefix = prefix.to_str().unwrap();
+        assert_eq!(
+            format!(
+                "{{\"path\":\"{}\",\"sha256\":null,\"index_files\":[{{\"path\":\"{}.amb\",\
+                \"sha256\":\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\"}}]}}",
+                prefix, prefix
+            ),
+            reference_json(prefix)
+        );
+    }
+
+    #[test]
+    fn test_provenance_path_of_directory() {
+        assert_eq!("out_dir.provenance.json", provenance_path("out_dir/"));
+    }
+}
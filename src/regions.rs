@@ -0,0 +1,900 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+use rust_htslib::bam;
+use rust_htslib::bam::record::Cigar;
+
+use bam_generator::*;
+use coverage_takers::*;
+use mosdepth_genome_coverage_estimators::*;
+use strandedness::*;
+use FlagFilter;
+use ReadsMapped;
+
+/// An interval of a reference sequence, 0-based and half-open as in BED
+/// files. reverse_strand is true for features on the '-' strand.
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    pub contig: String,
+    pub start: u64,
+    pub end: u64,
+    pub reverse_strand: bool,
+    pub entry_index: usize,
+}
+
+/// A set of regions, each of which is assigned to an entry that is reported
+/// in the output.
+#[derive(Debug)]
+pub struct Regions {
+    pub regions: Vec<Region>,
+    pub entry_names: Vec<String>,
+}
+
+impl Regions {
+    /// Read regions from a BED file. When aggregate_by_name is true, regions
+    /// are grouped into entries by the name (4th) column, otherwise each
+    /// region is its own entry named contig:start-end.
+    pub fn from_bed_file(path: &str, aggregate_by_name: bool) -> Regions {
+        let file = File::open(path).unwrap_or_else(|e| {
+            error!("Failed to open regions file {}: {}", path, e);
+            process::exit(1);
+        });
+        let mut regions = vec![];
+        let mut entry_names = vec![];
+        let mut entry_name_to_index: HashMap<String, usize> = HashMap::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.unwrap_or_else(|e| {
+                error!("Failed to read line from regions file {}: {}", path, e);
+                process::exit(1);
+            });
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let parse_position = |s: Option<&&str>| -> u64 {
+                match s.map(|s| s.parse::<u64>()) {
+                    Some(Ok(pos)) => pos,
+                    _ => {
+                        error!(
+                            "Failed to parse line {} of regions file {}: {}",
+                            i + 1,
+                            path,
+                            line
+                        );
+                        process::exit(1);
+                    }
+                }
+            };
+            let start = parse_position(fields.get(1));
+            let end = parse_position(fields.get(2));
+            if end <= start {
+                error!(
+                    "Region on line {} of regions file {} has an end which is \
+                    not after its start: {}",
+                    i + 1,
+                    path,
+                    line
+                );
+                process::exit(1);
+            }
+            let entry_name = match aggregate_by_name {
+                true => match fields.get(3) {
+                    Some(name) => name.to_string(),
+                    None => {
+                        error!(
+                            "Region on line {} of regions file {} has no name column, \
+                            so cannot be aggregated by name: {}",
+                            i + 1,
+                            path,
+                            line
+                        );
+                        process::exit(1);
+                    }
+                },
+                false => format!("{}:{}-{}", fields[0], start, end),
+            };
+            let entry_index = *entry_name_to_index
+                .entry(entry_name.clone())
+                .or_insert_with(|| {
+                    entry_names.push(entry_name);
+                    entry_names.len() - 1
+                });
+            regions.push(Region {
+                contig: fields[0].to_string(),
+                start,
+                end,
+                reverse_strand: fields.get(5) == Some(&"-"),
+                entry_index,
+            });
+        }
+        info!(
+            "Read {} regions in {} entries from {}",
+            regions.len(),
+            entry_names.len(),
+            path
+        );
+        Regions {
+            regions: merge_overlapping_regions(regions),
+            entry_names,
+        }
+    }
+
+    /// Read features from a GFF3 or GTF annotation file. Only features whose
+    /// type (3rd column) is one of feature_types are included, and these are
+    /// grouped into entries by the value of the given attribute e.g. ID,
+    /// gene_id or KO. Features with multiple comma-separated values for the
+    /// attribute are included in each of the corresponding entries.
+    pub fn from_gff_file(path: &str, feature_types: &[&str], group_by_attribute: &str) -> Regions {
+        let file = File::open(path).unwrap_or_else(|e| {
+            error!("Failed to open annotation file {}: {}", path, e);
+            process::exit(1);
+        });
+        let mut regions = vec![];
+        let mut entry_names = vec![];
+        let mut entry_name_to_index: HashMap<String, usize> = HashMap::new();
+        let mut num_features_without_attribute: usize = 0;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.unwrap_or_else(|e| {
+                error!("Failed to read line from annotation file {}: {}", path, e);
+                process::exit(1);
+            });
+            if line.starts_with("##FASTA") {
+                // Sequences may follow the annotations in GFF3 files
+                break;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 9 {
+                error!(
+                    "Line {} of annotation file {} does not have 9 tab-separated columns: {}",
+                    i + 1,
+                    path,
+                    line
+                );
+                process::exit(1);
+            }
+            if !feature_types.contains(&fields[2]) {
+                continue;
+            }
+            let (start, end) = match (fields[3].parse::<u64>(), fields[4].parse::<u64>()) {
+                (Ok(start), Ok(end)) if start >= 1 && end >= start => (start - 1, end),
+                _ => {
+                    error!(
+                        "Failed to parse feature coordinates on line {} of annotation file {}: {}",
+                        i + 1,
+                        path,
+                        line
+                    );
+                    process::exit(1);
+                }
+            };
+            let values = match gff_attribute_values(fields[8], group_by_attribute) {
+                Some(values) => values,
+                None => {
+                    num_features_without_attribute += 1;
+                    continue;
+                }
+            };
+            for value in values {
+                let entry_index = *entry_name_to_index.entry(value.clone()).or_insert_with(|| {
+                    entry_names.push(value);
+                    entry_names.len() - 1
+                });
+                regions.push(Region {
+                    contig: fields[0].to_string(),
+                    start,
+                    end,
+                    reverse_strand: fields[6] == "-",
+                    entry_index,
+                });
+            }
+        }
+        if num_features_without_attribute > 0 {
+            warn!(
+                "Ignored {} features in {} which have no '{}' attribute",
+                num_features_without_attribute, path, group_by_attribute
+            );
+        }
+        info!(
+            "Read {} features in {} groups from {}",
+            regions.len(),
+            entry_names.len(),
+            path
+        );
+        Regions {
+            regions: merge_overlapping_regions(regions),
+            entry_names,
+        }
+    }
+
+    /// Reassign each region to an entry named by the genome its contig
+    /// belongs to. Regions on contigs not assigned to any genome are removed.
+    pub fn group_by_genome<F: Fn(&str) -> Option<String>>(self, genome_of_contig: F) -> Regions {
+        let mut regions = vec![];
+        let mut entry_names = vec![];
+        let mut entry_name_to_index: HashMap<String, usize> = HashMap::new();
+        for region in self.regions {
+            if let Some(genome) = genome_of_contig(&region.contig) {
+                let entry_index = *entry_name_to_index
+                    .entry(genome.clone())
+                    .or_insert_with(|| {
+                        entry_names.push(genome);
+                        entry_names.len() - 1
+                    });
+                regions.push(Region {
+                    entry_index,
+                    ..region
+                });
+            }
+        }
+        Regions {
+            regions: merge_overlapping_regions(regions),
+            entry_names,
+        }
+    }
+
+    /// Total length of the regions of each entry.
+    pub fn entry_lengths(&self) -> Vec<u64> {
+        let mut lengths = vec![0; self.entry_names.len()];
+        for region in self.regions.iter() {
+            lengths[region.entry_index] += region.end - region.start;
+        }
+        lengths
+    }
+}
+
+/// Merge overlapping regions of the same entry on the same strand of a
+/// contig, so that bases within several of them are only counted once. The
+/// regions are returned sorted by entry, contig and position.
+fn merge_overlapping_regions(mut regions: Vec<Region>) -> Vec<Region> {
+    regions.sort_by(|a, b| {
+        (a.entry_index, &a.contig, a.reverse_strand, a.start).cmp(&(
+            b.entry_index,
+            &b.contig,
+            b.reverse_strand,
+            b.start,
+        ))
+    });
+    let mut merged: Vec<Region> = Vec::with_capacity(regions.len());
+    for region in regions {
+        if let Some(last) = merged.last_mut() {
+            if last.entry_index == region.entry_index
+                && last.contig == region.contig
+                && last.reverse_strand == region.reverse_strand
+                && region.start < last.end
+            {
+                last.end = std::cmp::max(last.end, region.end);
+                continue;
+            }
+        }
+        merged.push(region);
+    }
+    merged
+}
+
+/// Parse the values of an attribute from the 9th column of a GFF3
+/// (key=value;...) or GTF (key "value"; ...) line. Returns None if the
+/// attribute is not present.
+fn gff_attribute_values(attributes: &str, key: &str) -> Option<Vec<String>> {
+    for attribute in attributes.split(';') {
+        let attribute = attribute.trim();
+        let (k, v) = match attribute.find('=') {
+            Some(i) => (&attribute[..i], &attribute[i + 1..]),
+            None => match attribute.find(' ') {
+                Some(i) => (&attribute[..i], attribute[i + 1..].trim().trim_matches('"')),
+                None => continue,
+            },
+        };
+        if k == key {
+            let values: Vec<String> = v
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect();
+            if !values.is_empty() {
+                return Some(values);
+            }
+        }
+    }
+    None
+}
+
+/// Depth at each position, given the changes in depth at each position.
+fn cumulative_depths(ups_and_downs: &[i32]) -> Vec<i32> {
+    let mut depths = Vec::with_capacity(ups_and_downs.len());
+    let mut cumulative_sum: i32 = 0;
+    for current in ups_and_downs {
+        cumulative_sum += current;
+        depths.push(cumulative_sum);
+    }
+    depths
+}
+
+/// The changes in depth within a region, starting from the depth at its
+/// first position.
+fn region_ups_and_downs(ups_and_downs: &[i32], depths: &[i32], region: &Region) -> Vec<i32> {
+    let start = region.start as usize;
+    let mut region_ups_and_downs = ups_and_downs[start..region.end as usize].to_vec();
+    if start > 0 {
+        region_ups_and_downs[0] += depths[start - 1];
+    }
+    region_ups_and_downs
+}
+
+/// The intervals of each reference sequence covered by at least one region,
+/// as (tid, start, end), sorted and without overlaps.
+fn fetch_intervals(tid_to_regions: &[Vec<usize>], regions: &Regions) -> Vec<(u32, u64, u64)> {
+    let mut intervals: Vec<(u32, u64, u64)> = vec![];
+    for (tid, region_indices) in tid_to_regions.iter().enumerate() {
+        let mut tid_regions: Vec<&Region> = region_indices
+            .iter()
+            .map(|i| &regions.regions[*i])
+            .collect();
+        tid_regions.sort_by_key(|r| r.start);
+        let first_index = intervals.len();
+        for region in tid_regions {
+            if intervals.len() > first_index {
+                let last = intervals.last_mut().unwrap();
+                if region.start <= last.2 {
+                    last.2 = std::cmp::max(last.2, region.end);
+                    continue;
+                }
+            }
+            intervals.push((tid as u32, region.start, region.end));
+        }
+    }
+    intervals
+}
+
+/// Calculate coverage of each entry, considering only the bases within its
+/// regions, and only the reads overlapping those regions. If a BAM file is
+/// indexed, only the reads overlapping the regions are read from it. For stranded
+/// libraries, sense and antisense are relative to the strand of each region.
+#[allow(clippy::too_many_arguments)]
+pub fn region_coverage<R: NamedBamReader, G: NamedBamReaderGenerator<R>, T: CoverageTaker>(
+    bam_readers: Vec<G>,
+    regions: &Regions,
+    coverage_taker: &mut T,
+    coverage_estimators: &[CoverageEstimator],
+    print_zero_coverage_entries: bool,
+    flag_filters: &FlagFilter,
+    threads: u16,
+    strandedness: Strandedness,
+) -> Vec<ReadsMapped> {
+    let mut reads_mapped_vector = vec![];
+    let entry_lengths = regions.entry_lengths();
+    for bam_generator in bam_readers {
+        let mut bam_generated = bam_generator.start();
+        bam_generated.set_threads(threads as usize);
+
+        let stoit_name = &(bam_generated.name().to_string());
+        debug!("Working on stoit {}", stoit_name);
+        coverage_taker.start_stoit(stoit_name);
+        let header = bam_generated.header().clone();
+        let target_names = header.target_names();
+
+        // Collect the regions of each reference sequence
+        let mut tid_to_regions: Vec<Vec<usize>> = vec![vec![]; target_names.len()];
+        let contig_name_to_tid: HashMap<&[u8], usize> = target_names
+            .iter()
+            .enumerate()
+            .map(|(tid, name)| (*name, tid))
+            .collect();
+        for (i, region) in regions.regions.iter().enumerate() {
+            match contig_name_to_tid.get(region.contig.as_bytes()) {
+                Some(tid) => {
+                    if region.end > header.target_len(*tid as u32).unwrap() {
+                        error!(
+                            "Region {}:{}-{} extends past the end of the reference sequence",
+                            region.contig, region.start, region.end
+                        );
+                        process::exit(1);
+                    }
+                    tid_to_regions[*tid].push(i)
+                }
+                None => {
+                    warn!(
+                        "Reference sequence '{}' of region {}:{}-{} was not found in the BAM file, \
+                        so this region is ignored",
+                        region.contig, region.start, region.end
+                    );
+                }
+            }
+        }
+
+        let mut per_entry_coverage_estimators = vec![];
+        for _ in regions.entry_names.iter() {
+            let mut estimators = coverage_estimators.to_vec();
+            for estimator in estimators.iter_mut() {
+                estimator.setup();
+            }
+            per_entry_coverage_estimators.push(estimators);
+        }
+        let mut reads_mapped_in_each_entry: Vec<u64> = vec![0; regions.entry_names.len()];
+        let mut reads_mapped_in_each_region: Vec<u64> = vec![0; regions.regions.len()];
+        // Reads transcribed from the reverse strand of the reference
+        let mut antisense_reads_mapped_in_each_region: Vec<u64> = vec![0; regions.regions.len()];
+        let mut last_read_counted_in_each_entry: Vec<u64> = vec![0; regions.entry_names.len()];
+        let mut num_primary_reads: u64 = 0;
+
+        let mut add_contig_regions =
+            |tid: usize,
+             ups_and_downs: &[i32],
+             antisense_ups_and_downs: &[i32],
+             reads_mapped_in_each_region: &[u64],
+             antisense_reads_mapped_in_each_region: &[u64]| {
+                let depths = cumulative_depths(ups_and_downs);
+                let antisense_depths = cumulative_depths(antisense_ups_and_downs);
+                for region_index in tid_to_regions[tid].iter() {
+                    let region = &regions.regions[*region_index];
+                    let forward = region_ups_and_downs(ups_and_downs, &depths, region);
+                    let reverse = match strandedness.is_stranded() {
+                        true => {
+                            region_ups_and_downs(antisense_ups_and_downs, &antisense_depths, region)
+                        }
+                        false => vec![],
+                    };
+                    let num_reads = reads_mapped_in_each_region[*region_index];
+                    let num_reverse_reads = antisense_reads_mapped_in_each_region[*region_index];
+                    let (sense, antisense, num_antisense_reads) =
+                        match strandedness.is_stranded() && region.reverse_strand {
+                            true => (reverse, forward, num_reads - num_reverse_reads),
+                            false => (forward, reverse, num_reverse_reads),
+                        };
+                    add_contig_to_estimators(
+                        &mut per_entry_coverage_estimators[region.entry_index],
+                        strandedness,
+                        &sense,
+                        &antisense,
+                        num_reads,
+                        num_antisense_reads,
+                        0,
+                        0,
+                    );
+                }
+            };
+
+        // Fetch each interval containing regions if the BAM file is indexed,
+        // otherwise read the whole file.
+        let intervals = fetch_intervals(&tid_to_regions, regions);
+        let indexed = match intervals.first() {
+            Some((tid, start, end)) => bam_generated.fetch(*tid, *start, *end),
+            None => false,
+        };
+        if indexed {
+            debug!(
+                "Fetching reads from {} intervals using the BAM index",
+                intervals.len()
+            );
+        }
+        let mut fetch_index: usize = 0;
+        let mut next_record = |record: &mut bam::record::Record| -> bool {
+            loop {
+                match bam_generated.read(record) {
+                    Some(Ok(())) => {
+                        // Reads overlapping the previous interval were already
+                        // read when it was fetched.
+                        if indexed && fetch_index > 0 {
+                            let (previous_tid, _, previous_end) = intervals[fetch_index - 1];
+                            if record.tid() as u32 == previous_tid
+                                && (record.pos() as u64) < previous_end
+                            {
+                                continue;
+                            }
+                        }
+                        return true;
+                    }
+                    None => {
+                        if !indexed || fetch_index + 1 >= intervals.len() {
+                            return false;
+                        }
+                        fetch_index += 1;
+                        let (tid, start, end) = intervals[fetch_index];
+                        bam_generated.fetch(tid, start, end);
+                    }
+                    Some(e) => {
+                        panic!("Error reading BAM record: {:?}", e)
+                    }
+                }
+            }
+        };
+
+        let mut record: bam::record::Record = bam::record::Record::new();
+        let mut last_tid: i32 = -1;
+        let mut ups_and_downs: Vec<i32> = Vec::new();
+        let mut antisense_ups_and_downs: Vec<i32> = Vec::new();
+        let mut seen_ref_ids = BTreeSet::new();
+        while next_record(&mut record) {
+            if !flag_filters.passes(&record) || record.is_unmapped() {
+                continue;
+            }
+            let tid = record.tid();
+            if tid_to_regions[tid as usize].is_empty() {
+                continue;
+            }
+            if tid != last_tid {
+                if tid < last_tid {
+                    error!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                    panic!("BAM file appears to be unsorted. Input BAM files must be sorted by reference (i.e. by samtools sort)");
+                }
+                if last_tid != -1 {
+                    add_contig_regions(
+                        last_tid as usize,
+                        &ups_and_downs,
+                        &antisense_ups_and_downs,
+                        &reads_mapped_in_each_region,
+                        &antisense_reads_mapped_in_each_region,
+                    );
+                }
+                ups_and_downs =
+                    vec![0; header.target_len(tid as u32).expect("Corrupt BAM file?") as usize];
+                if strandedness.is_stranded() {
+                    antisense_ups_and_downs = vec![0; ups_and_downs.len()];
+                }
+                last_tid = tid;
+                seen_ref_ids.insert(tid as usize);
+            }
+
+            let is_antisense = strandedness.is_antisense(&record);
+            let pileup = match is_antisense {
+                true => &mut antisense_ups_and_downs,
+                false => &mut ups_and_downs,
+            };
+            let mut cursor: usize = record.pos() as usize;
+            for cig in record.cigar().iter() {
+                match cig {
+                    Cigar::Match(_) | Cigar::Diff(_) | Cigar::Equal(_) => {
+                        pileup[cursor] += 1;
+                        let final_pos = cursor + cig.len() as usize;
+                        if final_pos < pileup.len() {
+                            // True unless the read hits the contig end.
+                            pileup[final_pos] -= 1;
+                        }
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Del(_) | Cigar::RefSkip(_) => {
+                        cursor += cig.len() as usize;
+                    }
+                    Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {}
+                }
+            }
+
+            // Count the read only once against each entry, against the first
+            // of the entry's regions it overlaps, so that the counts of an
+            // entry's regions sum to the number of reads of the entry.
+            if !record.is_supplementary() && !record.is_secondary() {
+                num_primary_reads += 1;
+                let read_start = record.pos() as u64;
+                let read_end = cursor as u64;
+                for region_index in tid_to_regions[tid as usize].iter() {
+                    let region = &regions.regions[*region_index];
+                    if read_start < region.end
+                        && read_end > region.start
+                        && last_read_counted_in_each_entry[region.entry_index] != num_primary_reads
+                    {
+                        last_read_counted_in_each_entry[region.entry_index] = num_primary_reads;
+                        reads_mapped_in_each_entry[region.entry_index] += 1;
+                        reads_mapped_in_each_region[*region_index] += 1;
+                        if is_antisense {
+                            antisense_reads_mapped_in_each_region[*region_index] += 1;
+                        }
+                    }
+                }
+            }
+        }
+        if last_tid != -1 {
+            add_contig_regions(
+                last_tid as usize,
+                &ups_and_downs,
+                &antisense_ups_and_downs,
+                &reads_mapped_in_each_region,
+                &antisense_reads_mapped_in_each_region,
+            );
+        }
+
+        // Regions on reference sequences without any reads are unobserved
+        let mut unobserved_lengths: Vec<Vec<u64>> = vec![vec![]; regions.entry_names.len()];
+        for (tid, region_indices) in tid_to_regions.iter().enumerate() {
+            if !seen_ref_ids.contains(&tid) {
+                for region_index in region_indices {
+                    let region = &regions.regions[*region_index];
+                    unobserved_lengths[region.entry_index].push(region.end - region.start);
+                }
+            }
+        }
+
+        let mut num_mapped_reads_total: u64 = 0;
+        for (i, entry_name) in regions.entry_names.iter().enumerate() {
+            let coverages: Vec<f32> = per_entry_coverage_estimators[i]
+                .iter_mut()
+                .map(|estimator| estimator.calculate_coverage(&unobserved_lengths[i]))
+                .collect();
+            let any_nonzero_coverage = coverages.iter().any(|c| *c > 0.0);
+            if any_nonzero_coverage {
+                num_mapped_reads_total += reads_mapped_in_each_entry[i];
+            }
+            if print_zero_coverage_entries || any_nonzero_coverage {
+                coverage_taker.start_entry(i, entry_name);
+                for (coverage, estimator) in coverages
+                    .iter()
+                    .zip(per_entry_coverage_estimators[i].iter())
+                {
+                    if *coverage > 0.0 {
+                        estimator.print_coverage(coverage, coverage_taker);
+                    } else {
+                        estimator.print_zero_coverage(coverage_taker, entry_lengths[i]);
+                    }
+                }
+                coverage_taker.finish_entry();
+            }
+        }
+
+        let reads_mapped = ReadsMapped {
+            num_mapped_reads: num_mapped_reads_total as f64,
+            num_reads: bam_generated.num_detected_primary_alignments(),
+        };
+        info!(
+            "In sample '{}', found {} reads mapped to regions out of {} total ({:.*}%)",
+            stoit_name,
+            reads_mapped.num_mapped_reads,
+            reads_mapped.num_reads,
+            2,
+            reads_mapped.num_mapped_reads * 100.0 / reads_mapped.num_reads as f64
+        );
+        reads_mapped_vector.push(reads_mapped);
+
+        bam_generated.finish();
+    }
+    reads_mapped_vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::str;
+    use OutputWriter;
+
+    fn test_with_stream(expected: &str, bed_contents: &str, aggregate_by_name: bool) {
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(bed_contents.as_bytes()).unwrap();
+        bed.flush().unwrap();
+        test_regions_with_stream(
+            expected,
+            &Regions::from_bed_file(bed.path().to_str().unwrap(), aggregate_by_name),
+        );
+    }
+
+    fn test_regions_with_stream(expected: &str, regions: &Regions) {
+        test_regions_with_bam(expected, regions, "tests/data/2seqs.reads_for_seq1.bam")
+    }
+
+    fn test_regions_with_bam(expected: &str, regions: &Regions, bam_path: &str) {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    OutputWriter::generate(Some(t)),
+                );
+            region_coverage(
+                generate_named_bam_readers_from_bam_files(vec![bam_path]),
+                regions,
+                &mut coverage_taker,
+                &[CoverageEstimator::new_estimator_mean(0.0, 0, false)],
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+                Strandedness::Unstranded,
+            );
+        }
+        let mut buf = vec![];
+        std::fs::File::open(tf.path())
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(expected, str::from_utf8(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_each_region_an_entry() {
+        test_with_stream(
+            "2seqs.reads_for_seq1\tseq1:0-400\t1.085\n\
+            2seqs.reads_for_seq1\tseq1:400-800\t1.405\n\
+            2seqs.reads_for_seq1\tseq2:0-100\t0\n",
+            "seq1\t0\t400\nseq1\t400\t800\nseq2\t0\t100\n",
+            false,
+        );
+    }
+
+    #[test]
+    fn test_indexed_bam_file() {
+        let td = tempfile::TempDir::new().unwrap();
+        let bam_path = td.path().join("2seqs.reads_for_seq1.bam");
+        std::fs::copy("tests/data/2seqs.reads_for_seq1.bam", &bam_path).unwrap();
+        bam::index::build(&bam_path, None, bam::index::Type::Bai, 1).unwrap();
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(b"seq1\t0\t400\nseq1\t400\t800\nseq2\t0\t100\n")
+            .unwrap();
+        bed.flush().unwrap();
+        test_regions_with_bam(
+            "2seqs.reads_for_seq1\tseq1:0-400\t1.085\n\
+            2seqs.reads_for_seq1\tseq1:400-800\t1.405\n\
+            2seqs.reads_for_seq1\tseq2:0-100\t0\n",
+            &Regions::from_bed_file(bed.path().to_str().unwrap(), false),
+            bam_path.to_str().unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_indexed_bam_file_reads_mapped() {
+        // The BAM file has a supplementary alignment, which is not counted
+        // whether or not the BAM file is indexed.
+        let td = tempfile::TempDir::new().unwrap();
+        let bam_path = td.path().join("with_supplementary.bam");
+        std::fs::copy(
+            "tests/data/2seqs.bad_read.1.with_supplementary.bam",
+            &bam_path,
+        )
+        .unwrap();
+        let bam_path = bam_path.to_str().unwrap();
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(b"seq1\t0\t100\n").unwrap();
+        bed.flush().unwrap();
+        let regions = Regions::from_bed_file(bed.path().to_str().unwrap(), false);
+        let reads_mapped = || {
+            let mut coverage_taker = CoverageTakerType::new_cached_single_float_coverage_taker(1);
+            region_coverage(
+                generate_named_bam_readers_from_bam_files(vec![bam_path]),
+                &regions,
+                &mut coverage_taker,
+                &[CoverageEstimator::new_estimator_read_count()],
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+                Strandedness::Unstranded,
+            )
+        };
+        let unindexed = reads_mapped();
+        assert_eq!(20, unindexed[0].num_reads);
+        bam::index::build(bam_path, None, bam::index::Type::Bai, 1).unwrap();
+        assert_eq!(unindexed, reads_mapped());
+    }
+
+    #[test]
+    fn test_fetch_intervals() {
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(b"seq1\t400\t800\nseq1\t0\t500\nseq2\t0\t100\nseq1\t900\t950\n")
+            .unwrap();
+        bed.flush().unwrap();
+        let regions = Regions::from_bed_file(bed.path().to_str().unwrap(), false);
+        assert_eq!(
+            vec![(0, 0, 800), (0, 900, 950), (1, 0, 100)],
+            fetch_intervals(&[vec![0, 1, 3], vec![2]], &regions)
+        );
+    }
+
+    #[test]
+    fn test_regions_aggregated_by_name() {
+        test_with_stream(
+            "2seqs.reads_for_seq1\tgeneA\t1.0633334\n\
+            2seqs.reads_for_seq1\tgeneB\t0\n",
+            "# comment\nseq1\t0\t400\tgeneA\nseq2\t0\t100\tgeneB\nseq1\t800\t1000\tgeneA\n",
+            true,
+        );
+    }
+
+    #[test]
+    fn test_gff_features_grouped_by_attribute() {
+        let mut gff = tempfile::NamedTempFile::new().unwrap();
+        gff.write_all(
+            b"##gff-version 3\n\
+            seq1\tprodigal\tgene\t1\t1000\t.\t+\t.\tID=gene1\n\
+            seq1\tprodigal\tCDS\t1\t400\t.\t+\t0\tID=cds1;KO=K00001\n\
+            seq2\tprodigal\tCDS\t1\t100\t.\t-\t0\tID=cds2;KO=K00002\n\
+            seq1\tprodigal\tCDS\t801\t1000\t.\t+\t0\tID=cds3;KO=K00001\n\
+            seq2\tprodigal\tCDS\t201\t300\t.\t+\t0\tID=cds4\n\
+            ##FASTA\n\
+            >seq1\n",
+        )
+        .unwrap();
+        gff.flush().unwrap();
+        let regions = Regions::from_gff_file(gff.path().to_str().unwrap(), &["CDS"], "KO");
+        assert_eq!(vec!["K00001", "K00002"], regions.entry_names);
+        assert_eq!(3, regions.regions.len());
+        assert_eq!(
+            Region {
+                contig: "seq1".to_string(),
+                start: 800,
+                end: 1000,
+                reverse_strand: false,
+                entry_index: 0
+            },
+            regions.regions[1]
+        );
+        test_regions_with_stream(
+            "2seqs.reads_for_seq1\tK00001\t1.0633334\n\
+            2seqs.reads_for_seq1\tK00002\t0\n",
+            &regions,
+        );
+    }
+
+    #[test]
+    fn test_gff_overlapping_features_counted_once() {
+        // Reads overlapping both features of gene1, and the bases where they
+        // overlap, are counted once, as for a single feature over seq1.
+        let mut gff = tempfile::NamedTempFile::new().unwrap();
+        gff.write_all(
+            b"seq1\tprodigal\tCDS\t1\t500\t.\t+\t0\tgene_id \"gene1\"\n\
+            seq1\tprodigal\tCDS\t301\t1000\t.\t+\t0\tgene_id \"gene1\"\n\
+            seq1\tprodigal\tCDS\t1\t1000\t.\t+\t0\tgene_id \"gene2\"\n",
+        )
+        .unwrap();
+        gff.flush().unwrap();
+        let regions = Regions::from_gff_file(gff.path().to_str().unwrap(), &["CDS"], "gene_id");
+        assert_eq!(2, regions.regions.len());
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut coverage_taker =
+                CoverageTakerType::new_single_float_coverage_streaming_coverage_printer(
+                    OutputWriter::generate(Some(tf.path().to_str().unwrap())),
+                );
+            region_coverage(
+                generate_named_bam_readers_from_bam_files(vec![
+                    "tests/data/2seqs.reads_for_seq1.bam",
+                ]),
+                &regions,
+                &mut coverage_taker,
+                &[
+                    CoverageEstimator::new_estimator_read_count(),
+                    CoverageEstimator::new_estimator_mean(0.0, 0, false),
+                ],
+                true,
+                &FlagFilter {
+                    include_improper_pairs: true,
+                    include_secondary: false,
+                    include_supplementary: false,
+                },
+                1,
+                Strandedness::Unstranded,
+            );
+        }
+        let output = std::fs::read_to_string(tf.path()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(2, lines.len());
+        assert_eq!(
+            lines[0].replace("gene1", "gene2"),
+            lines[1],
+            "gene1 and gene2 should have the same count and mean coverage"
+        );
+    }
+
+    #[test]
+    fn test_gff_attribute_values() {
+        assert_eq!(
+            Some(vec!["g1".to_string()]),
+            gff_attribute_values("gene_id \"g1\"; transcript_id \"t1\";", "gene_id")
+        );
+        assert_eq!(
+            Some(vec!["K1".to_string(), "K2".to_string()]),
+            gff_attribute_values("ID=cds1;KO=K1,K2", "KO")
+        );
+        assert_eq!(None, gff_attribute_values("ID=cds1", "KO"));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::process;
+
+use bam_generator::{parse_mapping_program_name, MappingProgram};
+use mapping_parameters::ReadFormat;
+
+/// Read filtering thresholds of a single sample, which override those given
+/// on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SampleReadFilters {
+    pub min_percent_identity: Option<f32>,
+    pub min_aligned_percent: Option<f32>,
+    pub min_aligned_length: Option<u32>,
+}
+
+impl SampleReadFilters {
+    pub fn is_empty(&self) -> bool {
+        self.min_percent_identity.is_none()
+            && self.min_aligned_percent.is_none()
+            && self.min_aligned_length.is_none()
+    }
+}
+
+/// The reads of one sample, as described by a line of a sample sheet.
+#[derive(Debug, Clone)]
+pub struct SampleSheetEntry {
+    pub sample: String,
+    pub read_format: ReadFormat,
+    pub read1: String,
+    pub read2: Option<String>,
+    pub mapper: Option<MappingProgram>,
+    pub read_filters: SampleReadFilters,
+}
+
+/// A tab-separated file with a header line describing one sample per line,
+/// used instead of specifying reads on the command line.
+#[derive(Debug)]
+pub struct SampleSheet {
+    pub entries: Vec<SampleSheetEntry>,
+}
+
+const COLUMNS: &[&str] = &[
+    "sample",
+    "read1",
+    "read2",
+    "interleaved",
+    "single",
+    "mapper",
+    "min-read-percent-identity",
+    "min-read-aligned-percent",
+    "min-read-aligned-length",
+];
+
+impl SampleSheet {
+    /// Read the sample sheet given with --sample-sheet, if any.
+    pub fn generate_from_clap(m: &clap::ArgMatches) -> Option<SampleSheet> {
+        m.get_one::<String>("sample-sheet").map(|path| {
+            let file = File::open(path).unwrap_or_else(|e| {
+                error!("Failed to open sample sheet {}: {}", path, e);
+                process::exit(1);
+            });
+            SampleSheet::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                error!("Failed to parse sample sheet {}: {}", path, e);
+                process::exit(1);
+            })
+        })
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<SampleSheet, String> {
+        let mut lines = reader.lines().enumerate();
+        let header = match lines.next() {
+            Some((_, Ok(line))) => line,
+            Some((_, Err(e))) => return Err(e.to_string()),
+            None => return Err("The sample sheet is empty".to_string()),
+        };
+        let columns: Vec<&str> = header.trim_end_matches('\r').split('\t').collect();
+        let column_index = |name: &str| columns.iter().position(|c| *c == name);
+        for column in columns.iter() {
+            if !COLUMNS.contains(column) {
+                return Err(format!("Unexpected column '{}' in header line", column));
+            }
+        }
+        let sample_column = match column_index("sample") {
+            Some(i) => i,
+            None => return Err("No 'sample' column found in header line".to_string()),
+        };
+        let read1_column = column_index("read1");
+        let read2_column = column_index("read2");
+        let interleaved_column = column_index("interleaved");
+        let single_column = column_index("single");
+        let mapper_column = column_index("mapper");
+        let percent_identity_column = column_index("min-read-percent-identity");
+        let aligned_percent_column = column_index("min-read-aligned-percent");
+        let aligned_length_column = column_index("min-read-aligned-length");
+        if read1_column.is_some() != read2_column.is_some() {
+            return Err("The 'read1' and 'read2' columns must be given together".to_string());
+        }
+
+        let mut entries = vec![];
+        let mut seen_samples = HashSet::new();
+        for (line_index, line) in lines {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let line_number = line_index + 1;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != columns.len() {
+                return Err(format!(
+                    "Line {} has {} fields, but the header line has {}",
+                    line_number,
+                    fields.len(),
+                    columns.len()
+                ));
+            }
+            let field = |column: Option<usize>| match column.map(|i| fields[i]) {
+                Some("") | None => None,
+                Some(f) => Some(f.to_string()),
+            };
+
+            let sample = fields[sample_column].to_string();
+            if sample.is_empty() {
+                return Err(format!("Line {} has no sample name", line_number));
+            }
+            if !seen_samples.insert(sample.clone()) {
+                return Err(format!("Sample '{}' is specified more than once", sample));
+            }
+
+            let (read_format, read1, read2) = match (
+                field(read1_column),
+                field(read2_column),
+                field(interleaved_column),
+                field(single_column),
+            ) {
+                (Some(r1), Some(r2), None, None) => (ReadFormat::Coupled, r1, Some(r2)),
+                (None, None, Some(i), None) => (ReadFormat::Interleaved, i, None),
+                (None, None, None, Some(s)) => (ReadFormat::Single, s, None),
+                _ => {
+                    return Err(format!(
+                        "Sample '{}' must have reads in either both the 'read1' and 'read2' \
+                        columns, the 'interleaved' column or the 'single' column",
+                        sample
+                    ))
+                }
+            };
+
+            let mapper = match field(mapper_column) {
+                Some(name) => match parse_mapping_program_name(&name) {
+                    Some(mapper) => Some(mapper),
+                    None => {
+                        return Err(format!("Unknown mapper '{}' for sample '{}'", name, sample))
+                    }
+                },
+                None => None,
+            };
+
+            let percentage = |column: Option<usize>| match field(column) {
+                Some(value) => parse_percentage(&value).map(Some).ok_or_else(|| {
+                    format!("Invalid percentage '{}' for sample '{}'", value, sample)
+                }),
+                None => Ok(None),
+            };
+            let read_filters = SampleReadFilters {
+                min_percent_identity: percentage(percent_identity_column)?,
+                min_aligned_percent: percentage(aligned_percent_column)?,
+                min_aligned_length: match field(aligned_length_column) {
+                    Some(value) => match value.parse::<u32>() {
+                        Ok(length) => Some(length),
+                        Err(_) => {
+                            return Err(format!(
+                                "Invalid aligned length '{}' for sample '{}'",
+                                value, sample
+                            ))
+                        }
+                    },
+                    None => None,
+                },
+            };
+
+            entries.push(SampleSheetEntry {
+                sample,
+                read_format,
+                read1,
+                read2,
+                mapper,
+                read_filters,
+            });
+        }
+        if entries.is_empty() {
+            return Err("No samples were specified".to_string());
+        }
+        Ok(SampleSheet { entries })
+    }
+
+    /// Whether any sample has its own read filtering thresholds.
+    pub fn has_read_filters(&self) -> bool {
+        self.entries.iter().any(|e| !e.read_filters.is_empty())
+    }
+}
+
+/// Parse a percentage given either as a fraction or out of 100, as for the
+/// equivalent command line options.
+fn parse_percentage(value: &str) -> Option<f32> {
+    match value.parse::<f32>() {
+        Ok(p) if (1.0..=100.0).contains(&p) => Some(p / 100.0),
+        Ok(p) if (0.0..1.0).contains(&p) => Some(p),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_sheet_parsing() {
+        let sheet = SampleSheet::from_reader(
+            "sample\tread1\tread2\tsingle\tmapper\n\
+            s1\ta_1.fq\ta_2.fq\t\t\n\
+            s2\t\t\tb.fq\tminimap2-ont\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(2, sheet.entries.len());
+        assert_eq!("s1", sheet.entries[0].sample);
+        assert!(matches!(sheet.entries[0].read_format, ReadFormat::Coupled));
+        assert_eq!("a_1.fq", sheet.entries[0].read1);
+        assert_eq!(Some("a_2.fq".to_string()), sheet.entries[0].read2);
+        assert!(sheet.entries[0].mapper.is_none());
+        assert!(matches!(sheet.entries[1].read_format, ReadFormat::Single));
+        assert_eq!("b.fq", sheet.entries[1].read1);
+        assert!(matches!(
+            sheet.entries[1].mapper,
+            Some(MappingProgram::MINIMAP2_ONT)
+        ));
+        assert!(!sheet.has_read_filters());
+    }
+
+    #[test]
+    fn test_sample_sheet_read_filters() {
+        let sheet = SampleSheet::from_reader(
+            "sample\tsingle\tmin-read-percent-identity\tmin-read-aligned-length\n\
+            s1\ta.fq\t95\t\n\
+            s2\tb.fq\t0.8\t100\n\
+            s3\tc.fq\t\t\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(sheet.has_read_filters());
+        assert_eq!(
+            SampleReadFilters {
+                min_percent_identity: Some(0.95),
+                min_aligned_percent: None,
+                min_aligned_length: None,
+            },
+            sheet.entries[0].read_filters
+        );
+        assert_eq!(
+            SampleReadFilters {
+                min_percent_identity: Some(0.8),
+                min_aligned_percent: None,
+                min_aligned_length: Some(100),
+            },
+            sheet.entries[1].read_filters
+        );
+        assert!(sheet.entries[2].read_filters.is_empty());
+        assert!(SampleSheet::from_reader(
+            "sample\tsingle\tmin-read-percent-identity\ns1\ta.fq\t101\n".as_bytes()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sample_sheet_errors() {
+        // Duplicate sample names
+        assert!(
+            SampleSheet::from_reader("sample\tsingle\ns1\ta.fq\ns1\tb.fq\n".as_bytes()).is_err()
+        );
+        // Reads in more than one format
+        assert!(SampleSheet::from_reader(
+            "sample\tsingle\tinterleaved\ns1\ta.fq\tb.fq\n".as_bytes()
+        )
+        .is_err());
+        // read1 without read2
+        assert!(SampleSheet::from_reader("sample\tread1\ns1\ta.fq\n".as_bytes()).is_err());
+        // Unknown mapper
+        assert!(
+            SampleSheet::from_reader("sample\tsingle\tmapper\ns1\ta.fq\tbowtie\n".as_bytes())
+                .is_err()
+        );
+    }
+}
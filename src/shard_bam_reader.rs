@@ -313,6 +313,10 @@ impl<'a, T> NamedBamReaderGenerator<ShardedBamReader> for ShardedBamReaderGenera
 where
     T: GenomeExclusion,
 {
+    fn stoit_name(&self) -> &str {
+        &(self.stoit_name)
+    }
+
     fn start(self) -> ShardedBamReader {
         let mut new_header = bam::header::Header::new();
         let mut tid_offsets: Vec<i32> = vec![];
@@ -0,0 +1,140 @@
+use rust_htslib::bam::record::Record;
+
+use mosdepth_genome_coverage_estimators::*;
+
+/// How the reads of a stranded RNA-seq library relate to the strand that was
+/// transcribed. For Forward libraries the first read of each pair (or each
+/// single read) has the same orientation as the transcript, and for Reverse
+/// libraries (e.g. dUTP) it has the opposite orientation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strandedness {
+    Unstranded,
+    Forward,
+    Reverse,
+}
+
+impl Strandedness {
+    pub fn is_stranded(&self) -> bool {
+        *self != Strandedness::Unstranded
+    }
+
+    /// Whether a mapped record derives from a transcript of the reverse
+    /// strand of the reference. Always false for unstranded libraries.
+    pub fn is_antisense(&self, record: &Record) -> bool {
+        // Orientation of the first read of the fragment, inferred from the
+        // mate if this record is the second read.
+        let first_read_reverse = if record.is_paired() && record.is_last_in_template() {
+            !record.is_reverse()
+        } else {
+            record.is_reverse()
+        };
+        match self {
+            Strandedness::Unstranded => false,
+            Strandedness::Forward => first_read_reverse,
+            Strandedness::Reverse => !first_read_reverse,
+        }
+    }
+}
+
+/// Add the pileup of a contig to each coverage estimator. For stranded
+/// libraries, the first half of the estimators are given the pileup of sense
+/// reads, and the second half the pileup of antisense reads.
+/// total_mismatches is the count over reads of both strands, of which
+/// num_antisense_mismatches are from antisense reads.
+pub fn add_contig_to_estimators(
+    coverage_estimators: &mut [CoverageEstimator],
+    strandedness: Strandedness,
+    ups_and_downs: &[i32],
+    antisense_ups_and_downs: &[i32],
+    num_mapped_reads: u64,
+    num_antisense_mapped_reads: u64,
+    total_mismatches: u64,
+    num_antisense_mismatches: u64,
+) {
+    if strandedness.is_stranded() {
+        let num_sense_estimators = coverage_estimators.len() / 2;
+        let (sense_estimators, antisense_estimators) =
+            coverage_estimators.split_at_mut(num_sense_estimators);
+        for estimator in sense_estimators.iter_mut() {
+            estimator.add_contig(
+                ups_and_downs,
+                (num_mapped_reads - num_antisense_mapped_reads) as f64,
+                total_mismatches.saturating_sub(num_antisense_mismatches),
+            );
+        }
+        for estimator in antisense_estimators.iter_mut() {
+            estimator.add_contig(
+                antisense_ups_and_downs,
+                num_antisense_mapped_reads as f64,
+                num_antisense_mismatches,
+            );
+        }
+    } else {
+        for estimator in coverage_estimators.iter_mut() {
+            estimator.add_contig(ups_and_downs, num_mapped_reads as f64, total_mismatches);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_with_flags(flags: u16) -> Record {
+        let mut record = Record::new();
+        record.set_flags(flags);
+        record
+    }
+
+    #[test]
+    fn test_is_antisense() {
+        // read 1 forward, read 1 reverse, read 2 forward, read 2 reverse, single reverse
+        let records = vec![
+            record_with_flags(0x1 | 0x40),
+            record_with_flags(0x1 | 0x40 | 0x10),
+            record_with_flags(0x1 | 0x80),
+            record_with_flags(0x1 | 0x80 | 0x10),
+            record_with_flags(0x10),
+        ];
+        let antisense =
+            |s: Strandedness| -> Vec<bool> { records.iter().map(|r| s.is_antisense(r)).collect() };
+        assert_eq!(
+            vec![false, false, false, false, false],
+            antisense(Strandedness::Unstranded)
+        );
+        assert_eq!(
+            vec![false, true, true, false, true],
+            antisense(Strandedness::Forward)
+        );
+        assert_eq!(
+            vec![true, false, false, true, false],
+            antisense(Strandedness::Reverse)
+        );
+    }
+
+    #[test]
+    fn test_add_contig_to_estimators_splits_mismatches() {
+        let mut estimators = vec![
+            CoverageEstimator::new_estimator_mean(0.0, 0, true),
+            CoverageEstimator::new_estimator_mean(0.0, 0, true),
+        ];
+        for estimator in estimators.iter_mut() {
+            estimator.setup();
+        }
+        add_contig_to_estimators(
+            &mut estimators,
+            Strandedness::Forward,
+            &[2, 0, 0, 0],
+            &[1, 0, 0, 0],
+            3,
+            1,
+            3,
+            1,
+        );
+        let coverages: Vec<f32> = estimators
+            .iter_mut()
+            .map(|estimator| estimator.calculate_coverage(&[]))
+            .collect();
+        assert_eq!(vec![1.5, 0.75], coverages);
+    }
+}
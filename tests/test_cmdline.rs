@@ -63,6 +63,28 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_filter_pair_orientation() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t = tf.path().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "--pair-orientation",
+                "rf",
+                "-b",
+                "tests/data/2seqs.bad_read.1.bam",
+                "-o",
+                t,
+            ])
+            .succeeds()
+            .unwrap();
+        Assert::command(&["samtools", "view", t])
+            .stdout()
+            .doesnt_contain("1\t99\tseq1")
+            .unwrap();
+    }
+
     #[test]
     fn test_contig_tempdir_index_creation() {
         let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
@@ -209,6 +231,69 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_sample_sheet_input() {
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            tf,
+            "sample\tinterleaved\nbad_sample\ttests/data/bad_reads.interleaved.fq"
+        )
+        .unwrap();
+        tf.flush().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--contig-end-exclusion",
+                "0",
+                "-r",
+                "tests/data/2seqs.fasta",
+                "--output-format",
+                "sparse",
+                "--sample-sheet",
+                tf.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .contains(
+                "2seqs.fasta/bad_sample\tseq1\t0.899\n\
+                 2seqs.fasta/bad_sample\tseq2\t0",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sample_sheet_mixed_mappers() {
+        let mut tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            tf,
+            "sample\tinterleaved\tmapper\n\
+            minimap2_sample\ttests/data/bad_reads.interleaved.fq\t\n\
+            bwa_sample\ttests/data/bad_reads.interleaved.fq\tbwa-mem"
+        )
+        .unwrap();
+        tf.flush().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--contig-end-exclusion",
+                "0",
+                "-r",
+                "tests/data/2seqs.fasta",
+                "--output-format",
+                "sparse",
+                "--sample-sheet",
+                tf.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .contains(
+                "2seqs.fasta/minimap2_sample\tseq1\t0.899\n\
+                 2seqs.fasta/minimap2_sample\tseq2\t0\n\
+                 2seqs.fasta/bwa_sample\tseq1\t",
+            )
+            .unwrap();
+    }
+
     #[test]
     fn test_filtered_interleaved_input() {
         Assert::main_binary()
@@ -328,6 +413,75 @@ mod tests {
             .is_file());
     }
 
+    #[test]
+    fn test_per_base_depth_output() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth_dir = td.path().join("depths");
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--bam-files",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--per-base-depth-output",
+                depth_dir.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+        let mut contents = String::new();
+        std::fs::File::open(depth_dir.join("2seqs.reads_for_seq1.bedgraph"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(contents.starts_with(
+            "track type=bedGraph name=\"2seqs.reads_for_seq1\"\n\
+            seq1\t0\t29\t0\n\
+            seq1\t29\t129\t1\n\
+            seq1\t129\t221\t0\n\
+            seq1\t221\t285\t1\n\
+            seq1\t285\t301\t2\n"
+        ));
+        assert_eq!(26, contents.lines().count());
+    }
+
+    #[test]
+    fn test_per_base_depth_compression() {
+        let td = tempfile::TempDir::new().unwrap();
+        let depth_dir = td.path().join("depths");
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--bam-files",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--per-base-depth-output",
+                depth_dir.to_str().unwrap(),
+                "--per-base-depth-compression",
+                "gz",
+            ])
+            .succeeds()
+            .unwrap();
+        // gzip magic number
+        let contents = std::fs::read(depth_dir.join("2seqs.reads_for_seq1.bedgraph.gz")).unwrap();
+        assert_eq!(&[0x1f, 0x8b], &contents[..2]);
+
+        // Compression does not follow the output file
+        let uncompressed_dir = td.path().join("uncompressed");
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--bam-files",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--per-base-depth-output",
+                uncompressed_dir.to_str().unwrap(),
+                "-o",
+                td.path().join("coverage.tsv.gz").to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+        assert!(uncompressed_dir
+            .join("2seqs.reads_for_seq1.bedgraph")
+            .is_file());
+    }
+
     #[test]
     fn test_non_existant_cache_bam_files() {
         Assert::main_binary()
@@ -437,6 +591,31 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_genome_consensus_ani_mapping_reads() {
+        // minimap2 must be run with --MD for the reference bases to be known
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--output-format",
+                "sparse",
+                "--reference",
+                "tests/data/7seqs.fna",
+                "-s",
+                "~",
+                "-m",
+                "consensus_ani",
+                "snv_count",
+            ])
+            .succeeds()
+            .stdout()
+            .contains("Sample\tGenome\tConsensus ANI\tSNV Count\n")
+            .unwrap();
+    }
+
     #[test]
     fn test_relative_abundance_and_mean() {
         Assert::main_binary()
@@ -1696,6 +1875,37 @@ genome6	26.697144
             .unwrap();
     }
 
+    #[test]
+    fn test_concurrent_mappings() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "mean",
+                "count",
+                "-p",
+                "minimap2-ont",
+                "--single",
+                "tests/data/ont.reads.fq.gz",
+                "tests/data/ont.reads.fq.gz",
+                "tests/data/ont.reads.fq.gz",
+                "-r",
+                "tests/data/ont.ref.fna",
+                "-t",
+                "2",
+                "--concurrent-mappings",
+                "2",
+            ])
+            .succeeds()
+            .stdout()
+            .is(
+                "Contig	ont.ref.fna/ont.reads.fq.gz Mean	ont.ref.fna/ont.reads.fq.gz Read Count	ont.ref.fna/ont.reads.fq.gz Mean	ont.ref.fna/ont.reads.fq.gz Read Count	ont.ref.fna/ont.reads.fq.gz Mean	ont.ref.fna/ont.reads.fq.gz Read Count\n\
+                ctg4	0.024660854	5	0.024660854	5	0.024660854	5\n\
+                ctg5	0.0041760243	3	0.0041760243	3	0.0041760243	3\n\
+                ctg6	0.0021053297	1	0.0021053297	1	0.0021053297	1\n")
+            .unwrap();
+    }
+
     #[test]
     fn test_pb_two_samples() {
         Assert::main_binary()
@@ -2383,6 +2593,397 @@ genome6~random_sequence_length_11003	0	0	0
         )
     }
 
+    #[test]
+    fn test_contig_provenance() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output = td.path().join("coverage.tsv");
+        let output = output.to_str().unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-r",
+                "tests/data/2seqs.fasta",
+                "--interleaved",
+                "tests/data/bad_reads.interleaved.fq",
+                "-o",
+                output,
+            ])
+            .succeeds()
+            .unwrap();
+
+        let provenance = std::fs::read_to_string(format!("{}.provenance.json", output)).unwrap();
+        assert!(provenance.contains(&format!(
+            "\"coverm_version\":\"{}\"",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(provenance.contains("\"interleaved\":[\"tests/data/bad_reads.interleaved.fq\"]"));
+        assert!(provenance.contains("\"samtools\":"));
+        assert!(provenance.contains("\"minimap2\":"));
+        assert!(provenance.contains("{\"path\":\"tests/data/2seqs.fasta\",\"sha256\":\""));
+        assert!(provenance
+            .contains("{\"sample\":\"2seqs.fasta/bad_reads.interleaved.fq\",\"mapped_reads\":"));
+    }
+
+    #[test]
+    fn test_contig_provenance_of_index_reference() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output = td.path().join("coverage.tsv");
+        let output = output.to_str().unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--mapper",
+                "bwa-mem",
+                "-r",
+                "tests/data/7seqs.fna.bwa1",
+                "--coupled",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "-o",
+                output,
+            ])
+            .succeeds()
+            .unwrap();
+
+        let provenance = std::fs::read_to_string(format!("{}.provenance.json", output)).unwrap();
+        assert!(provenance.contains(
+            "{\"path\":\"tests/data/7seqs.fna.bwa1\",\"sha256\":null,\"index_files\":\
+            [{\"path\":\"tests/data/7seqs.fna.bwa1.amb\",\"sha256\":\""
+        ));
+    }
+
+    #[test]
+    fn test_filter_provenance() {
+        let td = tempfile::TempDir::new().unwrap();
+        let output = td.path().join("filtered.bam");
+        let output = output.to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "filter",
+                "-b",
+                "tests/data/2seqs.bad_read.1.bam",
+                "-o",
+                output,
+            ])
+            .succeeds()
+            .unwrap();
+        let provenance = std::fs::read_to_string(format!("{}.provenance.json", output)).unwrap();
+        assert!(provenance.contains("{\"sample\":\"tests/data/2seqs.bad_read.1.bam\""));
+    }
+
+    #[test]
+    fn test_resume_interrupted_run() {
+        let td = tempfile::TempDir::new().unwrap();
+        let second = td.path().join("second.bam");
+        let second = second.to_str().unwrap();
+        let fresh = td.path().join("fresh.tsv");
+        let fresh = fresh.to_str().unwrap();
+        let resumed = td.path().join("resumed.tsv");
+        let resumed = resumed.to_str().unwrap();
+        let bam = std::fs::read("tests/data/7seqs.reads_for_seq1_and_seq2.bam").unwrap();
+        std::fs::write(second, &bam).unwrap();
+        let args = |output: &str| {
+            vec![
+                "contig".to_string(),
+                "-b".to_string(),
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam".to_string(),
+                second.to_string(),
+                "-o".to_string(),
+                output.to_string(),
+                "--resume".to_string(),
+            ]
+        };
+
+        Assert::main_binary()
+            .with_args(&args(fresh))
+            .succeeds()
+            .unwrap();
+        let expected = std::fs::read_to_string(fresh).unwrap();
+
+        // Interrupt the run by truncating the second BAM file after its
+        // header block, so only the first sample is checkpointed.
+        std::fs::write(second, &bam[..600]).unwrap();
+        Assert::main_binary()
+            .with_args(&args(resumed))
+            .fails()
+            .unwrap();
+        assert!(std::path::Path::new(&format!("{}.checkpoint/sample_0.tsv", resumed)).exists());
+
+        std::fs::write(second, &bam).unwrap();
+        Assert::main_binary()
+            .with_args(&args(resumed))
+            .succeeds()
+            .unwrap();
+        assert_eq!(expected, std::fs::read_to_string(resumed).unwrap());
+        assert!(!std::path::Path::new(&format!("{}.checkpoint", resumed)).exists());
+    }
+
+    #[test]
+    fn test_resume_discards_checkpoint_with_different_parameters() {
+        let td = tempfile::TempDir::new().unwrap();
+        let fresh = td.path().join("fresh.tsv");
+        let fresh = fresh.to_str().unwrap();
+        let resumed = td.path().join("resumed.tsv");
+        let resumed = resumed.to_str().unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "-o",
+                fresh,
+            ])
+            .succeeds()
+            .unwrap();
+
+        // Pre-seed a checkpoint as if written by a run with other parameters
+        let checkpoint = format!("{}.checkpoint", resumed);
+        std::fs::create_dir(&checkpoint).unwrap();
+        std::fs::write(format!("{}/parameters", checkpoint), "fingerprint\tstale\n").unwrap();
+        std::fs::write(
+            format!("{}/sample_0.tsv", checkpoint),
+            "stoit\t7seqs.reads_for_seq1_and_seq2\t0\t0\n\
+            entry\t0\tgenome1~random_sequence_length_11000\n\
+            coverage\t999\n\
+            finish_entry\n",
+        )
+        .unwrap();
+
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "-o",
+                resumed,
+                "--resume",
+            ])
+            .succeeds()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(fresh).unwrap(),
+            std::fs::read_to_string(resumed).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let td = tempfile::TempDir::new().unwrap();
+        let copy = td.path().join("copy.bam");
+        let copy = copy.to_str().unwrap();
+        std::fs::copy("tests/data/7seqs.reads_for_seq1_and_seq2.bam", copy).unwrap();
+        let output = |name: &str| td.path().join(name).to_str().unwrap().to_string();
+        let genome = |bam_files: &[&str], output: &str| {
+            let mut args = vec![
+                "genome",
+                "-s",
+                "~",
+                "-m",
+                "relative_abundance",
+                "mean",
+                "rpkm",
+                "tpm",
+                "--output-format",
+                "sparse",
+                "-o",
+                output,
+                "-b",
+            ];
+            args.extend(bam_files);
+            Assert::main_binary().with_args(&args).succeeds().unwrap();
+        };
+        genome(
+            &["tests/data/7seqs.reads_for_seq1_and_seq2.bam", copy],
+            &output("both.tsv"),
+        );
+        genome(
+            &["tests/data/7seqs.reads_for_seq1_and_seq2.bam"],
+            &output("first.tsv"),
+        );
+        genome(&[copy], &output("second.tsv"));
+
+        Assert::main_binary()
+            .with_args(&[
+                "merge",
+                "-i",
+                &output("first.tsv"),
+                &output("second.tsv"),
+                "--output-format",
+                "sparse",
+                "-o",
+                &output("merged.tsv"),
+            ])
+            .succeeds()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output("both.tsv")).unwrap(),
+            std::fs::read_to_string(output("merged.tsv")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_biom_json_output() {
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-s",
+                "~",
+                "-m",
+                "mean",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "biom-json",
+            ])
+            .succeeds()
+            .stdout()
+            .contains("\"format\":\"Biological Observation Matrix 1.0.0\"")
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "hdf5"))]
+    fn test_biom_output_requires_hdf5() {
+        let td = tempfile::TempDir::new().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-s",
+                "~",
+                "-m",
+                "mean",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "biom",
+                "-o",
+                td.path().join("table.biom").to_str().unwrap(),
+            ])
+            .fails()
+            .stderr()
+            .contains("--features hdf5")
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "hdf5")]
+    fn test_biom_output() {
+        let td = tempfile::TempDir::new().unwrap();
+        let path = td.path().join("table.biom");
+        Assert::main_binary()
+            .with_args(&[
+                "genome",
+                "-s",
+                "~",
+                "-m",
+                "mean",
+                "-b",
+                "tests/data/7seqs.reads_for_seq1_and_seq2.bam",
+                "--output-format",
+                "biom",
+                "-o",
+                path.to_str().unwrap(),
+            ])
+            .succeeds()
+            .unwrap();
+        // HDF5 file signature
+        assert_eq!(b"\x89HDF\r\n\x1a\n", &std::fs::read(&path).unwrap()[..8]);
+    }
+
+    #[test]
+    fn test_vamb_prints_zero_coverage_contigs() {
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-m",
+                "vamb",
+                "--no-zeros",
+                "-b",
+                "tests/data/2seqs.reads_for_seq1.bam",
+            ])
+            .succeeds()
+            .stdout()
+            .contains("\nseq2\t0\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_regions_shorter_than_contig_end_exclusion() {
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(b"seq1\t0\t400\nseq1\t400\t500\n").unwrap();
+        bed.flush().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--output-format",
+                "sparse",
+                "-b",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--regions",
+                bed.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("Sample\tRegion\tMean\n\
+                2seqs.reads_for_seq1\tseq1:0-400\t1.085\n\
+                2seqs.reads_for_seq1\tseq1:400-500\t2.4\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_annotation_features_shorter_than_contig_end_exclusion() {
+        let mut gff = tempfile::NamedTempFile::new().unwrap();
+        gff.write_all(
+            b"##gff-version 3\n\
+            seq1\tprodigal\tCDS\t1\t400\t.\t+\t0\tID=cds1\n\
+            seq1\tprodigal\tCDS\t401\t500\t.\t+\t0\tID=cds2\n",
+        )
+        .unwrap();
+        gff.flush().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--output-format",
+                "sparse",
+                "-m",
+                "mean",
+                "median",
+                "length",
+                "-b",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--annotation",
+                gff.path().to_str().unwrap(),
+            ])
+            .succeeds()
+            .stdout()
+            .is("Sample\tFeature\tMean\tMedian\tLength\n\
+                2seqs.reads_for_seq1\tcds1\t1.085\t1\t400\n\
+                2seqs.reads_for_seq1\tcds2\t2.4\t2\t100\n")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_regions_with_contig_end_exclusion() {
+        let mut bed = tempfile::NamedTempFile::new().unwrap();
+        bed.write_all(b"seq1\t0\t400\n").unwrap();
+        bed.flush().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "-b",
+                "tests/data/2seqs.reads_for_seq1.bam",
+                "--regions",
+                bed.path().to_str().unwrap(),
+                "--contig-end-exclusion",
+                "10",
+            ])
+            .fails()
+            .unwrap();
+    }
+
     #[test]
     fn test_autoconcatenation_with_clashing() {
         Assert::main_binary()
@@ -2808,6 +3409,82 @@ genome6~random_sequence_length_11003	0	0	0
             .unwrap();
     }
 
+    #[test]
+    fn test_contig_bowtie2() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t_full = tf.path().to_str().unwrap();
+        std::fs::copy("tests/data/7seqs.fna", t_full).unwrap();
+        let t = tf.path().file_name().unwrap().to_str().unwrap();
+        Assert::main_binary()
+            .with_args(&[
+                "contig",
+                "--mapper",
+                "bowtie2",
+                "--output-format",
+                "sparse",
+                "--min-read-percent-identity-pair",
+                "0.95",
+                "--contig-end-exclusion",
+                "0",
+                "-r",
+                t_full,
+                "-1",
+                "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                "-2",
+                "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                "--proper-pairs-only",
+            ])
+            .succeeds()
+            .stdout()
+            .contains(
+                format!(
+                    "{}/reads_for_seq1_and_seq2.1.fq.gz\tgenome2~seq1\t1.2
+{}/reads_for_seq1_and_seq2.1.fq.gz\tgenome3~random_sequence_length_11001\t0
+{}/reads_for_seq1_and_seq2.1.fq.gz\tgenome4~random_sequence_length_11002\t0
+{}/reads_for_seq1_and_seq2.1.fq.gz\tgenome5~seq2\t1.2",
+                    t, t, t, t
+                )
+                .as_str(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_index_cache_directory() {
+        let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();
+        let t_full = tf.path().to_str().unwrap();
+        std::fs::copy("tests/data/7seqs.fna", t_full).unwrap();
+        let t = tf.path().file_name().unwrap().to_str().unwrap();
+        let cache = tempfile::TempDir::new().unwrap();
+        for _ in 0..2 {
+            Assert::main_binary()
+                .with_args(&[
+                    "contig",
+                    "--output-format",
+                    "sparse",
+                    "--contig-end-exclusion",
+                    "0",
+                    "-r",
+                    t_full,
+                    "--index-cache-directory",
+                    cache.path().to_str().unwrap(),
+                    "-1",
+                    "tests/data/reads_for_seq1_and_seq2.1.fq.gz",
+                    "-2",
+                    "tests/data/reads_for_seq1_and_seq2.2.fq.gz",
+                ])
+                .succeeds()
+                .stdout()
+                .contains(
+                    format!("{}/reads_for_seq1_and_seq2.1.fq.gz\tgenome2~seq1\t1.2", t).as_str(),
+                )
+                .unwrap();
+        }
+        // The second run reuses the index cached by the first
+        let entries: Vec<_> = std::fs::read_dir(cache.path()).unwrap().collect();
+        assert_eq!(1, entries.len());
+    }
+
     #[test]
     fn test_genome_strobealign() {
         let tf: tempfile::NamedTempFile = tempfile::NamedTempFile::new().unwrap();